@@ -1,3 +1,16 @@
+DROP TABLE IF EXISTS calls;
+DROP TABLE IF EXISTS conversations;
+DROP TABLE IF EXISTS user_groups;
+DROP TABLE IF EXISTS prekeys;
+DROP TABLE IF EXISTS devices;
+DROP TABLE IF EXISTS public_keys;
+DROP TABLE IF EXISTS legal_holds;
+DROP TABLE IF EXISTS incoming_webhooks;
+DROP TABLE IF EXISTS messages;
+DROP TABLE IF EXISTS dead_letters;
+DROP TABLE IF EXISTS webhook_deliveries;
+DROP TABLE IF EXISTS webhooks;
+DROP TABLE IF EXISTS bots;
 DROP TABLE IF EXISTS uploads;
 DROP TABLE IF EXISTS accounts;
 DROP TABLE IF EXISTS comments;
@@ -5,7 +5,10 @@ CREATE TABLE users (
   user_id SERIAL PRIMARY KEY,
   first_name VARCHAR NOT NULL,
   last_name VARCHAR NOT NULL,
-  email VARCHAR NOT NULL
+  email VARCHAR NOT NULL,
+  /* Whether the user agrees to their messages being used as training data */
+  consent BOOLEAN NOT NULL DEFAULT 'f',
+  consent_updated TIMESTAMPTZ
 )
 
 CREATE TABLE posts (
@@ -49,4 +52,169 @@ CREATE TABLE uploads (
   file_name VARCHAR NOT NULL,
   user_id INTEGER NOT NULL,
   FOREIGN KEY (user_id) REFERENCES users(user_id)
-)
\ No newline at end of file
+)
+
+/* Bot accounts.  Bots log in with an API token, only its hash is kept here */
+CREATE TABLE bots (
+  bot_id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL UNIQUE,
+  owner VARCHAR NOT NULL,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  rooms TEXT[] NOT NULL DEFAULT '{}',
+  created_on TIMESTAMPTZ NOT NULL
+)
+
+/* Outgoing webhooks.  room is NULL for a webhook that gets events from every room, and only
+   webhooks with direct_messages set get messages that have no room */
+CREATE TABLE webhooks (
+  webhook_id SERIAL PRIMARY KEY,
+  url VARCHAR NOT NULL,
+  room VARCHAR,
+  events TEXT[] NOT NULL,
+  direct_messages BOOLEAN NOT NULL DEFAULT 'f',
+  secret VARCHAR NOT NULL,
+  created_by VARCHAR NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL
+)
+
+/* One row per attempt to deliver an event to a webhook */
+CREATE TABLE webhook_deliveries (
+  attempt_id SERIAL PRIMARY KEY,
+  webhook_id INTEGER NOT NULL,
+  delivery VARCHAR NOT NULL,
+  event VARCHAR NOT NULL,
+  attempt INTEGER NOT NULL,
+  status INTEGER,
+  error TEXT,
+  sent_on TIMESTAMPTZ NOT NULL,
+  FOREIGN KEY (webhook_id) REFERENCES webhooks(webhook_id) ON DELETE CASCADE
+)
+
+/* Deliveries that failed every attempt */
+CREATE TABLE dead_letters (
+  letter_id SERIAL PRIMARY KEY,
+  webhook_id INTEGER NOT NULL,
+  delivery VARCHAR NOT NULL,
+  event VARCHAR NOT NULL,
+  payload TEXT NOT NULL,
+  last_error TEXT NOT NULL,
+  attempts INTEGER NOT NULL,
+  failed_on TIMESTAMPTZ NOT NULL,
+  FOREIGN KEY (webhook_id) REFERENCES webhooks(webhook_id) ON DELETE CASCADE
+)
+
+/* Chat messages.  room is NULL for messages in the lobby and for direct messages */
+CREATE TABLE messages (
+  message_id BIGSERIAL PRIMARY KEY,
+  sender VARCHAR NOT NULL,
+  recipients TEXT[] NOT NULL,
+  /* Everybody it was sent to, with the addresses in recipients (eg @room) expanded */
+  delivered_to TEXT[] NOT NULL DEFAULT '{}',
+  room VARCHAR,
+  body TEXT NOT NULL,
+  bot BOOLEAN NOT NULL DEFAULT 'f',
+  encrypted BOOLEAN NOT NULL DEFAULT 'f',
+  annotations JSONB NOT NULL DEFAULT '{}',
+  sent_on TIMESTAMPTZ NOT NULL,
+  /* The direct message conversation it is in, if any */
+  conversation_id INTEGER
+)
+
+/* Tokens that let other tools post into a room.  Only a hash of the token is kept */
+CREATE TABLE incoming_webhooks (
+  hook_id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  room VARCHAR NOT NULL,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  created_by VARCHAR NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL
+)
+
+/* Conversations that retention must never purge.  Either room is set, members has the people
+   whose direct messages are held, or conversation_id is the conversation that is held */
+CREATE TABLE legal_holds (
+  hold_id SERIAL PRIMARY KEY,
+  room VARCHAR,
+  members TEXT[] NOT NULL DEFAULT '{}',
+  conversation_id INTEGER,
+  reason TEXT NOT NULL,
+  created_by VARCHAR NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL
+)
+
+/* Public keys people can log in with instead of Google.  A revoked key is kept, with the time it
+   was revoked */
+CREATE TABLE public_keys (
+  key_id SERIAL PRIMARY KEY,
+  username VARCHAR NOT NULL,
+  algorithm VARCHAR NOT NULL,
+  public_key TEXT NOT NULL,
+  fingerprint VARCHAR NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL,
+  revoked_on TIMESTAMPTZ,
+  CHECK (algorithm in ('rsa', 'ed25519'))
+)
+
+CREATE UNIQUE INDEX public_keys_active_fingerprint ON public_keys (fingerprint)
+  WHERE revoked_on IS NULL
+
+/* The devices people use for end to end encrypted messages, with their public identity key and
+   signed pre-key */
+CREATE TABLE devices (
+  device_id SERIAL PRIMARY KEY,
+  username VARCHAR NOT NULL,
+  name VARCHAR NOT NULL,
+  identity_key TEXT NOT NULL,
+  signed_prekey TEXT NOT NULL,
+  prekey_signature TEXT NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL,
+  updated_on TIMESTAMPTZ NOT NULL
+)
+
+/* One-time pre-keys.  Each is deleted as it is handed out */
+CREATE TABLE prekeys (
+  prekey_id SERIAL PRIMARY KEY,
+  device_id INTEGER NOT NULL,
+  key_id INTEGER NOT NULL,
+  public_key TEXT NOT NULL,
+  UNIQUE (device_id, key_id),
+  FOREIGN KEY (device_id) REFERENCES devices(device_id) ON DELETE CASCADE
+)
+
+/* Named groups of users that messages can be addressed to, as @group:<name> */
+CREATE TABLE user_groups (
+  name VARCHAR PRIMARY KEY,
+  owner VARCHAR NOT NULL,
+  members TEXT[] NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL
+)
+
+/* Direct message conversations.  There is only one direct (one to one) conversation for each pair
+   of people, and the members are kept sorted */
+CREATE TABLE conversations (
+  conversation_id SERIAL PRIMARY KEY,
+  members TEXT[] NOT NULL,
+  direct BOOLEAN NOT NULL,
+  created_by VARCHAR NOT NULL,
+  created_on TIMESTAMPTZ NOT NULL,
+  last_activity TIMESTAMPTZ NOT NULL
+)
+
+CREATE UNIQUE INDEX conversations_direct ON conversations (members) WHERE direct
+
+/* Calls that are over (see signaling.rs).  The duration is in seconds, from when the call was
+   accepted */
+CREATE TABLE calls (
+  call_id VARCHAR PRIMARY KEY,
+  caller VARCHAR NOT NULL,
+  callee VARCHAR NOT NULL,
+  state VARCHAR NOT NULL,
+  started TIMESTAMPTZ NOT NULL,
+  accepted TIMESTAMPTZ,
+  ended TIMESTAMPTZ NOT NULL,
+  duration BIGINT NOT NULL
+)
+
+CREATE INDEX calls_caller ON calls (caller, ended)
+
+CREATE INDEX calls_callee ON calls (callee, ended)
\ No newline at end of file
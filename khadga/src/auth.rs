@@ -24,7 +24,7 @@
 //! user's system.
 
 use crate::{data::User,
-            jwt::jwt::{create_jwt, JWTResponse},
+            jwt::jwt::{create_jwt, user_from_token, JWTResponse},
             pgdb::{pgdb,
                    models}};
 use tokio_postgres::{Client};
@@ -35,7 +35,10 @@ use std::convert::Infallible;
 use warp::{filters::BoxedFilter,
            http::{Response,
                   StatusCode},
+           reject::{self,
+                    Reject},
            Filter,
+           Rejection,
            Reply};
 use log::{info, error};
 use lazy_static::lazy_static;
@@ -45,6 +48,7 @@ lazy_static! {
     pub static ref CONFIG: Settings =  Settings::new().expect("Unable to get config settings");
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
@@ -73,9 +77,7 @@ struct AuthPost {
     token: String
 }
 
-
-
-/// FIXME: This method is now deprecated, but might return once we have a freemium/premium model
+// FIXME: This method is now deprecated, but might return once we have a freemium/premium model
 /*
 pub fn register() -> BoxedFilter<(impl Reply,)> {
     let route = warp::post()
@@ -114,6 +116,63 @@ pub fn register() -> BoxedFilter<(impl Reply,)> {
 }
 */
 
+/// Rejection for requests without a valid jwt cookie
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+/// Rejection for requests from a user who is logged in, but isn't allowed to do what they asked
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+/// Filter that extracts the name of the logged in user from the jwt cookie
+///
+/// Requests without the cookie, or with an expired or invalid token, are rejected with
+/// `Unauthorized`.  Add `handle_rejection` as a `recover` to turn that into a 401.
+pub fn authenticated() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::cookie::optional("jwt").and_then(|token: Option<String>| async move {
+        match token.map(|t| user_from_token(&t)) {
+            Some(Ok(user)) => Ok(user),
+            _ => Err(reject::custom(Unauthorized)),
+        }
+    })
+}
+
+/// Filter that only lets through users listed as `admins` in the config
+///
+/// Extracts the admin's name, like `authenticated` does.  Anybody else who is logged in is rejected
+/// with `Forbidden`.
+pub fn admin() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    authenticated().and_then(|user: String| async move {
+        if CONFIG.admins.contains(&user) {
+            Ok(user)
+        } else {
+            Err(reject::custom(Forbidden))
+        }
+    })
+}
+
+/// Turns our custom rejections into the matching HTTP status
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, text) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Not logged in")
+    } else if err.find::<Forbidden>().is_some() {
+        (StatusCode::FORBIDDEN, "Not allowed")
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found")
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+    } else {
+        error!("Unhandled rejection: {:?}", err);
+        (StatusCode::BAD_REQUEST, "Bad request")
+    };
+
+    Ok(warp::reply::with_status(text, status))
+}
+
 pub fn build_cookie(
     key: &str,
     val: &str,
@@ -126,12 +185,66 @@ pub fn build_cookie(
         cookie = cookie + &format!(" expires={}", expires_at.to_rfc2822())
     }
 
-    let final_cookie = flags.into_iter()
-        .fold(cookie.clone(), |mut acc, next| {
-            acc = acc + "; " + &next;
-            return acc
-        });
-    final_cookie
+    flags.iter()
+        .fold(cookie, |mut acc, next| {
+            acc = acc + "; " + next;
+            acc
+        })
+}
+
+/// Builds the reply that logs a user in
+///
+/// The body is the JWT response, and the `jwt`, `expiry` and `khadga_user` cookies are set.  Both
+/// the Google sign in (`make_verify_request`) and the public key sign in (see `keys`) end here.
+pub fn session_reply(username: &str, email: &str) -> Result<Response<String>, warp::http::Error> {
+    let builder = Response::builder();
+    match create_jwt(username, email) {
+        Ok(jwt) => {
+            let jwt_resp: JWTResponse = serde_json::from_str(&jwt)
+                .expect("Could not deserialize");
+
+            let duration = Duration::minutes(15);
+            let exp = Utc::now() + duration;
+            let secure_flags = vec!["secure", "samesite=strict"];
+            // This kind of sucks, but this is how you can concat slice/vecs
+            let http_only_flags = [&["httpOnly"], &secure_flags[..]].concat();
+
+            let cookie = build_cookie(
+                "jwt",
+                &jwt_resp.token,
+                Some(duration),
+                &http_only_flags
+            );
+            info!("jwt cookie: {}", cookie);
+
+            let expires = build_cookie("expiry", &exp.to_rfc2822(), None, &[]);
+            info!("expiry cookie: {}", expires);
+
+            let username_s: Vec<&str> = email.split("@").collect();
+            let username = username_s.first().expect("Could not determine username");
+            let khadga_user = build_cookie(
+                "khadga_user",
+                username,
+                Some(duration),
+                &secure_flags
+            );
+            info!("khadga_user cookie: {}", khadga_user);
+
+            let resp = builder
+                .status(StatusCode::OK)
+                .header("Set-Cookie", cookie)
+                .header("Set-Cookie", expires)
+                .header("Set-Cookie", khadga_user);
+            info!("{:?}", resp);
+
+            resp.body(jwt)
+        },
+        Err(e) => {
+            builder
+                .status(StatusCode::from_u16(403).unwrap())
+                .body(format!("Unable to generate JWT token: {}", e))
+        }
+    }
 }
 
 /// Will perform a verification request from the mimir service
@@ -168,7 +281,7 @@ pub async fn make_verify_request(
 
     // Lookup user in database.  If he doesn't exist, generate a user.
     let db_client: Client;
-    match pgdb::establish_connection("test_db").await {
+    match pgdb::establish_connection(&CONFIG.db.name).await {
         Ok((client, _)) => {
             db_client = client;
         },
@@ -179,13 +292,28 @@ pub async fn make_verify_request(
             return Ok(resp)
         }
     };
-    
+
+    // A bot's name is taken, people can't log in under it (see `bots`)
+    match pgdb::bot_exists(&db_client, &CONFIG.db.tables.bots, &user.user_name).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return Ok(builder.status(StatusCode::CONFLICT)
+                .body("That name belongs to a bot".into())
+                .expect("Unable to create HTTP Response"))
+        }
+        Err(e) => {
+            return Ok(builder.status(StatusCode::from_u16(500).unwrap())
+                .body(format!("Unable to look up bots: {}", e))
+                .expect("Unable to create HTTP Response"))
+        }
+    }
+
     let resp = match response {
         Ok(resp) => {
             if resp.status() != 201 {
                 builder
                     .status(resp.status())
-                    .body(format!("Unable to generate JWT token"))
+                    .body("Unable to generate JWT token".into())
             } else {                      // Generate JWT
                 let dbuser = models::User {
                     email: user.email.clone(),
@@ -222,53 +350,7 @@ pub async fn make_verify_request(
                     }
                 };
 
-                match create_jwt(&user.user_name, &user.email) {
-                    Ok(jwt) => {
-                        let jwt_resp: JWTResponse = serde_json::from_str(&jwt)
-                            .expect("Could not deserialize");
-
-                        let duration = Duration::minutes(15);
-                        let exp = Utc::now() + duration;
-                        let secure_flags = vec!["secure", "samesite=strict"];
-                        // This kind of sucks, but this is how you can concat slice/vecs
-                        let http_only_flags = [&["httpOnly"], &secure_flags[..]].concat();
-
-                        let cookie = build_cookie(
-                            "jwt",
-                            &jwt_resp.token,
-                            Some(duration),
-                            &http_only_flags
-                        );
-                        info!("jwt cookie: {}", cookie);
-
-                        let expires = build_cookie("expiry", &exp.to_rfc2822(), None, &vec![]);
-                        info!("expiry cookie: {}", expires);
-
-                        let username_s: Vec<&str> = user.email.split("@").collect();
-                        let username = username_s.get(0).expect("Could not determine username");
-                        let khadga_user = build_cookie(
-                            "khadga_user",
-                            &username,
-                            Some(duration),
-                            &secure_flags
-                        );
-                        info!("khadga_user cookie: {}", khadga_user);
-
-                        let resp = builder
-                            .status(StatusCode::OK)
-                            .header("Set-Cookie", cookie)
-                            .header("Set-Cookie", expires)
-                            .header("Set-Cookie", khadga_user);
-                        info!("{:?}", resp);
-
-                        resp.body(jwt)
-                    },
-                    Err(e) => {
-                        builder
-                            .status(StatusCode::from_u16(403).unwrap())
-                            .body(format!("Unable to generate JWT token: {}", e))
-                    }
-                }
+                session_reply(&user.user_name, &user.email)
             }
         },
         Err(err) => {
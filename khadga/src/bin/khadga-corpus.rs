@@ -0,0 +1,91 @@
+//! Command line tool to write the training data corpus
+//!
+//! ```text
+//! khadga-corpus --out <dir> [--shard-size <records>]
+//! ```
+//!
+//! The pseudonyms are keyed with the `KHADGA_CORPUS_SALT` environment variable.  Use the same salt
+//! every time to get the same pseudonyms, and keep it secret.  This reads the same config as
+//! khadga, so run it from the same directory.  See `khadga::corpus` for what ends up in the corpus.
+
+use futures::StreamExt;
+use khadga::{auth::CONFIG,
+             corpus::{Pseudonymizer,
+                      ShardWriter},
+             pgdb::pgdb};
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: KHADGA_CORPUS_SALT=<salt> khadga-corpus --out <dir> \
+                     [--shard-size <records>]";
+
+fn parse_args(args: &[String]) -> Result<(PathBuf, usize), String> {
+    let mut out = None;
+    let mut shard_size = 10_000;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--out" => out = Some(PathBuf::from(value)),
+            "--shard-size" => {
+                shard_size = value.parse().map_err(|_| format!("Bad shard size {}", value))?
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok((out.ok_or("--out is required")?, shard_size))
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let salt = std::env::var("KHADGA_CORPUS_SALT").unwrap_or_default();
+    let (out, shard_size) = match parse_args(&args) {
+        Ok(_) if salt.is_empty() => {
+            eprintln!("KHADGA_CORPUS_SALT must be set\n{}", USAGE);
+            std::process::exit(2);
+        }
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let tables = &CONFIG.db.tables;
+    let db = pgdb::connect(&CONFIG.db.name)
+        .await
+        .unwrap_or_else(|e| panic!("Could not connect to the database: {}", e));
+    let users = pgdb::list_consents(&db, &tables.users)
+        .await
+        .unwrap_or_else(|e| panic!("Could not read the users: {}", e));
+    let consenting = users.iter().filter(|u| u.consent).count();
+    // Every user is pseudonymized, whether or not they consented, since anybody can be mentioned
+    let pseudonyms = Pseudonymizer::new(&salt, &users);
+
+    let rows = pgdb::stream_consented_messages(&db, &tables.messages, &tables.users)
+        .await
+        .unwrap_or_else(|e| panic!("Could not read the messages: {}", e));
+    futures::pin_mut!(rows);
+
+    let mut writer = ShardWriter::new(&out, shard_size)
+        .unwrap_or_else(|e| panic!("Could not create {}: {}", out.display(), e));
+    while let Some(row) = rows.next().await {
+        let row = row.unwrap_or_else(|e| panic!("Could not read the messages: {}", e));
+        let record = pseudonyms.record(&pgdb::row_to_message(&row));
+        writer
+            .write(&record)
+            .unwrap_or_else(|e| panic!("Could not write the corpus: {}", e));
+    }
+    let manifest = writer
+        .finish(consenting)
+        .unwrap_or_else(|e| panic!("Could not write the manifest: {}", e));
+
+    eprintln!(
+        "Wrote {} messages from {} consenting users in {} shards to {}",
+        manifest.records,
+        consenting,
+        manifest.shards.len(),
+        out.display()
+    );
+}
@@ -0,0 +1,102 @@
+//! Command line tool to export a conversation
+//!
+//! ```text
+//! khadga-export (--room <room> | --dm <user> <user>) [--since <time>] [--until <time>]
+//!               [--format jsonl|csv|text] [--out <file>]
+//! ```
+//!
+//! This reads the same config as khadga, so run it from the same directory.  The whole
+//! conversation is exported, not just what one person saw.  Without `--out`, the export is written
+//! to stdout.
+
+use futures::StreamExt;
+use khadga::{auth::CONFIG,
+             export::{export,
+                      Format},
+             pgdb::{models::ExportQuery,
+                    pgdb},
+             search::parse_time};
+use std::{fs::File,
+          io::{self,
+               BufWriter,
+               Write}};
+
+const USAGE: &str = "usage: khadga-export (--room <room> | --dm <user> <user>) [--since <time>] \
+                     [--until <time>] [--format jsonl|csv|text] [--out <file>]";
+
+struct Args {
+    query: ExportQuery,
+    format: Format,
+    out: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut query = ExportQuery::default();
+    let mut format = Format::Jsonl;
+    let mut out = None;
+
+    let mut args = args.iter();
+    let value = |name: &str, args: &mut std::slice::Iter<String>| {
+        args.next()
+            .cloned()
+            .ok_or(format!("{} needs a value", name))
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--room" => query.room = Some(value(arg, &mut args)?),
+            "--dm" => {
+                let first = value(arg, &mut args)?;
+                query.dm = Some((first, value(arg, &mut args)?));
+            }
+            "--since" => query.since = Some(parse_time(&value(arg, &mut args)?)?),
+            "--until" => query.until = Some(parse_time(&value(arg, &mut args)?)?),
+            "--format" => format = value(arg, &mut args)?.parse()?,
+            "--out" => out = Some(value(arg, &mut args)?),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    if query.room.is_some() == query.dm.is_some() {
+        return Err("Give either --room or --dm".into());
+    }
+    Ok(Args { query, format, out })
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let db = pgdb::connect(&CONFIG.db.name)
+        .await
+        .unwrap_or_else(|e| panic!("Could not connect to the database: {}", e));
+    let lines = export(&db, &args.query, args.format)
+        .await
+        .unwrap_or_else(|e| panic!("Could not read the messages: {}", e));
+    futures::pin_mut!(lines);
+
+    let mut writer: BufWriter<Box<dyn Write>> = match &args.out {
+        Some(path) => BufWriter::new(Box::new(
+            File::create(path).unwrap_or_else(|e| panic!("Could not create {}: {}", path, e)),
+        )),
+        None => BufWriter::new(Box::new(io::stdout())),
+    };
+    let mut count = 0;
+    while let Some(line) = lines.next().await {
+        let line = line.unwrap_or_else(|e| panic!("Could not read the messages: {}", e));
+        writer
+            .write_all(line.as_bytes())
+            .expect("Could not write the export");
+        count += 1;
+    }
+    writer.flush().expect("Could not write the export");
+    if args.format.header().is_some() {
+        count -= 1;
+    }
+    eprintln!("Exported {} messages", count);
+}
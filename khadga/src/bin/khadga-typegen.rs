@@ -0,0 +1,76 @@
+//! Command line tool to write the protocol types for vision
+//!
+//! ```text
+//! khadga-typegen [--ts <file>] [--schema <file>] [--check]
+//! ```
+//!
+//! Writes the TypeScript and the JSON Schema worked out from the khadga message types (see
+//! `khadga::typegen`).  By default they go into vision's source, so run it from the khadga
+//! directory.  With `--check`, nothing is written, and it fails if either file is out of date.
+
+use khadga::typegen::{self,
+                      SCHEMA_PATH,
+                      TS_PATH};
+use std::fs;
+
+const USAGE: &str = "usage: khadga-typegen [--ts <file>] [--schema <file>] [--check]";
+
+struct Args {
+    ts: String,
+    schema: String,
+    check: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args {
+        ts: TS_PATH.into(),
+        schema: SCHEMA_PATH.into(),
+        check: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => parsed.check = true,
+            "--ts" | "--schema" => {
+                let value = args.next().cloned().ok_or(format!("{} needs a value", arg))?;
+                if arg == "--ts" {
+                    parsed.ts = value;
+                } else {
+                    parsed.schema = value;
+                }
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+
+    let reg = typegen::protocol().unwrap_or_else(|e| panic!("Could not trace the types: {}", e));
+    let schema = serde_json::to_string_pretty(&reg.json_schema()).expect("The schema is JSON");
+    let schema = schema + "\n";
+    let outputs = [(&args.ts, reg.typescript()), (&args.schema, schema)];
+
+    let mut stale = false;
+    for (path, contents) in outputs.iter() {
+        if args.check {
+            if fs::read_to_string(path).ok().as_ref() != Some(contents) {
+                eprintln!("{} is out of date", path);
+                stale = true;
+            }
+        } else {
+            fs::write(path, contents).unwrap_or_else(|e| panic!("Could not write {}: {}", path, e));
+            println!("Wrote {}", path);
+        }
+    }
+    if stale {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,407 @@
+//! Bot accounts
+//!
+//! A bot is a program that takes part in chat like any other user.  A logged in user registers a
+//! bot with `POST /bots`, and gets back an API token.  The token is only shown that one time,
+//! khadga just keeps a hash of it.  The bot then opens a websocket to `/bot/chat` with an
+//! `Authorization: Bearer <token>` header, and from then on speaks the same protocol as the vision
+//! client.
+//!
+//! Bots and people share their names.  A bot can't be registered under the name of a user khadga
+//! knows of or of an admin, and nobody can log in or open a chat session under the name of a bot.
+//! Deleting a bot ends its session the same way an admin would, so it leaves its rooms properly.
+//!
+//! Bots differ from people in a few ways:
+//!
+//! - Every message a bot sends has `bot` set, so the client can render it differently
+//! - A bot is not put in the lobby.  It joins the rooms it was registered with, and can `/join` or
+//!   `/leave` more rooms as it runs
+//! - A bot receives every chat message sent to a room it is in, not just the ones that name it as
+//!   a recipient.  Only messages that explicitly name the room (with `room`) count, so direct
+//!   messages are never shown to bots.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::{peer,
+                   user_connected,
+                   ChatState},
+            data::AccountKind,
+            pgdb::{models,
+                   pgdb},
+            reply::error_reply,
+            sessions,
+            state::Peer,
+            util::{random_bytes,
+                   valid_name}};
+use chrono::Utc;
+use log::{error,
+          info};
+use ring::digest;
+use serde::{Deserialize,
+            Serialize};
+use serde_json::json;
+use std::{collections::HashMap,
+          convert::Infallible,
+          sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           ws::Ws,
+           Filter,
+           Reply};
+
+/// Registered bots, keyed by name
+pub type Bots = Arc<Mutex<HashMap<String, models::Bot>>>;
+
+const TOKEN_PREFIX: &str = "kbt_";
+
+/// Creates a new random token that starts with the prefix
+pub fn random_token(prefix: &str) -> String {
+    let bytes: [u8; 32] = random_bytes();
+    format!("{}{}", prefix, base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+/// Creates a new random API token
+pub fn new_token() -> String {
+    random_token(TOKEN_PREFIX)
+}
+
+/// The hash of a token, which is what gets stored
+pub fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    base64::encode(hash.as_ref())
+}
+
+/// Looks up the bot that owns the token
+pub async fn authenticate(bots: &Bots, token: &str) -> Option<models::Bot> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    let hash = hash_token(token);
+    let bots = bots.lock().await;
+    bots.values().find(|b| b.token_hash == hash).cloned()
+}
+
+/// The rooms the bot should be put in when it connects
+pub async fn rooms_for(bots: &Bots, name: &str) -> Vec<String> {
+    bots.lock()
+        .await
+        .get(name)
+        .map(|b| b.rooms.clone())
+        .unwrap_or_default()
+}
+
+/// Loads the registered bots from the database
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let table = &CONFIG.db.tables.bots;
+
+    if let Err(e) = pgdb::make_table_bots(table, db).await {
+        error!("Unable to create the {} table: {}", table, e);
+        return;
+    }
+    match pgdb::list_bots(db, table).await {
+        Ok(list) => {
+            info!("Loaded {} bots", list.len());
+            let mut bots = state.bots.lock().await;
+            for bot in list {
+                bots.insert(bot.name.clone(), bot);
+            }
+        }
+        Err(e) => error!("Unable to load bots: {}", e),
+    }
+}
+
+/// Why the name can't be given to a bot or an incoming webhook, if it can't.  People, bots and
+/// integrations all post under the same names, so none of them may look like another.
+pub async fn name_taken(
+    state: &ChatState,
+    name: &str,
+) -> Result<Option<&'static str>, tokio_postgres::Error> {
+    if state.users.lock().await.contains_key(name) {
+        return Ok(Some("A user with that name is online"));
+    }
+    if state.bots.lock().await.contains_key(name) {
+        return Ok(Some("A bot with that name already exists"));
+    }
+    // Somebody who isn't online could still log in under the name, so check everybody we know of
+    let registered = match &state.db {
+        Some(db) => pgdb::find_user(db, &CONFIG.db.tables.users, name).await?.is_some(),
+        None => false,
+    };
+    if registered || CONFIG.admins.iter().any(|admin| admin == name) {
+        return Ok(Some("A user with that name already exists"));
+    }
+    Ok(None)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewBot {
+    pub name: String,
+    #[serde(default)]
+    pub rooms: Vec<String>,
+}
+
+/// What we tell people about a bot.  The token hash is never sent out.
+#[derive(Serialize, Debug)]
+pub struct BotInfo {
+    pub name: String,
+    pub owner: String,
+    pub rooms: Vec<String>,
+    pub created_on: chrono::DateTime<Utc>,
+}
+
+impl From<&models::Bot> for BotInfo {
+    fn from(bot: &models::Bot) -> Self {
+        BotInfo {
+            name: bot.name.clone(),
+            owner: bot.owner.clone(),
+            rooms: bot.rooms.clone(),
+            created_on: bot.created_on,
+        }
+    }
+}
+
+/// Registers a new bot owned by the logged in user and returns its token
+pub async fn create_bot(
+    owner: String,
+    new_bot: NewBot,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if !valid_name(&new_bot.name) {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "Bot names are 1 to 32 letters, digits, - or _",
+        ));
+    }
+    match name_taken(&state, &new_bot.name).await {
+        Ok(None) => {}
+        Ok(Some(reason)) => return Ok(error_reply(StatusCode::CONFLICT, reason)),
+        Err(e) => {
+            error!("Unable to look up user {}: {}", new_bot.name, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save bot"));
+        }
+    }
+
+    let token = new_token();
+    let mut bot = models::Bot {
+        bot_id: -1,
+        name: new_bot.name,
+        owner,
+        token_hash: hash_token(&token),
+        rooms: new_bot.rooms,
+        created_on: Utc::now(),
+    };
+
+    let mut bots = state.bots.lock().await;
+    if bots.contains_key(&bot.name) {
+        return Ok(error_reply(StatusCode::CONFLICT, "A bot with that name already exists"));
+    }
+    if let Some(db) = &state.db {
+        match pgdb::insert_bot(db, &CONFIG.db.tables.bots, &bot).await {
+            Ok(id) => bot.bot_id = id,
+            Err(e) => {
+                error!("Unable to save bot {}: {}", bot.name, e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save bot"));
+            }
+        }
+    }
+    info!("{} registered bot {}", bot.owner, bot.name);
+
+    let mut body = json!(BotInfo::from(&bot));
+    body["token"] = json!(token);
+    bots.insert(bot.name.clone(), bot);
+    Ok(reply::with_status(reply::json(&body), StatusCode::CREATED))
+}
+
+/// Lists the bots owned by the logged in user
+pub async fn list_bots(owner: String, state: ChatState) -> Result<Json, Infallible> {
+    let bots = state.bots.lock().await;
+    let mine: Vec<BotInfo> = bots
+        .values()
+        .filter(|b| b.owner == owner)
+        .map(BotInfo::from)
+        .collect();
+    Ok(reply::json(&mine))
+}
+
+/// Deletes a bot, which revokes its token.  If the bot is connected, it is dropped.
+pub async fn delete_bot(
+    name: String,
+    owner: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let mut bots = state.bots.lock().await;
+    match bots.get(&name) {
+        Some(bot) if bot.owner == owner => {}
+        Some(_) => return Ok(error_reply(StatusCode::FORBIDDEN, "That is not your bot")),
+        None => return Ok(error_reply(StatusCode::NOT_FOUND, "No such bot")),
+    }
+
+    if let Some(db) = &state.db {
+        if let Err(e) = pgdb::delete_bot(db, &CONFIG.db.tables.bots, &name).await {
+            error!("Unable to delete bot {}: {}", name, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to delete bot"));
+        }
+    }
+    bots.remove(&name);
+    drop(bots);
+    // The connection leaves its rooms and tells everybody once it stops reading
+    sessions::disconnect(&state.users, &name, "The bot was deleted").await;
+    info!("{} deleted bot {}", owner, name);
+
+    Ok(reply::with_status(reply::json(&json!({ "deleted": name })), StatusCode::OK))
+}
+
+/// The websocket endpoint for bots.  The bot authenticates with its token rather than the cookie.
+pub async fn bot_chat(
+    ws: Ws,
+    peer: Peer,
+    authorization: Option<String>,
+    state: ChatState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let token = authorization
+        .as_ref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim);
+    let bot = match token {
+        Some(token) => authenticate(&state.bots, token).await,
+        None => None,
+    };
+
+    match bot {
+        Some(bot) => {
+            info!("Bot {} starting chat", bot.name);
+            Ok(Box::new(ws.on_upgrade(move |socket| {
+                user_connected(socket, state, bot.name, AccountKind::Bot, peer)
+            })))
+        }
+        None => Ok(Box::new(error_reply(StatusCode::UNAUTHORIZED, "Invalid bot token"))),
+    }
+}
+
+/// All the bot endpoints
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path("bots"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_bot);
+
+    let list = warp::get()
+        .and(warp::path("bots"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(list_bots);
+
+    let delete = warp::delete()
+        .and(warp::path!("bots" / String))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(delete_bot);
+
+    let chat = warp::path!("bot" / "chat")
+        .and(warp::ws())
+        .and(peer())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state)
+        .and_then(bot_chat);
+
+    create
+        .or(list)
+        .or(delete)
+        .or(chat)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commands::Dispatcher,
+                filter::FilterChain,
+                state::UserInfo};
+    use tokio::sync::{mpsc,
+                      oneshot};
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let owner = "stoner".to_string();
+        let new_bot = NewBot {
+            name: "dicebot".into(),
+            rooms: vec!["dnd".into()],
+        };
+        let resp = create_bot(owner.clone(), new_bot, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let bytes = warp::hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let token = body["token"].as_str().unwrap().to_string();
+        assert!(token.starts_with(TOKEN_PREFIX));
+
+        let bot = authenticate(&state.bots, &token).await.expect("Token should be valid");
+        assert_eq!(bot.name, "dicebot");
+        assert_eq!(rooms_for(&state.bots, "dicebot").await, vec!["dnd".to_string()]);
+        assert!(authenticate(&state.bots, "kbt_nope").await.is_none());
+
+        // Same name twice is a conflict
+        let again = NewBot {
+            name: "dicebot".into(),
+            rooms: vec![],
+        };
+        let resp = create_bot(owner, again, state.clone()).await.unwrap().into_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Only the owner can delete it, and then the token stops working
+        let resp = delete_bot("dicebot".into(), "whammo".into(), state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = delete_bot("dicebot".into(), "stoner".into(), state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(authenticate(&state.bots, &token).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_connected_bot() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let new_bot = NewBot {
+            name: "dicebot".into(),
+            rooms: vec![],
+        };
+        create_bot("stoner".into(), new_bot, state.clone()).await.unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let mut info = UserInfo::new(Some(tx));
+        info.kind = AccountKind::Bot;
+        info.disconnect = Some(disconnect_tx);
+        state.users.lock().await.insert("dicebot".into(), info);
+
+        // The bot is told why and its connection is asked to stop, which then cleans up after it
+        let resp = delete_bot("dicebot".into(), "stoner".into(), state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(rx.recv().await.unwrap().unwrap().is_close());
+        assert_eq!(disconnect_rx.await, Ok(()));
+    }
+}
@@ -1,10 +1,55 @@
 // #![deny(warnings)]
-use crate::message::{self,
-                     CommandRequestMsg,
-                     ConnectionMsg,
-                     Message as KMessage,
-                     MessageEvent::{self,
-                                    CommandRequest}};
+use crate::{auth::CONFIG,
+            bots::{self,
+                   Bots},
+            commands::{CommandContext,
+                       Dispatcher},
+            conversations,
+            data::AccountKind,
+            filter::{FilterChain,
+                     Rejection},
+            groups::{self,
+                     Groups},
+            incoming::IncomingHooks,
+            keys::Challenges,
+            message::{self,
+                      CommandReplyMsg,
+                      CommandRequestMsg,
+                      CommandTypes,
+                      ConnectionMsg,
+                      Message as KMessage,
+                      MessageEvent},
+            pgdb::{models,
+                   pgdb::{self,
+                          DbClient}},
+            protocol::{self,
+                       Negotiated,
+                       Welcome},
+            questions::{self,
+                        QuestionIndex,
+                        Questions},
+            rooms::{self,
+                    Rooms},
+            signaling::{self,
+                        CallTable,
+                        Calls},
+            state::{MessageInventory,
+                    Peer,
+                    Sender,
+                    UserInfo,
+                    Users},
+            trends::{self,
+                     TrendTracker,
+                     Trends},
+            webhooks::{self,
+                       Webhooks},
+            wire::{self,
+                   Encoded,
+                   Envelope,
+                   Event,
+                   Request}};
+use chrono::{TimeZone,
+             Utc};
 use std::{collections::HashMap,
           sync::Arc};
 
@@ -13,18 +58,74 @@ use futures::{FutureExt,
 use log::{debug,
           error,
           info};
-use serde_json;
+use serde::Serialize;
 use tokio::{sync::{mpsc,
+                   oneshot,
                    Mutex},
             time::Duration};
 use warp::{ws::{Message,
-                WebSocket}};
+                WebSocket},
+           Filter};
 
-/// Our state of currently connected users.
+/// The websocket close code for a connection under a name that is already connected, or that
+/// belongs to a bot
+pub const CLOSE_ALREADY_CONNECTED: u16 = 4002;
+
+/// Everything that is shared between the chat connections.
 ///
-/// - Key is their id
-/// - Value is a sender of `warp::ws::Message`
-pub type Users = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Result<Message, warp::Error>>>>>;
+/// This is built once at startup and a clone is handed to each connection.  All the fields are
+/// reference counted, so cloning is cheap.
+#[derive(Clone)]
+pub struct ChatState {
+    /// Our state of currently connected users.
+    ///
+    /// - Key is their id
+    /// - Value is their `UserInfo`, which holds the sender of `warp::ws::Message`
+    pub users: Users,
+    pub rooms: Rooms,
+    /// Named groups that messages can be addressed to (see `groups`)
+    pub groups: Groups,
+    pub bots: Bots,
+    pub filters: Arc<FilterChain>,
+    pub commands: Arc<Dispatcher>,
+    pub webhooks: Webhooks,
+    pub incoming: IncomingHooks,
+    pub trends: Trends,
+    pub questions: Questions,
+    /// Outstanding public key login challenges (see `keys`)
+    pub challenges: Challenges,
+    /// WebRTC calls in progress (see `signaling`)
+    pub calls: CallTable,
+    /// None if khadga couldn't connect to the database.  Chat still works, but nothing is saved.
+    pub db: Option<DbClient>,
+}
+
+impl ChatState {
+    pub fn new(filters: FilterChain, commands: Dispatcher, db: Option<DbClient>) -> Self {
+        ChatState {
+            users: Arc::new(Mutex::new(HashMap::new())),
+            rooms: rooms::new_rooms(),
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            bots: Arc::new(Mutex::new(HashMap::new())),
+            filters: Arc::new(filters),
+            commands: Arc::new(commands),
+            webhooks: Arc::new(Mutex::new(HashMap::new())),
+            incoming: Arc::new(Mutex::new(HashMap::new())),
+            trends: Arc::new(Mutex::new(TrendTracker::new(Default::default()))),
+            questions: Arc::new(Mutex::new(QuestionIndex::new(Default::default()))),
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+            calls: Arc::new(Mutex::new(Calls::new(Default::default()))),
+            db,
+        }
+    }
+}
+
+/// Filter that extracts where a chat connection comes from, for `user_connected`
+pub fn peer() -> impl Filter<Extract = (Peer,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(|remote_addr, user_agent| Peer { remote_addr, user_agent })
+}
 
 /// Return a `Future` that is basically a state machine managing this specific user's connection.
 ///
@@ -33,8 +134,18 @@ pub type Users = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Result<Message,
 /// notify them that a new user is connected. As long as the websocket stays open, a spawned async
 /// task will handle messages coming from the client's websocket.  When the client disconnects, that
 /// client/user will be removed from the shared map and a disconnect event will be sent.
-pub async fn user_connected(ws: WebSocket, users: Users, username: String) {
-    info!("new chat user: {}", username);
+///
+/// The client first gets to say which protocol version it speaks (see `protocol`).  Bots (see
+/// `bots`) use the same function once their token has been checked.  An admin can end the
+/// connection early through `sessions`.
+pub async fn user_connected(
+    ws: WebSocket,
+    state: ChatState,
+    username: String,
+    kind: AccountKind,
+    peer: Peer,
+) {
+    info!("new chat user: {} ({:?})", username, kind);
 
     // Split the socket into a sender and receive of messages.
     let (user_ws_tx, mut user_ws_rx) = ws.split();
@@ -64,11 +175,134 @@ pub async fn user_connected(ws: WebSocket, users: Users, username: String) {
         }
     }));
 
+    // The handshake comes before anything else, so a client we can't talk to never shows up as
+    // connected.  Clients from before the handshake start with a normal message, or say nothing.
+    let hello_timeout = Duration::from_millis(protocol::HELLO_TIMEOUT_MS);
+    let (negotiated, first) = match tokio::time::timeout(hello_timeout, user_ws_rx.next()).await {
+        Ok(Some(Ok(msg))) => match msg.to_str().ok().and_then(protocol::parse_hello) {
+            Some(hello) => match hello.and_then(|hello| protocol::negotiate(&hello)) {
+                Ok(negotiated) => {
+                    let welcome = Welcome::new(negotiated.clone());
+                    let to = vec![username.clone()];
+                    let welcome =
+                        KMessage::new("khadga".into(), to, MessageEvent::Welcome, welcome);
+                    let welcome = Encoded::new(&welcome);
+                    let _ = tx.send(Ok(Message::text(welcome.for_version(negotiated.version))));
+                    (negotiated, None)
+                }
+                Err(reason) => {
+                    info!("{}: handshake failed: {}", username, reason);
+                    let close = Message::close_with(protocol::CLOSE_UNSUPPORTED, reason);
+                    let _ = tx.send(Ok(close));
+                    return;
+                }
+            },
+            None => (Negotiated::legacy(), Some(msg)),
+        },
+        Ok(Some(Err(e))) => {
+            error!("websocket error(uid={}): {}", username, e);
+            return;
+        }
+        Ok(None) => return,
+        Err(_) => (Negotiated::legacy(), None),
+    };
+    info!("{}: speaking protocol version {}", username, negotiated.version);
+
+    let (session_id, mut disconnect_rx) =
+        match open_session(&state, &username, kind, peer, negotiated, tx.clone()).await {
+            Ok(opened) => opened,
+            Err(reason) => {
+                info!("{}: {}", username, reason);
+                let _ = tx.send(Ok(Message::close_with(CLOSE_ALREADY_CONNECTED, reason)));
+                return;
+            }
+        };
+    if let Some(msg) = first {
+        user_message(username.clone(), msg, &state).await;
+    }
+
+    // Every time the user sends a message handle it.  Note that since we are calling .await here
+    // and we are not in a tokio task, this will block here.  We won't proceed to the
+    // user_disconnected until the connection breaks, which will cause the let Some(result) to
+    // not be true, thus breaking out of the loop
+    info!("{}: listening for messages", username);
+    loop {
+        let result = tokio::select! {
+            result = user_ws_rx.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            Ok(()) = &mut disconnect_rx => {
+                info!("{} was disconnected by an admin", username);
+                break;
+            }
+        };
+        let copy_name = username.clone();
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("websocket error(uid={}): {}", copy_name, e);
+                continue;
+            }
+        };
+        user_message(copy_name, msg, &state).await;
+    }
+    info!("{} has disconnected", username);
+
+    // user_ws_rx stream will keep processing as long as the user stays
+    // connected. Once they disconnect, then...
+    close_session(&state, &username, &session_id).await;
+}
+
+/// Adds a user to the connected users and tells everybody else they are here
+///
+/// This is the part of a connection that doesn't care how the messages get to the client.  The
+/// websocket in `user_connected` and the event stream in `sse` both use it, with `tx` feeding
+/// whatever sends to the client.  It returns the id of the new session, which is what closes it
+/// again, and a receiver that fires if an admin disconnects the session.
+///
+/// There is only ever one session per name.  If the user is already connected, somewhere else or
+/// in another tab, the new session is refused and the one already there is left alone.  A person
+/// can't open a session under a bot's name either.
+pub async fn open_session(
+    state: &ChatState,
+    username: &str,
+    kind: AccountKind,
+    peer: Peer,
+    protocol: Negotiated,
+    tx: Sender,
+) -> Result<(String, oneshot::Receiver<()>), String> {
+    let users = &state.users;
+
+    // Save the sender in our list of connected users.
+    // We created a nested scope here so that we release the lock.  If we don't, the call to
+    // get_users will deadlock waiting for the lock here to release.
+    // Only the bot itself may speak under a bot's name
+    if kind == AccountKind::User && state.bots.lock().await.contains_key(username) {
+        return Err(format!("{} is the name of a bot", username));
+    }
+    let (disconnect_tx, disconnect_rx) = oneshot::channel();
+    let session_id;
+    {
+        let mut list = users.lock().await;
+        if list.contains_key(username) {
+            return Err(format!("{} is already connected", username));
+        }
+        let mut info = UserInfo::new(Some(tx));
+        info.kind = kind;
+        info.peer = peer;
+        info.protocol = protocol;
+        info.disconnect = Some(disconnect_tx);
+        session_id = info.session_id.clone();
+        list.insert(username.to_string(), info);
+    }
+
     // Send a ping message every 10 seconds.  If user has disconnected, they wont be in the shared
-    // map, and the while loop will break
+    // map (or somebody else's session will be), and the while loop will break
     let mut interval = tokio::time::interval(Duration::from_millis(10000));
     let loop_users = users.clone();
-    let loop_uname = username.clone();
+    let loop_uname = username.to_string();
+    let loop_session = session_id.clone();
 
     // We avoid acquiring the lock too long in the loop by grabbing it and then at the end of the
     // if/else, we release the lock.  This does mean that if tx has a lot to send, the lock will be
@@ -76,13 +310,15 @@ pub async fn user_connected(ws: WebSocket, users: Users, username: String) {
     tokio::task::spawn(async move {
         loop {
             interval.tick().await;
-            if let Some(user_tx) = loop_users.lock().await.get(&loop_uname) {
+            let list = loop_users.lock().await;
+            let ours = list.get(&loop_uname).filter(|info| info.session_id == loop_session);
+            if let Some(UserInfo { sender: Some(user_tx), protocol, .. }) = ours {
                 let mut msg = CommandRequestMsg::default();
                 msg.cmd.id = "khadga-1".into(); // FIXME: append timestamp
                 let cmsg =
                     KMessage::new("khadga".into(), vec![], MessageEvent::CommandRequest, msg);
-                let cmsg = serde_json::to_string(&cmsg).expect("Could not parse to CommandMsg");
-                match user_tx.send(Ok(Message::text(cmsg))) {
+                let cmsg = Encoded::new(&cmsg);
+                match user_tx.send(Ok(Message::text(cmsg.for_version(protocol.version)))) {
                     Ok(_) => {}
                     _ => error!("Unable to send ping message"),
                 };
@@ -92,21 +328,21 @@ pub async fn user_connected(ws: WebSocket, users: Users, username: String) {
         }
     });
 
-    let copy_uname = username.clone();
-
-    // Save the sender in our list of connected users.
-    // We created a nested scope here so that we release the lock.  If we don't, the call to
-    // get_users will deadlock waiting for the lock here to release.
-    {
-        users.lock().await.insert(copy_uname, tx);
+    match kind {
+        AccountKind::User => rooms::join(&state.rooms, rooms::LOBBY, username).await,
+        AccountKind::Bot => {
+            for room in bots::rooms_for(&state.bots, username).await {
+                rooms::join(&state.rooms, &room, username).await;
+            }
+        }
     }
-    let user_list = get_users(&users).await;
+    let user_list = get_users(users).await;
 
     // Send back a list of connected users.  Remember that tx is connected to rx.  Earlier
     // we forwarded rx channel to user_tx.  So anything we send via tx2 will also be received
     // by rx, and therefore will be sent over to user_tx and then over the websocket
     let conn_list = ConnectionMsg::new(user_list);
-    let connect_msg = KMessage::new(username.clone(), vec![], MessageEvent::Connect, conn_list);
+    let connect_msg = KMessage::new(username.to_string(), vec![], MessageEvent::Connect, conn_list);
 
     // Send a connection event to each connected user
     // FIXME:  I think we can put this in the loop above.  No need to clone again
@@ -117,43 +353,37 @@ pub async fn user_connected(ws: WebSocket, users: Users, username: String) {
     let event_users = users.clone();
     {
         let list = event_users.lock().await;
-        for (user, tx) in list.iter() {
+        let conn_msg = Encoded::new(&connect_msg);
+        for (user, tx) in senders(&list) {
             debug!("Sending connect event to {}", user);
-            let conn_msg_str =
-                serde_json::to_string(&connect_msg).expect("Unable to serialize to Message");
+            let conn_msg_str = conn_msg.for_version(list[user].protocol.version);
             tx.send(Ok(Message::text(conn_msg_str)))
                 .expect("Failed to send to tx");
         }
     }
+    webhooks::notify(state, &connect_msg).await;
     debug!("{}: Done sending connected event messages", username);
+    Ok((session_id, disconnect_rx))
+}
 
-    // Every time the user sends a message handle it.  Note that since we are calling .await here
-    // and we are not in a tokio task, this will block here.  We won't proceed to the
-    // user_disconnected until the connection breaks, which will cause the let Some(result) to
-    // not be true, thus breaking out of the loop
-    info!("{}: listening for messages", username);
-    while let Some(result) = user_ws_rx.next().await {
-        let copy_name = username.clone();
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                error!("websocket error(uid={}): {}", copy_name, e);
-                continue;
-            }
-        };
-        user_message(copy_name, msg, &users).await;
+/// Removes a user from the rooms and connected users, and tells everybody they left
+///
+/// Only the session that `open_session` handed the id to can close it.  If the name has since gone
+/// to another session, that one is left alone.
+pub async fn close_session(state: &ChatState, username: &str, session_id: &str) {
+    let ours = match state.users.lock().await.get(username) {
+        Some(info) => info.session_id == session_id,
+        None => false,
+    };
+    if !ours {
+        return;
     }
-    info!("{} has disconnected", username);
-
-    // user_ws_rx stream will keep processing as long as the user stays
-    // connected. Once they disconnect, then...
-    // Make an extra clone to give to our disconnection handler...
-    let copy_name = username.clone();
-    let users2 = users.clone();
-    user_disconnected(copy_name, &users2).await;
+    rooms::leave_all(&state.rooms, username).await;
+    signaling::leave(state, username).await;
+    user_disconnected(username.to_string(), state).await;
 }
 
-async fn user_message(my_id: String, msg: Message, users: &Users) {
+async fn user_message(my_id: String, msg: Message, state: &ChatState) {
     // Skip any non-Text messages...
     let msg = if let Ok(s) = msg.to_str() {
         s
@@ -161,41 +391,233 @@ async fn user_message(my_id: String, msg: Message, users: &Users) {
         debug!("Unable to process message");
         return;
     };
+    user_text(my_id, msg, state).await;
+}
+
+/// Handles a message sent by a connected user, in either format (see `wire`), however it got here
+pub async fn user_text(my_id: String, msg: &str, state: &ChatState) {
+    let users = &state.users;
     // debug!("Raw Message from {} is {:#?}", my_id, msg);
 
-    let mesg: message::Message<String> = serde_json::from_str(msg).expect("Unable to parse");
-    let message: String = match mesg.event_type {
-        CommandRequest => {
-            let cmd_body: CommandRequestMsg<String> =
-                serde_json::from_str(&mesg.body).expect("Unable to deserialize");
-            debug!("{:#?}", cmd_body.cmd);
-            // TODO: Do something with the request and send back CommandReply
-            if cmd_body.cmd.ack {
-                info!("From {} got args {}", my_id, cmd_body.args);
-            } else {
-                return;
+    // Keep track of how much the user has sent, and when they last sent something
+    let kind = {
+        let mut list = users.lock().await;
+        match list.remove(&my_id) {
+            Some(info) => {
+                let kind = info.kind;
+                list.insert(my_id.clone(), info + MessageInventory::new(msg.len(), None));
+                kind
             }
+            None => AccountKind::User,
+        }
+    };
+
+    let mut envelope = match wire::decode(msg) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            info!("{}: unable to decode message: {}", my_id, e);
+            return;
+        }
+    };
+    // Clients don't get to decide these for themselves.  Annotations are only ever added by khadga
+    // (the filters, commands and integrations), and retention and export go by them.
+    envelope.bot = kind == AccountKind::Bot;
+    envelope.sender = my_id.clone();
+    envelope.annotations.clear();
 
-            let cmd_msg = mesg.from(cmd_body);
-            serde_json::to_string(&cmd_msg).expect("Unable to serialize to string")
+    match &envelope.event {
+        Event::Message(text) => chat_message(my_id, envelope.message(text.clone()), state).await,
+        // Slash commands are for khadga itself, so they are answered rather than relayed
+        Event::CommandRequest(Request::Slash { id, line }) => {
+            run_command(&my_id, &envelope, id, line, state).await
+        }
+        // Signaling only goes to the other side of the call (see `signaling`)
+        Event::CommandRequest(Request::SDPOffer { .. })
+        | Event::CommandRequest(Request::SDPAnswer { .. })
+        | Event::CommandRequest(Request::IceCandidate { .. }) => {
+            signaling::handle(state, &my_id, &envelope).await
+        }
+        Event::CommandRequest(Request::Call { id, action }) => {
+            signaling::call(state, &my_id, &envelope, id, *action).await
+        }
+        Event::CommandReply(_) | Event::Data(_) => {
+            let mesg = envelope.to_legacy();
+            match groups::check(state, &mesg).await {
+                Ok(()) => relay(state, &mesg).await,
+                Err(reason) => {
+                    let refused = envelope.message(String::new());
+                    reject(users, &my_id, Rejection::new("groups", reason, &refused)).await
+                }
+            }
         }
-        // TODO: match on other event_types
-        _ => format!("{}", msg),
+        // The handshake only happens once, at the start
+        Event::Hello(_) => debug!("{}: ignoring a Hello after the handshake", my_id),
+        // Everything else only ever comes from khadga itself
+        event => debug!("{}: ignoring a {} from a client", my_id, event.event_type()),
     };
+}
+
+/// Tells the sender why their message went nowhere
+async fn reject(users: &Users, my_id: &str, rejection: Rejection) {
+    info!("{}: {}", my_id, rejection);
+    let reply =
+        KMessage::new("khadga".into(), vec![my_id.into()], MessageEvent::Rejected, rejection);
+    send_to(users, my_id, &reply).await;
+}
+
+/// Checks a chat message from a user, then posts it
+async fn chat_message(my_id: String, mut mesg: KMessage<String>, state: &ChatState) {
+    if let Err(rejection) = send_chat(state, &my_id, &mut mesg).await {
+        reject(&state.users, &my_id, rejection).await;
+    }
+}
+
+/// Checks a chat message from a user and posts it, or says why it can't be sent
+///
+/// Anything a user says goes this way, whether they typed it or a slash command like `/me` built
+/// it for them.  The message is left as it was posted, after the filters had their say.
+pub(crate) async fn send_chat(
+    state: &ChatState,
+    my_id: &str,
+    mesg: &mut KMessage<String>,
+) -> Result<(), Rejection> {
+    // Encryption is per device, which only works when the recipients are known
+    if mesg.encrypted && (mesg.room.is_some() || groups::has_addresses(&mesg.recipients)) {
+        let reason = "Only direct messages can be encrypted".to_string();
+        return Err(Rejection::new("e2e", reason, mesg));
+    }
+
+    if let Err(reason) = groups::check(state, mesg).await {
+        return Err(Rejection::new("groups", reason, mesg));
+    }
+
+    if let Err(reason) = conversations::attach(state, my_id, mesg).await {
+        return Err(Rejection::new("conversations", reason, mesg));
+    }
+
+    // Run the message through the filters before anybody else gets to see it
+    state.filters.apply(mesg)?;
+
+    post(state, mesg).await;
+    Ok(())
+}
+
+/// Runs a slash command and sends the `CommandReply` back to the caller
+async fn run_command(my_id: &str, envelope: &Envelope, id: &str, line: &str, state: &ChatState) {
+    let request = envelope.message(line.to_string());
+    let room = request.room.clone().unwrap_or_else(|| rooms::LOBBY.into());
+    let ctx = CommandContext {
+        caller: my_id,
+        room: &room,
+        request: &request,
+        state,
+    };
+    info!("{} running command {}", my_id, line);
+    let response = state.commands.dispatch(&ctx, line).await;
+
+    let reply = CommandReplyMsg::new(CommandTypes::Slash, false, id.to_string(), response);
+    let mut reply =
+        KMessage::new("khadga".into(), vec![my_id.into()], MessageEvent::CommandReply, reply);
+    reply.room = request.room.clone();
+    send_to(&state.users, my_id, &reply).await;
+}
+
+/// Saves a chat message and then relays it
+///
+/// Every chat message goes this way, whether it came from a person, a bot, a slash command or an
+/// incoming webhook.  Only `MessageEvent::Message` events are saved, and only if there is a
+/// database.  Who it was delivered to is saved as well, since the recipients can be addresses that
+/// mean different people later on (see `groups`).
+pub async fn post(state: &ChatState, mesg: &KMessage<String>) {
+    if let (Some(db), MessageEvent::Message) = (&state.db, &mesg.event_type) {
+        let mut delivered_to: Vec<String> = groups::expand(state, mesg).await.into_iter().collect();
+        delivered_to.sort();
+        let saved = models::ChatMessage {
+            message_id: -1,
+            sender: mesg.sender.clone(),
+            recipients: mesg.recipients.clone(),
+            delivered_to,
+            room: mesg.room.clone(),
+            body: mesg.body.clone(),
+            bot: mesg.bot,
+            encrypted: mesg.encrypted,
+            annotations: mesg.annotations.clone(),
+            sent_on: Utc.timestamp_millis(mesg.time),
+            conversation_id: mesg.conversation,
+        };
+        if let Err(e) = pgdb::insert_message(db, &CONFIG.db.tables.messages, &saved).await {
+            error!("Unable to save message from {}: {}", mesg.sender, e);
+        }
+        if let Some(id) = mesg.conversation {
+            conversations::touch(db, id, &saved.sent_on).await;
+        }
+    }
+    // There is nothing to learn from ciphertext
+    if let (MessageEvent::Message, false) = (&mesg.event_type, mesg.encrypted) {
+        trends::observe(state, mesg).await;
+        relay(state, mesg).await;
+        // After the relay, so any suggestion shows up under the question
+        questions::observe(state, mesg).await;
+    } else {
+        relay(state, mesg).await;
+    }
+}
 
-    // debug!("From {} got message {}", my_id, message);
+/// Sends the message to each of its recipients that is connected
+///
+/// Addresses like `@room` in the recipients are expanded (see `groups`).  Chat messages that name a
+/// room are also sent to every bot in that room, and anything relayed is passed on to the webhooks
+/// that asked for it.
+pub async fn relay<T: Serialize>(state: &ChatState, mesg: &KMessage<T>) {
+    let message = Encoded::new(mesg);
 
-    // New message from this user, send it to everyone else (except same uid)...
-    // FIXME: Send only to the recipients in _mesg.
-    for (usr, tx) in users.lock().await.iter_mut() {
-        if mesg.recipients.contains(usr) {
+    let room_members = match (&mesg.room, &mesg.event_type) {
+        (Some(room), MessageEvent::Message) => {
+            rooms::members(&state.rooms, room).await.unwrap_or_default()
+        }
+        _ => vec![],
+    };
+    let recipients = groups::expand(state, mesg).await;
+
+    let list = state.users.lock().await;
+    for (usr, tx) in senders(&list) {
+        let bot_in_room = list[usr].kind == AccountKind::Bot
+            && *usr != mesg.sender
+            && room_members.contains(usr);
+        if !list[usr].protocol.accepts(&mesg.event_type) {
+            continue;
+        }
+        if recipients.contains(usr) || bot_in_room {
             info!("Sending message to {}", usr);
-            if let Err(_disconnected) = tx.send(Ok(Message::text(message.clone()))) {
+            let text = message.for_version(list[usr].protocol.version);
+            if let Err(_disconnected) = tx.send(Ok(Message::text(text))) {
                 // The tx is disconnected, our `user_disconnected` code should be happening in
                 // another task, nothing more to do here.
             }
         }
     }
+    drop(list);
+    webhooks::notify(state, mesg).await;
+}
+
+/// Sends a message to a single connected user.  Returns false if the user isn't connected, or
+/// didn't negotiate the feature the message needs (see `protocol`).
+pub async fn send_to<T: Serialize>(users: &Users, user: &str, mesg: &KMessage<T>) -> bool {
+    let message = Encoded::new(mesg);
+    match users.lock().await.get(user) {
+        Some(UserInfo { sender: Some(tx), protocol, .. })
+            if protocol.accepts(&mesg.event_type) =>
+        {
+            tx.send(Ok(Message::text(message.for_version(protocol.version)))).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// The connected users that we can actually send to
+fn senders(list: &HashMap<String, UserInfo>) -> impl Iterator<Item = (&String, &Sender)> {
+    list.iter()
+        .filter_map(|(user, info)| info.sender.as_ref().map(|tx| (user, tx)))
 }
 
 async fn get_users(users: &Users) -> Vec<String> {
@@ -211,14 +633,15 @@ async fn get_users(users: &Users) -> Vec<String> {
     }
 
     let mut _user_str = user_list.iter().fold("".into(), |mut acc: String, next| {
-        acc = acc + &next + "\n";
+        acc = acc + next + "\n";
         acc
     });
     debug!("Returning list of connected users");
     user_list
 }
 
-async fn user_disconnected(my_id: String, users: &Users) {
+async fn user_disconnected(my_id: String, state: &ChatState) {
+    let users = &state.users;
     error!("good bye user: {}", my_id);
 
     {
@@ -231,19 +654,139 @@ async fn user_disconnected(my_id: String, users: &Users) {
     // Send back a list of connected users.  Remember that tx is connected to rx.  Earlier
     // we forwarded rx channel to user_tx.  So anything we send via tx2 will also be received
     // by rx, and therefore will be sent over to user_tx and then over the websocket
-    let user_list = get_users(&users).await; // users.lock acquired and released here
+    let user_list = get_users(users).await; // users.lock acquired and released here
 
     let conn_list = ConnectionMsg::new(user_list);
     let connect_msg =
         message::Message::new(my_id.clone(), vec![], MessageEvent::Disconnect, conn_list);
 
-    for (user, tx) in users.lock().await.iter() {
+    let encoded = Encoded::new(&connect_msg);
+    let list = users.lock().await;
+    for (user, tx) in senders(&list) {
         if my_id != *user {
             debug!("Sending connection event to {}", user);
-            let connect_msg_str: String =
-                serde_json::to_string(&connect_msg).expect("Unable to serialize to Message");
+            let connect_msg_str = encoded.for_version(list[user].protocol.version);
             tx.send(Ok(Message::text(connect_msg_str)))
                 .expect("Failed to send to tx");
         }
     }
+    drop(list);
+    webhooks::notify(state, &connect_msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pgdb::models::Bot,
+                state::UserInfo,
+                wire::Envelope};
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_sender_is_the_session() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.users.lock().await.insert("whammo".into(), UserInfo::new(Some(tx)));
+        state.users.lock().await.insert("rubik".into(), UserInfo::new(None));
+
+        // rubik can't pretend to be stoner
+        let to = vec!["whammo".into()];
+        let forged = Envelope::new("stoner".into(), to.clone(), Event::Message("hi".into()));
+        user_text("rubik".into(), &serde_json::to_string(&forged).unwrap(), &state).await;
+        let got = rx.try_recv().expect("whammo gets the message").unwrap();
+        let got = wire::decode(got.to_str().unwrap()).unwrap();
+        assert_eq!(got.sender, "rubik");
+
+        // Nor make their message look like it came from an integration
+        let mut forged = Envelope::new("rubik".into(), to, Event::Message("hi".into()));
+        forged.annotations.insert("integration".into(), "ci".into());
+        user_text("rubik".into(), &serde_json::to_string(&forged).unwrap(), &state).await;
+        let got = rx.try_recv().expect("whammo gets the message").unwrap();
+        let got = wire::decode(got.to_str().unwrap()).unwrap();
+        assert!(got.annotations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_one_session_per_name() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (tx2, _rx2) = mpsc::unbounded_channel();
+        let open = |tx| {
+            let peer = Peer::default();
+            open_session(&state, "stoner", AccountKind::User, peer, Negotiated::legacy(), tx)
+        };
+        assert!(open(tx).await.is_ok());
+        assert!(open(tx2).await.is_err());
+
+        // The first session still gets stoner's messages
+        while rx.try_recv().is_ok() {}
+        let mesg = KMessage::new("khadga".into(), vec![], MessageEvent::Message, "hi");
+        assert!(send_to(&state.users, "stoner", &mesg).await);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_close_themselves() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let open = |tx| {
+            let peer = Peer::default();
+            open_session(&state, "stoner", AccountKind::User, peer, Negotiated::legacy(), tx)
+        };
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (first, _) = open(tx).await.unwrap();
+        close_session(&state, "stoner", &first).await;
+        assert!(state.users.lock().await.is_empty());
+
+        // A late close from the first session doesn't end the one that came after it
+        let (tx, _rx2) = mpsc::unbounded_channel();
+        let (second, _) = open(tx).await.unwrap();
+        assert_ne!(first, second);
+        close_session(&state, "stoner", &first).await;
+        assert!(state.users.lock().await.contains_key("stoner"));
+        let lobby = rooms::members(&state.rooms, rooms::LOBBY).await.unwrap();
+        assert_eq!(lobby, vec!["stoner".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_bot_names_are_taken() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let bot = Bot {
+            bot_id: 0,
+            name: "dicebot".into(),
+            owner: "stoner".into(),
+            token_hash: String::new(),
+            rooms: vec![],
+            created_on: Utc::now(),
+        };
+        state.bots.lock().await.insert("dicebot".into(), bot);
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let peer = Peer::default();
+        let opened =
+            open_session(&state, "dicebot", AccountKind::User, peer, Negotiated::legacy(), tx)
+                .await;
+        assert!(opened.is_err());
+        assert!(state.users.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_features_gate_events() {
+        let users: Users = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut info = UserInfo::new(Some(tx));
+        info.protocol = Negotiated {
+            version: 1,
+            codec: "json".into(),
+            features: vec!["suggestions".into()],
+        };
+        users.lock().await.insert("whammo".into(), info);
+
+        let to = vec!["whammo".to_string()];
+        let alert = KMessage::new("khadga".into(), to.clone(), MessageEvent::Alert, "trending");
+        assert!(!send_to(&users, "whammo", &alert).await);
+        assert!(rx.try_recv().is_err());
+        let tip = KMessage::new("khadga".into(), to, MessageEvent::Suggestion, "see above");
+        assert!(send_to(&users, "whammo", &tip).await);
+        assert!(rx.try_recv().is_ok());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,622 @@
+//! Server side slash commands
+//!
+//! A client runs a command by sending a `CommandRequest` message whose body is a
+//! `CommandRequestMsg` with an op of `CommandTypes::Slash`, and whose args are the command line
+//! the user typed, eg `/topic "Session 12" starts now`.  The `Dispatcher` splits the line into
+//! arguments (double or single quotes group words together), looks up the named command, checks
+//! the argument count, and runs it.  The caller always gets a `CommandReply` back whose response is
+//! a `CommandResponse`, holding either the command's output or an error explaining what went wrong.
+//!
+//! Commands are registered with the dispatcher at startup, so adding a new one is a matter of
+//! implementing `SlashCommand` and calling `Dispatcher::register`.
+
+use crate::{chat::{send_chat,
+                   send_to,
+                   ChatState},
+            mesh,
+            message::{Message,
+                      MessageEvent},
+            rooms::{self,
+                    Topic},
+            search::Search};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize,
+            Serialize};
+use serde_json::{json,
+                 Value};
+use std::collections::BTreeMap;
+
+/// What the caller gets back in the `response` of the `CommandReplyMsg`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CommandResponse {
+    Output(Value),
+    Error(CommandError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandError {
+    /// The command that was run, or the raw line if it couldn't be parsed
+    pub command: String,
+    pub message: String,
+    /// How the command should have been called, if the command is known
+    pub usage: Option<String>,
+}
+
+/// Everything a command needs to know about who called it and from where
+pub struct CommandContext<'a> {
+    pub caller: &'a str,
+    /// The room the request was sent from
+    pub room: &'a str,
+    /// The original request, so commands can reuse its recipients
+    pub request: &'a Message<String>,
+    pub state: &'a ChatState,
+}
+
+#[async_trait]
+pub trait SlashCommand: Send + Sync {
+    /// Name of the command without the leading slash
+    fn name(&self) -> &'static str;
+
+    /// One line usage, eg `/whois <user>`
+    fn usage(&self) -> &'static str;
+
+    /// Short description shown by `/help`
+    fn help(&self) -> &'static str;
+
+    /// Minimum and (optional) maximum number of arguments
+    fn arity(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String>;
+}
+
+/// Splits a command line into words
+///
+/// Words are separated by whitespace.  Double or single quotes group words together, and a
+/// backslash escapes the next character.
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                let next = chars.next().ok_or_else(|| "Trailing backslash".to_string())?;
+                current.push(next);
+                in_word = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => current.push(c),
+            ('"', None) | ('\'', None) => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (c, None) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (c, None) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if let Some(q) = quote {
+        return Err(format!("Unterminated {} quote", q));
+    }
+    if in_word {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// Holds the registered commands and runs them
+#[derive(Default)]
+pub struct Dispatcher {
+    commands: BTreeMap<&'static str, Box<dyn SlashCommand>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// A dispatcher with all the commands that ship with khadga
+    pub fn with_builtins() -> Self {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(Who {});
+        dispatcher.register(Me {});
+        dispatcher.register(TopicCmd {});
+        dispatcher.register(Help {});
+        dispatcher.register(Whois {});
+        dispatcher.register(Join {});
+        dispatcher.register(Leave {});
+        dispatcher.register(Search {});
+        dispatcher
+    }
+
+    /// Registers a command, replacing any command that already has the same name
+    pub fn register<C: SlashCommand + 'static>(&mut self, cmd: C) {
+        self.commands.insert(cmd.name(), Box::new(cmd));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn SlashCommand> {
+        self.commands.get(name).map(|c| c.as_ref())
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &dyn SlashCommand> {
+        self.commands.values().map(|c| c.as_ref())
+    }
+
+    /// Parses the command line and runs the command it names
+    pub async fn dispatch(&self, ctx: &CommandContext<'_>, line: &str) -> CommandResponse {
+        let error = |command: &str, message: String, usage: Option<&str>| {
+            CommandResponse::Error(CommandError {
+                command: command.into(),
+                message,
+                usage: usage.map(String::from),
+            })
+        };
+
+        let mut args = match split_args(line.trim()) {
+            Ok(args) => args,
+            Err(e) => return error(line, e, None),
+        };
+        if args.is_empty() {
+            return error(line, "Empty command".into(), None);
+        }
+
+        let name = args.remove(0);
+        let name = name.trim_start_matches('/');
+        let cmd = match self.get(name) {
+            Some(cmd) => cmd,
+            None => return error(name, format!("Unknown command /{}, try /help", name), None),
+        };
+
+        let (min, max) = cmd.arity();
+        if args.len() < min || max.map(|m| args.len() > m).unwrap_or(false) {
+            return error(name, "Wrong number of arguments".into(), Some(cmd.usage()));
+        }
+
+        match cmd.run(ctx, args).await {
+            Ok(output) => CommandResponse::Output(output),
+            Err(e) => error(name, e, Some(cmd.usage())),
+        }
+    }
+}
+
+/// Lists the users in a room
+pub struct Who {}
+
+#[async_trait]
+impl SlashCommand for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/who [room]"
+    }
+
+    fn help(&self) -> &'static str {
+        "List the users in this room, or in the named room"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (0, Some(1))
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let room = args.first().map(String::as_str).unwrap_or(ctx.room);
+        match rooms::members(&ctx.state.rooms, room).await {
+            // Who is in a room is only for the people in it
+            Some(members) if !members.iter().any(|m| m == ctx.caller) => {
+                Err(format!("You are not in {}", room))
+            }
+            Some(members) => Ok(json!({ "room": room, "users": members })),
+            None => Err(format!("No such room {}", room)),
+        }
+    }
+}
+
+/// Sends an emote, eg `/me rolls a d20` shows up as `* stoner rolls a d20`
+pub struct Me {}
+
+#[async_trait]
+impl SlashCommand for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Describe what you are doing"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (1, None)
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let text = format!("* {} {}", ctx.caller, args.join(" "));
+        let mut emote = Message::new(
+            ctx.caller.into(),
+            ctx.request.recipients.clone(),
+            MessageEvent::Message,
+            text,
+        );
+        emote.room = ctx.request.room.clone();
+        emote.conversation = ctx.request.conversation;
+        emote.annotations.insert("emote".into(), "true".into());
+
+        // An emote is a chat message like any other, so it gets the same checks and filters
+        send_chat(ctx.state, ctx.caller, &mut emote).await.map_err(|r| r.to_string())?;
+        Ok(json!({ "sent": emote.body }))
+    }
+}
+
+/// Shows or changes the topic of the room
+pub struct TopicCmd {}
+
+#[async_trait]
+impl SlashCommand for TopicCmd {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/topic [new topic]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the topic of this room, or set it"
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        if args.is_empty() {
+            let rooms = ctx.state.rooms.lock().await;
+            let room = rooms.get(ctx.room).ok_or(format!("No such room {}", ctx.room))?;
+            return Ok(json!({ "room": ctx.room, "topic": room.topic }));
+        }
+
+        let topic = Topic {
+            text: args.join(" "),
+            set_by: ctx.caller.into(),
+            time: Utc::now(),
+        };
+        let members = {
+            let mut rooms = ctx.state.rooms.lock().await;
+            let room = rooms.get_mut(ctx.room).ok_or(format!("No such room {}", ctx.room))?;
+            if !room.members.contains(ctx.caller) {
+                return Err(format!("You are not in {}", ctx.room));
+            }
+            room.topic = Some(topic.clone());
+            room.members.iter().cloned().collect::<Vec<String>>()
+        };
+
+        // Let everyone in the room know the topic changed
+        let mut notice = Message::new(
+            "khadga".into(),
+            members.clone(),
+            MessageEvent::Message,
+            format!("{} set the topic to: {}", ctx.caller, topic.text),
+        );
+        notice.room = Some(ctx.room.into());
+        notice.annotations.insert("topic".into(), topic.text.clone());
+        for member in members.iter() {
+            send_to(&ctx.state.users, member, &notice).await;
+        }
+
+        Ok(json!({ "room": ctx.room, "topic": topic }))
+    }
+}
+
+/// Lists the commands, or shows the usage of one of them
+pub struct Help {}
+
+#[async_trait]
+impl SlashCommand for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/help [command]"
+    }
+
+    fn help(&self) -> &'static str {
+        "List the available commands"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (0, Some(1))
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let describe = |cmd: &dyn SlashCommand| {
+            json!({ "name": cmd.name(), "usage": cmd.usage(), "help": cmd.help() })
+        };
+
+        match args.first() {
+            Some(name) => {
+                let name = name.trim_start_matches('/');
+                let cmd = ctx
+                    .state
+                    .commands
+                    .get(name)
+                    .ok_or(format!("Unknown command /{}", name))?;
+                Ok(describe(cmd))
+            }
+            None => {
+                let all: Vec<Value> = ctx.state.commands.commands().map(describe).collect();
+                Ok(json!({ "commands": all }))
+            }
+        }
+    }
+}
+
+/// Shows what the server knows about a user
+pub struct Whois {}
+
+#[async_trait]
+impl SlashCommand for Whois {
+    fn name(&self) -> &'static str {
+        "whois"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/whois <user>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show when a user logged in and when they last said something"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (1, Some(1))
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let user = &args[0];
+        let info = {
+            let users = ctx.state.users.lock().await;
+            users.get(user).map(|info| {
+                json!({
+                    "login_time": info.login_time,
+                    "last_message": info.last_message(),
+                })
+            })
+        };
+        let info = info.ok_or(format!("{} is not online", user))?;
+        let rooms = rooms::rooms_of(&ctx.state.rooms, user).await;
+
+        Ok(json!({
+            "user": user,
+            "login_time": info["login_time"],
+            "last_message": info["last_message"],
+            "rooms": rooms,
+        }))
+    }
+}
+
+/// Joins a room
+pub struct Join {}
+
+#[async_trait]
+impl SlashCommand for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/join <room>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Join a room, creating it if it doesn't exist"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (1, Some(1))
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let room = &args[0];
+        rooms::join(&ctx.state.rooms, room, ctx.caller).await;
+        Ok(json!({ "joined": room }))
+    }
+}
+
+/// Leaves a room
+pub struct Leave {}
+
+#[async_trait]
+impl SlashCommand for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/leave <room>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Leave a room"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (1, Some(1))
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let room = &args[0];
+        if room == rooms::LOBBY {
+            return Err("You can't leave the lobby".into());
+        }
+        if !rooms::leave(&ctx.state.rooms, room, ctx.caller).await {
+            return Err(format!("You are not in {}", room));
+        }
+        mesh::left_room(ctx.state, room, ctx.caller).await;
+        Ok(json!({ "left": room }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filter::FilterChain,
+                state::UserInfo};
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args("/me rolls  a d20").unwrap(), vec!["/me", "rolls", "a", "d20"]);
+        assert_eq!(
+            split_args(r#"/topic "Session 12" isn't\ here"#).unwrap_err(),
+            "Unterminated ' quote"
+        );
+        assert_eq!(
+            split_args(r#"/topic "Session 12" is\ 'on now'"#).unwrap(),
+            vec!["/topic", "Session 12", "is on now"]
+        );
+        assert_eq!(split_args("   ").unwrap(), Vec::<String>::new());
+    }
+
+    async fn setup() -> ChatState {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        for user in ["stoner", "whammo"].iter() {
+            state.users.lock().await.insert(user.to_string(), UserInfo::new(None));
+            rooms::join(&state.rooms, rooms::LOBBY, user).await;
+        }
+        state
+    }
+
+    async fn run(state: &ChatState, line: &str) -> CommandResponse {
+        let request =
+            Message::new("stoner".into(), vec![], MessageEvent::CommandRequest, line.into());
+        let ctx = CommandContext {
+            caller: "stoner",
+            room: rooms::LOBBY,
+            request: &request,
+            state,
+        };
+        state.commands.dispatch(&ctx, line).await
+    }
+
+    #[tokio::test]
+    async fn test_builtins() {
+        let state = setup().await;
+
+        let who = run(&state, "/who").await;
+        assert_eq!(
+            who,
+            CommandResponse::Output(json!({ "room": "lobby", "users": ["stoner", "whammo"] }))
+        );
+
+        match run(&state, "/topic Rolling for initiative").await {
+            CommandResponse::Output(out) => {
+                assert_eq!(out["topic"]["text"], "Rolling for initiative")
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+        match run(&state, "/topic").await {
+            CommandResponse::Output(out) => assert_eq!(out["topic"]["set_by"], "stoner"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        match run(&state, "/whois whammo").await {
+            CommandResponse::Output(out) => assert_eq!(out["rooms"], json!(["lobby"])),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        match run(&state, "/help").await {
+            CommandResponse::Output(out) => {
+                assert_eq!(out["commands"].as_array().unwrap().len(), 8)
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let state = setup().await;
+
+        match run(&state, "/whois").await {
+            CommandResponse::Error(e) => {
+                assert_eq!(e.command, "whois");
+                assert_eq!(e.usage, Some("/whois <user>".into()));
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+        match run(&state, "/whois nobody").await {
+            CommandResponse::Error(e) => assert_eq!(e.message, "nobody is not online"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+        match run(&state, "/frobnicate").await {
+            CommandResponse::Error(e) => assert_eq!(e.usage, None),
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let state = setup().await;
+
+        match run(&state, "/search").await {
+            CommandResponse::Error(e) => assert_eq!(e.command, "search"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+        // Filters without any terms
+        match run(&state, "/search room:dnd").await {
+            CommandResponse::Error(e) => assert_eq!(e.message, "Nothing to search for"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+        match run(&state, "/search sender:whammo dice").await {
+            CommandResponse::Error(e) => assert_eq!(e.message, "Chat history is not available"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rooms_you_are_not_in() {
+        let state = setup().await;
+        rooms::join(&state.rooms, "dnd", "whammo").await;
+
+        match run(&state, "/who dnd").await {
+            CommandResponse::Error(e) => assert_eq!(e.message, "You are not in dnd"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // An emote has to pass the same checks as any other chat message
+        let mut request =
+            Message::new("stoner".into(), vec![], MessageEvent::CommandRequest, "/me".into());
+        request.room = Some("dnd".into());
+        let ctx = CommandContext {
+            caller: "stoner",
+            room: "dnd",
+            request: &request,
+            state: &state,
+        };
+        match state.commands.dispatch(&ctx, "/me sneaks in").await {
+            CommandResponse::Error(e) => assert!(e.message.contains("You are not in dnd")),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        match run(&state, "/me waves").await {
+            CommandResponse::Output(out) => assert_eq!(out["sent"], "* stoner waves"),
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+}
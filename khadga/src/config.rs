@@ -5,7 +5,8 @@ use config::{Config,
              File};
 use serde::{Deserialize,
             Serialize};
-use std::fmt;
+use std::{collections::HashMap,
+          fmt};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct MongoCfg {
@@ -71,11 +72,25 @@ pub struct Tables {
     pub posts: String,
     pub accounts: String,
     pub uploads: String,
-    pub comments: String
+    pub comments: String,
+    pub bots: String,
+    pub webhooks: String,
+    pub webhook_deliveries: String,
+    pub dead_letters: String,
+    pub messages: String,
+    pub incoming_webhooks: String,
+    pub legal_holds: String,
+    pub public_keys: String,
+    pub devices: String,
+    pub prekeys: String,
+    pub user_groups: String,
+    pub conversations: String,
+    pub calls: String,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct DataBase {
+    pub name: String,
     pub tables: Tables,
     pub port: u16,
     pub tls: bool
@@ -89,8 +104,38 @@ impl fmt::Display for Tables {
             posts: {}
             accounts: {}
             uploads: {}
-            comments: {}"#,
-            self.users, self.posts, self.accounts, self.uploads, self.comments
+            comments: {}
+            bots: {}
+            webhooks: {}
+            webhook_deliveries: {}
+            dead_letters: {}
+            messages: {}
+            incoming_webhooks: {}
+            legal_holds: {}
+            public_keys: {}
+            devices: {}
+            prekeys: {}
+            user_groups: {}
+            conversations: {}
+            calls: {}"#,
+            self.users,
+            self.posts,
+            self.accounts,
+            self.uploads,
+            self.comments,
+            self.bots,
+            self.webhooks,
+            self.webhook_deliveries,
+            self.dead_letters,
+            self.messages,
+            self.incoming_webhooks,
+            self.legal_holds,
+            self.public_keys,
+            self.devices,
+            self.prekeys,
+            self.user_groups,
+            self.conversations,
+            self.calls
         )
     }
 }
@@ -99,10 +144,11 @@ impl fmt::Display for DataBase {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            r#"port: {}
+            r#"name: {}
+            port: {}
             tls: {}
             tables: {}"#,
-            self.port, self.tls, self.tables
+            self.name, self.port, self.tls, self.tables
         )
     }
 }
@@ -155,6 +201,260 @@ impl fmt::Display for MimirConfig {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProfanityCfg {
+    pub words: Vec<String>,
+    /// If true, messages containing a word are rejected instead of masked
+    pub reject: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LinksCfg {
+    pub upgrade_http: bool,
+    /// Query parameters starting with any of these prefixes are removed from links
+    pub strip_params: Vec<String>,
+}
+
+/// Settings for the chat message filters (see `filter::FilterChain`)
+#[derive(Deserialize, Serialize, Debug)]
+pub struct FilterCfg {
+    /// Names of the filters to run, in order
+    pub enabled: Vec<String>,
+    pub profanity: ProfanityCfg,
+    pub links: LinksCfg,
+    pub max_length: usize,
+}
+
+/// Settings for outgoing webhooks (see `webhooks`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WebhookCfg {
+    /// How many times a delivery is tried before it is moved to the dead letter table
+    pub max_attempts: u32,
+    /// How long to wait before the first retry.  This doubles after every failed attempt.
+    pub backoff_ms: u64,
+    /// The longest we will ever wait between two attempts
+    pub max_backoff_ms: u64,
+    pub timeout_secs: u64,
+}
+
+/// Settings for the trending terms (see `trends`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TrendCfg {
+    pub enabled: bool,
+    /// How many seconds of messages are counted together
+    pub bucket_secs: u64,
+    /// How many buckets make up "right now"
+    pub window_buckets: u64,
+    /// How many buckets before the window are used as the baseline
+    pub baseline_buckets: u64,
+    /// The fewest mentions in the window for a term to trend
+    pub min_count: u32,
+    /// How many standard deviations above the baseline a term has to be
+    pub threshold: f64,
+    /// Send an `Alert` event to the connected admins when a term starts trending
+    pub alert: bool,
+    pub alert_cooldown_secs: u64,
+}
+
+impl Default for TrendCfg {
+    fn default() -> Self {
+        TrendCfg {
+            enabled: true,
+            bucket_secs: 60,
+            window_buckets: 5,
+            baseline_buckets: 60,
+            min_count: 5,
+            threshold: 4.0,
+            alert: false,
+            alert_cooldown_secs: 1800,
+        }
+    }
+}
+
+/// Settings for suggesting earlier answers to similar questions (see `questions`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QuestionCfg {
+    pub enabled: bool,
+    /// The cosine similarity, from 0 to 1, a question needs to an earlier one to suggest it
+    pub threshold: f64,
+    /// How long after a question replies count as answers to it
+    pub answer_window_secs: u64,
+    pub max_answers: usize,
+    /// The most questions to remember.  The oldest are forgotten first.
+    pub max_questions: usize,
+}
+
+impl Default for QuestionCfg {
+    fn default() -> Self {
+        QuestionCfg {
+            enabled: true,
+            threshold: 0.6,
+            answer_window_secs: 600,
+            max_answers: 5,
+            max_questions: 10000,
+        }
+    }
+}
+
+/// How long saved messages and uploads are kept (see `retention`)
+///
+/// Periods are in days, and None means forever.  A rule for the room a message is in wins over a
+/// rule for its type, which wins over `message_days`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionCfg {
+    /// Run the purge job in the background
+    pub enabled: bool,
+    /// Only log what the job would purge
+    pub dry_run: bool,
+    pub interval_secs: u64,
+    /// How many rows are purged at a time
+    pub batch_size: i64,
+    /// If set, purged messages and uploads are moved here instead of being thrown away
+    pub archive_dir: Option<String>,
+    /// Where the uploaded files are kept
+    pub upload_dir: String,
+    pub message_days: Option<u32>,
+    /// Periods for particular rooms.  Messages without a room are in the lobby.
+    pub rooms: HashMap<String, u32>,
+    /// Periods for types of message: bot, integration, emote, topic or message
+    pub types: HashMap<String, u32>,
+    pub upload_days: Option<u32>,
+}
+
+impl Default for RetentionCfg {
+    fn default() -> Self {
+        RetentionCfg {
+            enabled: false,
+            dry_run: true,
+            interval_secs: 3600,
+            batch_size: 500,
+            archive_dir: None,
+            upload_dir: "uploads".into(),
+            message_days: None,
+            rooms: HashMap::new(),
+            types: HashMap::new(),
+            upload_days: None,
+        }
+    }
+}
+
+/// Settings for WebRTC calls (see `signaling`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct CallCfg {
+    /// How long a call rings before it is missed
+    pub ring_timeout_secs: u64,
+    /// The most people in a room's call (see `mesh`).  Everybody connects to everybody else, so
+    /// this can't be very big.
+    pub max_participants: usize,
+}
+
+impl Default for CallCfg {
+    fn default() -> Self {
+        CallCfg {
+            ring_timeout_secs: 30,
+            max_participants: 6,
+        }
+    }
+}
+
+/// Settings for the STUN server (see `stun`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct StunCfg {
+    pub enabled: bool,
+    /// The UDP address to listen on
+    pub listen: String,
+    /// Sent in the SOFTWARE attribute of every response
+    pub software: Option<String>,
+}
+
+impl Default for StunCfg {
+    fn default() -> Self {
+        StunCfg {
+            enabled: false,
+            listen: "0.0.0.0:3478".into(),
+            software: Some("khadga".into()),
+        }
+    }
+}
+
+/// Settings for the TURN relay (see `turn`)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct TurnCfg {
+    pub enabled: bool,
+    /// The UDP address to listen on.  TURN answers Binding requests too, so this can be the STUN
+    /// port, as long as the STUN server isn't also enabled.
+    pub listen: String,
+    /// The IP the relayed sockets are opened on, which is also what clients are told to use
+    pub relay_ip: String,
+    /// Told to clients instead of `relay_ip`, when khadga is behind a 1:1 NAT
+    pub external_ip: Option<String>,
+    pub realm: String,
+    /// Signs the passwords handed out.  A random one is made at startup if this isn't set.
+    pub secret: Option<String>,
+    /// How long the passwords handed out work for
+    pub credential_ttl_secs: i64,
+    pub default_lifetime_secs: u32,
+    pub max_lifetime_secs: u32,
+    pub max_allocations: usize,
+    pub max_allocations_per_user: usize,
+    /// How many bytes a second each allocation can relay, both ways together
+    pub bandwidth_bytes_per_sec: u64,
+    /// Peers nobody can relay to, as addresses or ranges like `10.0.0.0/8`.  By default these are
+    /// the loopback, private, link-local and multicast ranges, so the relay can't be used to get
+    /// into the network khadga is on.
+    pub denied_peers: Vec<String>,
+    /// Handed out with the credentials, eg `turn:khadga.example.com:3478`
+    pub urls: Vec<String>,
+    /// Sent in the SOFTWARE attribute of every response
+    pub software: Option<String>,
+}
+
+impl Default for TurnCfg {
+    fn default() -> Self {
+        TurnCfg {
+            enabled: false,
+            listen: "0.0.0.0:3478".into(),
+            relay_ip: "127.0.0.1".into(),
+            external_ip: None,
+            realm: "khadga".into(),
+            secret: None,
+            credential_ttl_secs: 24 * 60 * 60,
+            default_lifetime_secs: 600,
+            max_lifetime_secs: 3600,
+            max_allocations: 200,
+            max_allocations_per_user: 4,
+            bandwidth_bytes_per_sec: 500_000,
+            denied_peers: [
+                "0.0.0.0/8",
+                "10.0.0.0/8",
+                "100.64.0.0/10",
+                "127.0.0.0/8",
+                "169.254.0.0/16",
+                "172.16.0.0/12",
+                "192.0.0.0/24",
+                "192.168.0.0/16",
+                "198.18.0.0/15",
+                "224.0.0.0/4",
+                "240.0.0.0/4",
+                "::/128",
+                "::1/128",
+                "fc00::/7",
+                "fe80::/10",
+                "ff00::/8",
+            ]
+            .iter()
+            .map(|range| range.to_string())
+            .collect(),
+            urls: vec![],
+            software: Some("khadga".into()),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Settings {
     pub services: Services,
@@ -162,7 +462,24 @@ pub struct Settings {
     pub tls: TLS,
     pub host: String,
     pub port: u16,
-    pub db: DataBase
+    pub db: DataBase,
+    pub filters: FilterCfg,
+    /// Users that can use the admin endpoints
+    #[serde(default)]
+    pub admins: Vec<String>,
+    pub webhooks: WebhookCfg,
+    #[serde(default)]
+    pub trends: TrendCfg,
+    #[serde(default)]
+    pub questions: QuestionCfg,
+    #[serde(default)]
+    pub retention: RetentionCfg,
+    #[serde(default)]
+    pub calls: CallCfg,
+    #[serde(default)]
+    pub stun: StunCfg,
+    #[serde(default)]
+    pub turn: TurnCfg,
 }
 
 impl fmt::Display for Settings {
@@ -186,7 +503,7 @@ impl Settings {
             std::env::set_var("MIMIR_NODE_IP_SERVICE_SERVICE_PORT", "3000");
             
             for (k, v) in std::env::vars().filter(|(key, _)| {
-                return key.starts_with("MIMIR_NODE_IP_SERVICE_SERVICE")
+                key.starts_with("MIMIR_NODE_IP_SERVICE_SERVICE")
             }) {
                 println!("{} = {}", k, v);
             }
@@ -224,7 +541,7 @@ mod tests {
         println!("Settings = {:#?}", settings);
 
         let mut khadga_host: String = "0.0.0.0".into();
-        if let Ok(_) = std::env::var("KHADGA_DEV") {
+        if std::env::var("KHADGA_DEV").is_ok() {
             khadga_host = "localhost".into();
         }
 
@@ -240,7 +557,7 @@ mod tests {
         /* assert_eq!(mimir_host, settings.services.mimir.host); */
         assert_eq!("debug", settings.logging.level.repr());
 
-        assert_eq!(true, settings.tls.set);
+        assert!(settings.tls.set);
 
         std::env::remove_var("KHADGA_DEV");
         let _settings = Settings::new()?;
@@ -0,0 +1,372 @@
+//! Direct message conversations
+//!
+//! Direct messages are kept in conversations, each with a stable id, its members, when it was made
+//! and when it was last active.  There are two kinds:
+//!
+//! - one to one.  Every pair of people who message each other has exactly one.  A message with no
+//!   room and a single recipient (who isn't the sender) is put in it, and khadga sets the message's
+//!   `conversation` to its id.
+//! - group, for up to `MAX_MEMBERS` people.  These are made with the API below, and a message goes
+//!   to one by setting `conversation`.  The recipients are then the other members, whatever the
+//!   client said.
+//!
+//! Every message in a conversation moves its last activity up.  Conversations need the database,
+//! so without one, direct messages are relayed as before but aren't attached to anything.
+//!
+//! The endpoints are for people who are logged in, and only show conversations they are in:
+//!
+//! - `POST /conversations` with `{"members": ["whammo", "rubik"]}` makes a group conversation with
+//!   you in it.  With only one other person, you get your one to one conversation with them.
+//! - `GET /conversations` lists yours, the most recently active first
+//! - `GET /conversations/<id>` gets one
+//! - `GET /conversations/<id>/messages` gets its messages, newest first.  `limit` is how many (50
+//!   by default, at most 200), and `before` a message id to page back from.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            groups,
+            message::Message as KMessage,
+            pgdb::{models::Conversation,
+                   pgdb::{self,
+                          DbClient}},
+            reply::error_reply};
+use chrono::{DateTime,
+             TimeZone,
+             Utc};
+use log::{error,
+          info};
+use serde::Deserialize;
+use std::convert::Infallible;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// The most people in a conversation, including whoever made it
+pub const MAX_MEMBERS: usize = 10;
+/// How many conversations `GET /conversations` returns
+const LIST_LIMIT: i64 = 200;
+const DEFAULT_MESSAGES: i64 = 50;
+const MAX_MESSAGES: i64 = 200;
+
+/// The members of the one to one conversation a message belongs in, if it is a direct message
+///
+/// The members are sorted, so the same two people always get the same conversation.
+pub fn direct_members<T>(mesg: &KMessage<T>) -> Option<Vec<String>> {
+    match (&mesg.room, mesg.recipients.as_slice()) {
+        (None, [to]) if *to != mesg.sender && !groups::has_addresses(&mesg.recipients) => {
+            let mut members = vec![mesg.sender.clone(), to.clone()];
+            members.sort();
+            Some(members)
+        }
+        _ => None,
+    }
+}
+
+/// Sorts the members of a new conversation and adds its creator, or says what is wrong with them
+pub fn check_members(creator: &str, mut members: Vec<String>) -> Result<Vec<String>, String> {
+    members.push(creator.to_string());
+    members.sort();
+    members.dedup();
+    if let Some(bad) = members.iter().find(|m| m.is_empty() || m.starts_with('@')) {
+        return Err(format!("{:?} is not a username", bad));
+    }
+    match members.len() {
+        1 => Err("A conversation needs somebody else in it".into()),
+        n if n > MAX_MEMBERS => {
+            Err(format!("A conversation can have at most {} people", MAX_MEMBERS))
+        }
+        _ => Ok(members),
+    }
+}
+
+/// Puts a chat message from a user in its conversation
+///
+/// A message that names a conversation is checked, and its recipients set to the other members.
+/// A direct message is put in its one to one conversation.  `sender` is who the session belongs
+/// to, which is what membership is checked against.  The error is why the message can't be sent.
+pub async fn attach(
+    state: &ChatState,
+    sender: &str,
+    mesg: &mut KMessage<String>,
+) -> Result<(), String> {
+    let db = match (&state.db, mesg.conversation) {
+        (Some(db), _) => db,
+        (None, Some(_)) => return Err("Conversations are not available right now".into()),
+        (None, None) => return Ok(()),
+    };
+    let table = &CONFIG.db.tables.conversations;
+
+    if let Some(id) = mesg.conversation {
+        if mesg.room.is_some() {
+            return Err("Conversations aren't in rooms".into());
+        }
+        let conversation = match pgdb::get_conversation(db, table, id).await {
+            Ok(Some(conversation)) => conversation,
+            Ok(None) => return Err("No such conversation".into()),
+            Err(e) => {
+                error!("Unable to read conversation {}: {}", id, e);
+                return Err("Conversations are not available right now".into());
+            }
+        };
+        if !conversation.members.iter().any(|m| m == sender) {
+            return Err("You are not in that conversation".into());
+        }
+        mesg.sender = sender.to_string();
+        mesg.recipients = conversation.members.into_iter().filter(|m| m != sender).collect();
+        return Ok(());
+    }
+
+    if let Some(members) = direct_members(mesg) {
+        let time = Utc.timestamp_millis(mesg.time);
+        match pgdb::direct_conversation(db, table, &members, &mesg.sender, &time).await {
+            Ok(conversation) => mesg.conversation = Some(conversation.conversation_id),
+            // The message can still go through, it just won't be in the conversation
+            Err(e) => error!("Unable to find the conversation for {:?}: {}", members, e),
+        }
+    }
+    Ok(())
+}
+
+/// Moves the conversation's last activity up to the time of a new message
+pub async fn touch(db: &DbClient, id: i32, time: &DateTime<Utc>) {
+    let table = &CONFIG.db.tables.conversations;
+    if let Err(e) = pgdb::touch_conversation(db, table, id, time).await {
+        error!("Unable to update conversation {}: {}", id, e);
+    }
+}
+
+/// Creates the conversations table, and the column that attaches messages to them
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let tables = &CONFIG.db.tables;
+    if let Err(e) = pgdb::make_table_conversations(&tables.conversations, db).await {
+        error!("Unable to create the {} table: {}", tables.conversations, e);
+    }
+    if let Err(e) = pgdb::add_conversation_column(&tables.messages, db).await {
+        error!("Unable to add the conversation column to {}: {}", tables.messages, e);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewConversation {
+    pub members: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Page {
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+fn no_db() -> WithStatus<Json> {
+    error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")
+}
+
+/// `POST /conversations`
+pub async fn create_conversation(
+    user: String,
+    new: NewConversation,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let members = match check_members(&user, new.members) {
+        Ok(members) => members,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    };
+    let table = &CONFIG.db.tables.conversations;
+    let now = Utc::now();
+
+    if members.len() == 2 {
+        return match pgdb::direct_conversation(db, table, &members, &user, &now).await {
+            Ok(conversation) => Ok(reply::with_status(reply::json(&conversation), StatusCode::OK)),
+            Err(e) => {
+                error!("Unable to find the conversation for {:?}: {}", members, e);
+                let msg = "Unable to save conversation";
+                Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, msg))
+            }
+        };
+    }
+
+    let mut conversation = Conversation {
+        conversation_id: -1,
+        members,
+        direct: false,
+        created_by: user,
+        created_on: now,
+        last_activity: now,
+    };
+    match pgdb::insert_conversation(db, table, &conversation).await {
+        Ok(id) => conversation.conversation_id = id,
+        Err(e) => {
+            error!("Unable to save conversation: {}", e);
+            let msg = "Unable to save conversation";
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, msg));
+        }
+    }
+    info!("{} started conversation {}", conversation.created_by, conversation.conversation_id);
+    Ok(reply::with_status(reply::json(&conversation), StatusCode::CREATED))
+}
+
+/// `GET /conversations`
+pub async fn list_conversations(
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let table = &CONFIG.db.tables.conversations;
+    match pgdb::list_conversations(db, table, &user, LIST_LIMIT).await {
+        Ok(list) => Ok(reply::with_status(reply::json(&list), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to list the conversations of {}: {}", user, e);
+            let msg = "Unable to read conversations";
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, msg))
+        }
+    }
+}
+
+/// The conversation, if the user is in it
+async fn member_of(
+    db: &DbClient,
+    id: i32,
+    user: &str,
+) -> Result<Conversation, WithStatus<Json>> {
+    match pgdb::get_conversation(db, &CONFIG.db.tables.conversations, id).await {
+        Ok(Some(conversation)) if conversation.members.iter().any(|m| m == user) => {
+            Ok(conversation)
+        }
+        // Other people's conversations might as well not exist
+        Ok(_) => Err(error_reply(StatusCode::NOT_FOUND, "No such conversation")),
+        Err(e) => {
+            error!("Unable to read conversation {}: {}", id, e);
+            let msg = "Unable to read conversation";
+            Err(error_reply(StatusCode::INTERNAL_SERVER_ERROR, msg))
+        }
+    }
+}
+
+/// `GET /conversations/<id>`
+pub async fn get_conversation(
+    id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match member_of(db, id, &user).await {
+        Ok(conversation) => Ok(reply::with_status(reply::json(&conversation), StatusCode::OK)),
+        Err(reply) => Ok(reply),
+    }
+}
+
+/// `GET /conversations/<id>/messages`
+pub async fn messages(
+    id: i32,
+    user: String,
+    page: Page,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    if let Err(reply) = member_of(db, id, &user).await {
+        return Ok(reply);
+    }
+    let limit = page.limit.unwrap_or(DEFAULT_MESSAGES).clamp(1, MAX_MESSAGES);
+    let table = &CONFIG.db.tables.messages;
+    match pgdb::conversation_messages(db, table, id, page.before, limit).await {
+        Ok(messages) => Ok(reply::with_status(reply::json(&messages), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to read the messages of conversation {}: {}", id, e);
+            let msg = "Unable to read messages";
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, msg))
+        }
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path("conversations"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_conversation);
+
+    let list = warp::get()
+        .and(warp::path("conversations"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(list_conversations);
+
+    let get = warp::get()
+        .and(warp::path!("conversations" / i32))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(get_conversation);
+
+    let messages = warp::get()
+        .and(warp::path!("conversations" / i32 / "messages"))
+        .and(authenticated())
+        .and(warp::query())
+        .and(with_state)
+        .and_then(messages);
+
+    create
+        .or(list)
+        .or(get)
+        .or(messages)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageEvent;
+
+    #[test]
+    fn test_direct_members() {
+        let mesg = |to: &[&str], room: Option<&str>| {
+            let to = to.iter().map(|s| s.to_string()).collect();
+            let mut mesg = KMessage::new("whammo".into(), to, MessageEvent::Message, "hi");
+            mesg.room = room.map(String::from);
+            mesg
+        };
+        let expected = Some(vec!["stoner".to_string(), "whammo".to_string()]);
+        assert_eq!(direct_members(&mesg(&["stoner"], None)), expected);
+        assert_eq!(direct_members(&mesg(&["stoner"], Some("dnd"))), None);
+        assert_eq!(direct_members(&mesg(&["stoner", "rubik"], None)), None);
+        assert_eq!(direct_members(&mesg(&["whammo"], None)), None);
+        assert_eq!(direct_members(&mesg(&["@room"], None)), None);
+        assert_eq!(direct_members(&mesg(&[], None)), None);
+    }
+
+    #[test]
+    fn test_check_members() {
+        let members = vec!["whammo".into(), "rubik".into(), "whammo".into()];
+        assert_eq!(check_members("stoner", members).unwrap(), vec!["rubik", "stoner", "whammo"]);
+        assert!(check_members("stoner", vec!["stoner".into()]).is_err());
+        assert!(check_members("stoner", vec!["@all".into()]).is_err());
+        let crowd = (0..MAX_MEMBERS).map(|i| format!("user{}", i)).collect();
+        assert!(check_members("stoner", crowd).is_err());
+    }
+}
@@ -0,0 +1,463 @@
+//! Training data corpus
+//!
+//! The point of khadga is to collect chat for NLP training (see RATIONALE.md), but only from people
+//! who agreed to it.  Each user has a consent flag in the users table, which they can read and set
+//! with `GET /consent` and `PUT /consent` (`{ "consent": true }`).
+//!
+//! The `khadga-corpus` tool writes out the messages of consenting users as sharded JSON Lines,
+//! along with a `manifest.json` that lists the shards and their SHA-256 hashes.  Before anything
+//! is written:
+//!
+//! - Usernames are replaced by pseudonyms, both as speakers and where they are mentioned in the
+//!   text.  The pseudonyms are an HMAC of the username keyed with a salt, so the same salt gives the
+//!   same pseudonyms on every run.  Keep the salt secret, or the pseudonyms can be reversed.
+//! - The email addresses of known users are replaced with an address made from their pseudonym
+//! - Any other email addresses and phone numbers are replaced with `[EMAIL]` and `[PHONE]`
+//! - Recipients that are addresses, like `@room` or `@group:party`, are not people and are kept
+//!
+//! Messages from bots, and from people who have withdrawn consent, are left out.  So is anything
+//! somebody said before they consented.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            groups::Address,
+            pgdb::{models::{ChatMessage,
+                            UserConsent},
+                   pgdb},
+            reply::error_reply,
+            util::to_hex};
+use chrono::{DateTime,
+             Utc};
+use lazy_static::lazy_static;
+use log::{error,
+          info};
+use regex::Regex;
+use ring::{digest,
+           hmac};
+use serde::{Deserialize,
+            Serialize};
+use std::{convert::Infallible,
+          fs::File,
+          io::{self,
+               BufWriter,
+               Write},
+          path::{Path,
+                 PathBuf}};
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// Bump this when the record format changes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Usernames shorter than this are too likely to be ordinary words to replace in the text
+const MIN_MENTION_LEN: usize = 3;
+
+lazy_static! {
+    static ref EMAIL: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").expect("Bad email regex");
+    static ref PHONE: Regex =
+        Regex::new(r"\+?\(?\d[\d\s().-]{7,}\d").expect("Bad phone regex");
+    static ref DATE: Regex = Regex::new(r"\d{4}-\d{2}-\d{2}").expect("Bad date regex");
+}
+
+/// Replaces names and personal information with pseudonyms and placeholders
+pub struct Pseudonymizer {
+    key: hmac::Key,
+    /// Lower cased email of each known user, and the username it belongs to
+    emails: Vec<(String, String)>,
+    /// Matches any known username as a whole word
+    mentions: Option<Regex>,
+}
+
+impl Pseudonymizer {
+    pub fn new(salt: &str, users: &[UserConsent]) -> Self {
+        let emails = users
+            .iter()
+            .map(|u| (u.email.to_lowercase(), u.username.clone()))
+            .collect();
+
+        let mut names: Vec<&str> = users
+            .iter()
+            .map(|u| u.username.as_str())
+            .filter(|name| name.len() >= MIN_MENTION_LEN)
+            .collect();
+        // Longest first, so that a name isn't matched by a shorter name it starts with
+        names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+        let mentions = if names.is_empty() {
+            None
+        } else {
+            let names: Vec<String> = names.iter().map(|name| regex::escape(name)).collect();
+            Some(Regex::new(&format!(r"\b(?:{})\b", names.join("|"))).expect("Bad mention regex"))
+        };
+
+        Pseudonymizer {
+            key: hmac::Key::new(hmac::HMAC_SHA256, salt.as_bytes()),
+            emails,
+            mentions,
+        }
+    }
+
+    /// The pseudonym for a username
+    pub fn user(&self, name: &str) -> String {
+        let tag = hmac::sign(&self.key, name.as_bytes());
+        format!("user_{}", &to_hex(tag.as_ref())[..12])
+    }
+
+    /// The pseudonymous address for a known user's email, or None if nobody has that address
+    pub fn email(&self, email: &str) -> Option<String> {
+        let email = email.to_lowercase();
+        self.emails
+            .iter()
+            .find(|(known, _)| *known == email)
+            .map(|(_, name)| format!("{}@users.invalid", self.user(name)))
+    }
+
+    /// Pseudonymizes the usernames and emails in the text, and removes other PII
+    pub fn scrub(&self, text: &str) -> String {
+        // Emails first, since they can have usernames in them
+        let text = EMAIL.replace_all(text, |caps: &regex::Captures| {
+            self.email(&caps[0]).unwrap_or_else(|| "[EMAIL]".into())
+        });
+        let text = PHONE.replace_all(&text, |caps: &regex::Captures| {
+            // Phone numbers have 9 to 15 digits, and dates and times can look a lot like them
+            let digits = caps[0].chars().filter(char::is_ascii_digit).count();
+            if (9..=15).contains(&digits) && !DATE.is_match(&caps[0]) {
+                "[PHONE]".to_string()
+            } else {
+                caps[0].to_string()
+            }
+        });
+        match &self.mentions {
+            Some(mentions) => {
+                mentions.replace_all(&text, |caps: &regex::Captures| self.user(&caps[0])).into()
+            }
+            None => text.into(),
+        }
+    }
+
+    /// Turns a saved message into a corpus record
+    pub fn record(&self, msg: &ChatMessage) -> CorpusRecord {
+        let speaker = self.user(&msg.sender);
+        // Addresses like `@room` aren't anybody, so they are kept as they are
+        let recipients: Vec<String> = msg
+            .recipients
+            .iter()
+            .map(|r| match Address::parse(r) {
+                Address::User(name) => self.user(name),
+                _ => r.clone(),
+            })
+            .collect();
+        let conversation = match (&msg.room, msg.recipients.as_slice()) {
+            (Some(room), _) => format!("room:{}", room),
+            (None, [to]) if matches!(Address::parse(to), Address::User(_)) => {
+                let mut pair = [speaker.clone(), recipients[0].clone()];
+                pair.sort();
+                format!("direct:{}", pair.join(":"))
+            }
+            (None, _) => "room:lobby".into(),
+        };
+
+        CorpusRecord {
+            id: self.user(&format!("message:{}", msg.message_id)).replacen("user_", "msg_", 1),
+            conversation,
+            speaker,
+            recipients,
+            text: self.scrub(&msg.body),
+            time: msg.sent_on,
+        }
+    }
+}
+
+/// One line of a shard
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CorpusRecord {
+    pub id: String,
+    pub conversation: String,
+    pub speaker: String,
+    pub recipients: Vec<String>,
+    pub text: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardInfo {
+    pub file: String,
+    pub records: usize,
+    pub sha256: String,
+}
+
+/// Describes the corpus for the jobs that use it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub created: DateTime<Utc>,
+    pub records: usize,
+    pub consenting_users: usize,
+    pub shards: Vec<ShardInfo>,
+    pub pseudonymization: String,
+    pub pii_removed: Vec<String>,
+}
+
+struct Shard {
+    writer: BufWriter<File>,
+    hash: digest::Context,
+    file: String,
+    records: usize,
+}
+
+/// Writes records to numbered shards of up to `shard_size` records each
+pub struct ShardWriter {
+    dir: PathBuf,
+    shard_size: usize,
+    current: Option<Shard>,
+    shards: Vec<ShardInfo>,
+}
+
+impl ShardWriter {
+    pub fn new(dir: &Path, shard_size: usize) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(ShardWriter {
+            dir: dir.to_path_buf(),
+            shard_size: shard_size.max(1),
+            current: None,
+            shards: vec![],
+        })
+    }
+
+    pub fn write(&mut self, record: &CorpusRecord) -> io::Result<()> {
+        if self.current.is_none() {
+            let file = format!("shard-{:05}.jsonl", self.shards.len());
+            self.current = Some(Shard {
+                writer: BufWriter::new(File::create(self.dir.join(&file))?),
+                hash: digest::Context::new(&digest::SHA256),
+                file,
+                records: 0,
+            });
+        }
+
+        let mut line = serde_json::to_string(record).expect("Unable to serialize record");
+        line.push('\n');
+        let shard = self.current.as_mut().expect("There is always a shard here");
+        shard.writer.write_all(line.as_bytes())?;
+        shard.hash.update(line.as_bytes());
+        shard.records += 1;
+
+        if shard.records >= self.shard_size {
+            self.close_shard()?;
+        }
+        Ok(())
+    }
+
+    fn close_shard(&mut self) -> io::Result<()> {
+        if let Some(mut shard) = self.current.take() {
+            shard.writer.flush()?;
+            self.shards.push(ShardInfo {
+                file: shard.file,
+                records: shard.records,
+                sha256: to_hex(shard.hash.finish().as_ref()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Closes the last shard and writes `manifest.json`
+    pub fn finish(mut self, consenting_users: usize) -> io::Result<Manifest> {
+        self.close_shard()?;
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            created: Utc::now(),
+            records: self.shards.iter().map(|s| s.records).sum(),
+            consenting_users,
+            shards: self.shards,
+            pseudonymization: "hmac-sha256".into(),
+            pii_removed: vec!["usernames".into(), "emails".into(), "phone numbers".into()],
+        };
+        let file = File::create(self.dir.join("manifest.json"))?;
+        serde_json::to_writer_pretty(file, &manifest)?;
+        Ok(manifest)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Consent {
+    pub consent: bool,
+}
+
+/// `GET /consent`
+pub async fn get_consent(user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")),
+    };
+    match pgdb::get_consent(db, &CONFIG.db.tables.users, &user).await {
+        Ok(Some(consent)) => Ok(reply::with_status(reply::json(&Consent { consent }), StatusCode::OK)),
+        Ok(None) => Ok(error_reply(StatusCode::NOT_FOUND, "No such user")),
+        Err(e) => {
+            error!("Unable to read consent for {}: {}", user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read consent"))
+        }
+    }
+}
+
+/// `PUT /consent`
+pub async fn set_consent(
+    user: String,
+    consent: Consent,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")),
+    };
+    match pgdb::set_consent(db, &CONFIG.db.tables.users, &user, consent.consent).await {
+        Ok(0) => Ok(error_reply(StatusCode::NOT_FOUND, "No such user")),
+        Ok(_) => {
+            info!("{} set training data consent to {}", user, consent.consent);
+            Ok(reply::with_status(reply::json(&consent), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to set consent for {}: {}", user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to set consent"))
+        }
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let get = warp::get()
+        .and(warp::path("consent"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(get_consent);
+
+    let put = warp::put()
+        .and(warp::path("consent"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::body::json())
+        .and(with_state)
+        .and_then(set_consent);
+
+    get.or(put).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead,
+                  BufReader};
+
+    fn users() -> Vec<UserConsent> {
+        vec![
+            UserConsent {
+                username: "stoner".into(),
+                email: "Sean@Example.com".into(),
+                consent: true,
+            },
+            UserConsent {
+                username: "al".into(),
+                email: "al@example.com".into(),
+                consent: false,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_scrub() {
+        let p = Pseudonymizer::new("salt", &users());
+        let stoner = p.user("stoner");
+        assert_eq!(stoner, Pseudonymizer::new("salt", &[]).user("stoner"));
+        assert_ne!(stoner, Pseudonymizer::new("pepper", &[]).user("stoner"));
+        assert_eq!(stoner.len(), "user_".len() + 12);
+
+        let text = "ask stoner (sean@example.com) or bob@corp.io, call +1 (555) 123-4567. al knows";
+        assert_eq!(
+            p.scrub(text),
+            format!(
+                "ask {} ({}@users.invalid) or [EMAIL], call [PHONE]. al knows",
+                stoner, stoner
+            )
+        );
+        // Only whole words
+        assert_eq!(p.scrub("stonerific"), "stonerific");
+        assert_eq!(p.scrub("on 2020-09-30 12:00"), "on 2020-09-30 12:00");
+    }
+
+    #[test]
+    fn test_addresses() {
+        let p = Pseudonymizer::new("salt", &users());
+        let mut msg = ChatMessage {
+            message_id: 1,
+            sender: "stoner".into(),
+            recipients: vec!["@room".into()],
+            delivered_to: vec!["al".into(), "stoner".into()],
+            room: None,
+            body: "hello everybody".into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: Utc::now(),
+            conversation_id: None,
+        };
+        let record = p.record(&msg);
+        assert_eq!(record.recipients, vec!["@room".to_string()]);
+        assert_eq!(record.conversation, "room:lobby");
+
+        msg.recipients = vec!["al".into(), "@group:party".into()];
+        let record = p.record(&msg);
+        assert_eq!(record.recipients, vec![p.user("al"), "@group:party".into()]);
+        assert_eq!(record.conversation, "room:lobby");
+    }
+
+    #[test]
+    fn test_shards() {
+        let p = Pseudonymizer::new("salt", &users());
+        let dir = std::env::temp_dir().join(format!("khadga-corpus-{}", std::process::id()));
+        let mut writer = ShardWriter::new(&dir, 2).unwrap();
+
+        for i in 0..5 {
+            let msg = ChatMessage {
+                message_id: i,
+                sender: "stoner".into(),
+                recipients: vec!["al".into()],
+                delivered_to: vec!["al".into()],
+                room: None,
+                body: format!("message {} for al@example.com", i),
+                bot: false,
+                encrypted: false,
+                annotations: Default::default(),
+                sent_on: Utc::now(),
+                conversation_id: None,
+            };
+            writer.write(&p.record(&msg)).unwrap();
+        }
+        let manifest = writer.finish(1).unwrap();
+        assert_eq!(manifest.records, 5);
+        assert_eq!(
+            manifest.shards.iter().map(|s| s.records).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+
+        let shard = File::open(dir.join(&manifest.shards[0].file)).unwrap();
+        let first: CorpusRecord =
+            serde_json::from_str(&BufReader::new(shard).lines().next().unwrap().unwrap()).unwrap();
+        let al = p.user("al");
+        assert_eq!(first.speaker, p.user("stoner"));
+        assert_eq!(first.text, format!("message 0 for {}@users.invalid", al));
+        assert!(first.conversation.starts_with("direct:"));
+
+        let bytes = std::fs::read(dir.join(&manifest.shards[2].file)).unwrap();
+        let hash = to_hex(digest::digest(&digest::SHA256, &bytes).as_ref());
+        assert_eq!(hash, manifest.shards[2].sha256);
+        assert!(dir.join("manifest.json").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
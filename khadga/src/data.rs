@@ -1,8 +1,8 @@
 //! Contains data and schema that will be stored in mongodb
 //!
 //! User type contains the name of the user, a public key, the email, a creation date, and role
-//! Instead of using passwords for authentication, users will upload a public key from an RSA pair
-//! This is more secure than using a password, but not as invasive a MFA.
+//! Instead of using passwords for authentication, users upload a public key from an RSA or Ed25519
+//! pair (see `keys`).  This is more secure than using a password, but not as invasive a MFA.
 
 use serde::{Deserialize,
             Serialize};
@@ -42,6 +42,15 @@ impl User {
     }
 }
 
+/// What kind of account is on the other end of a chat connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AccountKind {
+    /// A person using the vision client
+    User,
+    /// A program that authenticated with a bot API token (see `bots`)
+    Bot,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) enum Role {
     User(String),
@@ -52,7 +61,7 @@ impl Display for Role {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         match self {
             Role::User(name) => write!(fmt, "User<{}>", name),
-            Role::Admin => write!(fmt, "{}", "Admin"),
+            Role::Admin => write!(fmt, "Admin"),
         }
     }
 }
@@ -0,0 +1,469 @@
+//! Key directory for end to end encrypted direct messages
+//!
+//! khadga never sees the plaintext of an encrypted message.  Clients encrypt direct messages for
+//! each of the recipient's devices and send the ciphertext as the body, with `encrypted: true`.
+//! The server relays and stores it as is, and leaves it out of search, trends, the training corpus
+//! and every filter but the length limit.  Encrypted messages in a room are rejected, since there
+//! is nobody in particular to encrypt them for.
+//!
+//! What the server does keep is the public half of each device's keys, in the style of X3DH:
+//!
+//! - an identity key, which stays the same for the life of the device
+//! - a signed pre-key, signed with the identity key, which the device rotates now and then
+//! - a pile of one-time pre-keys.  Each is handed out once and then deleted.
+//!
+//! Keys are base64 and opaque to khadga; checking the signature is up to the clients.  All routes
+//! need the `jwt` cookie:
+//!
+//! - `POST /e2e/devices` with `{"name", "identity_key", "signed_prekey", "prekey_signature",
+//!   "prekeys": [{"key_id", "public_key"}]}` adds a device
+//! - `GET /e2e/devices/<user>` lists someone's devices and how many one-time pre-keys each has
+//! - `PUT /e2e/devices/<id>/prekeys` with `{"signed_prekey", "prekey_signature"}` rotates the
+//!   signed pre-key, and `POST /e2e/devices/<id>/prekeys` with `[{"key_id", "public_key"}]` adds
+//!   more one-time pre-keys
+//! - `DELETE /e2e/devices/<id>` removes one of your devices, along with its pre-keys
+//! - `GET /e2e/bundles/<user>` is what you fetch before writing to somebody: every one of their
+//!   devices, each with one of its one-time pre-keys (or none, once they have run out)
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            pgdb::{models::{Device,
+                            PreKey},
+                   pgdb},
+            reply::error_reply};
+use chrono::Utc;
+use log::{error,
+          info};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// How many devices one user can have
+pub const MAX_DEVICES: usize = 10;
+/// How many one-time pre-keys can be uploaded at once
+pub const MAX_PREKEYS: usize = 100;
+/// Longest key or signature, in base64 characters
+pub const MAX_KEY_LEN: usize = 1024;
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Deserialize, Debug)]
+pub struct NewDevice {
+    pub name: String,
+    pub identity_key: String,
+    pub signed_prekey: String,
+    pub prekey_signature: String,
+    #[serde(default)]
+    pub prekeys: Vec<PreKey>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignedPreKey {
+    pub signed_prekey: String,
+    pub prekey_signature: String,
+}
+
+/// A device as others see it
+#[derive(Serialize, Debug)]
+pub struct DeviceInfo {
+    #[serde(flatten)]
+    pub device: Device,
+    /// How many one-time pre-keys are left
+    pub prekeys: i64,
+}
+
+/// What a sender needs to start a session with one device
+#[derive(Serialize, Debug)]
+pub struct Bundle {
+    pub device_id: i32,
+    pub name: String,
+    pub identity_key: String,
+    pub signed_prekey: String,
+    pub prekey_signature: String,
+    pub prekey: Option<PreKey>,
+}
+
+/// Checks that a key is non-empty base64 and not too long
+pub fn check_key(what: &str, key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(format!("The {} must be 1 to {} characters", what, MAX_KEY_LEN));
+    }
+    base64::decode(key).map(|_| ()).map_err(|_| format!("The {} is not valid base64", what))
+}
+
+pub fn check_prekeys(prekeys: &[PreKey]) -> Result<(), String> {
+    if prekeys.len() > MAX_PREKEYS {
+        return Err(format!("At most {} pre-keys can be added at once", MAX_PREKEYS));
+    }
+    for key in prekeys {
+        if key.key_id < 0 {
+            return Err("Pre-key ids can't be negative".into());
+        }
+        check_key("pre-key", &key.public_key)?;
+    }
+    Ok(())
+}
+
+pub fn check_device(device: &NewDevice) -> Result<(), String> {
+    let name = device.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("The device name must be 1 to {} characters", MAX_NAME_LEN));
+    }
+    check_key("identity key", &device.identity_key)?;
+    check_key("signed pre-key", &device.signed_prekey)?;
+    check_key("pre-key signature", &device.prekey_signature)?;
+    check_prekeys(&device.prekeys)
+}
+
+fn no_db() -> WithStatus<Json> {
+    error_reply(StatusCode::SERVICE_UNAVAILABLE, "The database is not available")
+}
+
+/// Whether the device belongs to the user
+async fn owns(state: &ChatState, user: &str, device_id: i32) -> Result<bool, WithStatus<Json>> {
+    let db = state.db.as_ref().ok_or_else(no_db)?;
+    let tables = &CONFIG.db.tables;
+    match pgdb::list_devices(db, &tables.devices, &tables.prekeys, user).await {
+        Ok(devices) => Ok(devices.iter().any(|(d, _)| d.device_id == device_id)),
+        Err(e) => {
+            error!("Unable to list the devices of {}: {}", user, e);
+            Err(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to list devices"))
+        }
+    }
+}
+
+/// `POST /e2e/devices`
+pub async fn add_device(
+    user: String,
+    new_device: NewDevice,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(e) = check_device(&new_device) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e));
+    }
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let tables = &CONFIG.db.tables;
+
+    match pgdb::list_devices(db, &tables.devices, &tables.prekeys, &user).await {
+        Ok(devices) if devices.len() >= MAX_DEVICES => {
+            let msg = format!("At most {} devices are allowed", MAX_DEVICES);
+            return Ok(error_reply(StatusCode::CONFLICT, &msg));
+        }
+        Ok(_) => (),
+        Err(e) => {
+            error!("Unable to list the devices of {}: {}", user, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to add device"));
+        }
+    }
+
+    let mut device = Device {
+        device_id: -1,
+        username: user.clone(),
+        name: new_device.name.trim().to_string(),
+        identity_key: new_device.identity_key,
+        signed_prekey: new_device.signed_prekey,
+        prekey_signature: new_device.prekey_signature,
+        created_on: Utc::now(),
+        updated_on: Utc::now(),
+    };
+    device.device_id = match pgdb::insert_device(db, &tables.devices, &device).await {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Unable to save a device for {}: {}", user, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to add device"));
+        }
+    };
+    let prekeys =
+        match pgdb::insert_prekeys(db, &tables.prekeys, device.device_id, &new_device.prekeys)
+            .await
+        {
+            Ok(added) => added as i64,
+            Err(e) => {
+                error!("Unable to save pre-keys for device {}: {}", device.device_id, e);
+                0
+            }
+        };
+    info!("{} added device {} ({})", user, device.device_id, device.name);
+    let info = DeviceInfo { device, prekeys };
+    Ok(reply::with_status(reply::json(&info), StatusCode::CREATED))
+}
+
+/// `GET /e2e/devices/<user>`
+pub async fn list_devices(
+    username: String,
+    _user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let tables = &CONFIG.db.tables;
+    match pgdb::list_devices(db, &tables.devices, &tables.prekeys, &username).await {
+        Ok(devices) => {
+            let devices: Vec<DeviceInfo> = devices
+                .into_iter()
+                .map(|(device, prekeys)| DeviceInfo { device, prekeys })
+                .collect();
+            Ok(reply::with_status(reply::json(&devices), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to list the devices of {}: {}", username, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to list devices"))
+        }
+    }
+}
+
+/// `POST /e2e/devices/<id>/prekeys`
+pub async fn add_prekeys(
+    device_id: i32,
+    user: String,
+    prekeys: Vec<PreKey>,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if let Err(e) = check_prekeys(&prekeys) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e));
+    }
+    match owns(&state, &user, device_id).await {
+        Ok(true) => (),
+        Ok(false) => return Ok(error_reply(StatusCode::NOT_FOUND, "No such device")),
+        Err(reply) => return Ok(reply),
+    }
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::insert_prekeys(db, &CONFIG.db.tables.prekeys, device_id, &prekeys).await {
+        Ok(added) => {
+            let body = json!({ "added": added });
+            Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to save pre-keys for device {}: {}", device_id, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to add pre-keys"))
+        }
+    }
+}
+
+/// `PUT /e2e/devices/<id>/prekeys`: rotates the signed pre-key
+pub async fn rotate_prekey(
+    device_id: i32,
+    user: String,
+    signed: SignedPreKey,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let checked = check_key("signed pre-key", &signed.signed_prekey)
+        .and_then(|_| check_key("pre-key signature", &signed.prekey_signature));
+    if let Err(e) = checked {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &e));
+    }
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let table = &CONFIG.db.tables.devices;
+    let (prekey, signature) = (&signed.signed_prekey, &signed.prekey_signature);
+    match pgdb::update_signed_prekey(db, table, &user, device_id, prekey, signature).await {
+        Ok(0) => Ok(error_reply(StatusCode::NOT_FOUND, "No such device")),
+        Ok(_) => {
+            let body = json!({ "rotated": device_id });
+            Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to rotate the pre-key of device {}: {}", device_id, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to rotate pre-key"))
+        }
+    }
+}
+
+/// `DELETE /e2e/devices/<id>`
+pub async fn remove_device(
+    device_id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::delete_device(db, &CONFIG.db.tables.devices, &user, device_id).await {
+        Ok(0) => Ok(error_reply(StatusCode::NOT_FOUND, "No such device")),
+        Ok(_) => {
+            info!("{} removed device {}", user, device_id);
+            let body = json!({ "removed": device_id });
+            Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to remove device {} of {}: {}", device_id, user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to remove device"))
+        }
+    }
+}
+
+/// `GET /e2e/bundles/<user>`
+///
+/// Takes one one-time pre-key from each device, so every call uses some up.
+pub async fn bundles(
+    username: String,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let tables = &CONFIG.db.tables;
+    let devices = match pgdb::list_devices(db, &tables.devices, &tables.prekeys, &username).await
+    {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Unable to list the devices of {}: {}", username, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to get bundles"));
+        }
+    };
+
+    let mut bundles = Vec::with_capacity(devices.len());
+    for (device, _) in devices {
+        let prekey = pgdb::take_prekey(db, &tables.prekeys, device.device_id)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Unable to take a pre-key of device {}: {}", device.device_id, e);
+                None
+            });
+        bundles.push(Bundle {
+            device_id: device.device_id,
+            name: device.name,
+            identity_key: device.identity_key,
+            signed_prekey: device.signed_prekey,
+            prekey_signature: device.prekey_signature,
+            prekey,
+        });
+    }
+    info!("{} fetched {} key bundles of {}", user, bundles.len(), username);
+    Ok(reply::with_status(reply::json(&bundles), StatusCode::OK))
+}
+
+/// Creates the device and pre-key tables
+pub async fn load(state: &ChatState) {
+    if let Some(db) = &state.db {
+        let tables = &CONFIG.db.tables;
+        if let Err(e) = pgdb::make_table_devices(&tables.devices, db).await {
+            error!("Unable to create the {} table: {}", tables.devices, e);
+        }
+        if let Err(e) = pgdb::make_table_prekeys(&tables.prekeys, &tables.devices, db).await {
+            error!("Unable to create the {} table: {}", tables.prekeys, e);
+        }
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+    let body_limit = warp::body::content_length_limit(256 * 1024);
+
+    let add = warp::post()
+        .and(warp::path!("e2e" / "devices"))
+        .and(authenticated())
+        .and(body_limit)
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(add_device);
+
+    let list = warp::get()
+        .and(warp::path!("e2e" / "devices" / String))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(list_devices);
+
+    let prekeys = warp::post()
+        .and(warp::path!("e2e" / "devices" / i32 / "prekeys"))
+        .and(authenticated())
+        .and(body_limit)
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(add_prekeys);
+
+    let rotate = warp::put()
+        .and(warp::path!("e2e" / "devices" / i32 / "prekeys"))
+        .and(authenticated())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(rotate_prekey);
+
+    let remove = warp::delete()
+        .and(warp::path!("e2e" / "devices" / i32))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(remove_device);
+
+    let bundles = warp::get()
+        .and(warp::path!("e2e" / "bundles" / String))
+        .and(authenticated())
+        .and(with_state)
+        .and_then(bundles);
+
+    add.or(list)
+        .or(prekeys)
+        .or(rotate)
+        .or(remove)
+        .or(bundles)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> NewDevice {
+        NewDevice {
+            name: "laptop".into(),
+            identity_key: base64::encode([1u8; 32]),
+            signed_prekey: base64::encode([2u8; 32]),
+            prekey_signature: base64::encode([3u8; 64]),
+            prekeys: (0..3)
+                .map(|key_id| PreKey {
+                    key_id,
+                    public_key: base64::encode([key_id as u8; 32]),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_check_device() {
+        assert!(check_device(&device()).is_ok());
+
+        let bad = NewDevice { name: "  ".into(), ..device() };
+        assert!(check_device(&bad).is_err());
+        let bad = NewDevice { identity_key: "not base64!".into(), ..device() };
+        assert!(check_device(&bad).unwrap_err().contains("identity key"));
+        let bad = NewDevice { signed_prekey: "A".repeat(MAX_KEY_LEN + 4), ..device() };
+        assert!(check_device(&bad).is_err());
+        let bad = NewDevice { prekey_signature: "".into(), ..device() };
+        assert!(check_device(&bad).is_err());
+    }
+
+    #[test]
+    fn test_check_prekeys() {
+        let key = |key_id| PreKey {
+            key_id,
+            public_key: "AAAA".into(),
+        };
+        assert!(check_prekeys(&[]).is_ok());
+        assert!(check_prekeys(&[key(-1)]).is_err());
+        let many: Vec<PreKey> = (0..=MAX_PREKEYS as i32).map(key).collect();
+        assert!(check_prekeys(&many).is_err());
+        assert!(check_prekeys(&many[1..]).is_ok());
+    }
+}
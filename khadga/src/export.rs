@@ -0,0 +1,333 @@
+//! Exporting conversations
+//!
+//! A conversation (a room, or the direct messages between two people) can be exported for a time
+//! range as JSON Lines, CSV or a plain text transcript.  This is available as `GET /export` and as
+//! the `khadga-export` command line tool.  Either way the messages are streamed from the database
+//! and written out one at a time, so even very large conversations never have to fit in memory.
+//!
+//! Each message is exported with everything khadga keeps for it: the sender, recipients, room,
+//! time, whether a bot sent it and the annotations added by the filters.  khadga doesn't have
+//! message edits, reactions or attachments yet, so there is nothing to export for those.
+//!
+//! `GET /export` takes these query parameters:
+//!
+//! - `room` or `with`: the room, or the user whose direct messages with you to export
+//! - `since` and `until`: a date (`2020-09-30`) or an RFC 3339 time
+//! - `format`: `jsonl` (the default), `csv` or `text`
+//!
+//! People only get the messages they sent or received.  Admins get the whole conversation.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            pgdb::{models::{ChatMessage,
+                            ExportQuery},
+                   pgdb::{self,
+                          DbClient}},
+            reply::text_error,
+            search::parse_time};
+use futures::{stream,
+              Stream,
+              StreamExt};
+use log::error;
+use serde::Deserialize;
+use std::{convert::Infallible,
+          str::FromStr};
+use warp::{filters::BoxedFilter,
+           http::{Response,
+                  StatusCode},
+           hyper::Body,
+           Filter,
+           Reply};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+    Text,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "text" | "txt" => Ok(Format::Text),
+            _ => Err(format!("Unknown format {}, expected jsonl, csv or text", s)),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+            Format::Text => "txt",
+        }
+    }
+
+    /// What goes before the first message
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Format::Csv => {
+                Some("message_id,sent_on,sender,recipients,room,bot,body,annotations\r\n".into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Formats one message, including the line ending
+    pub fn format(&self, msg: &ChatMessage) -> String {
+        match self {
+            Format::Jsonl => {
+                let mut line = serde_json::to_string(msg).expect("Unable to serialize message");
+                line.push('\n');
+                line
+            }
+            Format::Csv => {
+                let annotations =
+                    serde_json::to_string(&msg.annotations).expect("Unable to serialize");
+                let fields = [
+                    msg.message_id.to_string(),
+                    msg.sent_on.to_rfc3339(),
+                    msg.sender.clone(),
+                    msg.recipients.join(";"),
+                    msg.room.clone().unwrap_or_default(),
+                    msg.bot.to_string(),
+                    msg.body.clone(),
+                    annotations,
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                format!("{}\r\n", fields.join(","))
+            }
+            Format::Text => {
+                let time = msg.sent_on.format("%Y-%m-%d %H:%M:%S");
+                let bot = if msg.bot { " [bot]" } else { "" };
+                // Line up continuation lines under the start of the message
+                let body = if msg.encrypted {
+                    "[encrypted message]".to_string()
+                } else {
+                    msg.body.replace('\n', "\n    ")
+                };
+                if msg.annotations.contains_key("emote") {
+                    format!("[{}] {}\n", time, body)
+                } else {
+                    format!("[{}] {}{}: {}\n", time, msg.sender, bot, body)
+                }
+            }
+        }
+    }
+}
+
+/// Quotes a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Streams the formatted conversation
+pub async fn export(
+    db: &DbClient,
+    query: &ExportQuery,
+    format: Format,
+) -> Result<impl Stream<Item = Result<String, tokio_postgres::Error>>, tokio_postgres::Error> {
+    let rows = pgdb::stream_messages(db, &CONFIG.db.tables.messages, query).await?;
+    let header = stream::iter(format.header().map(Ok));
+    let lines = rows.map(move |row| row.map(|row| format.format(&pgdb::row_to_message(&row))));
+    Ok(header.chain(lines))
+}
+
+/// The query string of `GET /export`
+#[derive(Deserialize, Debug)]
+pub struct ExportParams {
+    pub room: Option<String>,
+    pub with: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub format: Option<String>,
+}
+
+impl ExportParams {
+    /// Checks the parameters and turns them into the query for the caller
+    pub fn to_query(&self, caller: &str, is_admin: bool) -> Result<(ExportQuery, Format), String> {
+        let format = match &self.format {
+            Some(f) => f.parse()?,
+            None => Format::Jsonl,
+        };
+        let dm = self.with.as_ref().map(|with| (caller.to_string(), with.clone()));
+        if self.room.is_some() == dm.is_some() {
+            return Err("Give either a room or a user to export the direct messages with".into());
+        }
+        let query = ExportQuery {
+            room: self.room.clone(),
+            dm,
+            member: if is_admin { None } else { Some(caller.to_string()) },
+            since: self.since.as_deref().map(parse_time).transpose()?,
+            until: self.until.as_deref().map(parse_time).transpose()?,
+        };
+        Ok((query, format))
+    }
+}
+
+/// The name of the exported file.  Anything but letters, digits, `_` and `-` becomes `_`, so the
+/// name is always safe to put in a header.
+pub fn file_name(query: &ExportQuery, format: Format) -> String {
+    let name = query
+        .room
+        .clone()
+        .or_else(|| query.dm.as_ref().map(|(a, b)| format!("{}-{}", a, b)))
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect();
+    format!("{}.{}", name, format.extension())
+}
+
+/// `GET /export`
+pub async fn export_handler(
+    caller: String,
+    params: ExportParams,
+    state: ChatState,
+) -> Result<Response<Body>, Infallible> {
+    let is_admin = CONFIG.admins.contains(&caller);
+    let (query, format) = match params.to_query(&caller, is_admin) {
+        Ok(q) => q,
+        Err(e) => return Ok(text_error(StatusCode::BAD_REQUEST, &e)),
+    };
+    let db = match &state.db {
+        Some(db) => db,
+        None => {
+            let msg = "Chat history is not available";
+            return Ok(text_error(StatusCode::SERVICE_UNAVAILABLE, msg));
+        }
+    };
+
+    let lines = match export(db, &query, format).await {
+        Ok(lines) => lines,
+        Err(e) => {
+            error!("Export for {} failed: {}", caller, e);
+            return Ok(text_error(StatusCode::INTERNAL_SERVER_ERROR, "Export failed"));
+        }
+    };
+    let disposition = format!("attachment; filename=\"{}\"", file_name(&query, format));
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header("Content-Disposition", disposition)
+        .body(Body::wrap_stream(lines))
+        .expect("Unable to create HTTP Response"))
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    warp::get()
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::query::<ExportParams>())
+        .and(with_state)
+        .and_then(export_handler)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone,
+                 Utc};
+
+    fn message(body: &str) -> ChatMessage {
+        ChatMessage {
+            message_id: 7,
+            sender: "stoner".into(),
+            recipients: vec!["whammo".into(), "rubik".into()],
+            delivered_to: vec!["whammo".into(), "rubik".into()],
+            room: Some("dnd".into()),
+            body: body.into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: Utc.ymd(2020, 9, 30).and_hms(19, 30, 0),
+            conversation_id: None,
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let msg = message("Roll \"initiative\",\nplease");
+
+        let line = Format::Jsonl.format(&msg);
+        let parsed: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(parsed["body"], msg.body.as_str());
+        assert!(line.ends_with('\n') && line.matches('\n').count() == 1);
+
+        assert_eq!(
+            Format::Csv.format(&msg),
+            "7,2020-09-30T19:30:00+00:00,stoner,whammo;rubik,dnd,false,\
+             \"Roll \"\"initiative\"\",\nplease\",{}\r\n"
+        );
+
+        assert_eq!(
+            Format::Text.format(&msg),
+            "[2020-09-30 19:30:00] stoner: Roll \"initiative\",\n    please\n"
+        );
+        let mut emote = message("* stoner rolls a d20");
+        emote.annotations.insert("emote".into(), "true".into());
+        assert_eq!(Format::Text.format(&emote), "[2020-09-30 19:30:00] * stoner rolls a d20\n");
+    }
+
+    #[test]
+    fn test_params() {
+        let params = ExportParams {
+            room: None,
+            with: Some("whammo".into()),
+            since: Some("2020-09-01".into()),
+            until: None,
+            format: Some("csv".into()),
+        };
+        let (query, format) = params.to_query("stoner", false).unwrap();
+        assert_eq!(format, Format::Csv);
+        assert_eq!(query.dm, Some(("stoner".into(), "whammo".into())));
+        assert_eq!(query.member, Some("stoner".into()));
+
+        let both = ExportParams {
+            room: Some("dnd".into()),
+            ..params
+        };
+        assert!(both.to_query("stoner", true).is_err());
+    }
+
+    #[test]
+    fn test_file_name() {
+        let (query, format) = ExportParams {
+            room: None,
+            with: Some("whammo\"\r\nX-Evil: 1".into()),
+            since: None,
+            until: None,
+            format: Some("text".into()),
+        }
+        .to_query("stoner", false)
+        .unwrap();
+        assert_eq!(file_name(&query, format), "stoner-whammo___X-Evil__1.txt");
+
+        let dnd = ExportQuery { room: Some("dnd_night-2".into()), ..Default::default() };
+        assert_eq!(file_name(&dnd, Format::Jsonl), "dnd_night-2.jsonl");
+    }
+}
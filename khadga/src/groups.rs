@@ -0,0 +1,497 @@
+//! Addressing many people at once
+//!
+//! Besides usernames, the `recipients` of a `message::Message` can have these addresses:
+//!
+//! - `@room`: everybody in the message's room, or the lobby if it doesn't name one
+//! - `@all`: everybody connected
+//! - `@group:<name>`: the members of a named group (see below)
+//! - `@role:admins`, `@role:bots` or `@role:users`: the `admins` in the config, the bot accounts,
+//!   or the people who aren't bots
+//!
+//! Only people in a room can send to it, whether with `@room` or by naming it in `room`, and only
+//! the `admins` can send to `@all` or a role.  Anything else is rejected before it is relayed (see
+//! `check`).
+//!
+//! They are expanded when the message is relayed.  Everybody gets the message once, however many
+//! ways they were addressed, and the sender doesn't get their own message back unless they named
+//! themselves.  The message keeps the recipients it was sent with, so clients can tell it went to a
+//! group, and who it actually went to is saved with it (see `chat::post`).  Encrypted messages
+//! can't be sent to an address, since they are encrypted per device.
+//!
+//! Groups are managed by anybody who is logged in:
+//!
+//! - `POST /groups` with `{"name": "mods", "members": ["stoner", "whammo"]}` makes one, owned by
+//!   you.  Names are lower case letters, digits, `-` and `_`.
+//! - `GET /groups` lists them, and `GET /groups/<name>` gets one
+//! - `PUT /groups/<name>` with `{"members": [...]}` replaces the members
+//! - `DELETE /groups/<name>` deletes it
+//!
+//! Only the owner or an admin can change or delete a group.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            data::AccountKind,
+            message::Message as KMessage,
+            pgdb::{models::UserGroup,
+                   pgdb},
+            reply::error_reply,
+            rooms};
+use chrono::Utc;
+use log::{debug,
+          error,
+          info};
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::{HashMap,
+                        HashSet},
+          convert::Infallible,
+          sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// The named groups, keyed by name
+pub type Groups = Arc<Mutex<HashMap<String, UserGroup>>>;
+
+pub const MAX_NAME_LEN: usize = 32;
+pub const MAX_MEMBERS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Admins,
+    Bots,
+    Users,
+}
+
+/// One of a message's recipients
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address<'a> {
+    User(&'a str),
+    Room,
+    All,
+    Group(&'a str),
+    Role(Role),
+    /// Starts with `@` but isn't any of the above
+    Unknown(&'a str),
+}
+
+impl<'a> Address<'a> {
+    pub fn parse(recipient: &'a str) -> Self {
+        if !recipient.starts_with('@') {
+            return Address::User(recipient);
+        }
+        match recipient {
+            "@room" => Address::Room,
+            "@all" => Address::All,
+            "@role:admins" => Address::Role(Role::Admins),
+            "@role:bots" => Address::Role(Role::Bots),
+            "@role:users" => Address::Role(Role::Users),
+            _ => match recipient.strip_prefix("@group:") {
+                Some(name) if valid_name(name) => Address::Group(name),
+                _ => Address::Unknown(recipient),
+            },
+        }
+    }
+}
+
+/// Whether any of the recipients is an address rather than a username
+pub fn has_addresses(recipients: &[String]) -> bool {
+    recipients.iter().any(|r| !matches!(Address::parse(r), Address::User(_)))
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Checks that the sender is allowed to send to the message's room and addresses
+pub async fn check<T>(state: &ChatState, mesg: &KMessage<T>) -> Result<(), String> {
+    let addresses: Vec<Address> = mesg.recipients.iter().map(|r| Address::parse(r)).collect();
+    if mesg.room.is_some() || addresses.contains(&Address::Room) {
+        let room = mesg.room.as_deref().unwrap_or(rooms::LOBBY);
+        let members = rooms::members(&state.rooms, room).await.unwrap_or_default();
+        if !members.contains(&mesg.sender) {
+            return Err(format!("You are not in {}", room));
+        }
+    }
+    let broadcast = addresses
+        .iter()
+        .any(|address| matches!(address, Address::All | Address::Role(_)));
+    if broadcast && !CONFIG.admins.contains(&mesg.sender) {
+        return Err("Only admins can send to @all or a role".into());
+    }
+    Ok(())
+}
+
+/// Everybody the message is for, with the addresses expanded
+pub async fn expand<T>(state: &ChatState, mesg: &KMessage<T>) -> HashSet<String> {
+    let mut to = HashSet::new();
+    for recipient in &mesg.recipients {
+        let members: Vec<String> = match Address::parse(recipient) {
+            Address::User(name) => {
+                to.insert(name.to_string());
+                continue;
+            }
+            Address::Room => {
+                let room = mesg.room.as_deref().unwrap_or(rooms::LOBBY);
+                rooms::members(&state.rooms, room).await.unwrap_or_default()
+            }
+            Address::All => state.users.lock().await.keys().cloned().collect(),
+            Address::Group(name) => state
+                .groups
+                .lock()
+                .await
+                .get(name)
+                .map(|group| group.members.clone())
+                .unwrap_or_default(),
+            Address::Role(Role::Admins) => CONFIG.admins.clone(),
+            Address::Role(role) => {
+                let kind = if role == Role::Bots {
+                    AccountKind::Bot
+                } else {
+                    AccountKind::User
+                };
+                let users = state.users.lock().await;
+                users
+                    .iter()
+                    .filter(|(_, info)| info.kind == kind)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            }
+            Address::Unknown(address) => {
+                debug!("{}: unknown address {}", mesg.sender, address);
+                continue;
+            }
+        };
+        to.extend(members.into_iter().filter(|name| *name != mesg.sender));
+    }
+    to
+}
+
+/// Creates the groups table and loads the groups
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let table = &CONFIG.db.tables.user_groups;
+    if let Err(e) = pgdb::make_table_user_groups(table, db).await {
+        error!("Unable to create the {} table: {}", table, e);
+        return;
+    }
+    match pgdb::list_user_groups(db, table).await {
+        Ok(list) => {
+            info!("Loaded {} groups", list.len());
+            let mut groups = state.groups.lock().await;
+            for group in list {
+                groups.insert(group.name.clone(), group);
+            }
+        }
+        Err(e) => error!("Unable to load groups: {}", e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewGroup {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Members {
+    pub members: Vec<String>,
+}
+
+/// Sorts the members and drops duplicates, or says what is wrong with them
+pub fn check_members(mut members: Vec<String>) -> Result<Vec<String>, String> {
+    members.sort();
+    members.dedup();
+    if members.len() > MAX_MEMBERS {
+        return Err(format!("A group can have at most {} members", MAX_MEMBERS));
+    }
+    match members.iter().find(|m| m.is_empty() || m.starts_with('@')) {
+        Some(bad) => Err(format!("{:?} is not a username", bad)),
+        None => Ok(members),
+    }
+}
+
+fn may_change(user: &str, group: &UserGroup) -> bool {
+    group.owner == user || CONFIG.admins.iter().any(|admin| admin == user)
+}
+
+/// `POST /groups`
+pub async fn create_group(
+    user: String,
+    new_group: NewGroup,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if !valid_name(&new_group.name) {
+        let msg = "Group names are lower case letters, digits, - and _";
+        return Ok(error_reply(StatusCode::BAD_REQUEST, msg));
+    }
+    let members = match check_members(new_group.members) {
+        Ok(members) => members,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    let group = UserGroup {
+        name: new_group.name,
+        owner: user,
+        members,
+        created_on: Utc::now(),
+    };
+    let mut groups = state.groups.lock().await;
+    if groups.contains_key(&group.name) {
+        return Ok(error_reply(StatusCode::CONFLICT, "There is already a group with that name"));
+    }
+    if let Some(db) = &state.db {
+        match pgdb::insert_user_group(db, &CONFIG.db.tables.user_groups, &group).await {
+            Ok(0) => {
+                let msg = "There is already a group with that name";
+                return Ok(error_reply(StatusCode::CONFLICT, msg));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Unable to save group {}: {}", group.name, e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save group"));
+            }
+        }
+    }
+    info!("{} created group {}", group.owner, group.name);
+    let body = json!(group);
+    groups.insert(group.name.clone(), group);
+    Ok(reply::with_status(reply::json(&body), StatusCode::CREATED))
+}
+
+/// `GET /groups`
+pub async fn list_groups(_user: String, state: ChatState) -> Result<Json, Infallible> {
+    let groups = state.groups.lock().await;
+    let mut all: Vec<&UserGroup> = groups.values().collect();
+    all.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(reply::json(&all))
+}
+
+/// `GET /groups/<name>`
+pub async fn get_group(
+    name: String,
+    _user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    match state.groups.lock().await.get(&name) {
+        Some(group) => Ok(reply::with_status(reply::json(group), StatusCode::OK)),
+        None => Ok(error_reply(StatusCode::NOT_FOUND, "No such group")),
+    }
+}
+
+/// `PUT /groups/<name>`
+pub async fn set_members(
+    name: String,
+    user: String,
+    members: Members,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let members = match check_members(members.members) {
+        Ok(members) => members,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    };
+    let mut groups = state.groups.lock().await;
+    let group = match groups.get_mut(&name) {
+        Some(group) => group,
+        None => return Ok(error_reply(StatusCode::NOT_FOUND, "No such group")),
+    };
+    if !may_change(&user, group) {
+        let msg = "Only the owner or an admin can change a group";
+        return Ok(error_reply(StatusCode::FORBIDDEN, msg));
+    }
+    if let Some(db) = &state.db {
+        let table = &CONFIG.db.tables.user_groups;
+        if let Err(e) = pgdb::update_group_members(db, table, &name, &members).await {
+            error!("Unable to update group {}: {}", name, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to update group"));
+        }
+    }
+    info!("{} set the members of group {}", user, name);
+    group.members = members;
+    Ok(reply::with_status(reply::json(group), StatusCode::OK))
+}
+
+/// `DELETE /groups/<name>`
+pub async fn delete_group(
+    name: String,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let mut groups = state.groups.lock().await;
+    match groups.get(&name) {
+        Some(group) if !may_change(&user, group) => {
+            let msg = "Only the owner or an admin can delete a group";
+            return Ok(error_reply(StatusCode::FORBIDDEN, msg));
+        }
+        Some(_) => {}
+        None => return Ok(error_reply(StatusCode::NOT_FOUND, "No such group")),
+    }
+    if let Some(db) = &state.db {
+        if let Err(e) = pgdb::delete_user_group(db, &CONFIG.db.tables.user_groups, &name).await {
+            error!("Unable to delete group {}: {}", name, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to delete group"));
+        }
+    }
+    groups.remove(&name);
+    info!("{} deleted group {}", user, name);
+    Ok(reply::with_status(reply::json(&json!({ "deleted": name })), StatusCode::OK))
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path("groups"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_group);
+
+    let list = warp::get()
+        .and(warp::path("groups"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(list_groups);
+
+    let get = warp::get()
+        .and(warp::path!("groups" / String))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(get_group);
+
+    let update = warp::put()
+        .and(warp::path!("groups" / String))
+        .and(authenticated())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(set_members);
+
+    let delete = warp::delete()
+        .and(warp::path!("groups" / String))
+        .and(authenticated())
+        .and(with_state)
+        .and_then(delete_group);
+
+    create
+        .or(list)
+        .or(get)
+        .or(update)
+        .or(delete)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commands::Dispatcher,
+                filter::FilterChain,
+                message::MessageEvent,
+                state::UserInfo};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Address::parse("stoner"), Address::User("stoner"));
+        assert_eq!(Address::parse("@room"), Address::Room);
+        assert_eq!(Address::parse("@all"), Address::All);
+        assert_eq!(Address::parse("@group:mods"), Address::Group("mods"));
+        assert_eq!(Address::parse("@role:bots"), Address::Role(Role::Bots));
+        assert_eq!(Address::parse("@group:Mods!"), Address::Unknown("@group:Mods!"));
+        assert_eq!(Address::parse("@role:kings"), Address::Unknown("@role:kings"));
+        assert!(has_addresses(&["stoner".into(), "@room".into()]));
+        assert!(!has_addresses(&["stoner".into()]));
+
+        let members = vec!["whammo".into(), "stoner".into(), "whammo".into()];
+        assert_eq!(check_members(members).unwrap(), vec!["stoner", "whammo"]);
+        assert!(check_members(vec!["@all".into()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expand() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        {
+            let mut users = state.users.lock().await;
+            for name in &["stoner", "whammo", "rubik"] {
+                users.insert(name.to_string(), UserInfo::new(None));
+            }
+            let mut dicebot = UserInfo::new(None);
+            dicebot.kind = AccountKind::Bot;
+            users.insert("dicebot".into(), dicebot);
+        }
+        rooms::join(&state.rooms, "dnd", "stoner").await;
+        rooms::join(&state.rooms, "dnd", "whammo").await;
+        state.groups.lock().await.insert(
+            "mods".into(),
+            UserGroup {
+                name: "mods".into(),
+                owner: "stoner".into(),
+                members: vec!["whammo".into(), "rubik".into(), "stoner".into()],
+                created_on: Utc::now(),
+            },
+        );
+
+        let expanded = |recipients: &[&str], room: Option<&str>| {
+            let recipients = recipients.iter().map(|r| r.to_string()).collect();
+            let mut mesg = KMessage::new("stoner".into(), recipients, MessageEvent::Message, "hi");
+            mesg.room = room.map(String::from);
+            let state = state.clone();
+            async move {
+                let mut to: Vec<String> = expand(&state, &mesg).await.into_iter().collect();
+                to.sort();
+                to
+            }
+        };
+
+        assert_eq!(expanded(&["@room"], Some("dnd")).await, vec!["whammo"]);
+        // Each person once, however they were addressed
+        assert_eq!(
+            expanded(&["@room", "@group:mods", "whammo"], Some("dnd")).await,
+            vec!["rubik", "whammo"]
+        );
+        assert_eq!(expanded(&["@all"], None).await, vec!["dicebot", "rubik", "whammo"]);
+        assert_eq!(expanded(&["@role:bots"], None).await, vec!["dicebot"]);
+        assert_eq!(expanded(&["@role:users", "stoner"], None).await.len(), 3);
+        assert!(expanded(&["@group:nobody", "@shrug"], None).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        rooms::join(&state.rooms, "dnd", "stoner").await;
+        rooms::join(&state.rooms, rooms::LOBBY, "rubik").await;
+
+        let mesg = |sender: &str, recipients: &[&str], room: Option<&str>| {
+            let recipients = recipients.iter().map(|r| r.to_string()).collect();
+            let mut mesg = KMessage::new(sender.into(), recipients, MessageEvent::Message, "hi");
+            mesg.room = room.map(String::from);
+            mesg
+        };
+
+        assert!(check(&state, &mesg("stoner", &["@room"], Some("dnd"))).await.is_ok());
+        // rubik isn't in dnd, however the room is used
+        let err = check(&state, &mesg("rubik", &["@room"], Some("dnd"))).await.unwrap_err();
+        assert_eq!(err, "You are not in dnd");
+        assert!(check(&state, &mesg("rubik", &["stoner"], Some("dnd"))).await.is_err());
+        assert!(check(&state, &mesg("rubik", &["@room"], None)).await.is_ok());
+        assert!(check(&state, &mesg("stoner", &["@room"], None)).await.is_err());
+        assert!(check(&state, &mesg("rubik", &["stoner"], None)).await.is_ok());
+
+        // Nobody is an admin in the test config
+        assert!(check(&state, &mesg("rubik", &["@all"], None)).await.is_err());
+        assert!(check(&state, &mesg("rubik", &["@role:admins"], None)).await.is_err());
+    }
+}
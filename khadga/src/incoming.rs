@@ -0,0 +1,376 @@
+//! Incoming webhooks
+//!
+//! These let tools like CI or monitoring post into a room without holding a websocket open.  An
+//! admin creates an incoming webhook for a room with `POST /hooks/incoming`, giving it the name the
+//! messages will come from (eg `ci`).  Like a bot's, the name can't be that of a user, an admin or a
+//! bot, so an integration can't pass itself off as one of them.  The reply has a token, which is
+//! only shown that one time.  The tool then posts to `/hooks/incoming/<token>` with a body like
+//!
+//! ```json
+//! { "text": "Build 42 passed" }
+//! ```
+//!
+//! That is turned into a chat message in the room, from the webhook's name and with an
+//! `integration` annotation so clients can tell it apart from people.  From there it goes down the
+//! same path as any other chat message: the filters, then `chat::post`, which saves and relays it.
+
+use crate::{auth::{admin,
+                   CONFIG},
+            bots::{hash_token,
+                   name_taken,
+                   random_token},
+            chat::{post,
+                   ChatState},
+            message::{Message as KMessage,
+                      MessageEvent},
+            pgdb::{models,
+                   pgdb},
+            reply::error_reply,
+            rooms,
+            util::valid_name};
+use chrono::Utc;
+use log::{error,
+          info};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::json;
+use std::{collections::HashMap,
+          convert::Infallible,
+          sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// Incoming webhooks, keyed by id
+pub type IncomingHooks = Arc<Mutex<HashMap<i32, models::IncomingWebhook>>>;
+
+const TOKEN_PREFIX: &str = "khk_";
+
+/// Looks up the incoming webhook that owns the token
+pub async fn authenticate(hooks: &IncomingHooks, token: &str) -> Option<models::IncomingWebhook> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    let hash = hash_token(token);
+    let hooks = hooks.lock().await;
+    hooks.values().find(|h| h.token_hash == hash).cloned()
+}
+
+/// Creates the incoming webhook table and loads the webhooks
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let table = &CONFIG.db.tables.incoming_webhooks;
+
+    if let Err(e) = pgdb::make_table_incoming_webhooks(table, db).await {
+        error!("Unable to create the {} table: {}", table, e);
+        return;
+    }
+    match pgdb::list_incoming_webhooks(db, table).await {
+        Ok(list) => {
+            info!("Loaded {} incoming webhooks", list.len());
+            let mut hooks = state.incoming.lock().await;
+            for hook in list {
+                hooks.insert(hook.hook_id, hook);
+            }
+        }
+        Err(e) => error!("Unable to load incoming webhooks: {}", e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewIncoming {
+    /// Who the messages will be from
+    pub name: String,
+    pub room: String,
+}
+
+/// What a tool posts to the webhook
+#[derive(Deserialize, Debug)]
+pub struct Payload {
+    pub text: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IncomingInfo {
+    pub id: i32,
+    pub name: String,
+    pub room: String,
+    pub created_by: String,
+    pub created_on: chrono::DateTime<Utc>,
+}
+
+impl From<&models::IncomingWebhook> for IncomingInfo {
+    fn from(hook: &models::IncomingWebhook) -> Self {
+        IncomingInfo {
+            id: hook.hook_id,
+            name: hook.name.clone(),
+            room: hook.room.clone(),
+            created_by: hook.created_by.clone(),
+            created_on: hook.created_on,
+        }
+    }
+}
+
+/// Creates an incoming webhook and returns its token
+pub async fn create_incoming(
+    user: String,
+    new_hook: NewIncoming,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if !valid_name(&new_hook.name) {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "Names are 1 to 32 letters, digits, - or _",
+        ));
+    }
+    if new_hook.room.trim().is_empty() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "No room given"));
+    }
+    match name_taken(&state, &new_hook.name).await {
+        Ok(None) => {}
+        Ok(Some(reason)) => return Ok(error_reply(StatusCode::CONFLICT, reason)),
+        Err(e) => {
+            error!("Unable to look up user {}: {}", new_hook.name, e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save incoming webhook",
+            ));
+        }
+    }
+
+    let token = random_token(TOKEN_PREFIX);
+    let mut hook = models::IncomingWebhook {
+        hook_id: -1,
+        name: new_hook.name,
+        room: new_hook.room,
+        token_hash: hash_token(&token),
+        created_by: user,
+        created_on: Utc::now(),
+    };
+
+    let mut hooks = state.incoming.lock().await;
+    match &state.db {
+        Some(db) => {
+            let table = &CONFIG.db.tables.incoming_webhooks;
+            match pgdb::insert_incoming_webhook(db, table, &hook).await {
+                Ok(id) => hook.hook_id = id,
+                Err(e) => {
+                    error!("Unable to save incoming webhook: {}", e);
+                    return Ok(error_reply(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Unable to save incoming webhook",
+                    ));
+                }
+            }
+        }
+        None => hook.hook_id = hooks.keys().max().map_or(1, |id| id + 1),
+    }
+    info!("{} created incoming webhook {} for {}", hook.created_by, hook.name, hook.room);
+
+    let mut body = json!(IncomingInfo::from(&hook));
+    body["token"] = json!(token);
+    body["url"] = json!(format!("/hooks/incoming/{}", token));
+    hooks.insert(hook.hook_id, hook);
+    Ok(reply::with_status(reply::json(&body), StatusCode::CREATED))
+}
+
+pub async fn list_incoming(_user: String, state: ChatState) -> Result<Json, Infallible> {
+    let hooks = state.incoming.lock().await;
+    let mut all: Vec<IncomingInfo> = hooks.values().map(IncomingInfo::from).collect();
+    all.sort_by_key(|h| h.id);
+    Ok(reply::json(&all))
+}
+
+pub async fn delete_incoming(
+    id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let mut hooks = state.incoming.lock().await;
+    if !hooks.contains_key(&id) {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "No such incoming webhook"));
+    }
+    if let Some(db) = &state.db {
+        let table = &CONFIG.db.tables.incoming_webhooks;
+        if let Err(e) = pgdb::delete_incoming_webhook(db, table, id).await {
+            error!("Unable to delete incoming webhook {}: {}", id, e);
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete incoming webhook",
+            ));
+        }
+    }
+    hooks.remove(&id);
+    info!("{} deleted incoming webhook {}", user, id);
+    Ok(reply::with_status(reply::json(&json!({ "deleted": id })), StatusCode::OK))
+}
+
+/// Turns the payload into a chat message in the webhook's room
+pub async fn receive(
+    token: String,
+    payload: Payload,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let hook = match authenticate(&state.incoming, &token).await {
+        Some(hook) => hook,
+        None => return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid webhook token")),
+    };
+    if payload.text.trim().is_empty() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "No text given"));
+    }
+
+    let recipients = rooms::members(&state.rooms, &hook.room)
+        .await
+        .unwrap_or_default();
+    let mut mesg = KMessage::new(hook.name.clone(), recipients, MessageEvent::Message, payload.text);
+    mesg.room = Some(hook.room.clone());
+    mesg.annotations.insert("integration".into(), hook.name.clone());
+
+    if let Err(rejection) = state.filters.apply(&mut mesg) {
+        info!("Incoming webhook {}: {}", hook.name, rejection);
+        return Ok(error_reply(StatusCode::UNPROCESSABLE_ENTITY, &rejection.reason));
+    }
+    post(&state, &mesg).await;
+
+    let body = json!({ "room": hook.room, "recipients": mesg.recipients.len(), "time": mesg.time });
+    Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+}
+
+/// The incoming webhook endpoints
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path!("hooks" / "incoming"))
+        .and(admin())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_incoming);
+
+    let list = warp::get()
+        .and(warp::path!("hooks" / "incoming"))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(list_incoming);
+
+    let delete = warp::delete()
+        .and(warp::path!("hooks" / "incoming" / i32))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(delete_incoming);
+
+    let receive = warp::post()
+        .and(warp::path!("hooks" / "incoming" / String))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and(with_state)
+        .and_then(receive);
+
+    create
+        .or(list)
+        .or(delete)
+        .or(receive)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bots::{create_bot,
+                       NewBot},
+                commands::Dispatcher,
+                filter::{FilterChain,
+                         MaxLength},
+                state::UserInfo};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_incoming() {
+        let filters = FilterChain::new().with(MaxLength::new(20));
+        let state = ChatState::new(filters, Dispatcher::with_builtins(), None);
+
+        // Somebody is in the room to see the message
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.users.lock().await.insert("stoner".into(), UserInfo::new(Some(tx)));
+        rooms::join(&state.rooms, "builds", "stoner").await;
+
+        let new_hook = NewIncoming {
+            name: "ci".into(),
+            room: "builds".into(),
+        };
+        let resp = create_incoming("admin".into(), new_hook, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let bytes = warp::hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let token = body["token"].as_str().unwrap().to_string();
+
+        let payload = Payload { text: "Build 42 passed".into() };
+        let resp = receive(token.clone(), payload, state.clone()).await.unwrap().into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let got = rx.recv().await.unwrap().unwrap();
+        let got: KMessage<String> = serde_json::from_str(got.to_str().unwrap()).unwrap();
+        assert_eq!(got.sender, "ci");
+        assert_eq!(got.body, "Build 42 passed");
+        assert_eq!(got.room, Some("builds".into()));
+        assert_eq!(got.annotations["integration"], "ci");
+
+        // The filters still apply
+        let payload = Payload { text: "This build log is far too long".into() };
+        let resp = receive(token, payload, state.clone()).await.unwrap().into_response();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let payload = Payload { text: "hi".into() };
+        let resp = receive("khk_bad".into(), payload, state).await.unwrap().into_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_taken_names() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        state.users.lock().await.insert("stoner".into(), UserInfo::new(None));
+
+        // A hook can't post as somebody who is online
+        let new_hook = NewIncoming {
+            name: "stoner".into(),
+            room: "builds".into(),
+        };
+        let resp = create_incoming("admin".into(), new_hook, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Nor as a bot
+        let new_bot = NewBot {
+            name: "dicebot".into(),
+            rooms: vec![],
+        };
+        let resp = create_bot("stoner".into(), new_bot, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let new_hook = NewIncoming {
+            name: "dicebot".into(),
+            room: "builds".into(),
+        };
+        let resp = create_incoming("admin".into(), new_hook, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert!(state.incoming.lock().await.is_empty());
+    }
+}
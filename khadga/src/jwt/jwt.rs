@@ -89,6 +89,14 @@ pub fn create_jwt(user: &str, email: &str) -> Result<String, JWTError> {
     token
 }
 
+/// Decodes a token (eg from the jwt cookie) and returns the user it was issued to
+///
+/// Unlike `validate_jwt`, this does not panic.  An expired or tampered with token is an Err.
+pub fn user_from_token(token: &str) -> Result<String, JWTError> {
+    let data = decode::<Claims>(token, &DecodingKey::from_secret(SECRET), &Validation::default())?;
+    Ok(data.claims.sub)
+}
+
 /// Validator for a given user and supplied token
 /// 
 /// FIXME: This is always panicking.  We need a way to handle this gracefully and return a Result
@@ -145,6 +153,17 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_user_from_token() -> TestResult {
+        let jwt = create_jwt("stoner", "foobar@gmail.com")?;
+        let jwt: JWTResponse = serde_json::from_str(&jwt)?;
+
+        assert_eq!(user_from_token(&jwt.token)?, "stoner");
+        assert!(user_from_token("not.a.token").is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_validate_token() -> TestResult {
         let jwt = create_jwt("stoner", "foobar@gmail.com")?;
@@ -0,0 +1,613 @@
+//! Logging in with a public key
+//!
+//! Besides signing in with Google, people can register public keys and log in by signing a
+//! challenge with the private half.  RSA (2048 bits or more) and Ed25519 keys are supported, in
+//! PEM: either `-----BEGIN PUBLIC KEY-----` (what `openssl pkey -pubout` and WebCrypto's `spki`
+//! export give) or `-----BEGIN RSA PUBLIC KEY-----`.
+//!
+//! Managing keys needs the `jwt` cookie from an earlier login:
+//!
+//! - `GET /keys` lists your keys, including revoked ones
+//! - `POST /keys` with `{"public_key": "<pem>"}` registers a key.  Add `"replaces": <id>` to
+//!   rotate, which revokes the old key once the new one is saved.
+//! - `DELETE /keys/<id>` revokes a key
+//!
+//! Logging in takes two requests:
+//!
+//! 1. `POST /login/challenge` with `{"username": "stoner"}`.  The reply has a `nonce` and the exact
+//!    `message` to sign, which is `khadga login <username> <nonce>`.  It is good for two minutes.
+//! 2. `POST /login/key` with `{"username": "stoner", "nonce": "...", "signature": "<base64>"}`.
+//!    RSA signatures can be PKCS#1 v1.5 or PSS, both with SHA-256.
+//!
+//! If the signature checks out against one of the user's keys, the reply is the same as for a
+//! Google login (see `auth::session_reply`): the JWT, and the `jwt`, `expiry` and `khadga_user`
+//! cookies.  Each nonce can only be used once, whether the login works or not.
+
+use crate::{auth::{authenticated,
+                   session_reply,
+                   CONFIG},
+            chat::ChatState,
+            pgdb::{models::PublicKey,
+                   pgdb},
+            reply::error_reply};
+use chrono::{DateTime,
+             Duration,
+             Utc};
+use log::{error,
+          info};
+use ring::{digest,
+           rand::{SecureRandom,
+                  SystemRandom},
+           signature::{self,
+                       UnparsedPublicKey}};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::json;
+use std::{collections::HashMap,
+          convert::Infallible,
+          fmt,
+          sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter,
+           http::{Response,
+                  StatusCode},
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// Outstanding login challenges, keyed by nonce
+pub type Challenges = Arc<Mutex<HashMap<String, Challenge>>>;
+
+const CHALLENGE_SECS: i64 = 120;
+/// Challenges are handed out to anyone, so don't let them pile up
+const MAX_CHALLENGES: usize = 10_000;
+
+const OID_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ed25519,
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyAlgorithm::Rsa => write!(f, "rsa"),
+            KeyAlgorithm::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+/// Splits one DER element off the input, returning its tag, its contents and what is left
+fn der_element(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let bad = || "Malformed key".to_string();
+    let tag = *input.first().ok_or_else(bad)?;
+    let first = *input.get(1).ok_or_else(bad)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 2 || input.len() < 2 + count {
+            return Err(bad());
+        }
+        let len = input[2..2 + count].iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + count)
+    };
+    if input.len() < header + len {
+        return Err(bad());
+    }
+    Ok((tag, &input[header..header + len], &input[header + len..]))
+}
+
+/// Checks an RSA public key in PKCS#1 form and returns how many bits it has
+fn rsa_bits(pkcs1: &[u8]) -> Result<usize, String> {
+    let (tag, seq, _) = der_element(pkcs1)?;
+    let (int_tag, modulus, _) = der_element(seq)?;
+    if tag != 0x30 || int_tag != 0x02 {
+        return Err("Malformed RSA key".into());
+    }
+    let leading = modulus.iter().take_while(|b| **b == 0).count();
+    Ok((modulus.len() - leading) * 8)
+}
+
+/// Parses a PEM public key into its algorithm and the key bytes ring wants
+///
+/// For RSA that is the PKCS#1 `RSAPublicKey`, and for Ed25519 the 32 byte key.
+pub fn parse_public_key(pem: &str) -> Result<(KeyAlgorithm, Vec<u8>), String> {
+    let pem = pem.trim();
+    let label = pem
+        .lines()
+        .next()
+        .and_then(|l| l.strip_prefix("-----BEGIN "))
+        .and_then(|l| l.strip_suffix("-----"))
+        .ok_or("The key should be in PEM")?;
+    let body: String = pem
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .flat_map(|l| l.trim().chars())
+        .collect();
+    let der = base64::decode(&body).map_err(|_| "The key isn't valid base64")?;
+
+    let (algorithm, key) = match label {
+        "RSA PUBLIC KEY" => (KeyAlgorithm::Rsa, der),
+        "PUBLIC KEY" => {
+            let (tag, spki, _) = der_element(&der)?;
+            let (alg_tag, alg, rest) = der_element(spki)?;
+            let (oid_tag, oid, _) = der_element(alg)?;
+            let (bits_tag, bits, _) = der_element(rest)?;
+            if tag != 0x30 || alg_tag != 0x30 || oid_tag != 0x06 || bits_tag != 0x03 {
+                return Err("Malformed key".into());
+            }
+            // The first byte of a BIT STRING is the number of unused bits
+            let key = match bits.split_first() {
+                Some((0, key)) => key.to_vec(),
+                _ => return Err("Malformed key".into()),
+            };
+            if oid == OID_RSA {
+                (KeyAlgorithm::Rsa, key)
+            } else if oid == OID_ED25519 {
+                (KeyAlgorithm::Ed25519, key)
+            } else {
+                return Err("Only RSA and Ed25519 keys are supported".into());
+            }
+        }
+        _ => return Err(format!("Expected a PUBLIC KEY, not {}", label)),
+    };
+
+    match algorithm {
+        KeyAlgorithm::Rsa if rsa_bits(&key)? < 2048 => {
+            Err("RSA keys need at least 2048 bits".into())
+        }
+        KeyAlgorithm::Ed25519 if key.len() != 32 => Err("Malformed Ed25519 key".into()),
+        _ => Ok((algorithm, key)),
+    }
+}
+
+/// The SHA-256 of the key, as ssh-keygen shows it
+pub fn fingerprint(key: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, key);
+    format!("SHA256:{}", base64::encode_config(hash.as_ref(), base64::STANDARD_NO_PAD))
+}
+
+/// Whether the signature of the message was made with the key
+pub fn verify(algorithm: KeyAlgorithm, key: &[u8], message: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        KeyAlgorithm::Rsa => {
+            let schemes: [&'static dyn signature::VerificationAlgorithm; 2] =
+                [&signature::RSA_PKCS1_2048_8192_SHA256, &signature::RSA_PSS_2048_8192_SHA256];
+            schemes
+                .iter()
+                .any(|scheme| UnparsedPublicKey::new(*scheme, key).verify(message, sig).is_ok())
+        }
+        KeyAlgorithm::Ed25519 => {
+            UnparsedPublicKey::new(&signature::ED25519, key).verify(message, sig).is_ok()
+        }
+    }
+}
+
+/// What gets signed to log in
+pub fn challenge_message(username: &str, nonce: &str) -> String {
+    format!("khadga login {} {}", username, nonce)
+}
+
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub username: String,
+    pub expires: DateTime<Utc>,
+}
+
+/// Hands out a new challenge for the user
+pub async fn new_challenge(challenges: &Challenges, username: &str) -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| "Unable to make a nonce")?;
+    let nonce = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+
+    let now = Utc::now();
+    let mut challenges = challenges.lock().await;
+    challenges.retain(|_, c| c.expires > now);
+    if challenges.len() >= MAX_CHALLENGES {
+        return Err("Too many logins in progress, try again soon".into());
+    }
+    let challenge = Challenge {
+        username: username.to_string(),
+        expires: now + Duration::seconds(CHALLENGE_SECS),
+    };
+    challenges.insert(nonce.clone(), challenge);
+    Ok(nonce)
+}
+
+/// Uses up the challenge, checking it was for this user and hasn't expired
+pub async fn take_challenge(
+    challenges: &Challenges,
+    username: &str,
+    nonce: &str,
+) -> Result<(), String> {
+    match challenges.lock().await.remove(nonce) {
+        Some(c) if c.username == username && c.expires > Utc::now() => Ok(()),
+        _ => Err("Unknown or expired challenge".into()),
+    }
+}
+
+/// Finds the active key that made the signature
+pub fn signed_by<'a>(keys: &'a [PublicKey], message: &[u8], sig: &[u8]) -> Option<&'a PublicKey> {
+    keys.iter().filter(|k| k.revoked_on.is_none()).find(|k| {
+        parse_public_key(&k.public_key)
+            .map(|(algorithm, key)| verify(algorithm, &key, message, sig))
+            .unwrap_or(false)
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewKey {
+    pub public_key: String,
+    /// The key this one replaces, which is revoked once this one is saved
+    pub replaces: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChallengeRequest {
+    pub username: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChallengeReply {
+    pub nonce: String,
+    pub message: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeyLogin {
+    pub username: String,
+    pub nonce: String,
+    /// Base64 signature of the challenge message
+    pub signature: String,
+}
+
+fn no_db() -> WithStatus<Json> {
+    error_reply(StatusCode::SERVICE_UNAVAILABLE, "The database is not available")
+}
+
+/// `GET /keys`
+pub async fn list_keys(user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::list_public_keys(db, &CONFIG.db.tables.public_keys, &user).await {
+        Ok(keys) => Ok(reply::with_status(reply::json(&keys), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to list the keys of {}: {}", user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to list keys"))
+        }
+    }
+}
+
+/// `POST /keys`: registers a key, or rotates one
+pub async fn add_key(
+    user: String,
+    new_key: NewKey,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let (algorithm, key) = match parse_public_key(&new_key.public_key) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    };
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    let table = &CONFIG.db.tables.public_keys;
+
+    if let Some(old) = new_key.replaces {
+        let keys = pgdb::list_public_keys(db, table, &user).await.unwrap_or_default();
+        if !keys.iter().any(|k| k.key_id == old && k.revoked_on.is_none()) {
+            return Ok(error_reply(StatusCode::NOT_FOUND, "No such key to replace"));
+        }
+    }
+
+    let mut saved = PublicKey {
+        key_id: -1,
+        username: user.clone(),
+        algorithm: algorithm.to_string(),
+        public_key: new_key.public_key.trim().to_string(),
+        fingerprint: fingerprint(&key),
+        created_on: Utc::now(),
+        revoked_on: None,
+    };
+    match pgdb::insert_public_key(db, table, &saved).await {
+        Ok(id) => saved.key_id = id,
+        Err(e) => {
+            // Most likely the unique index on active fingerprints
+            info!("Unable to save key {} for {}: {}", saved.fingerprint, user, e);
+            return Ok(error_reply(StatusCode::CONFLICT, "That key is already registered"));
+        }
+    }
+    if let Some(old) = new_key.replaces {
+        if let Err(e) = pgdb::revoke_public_key(db, table, &user, old).await {
+            error!("Unable to revoke key {} of {}: {}", old, user, e);
+        }
+    }
+    info!("{} registered {} key {}", user, saved.algorithm, saved.fingerprint);
+    Ok(reply::with_status(reply::json(&saved), StatusCode::CREATED))
+}
+
+/// `DELETE /keys/<id>`
+pub async fn revoke_key(
+    id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::revoke_public_key(db, &CONFIG.db.tables.public_keys, &user, id).await {
+        Ok(0) => Ok(error_reply(StatusCode::NOT_FOUND, "No such key")),
+        Ok(_) => {
+            info!("{} revoked key {}", user, id);
+            Ok(reply::with_status(reply::json(&json!({ "revoked": id })), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to revoke key {} of {}: {}", id, user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to revoke key"))
+        }
+    }
+}
+
+/// `POST /login/challenge`
+///
+/// Anybody gets a challenge, so this doesn't tell who has keys registered.
+pub async fn challenge(
+    request: ChallengeRequest,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    match new_challenge(&state.challenges, &request.username).await {
+        Ok(nonce) => {
+            let body = ChallengeReply {
+                message: challenge_message(&request.username, &nonce),
+                nonce,
+                expires: Utc::now() + Duration::seconds(CHALLENGE_SECS),
+            };
+            Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+        }
+        Err(e) => Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, &e)),
+    }
+}
+
+fn text_reply(status: StatusCode, text: &str) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(text.to_string())
+        .expect("Unable to create HTTP Response")
+}
+
+/// `POST /login/key`
+pub async fn key_login(login: KeyLogin, state: ChatState) -> Result<Response<String>, Infallible> {
+    let denied = || text_reply(StatusCode::UNAUTHORIZED, "Unable to log in with that signature");
+    if take_challenge(&state.challenges, &login.username, &login.nonce).await.is_err() {
+        return Ok(denied());
+    }
+    let sig = match base64::decode(login.signature.trim()) {
+        Ok(sig) => sig,
+        Err(_) => return Ok(denied()),
+    };
+    let db = match &state.db {
+        Some(db) => db,
+        None => {
+            let msg = "The database is not available";
+            return Ok(text_reply(StatusCode::SERVICE_UNAVAILABLE, msg));
+        }
+    };
+
+    let tables = &CONFIG.db.tables;
+    let (keys, user) = match (
+        pgdb::list_public_keys(db, &tables.public_keys, &login.username).await,
+        pgdb::find_user(db, &tables.users, &login.username).await,
+    ) {
+        (Ok(keys), Ok(Some(user))) => (keys, user),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Unable to look up {} for a key login: {}", login.username, e);
+            return Ok(text_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to log in"));
+        }
+        (_, Ok(None)) => return Ok(denied()),
+    };
+
+    let message = challenge_message(&login.username, &login.nonce);
+    match signed_by(&keys, message.as_bytes(), &sig) {
+        Some(key) => {
+            info!("{} logged in with key {}", user.username, key.fingerprint);
+            Ok(session_reply(&user.username, &user.email).unwrap_or_else(|e| {
+                error!("Unable to build the login reply: {}", e);
+                text_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to log in")
+            }))
+        }
+        None => {
+            info!("Failed key login for {}", login.username);
+            Ok(denied())
+        }
+    }
+}
+
+/// Creates the public key table
+pub async fn load(state: &ChatState) {
+    if let Some(db) = &state.db {
+        let table = &CONFIG.db.tables.public_keys;
+        if let Err(e) = pgdb::make_table_public_keys(table, db).await {
+            error!("Unable to create the {} table: {}", table, e);
+        }
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let list = warp::get()
+        .and(warp::path!("keys"))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(list_keys);
+
+    let add = warp::post()
+        .and(warp::path!("keys"))
+        .and(authenticated())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(add_key);
+
+    let revoke = warp::delete()
+        .and(warp::path!("keys" / i32))
+        .and(authenticated())
+        .and(with_state.clone())
+        .and_then(revoke_key);
+
+    let challenge = warp::post()
+        .and(warp::path!("login" / "challenge"))
+        .and(warp::body::content_length_limit(4 * 1024))
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(challenge);
+
+    let login = warp::post()
+        .and(warp::path!("login" / "key"))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_state)
+        .and_then(key_login);
+
+    list.or(add)
+        .or(revoke)
+        .or(challenge)
+        .or(login)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair,
+                          KeyPair,
+                          RsaKeyPair};
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let body = base64::encode(der);
+        let lines: Vec<&str> =
+            body.as_bytes().chunks(64).map(|c| std::str::from_utf8(c).unwrap()).collect();
+        format!("-----BEGIN {}-----\n{}\n-----END {}-----\n", label, lines.join("\n"), label)
+    }
+
+    /// A DER element with a short or two byte length
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if contents.len() < 0x80 {
+            out.push(contents.len() as u8);
+        } else {
+            out.extend_from_slice(&[0x82, (contents.len() >> 8) as u8, contents.len() as u8]);
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn spki(oid: &[u8], params: &[u8], key: &[u8]) -> Vec<u8> {
+        let alg = der(0x30, &[der(0x06, oid), params.to_vec()].concat());
+        let bits = der(0x03, &[&[0u8][..], key].concat());
+        der(0x30, &[alg, bits].concat())
+    }
+
+    fn stored(id: i32, pem: String) -> PublicKey {
+        let (algorithm, key) = parse_public_key(&pem).unwrap();
+        PublicKey {
+            key_id: id,
+            username: "stoner".into(),
+            algorithm: algorithm.to_string(),
+            public_key: pem,
+            fingerprint: fingerprint(&key),
+            created_on: Utc::now(),
+            revoked_on: None,
+        }
+    }
+
+    fn rsa_key() -> RsaKeyPair {
+        let pem = include_str!("../config/khadga-test-pvt.key");
+        let body: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        RsaKeyPair::from_pkcs8(&base64::decode(body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let rsa = rsa_key();
+        let pkcs1 = rsa.public_key().as_ref().to_vec();
+        let (algorithm, key) = parse_public_key(&pem("RSA PUBLIC KEY", &pkcs1)).unwrap();
+        assert_eq!((algorithm, key.clone()), (KeyAlgorithm::Rsa, pkcs1.clone()));
+        let wrapped = spki(OID_RSA, &[0x05, 0x00], &pkcs1);
+        assert_eq!(parse_public_key(&pem("PUBLIC KEY", &wrapped)).unwrap().1, key);
+
+        let ed = [7u8; 32];
+        let wrapped = spki(OID_ED25519, &[], &ed);
+        assert_eq!(
+            parse_public_key(&pem("PUBLIC KEY", &wrapped)).unwrap(),
+            (KeyAlgorithm::Ed25519, ed.to_vec())
+        );
+        assert!(parse_public_key(&pem("PUBLIC KEY", &spki(OID_ED25519, &[], &[7u8; 31]))).is_err());
+
+        // A 1024 bit key is too small
+        let small = der(0x30, &[der(0x02, &[0xc1; 128]), der(0x02, &[1, 0, 1])].concat());
+        assert!(parse_public_key(&pem("RSA PUBLIC KEY", &small)).is_err());
+        assert!(parse_public_key(&pem("PRIVATE KEY", &small)).is_err());
+        assert!(parse_public_key("ssh-ed25519 AAAA").is_err());
+        assert!(fingerprint(&ed).starts_with("SHA256:"));
+    }
+
+    #[test]
+    fn test_signatures() {
+        let rng = SystemRandom::new();
+        let message = challenge_message("stoner", "abc");
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let ed = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let ed_pem = pem("PUBLIC KEY", &spki(OID_ED25519, &[], ed.public_key().as_ref()));
+        let ed_sig = ed.sign(message.as_bytes());
+
+        let rsa = rsa_key();
+        let rsa_pem = pem("RSA PUBLIC KEY", rsa.public_key().as_ref());
+        let mut rsa_sig = vec![0; rsa.public_modulus_len()];
+        rsa.sign(&signature::RSA_PKCS1_SHA256, &rng, message.as_bytes(), &mut rsa_sig).unwrap();
+        let mut pss_sig = vec![0; rsa.public_modulus_len()];
+        rsa.sign(&signature::RSA_PSS_SHA256, &rng, message.as_bytes(), &mut pss_sig).unwrap();
+
+        let mut keys = vec![stored(1, ed_pem), stored(2, rsa_pem)];
+        let found = |keys: &[PublicKey], sig: &[u8]| {
+            signed_by(keys, message.as_bytes(), sig).map(|k| k.key_id)
+        };
+        assert_eq!(found(&keys, ed_sig.as_ref()), Some(1));
+        assert_eq!(found(&keys, &rsa_sig), Some(2));
+        assert_eq!(found(&keys, &pss_sig), Some(2));
+        // Signed something else
+        assert!(signed_by(&keys, b"khadga login whammo abc", &rsa_sig).is_none());
+
+        keys[1].revoked_on = Some(Utc::now());
+        assert_eq!(found(&keys, &rsa_sig), None);
+    }
+
+    #[tokio::test]
+    async fn test_challenges() {
+        let challenges: Challenges = Arc::new(Mutex::new(HashMap::new()));
+        let nonce = new_challenge(&challenges, "stoner").await.unwrap();
+        assert!(take_challenge(&challenges, "whammo", &nonce).await.is_err());
+        // Even a failed attempt uses it up
+        assert!(take_challenge(&challenges, "stoner", &nonce).await.is_err());
+
+        let nonce = new_challenge(&challenges, "stoner").await.unwrap();
+        assert!(take_challenge(&challenges, "stoner", &nonce).await.is_ok());
+        assert!(take_challenge(&challenges, "stoner", &nonce).await.is_err());
+
+        let nonce = new_challenge(&challenges, "stoner").await.unwrap();
+        challenges.lock().await.get_mut(&nonce).unwrap().expires = Utc::now();
+        assert!(take_challenge(&challenges, "stoner", &nonce).await.is_err());
+    }
+}
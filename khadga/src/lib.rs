@@ -1,10 +1,36 @@
 pub mod auth;
+pub mod bots;
 pub mod chat;
+pub mod commands;
 pub mod config;
+pub mod conversations;
+pub mod corpus;
 pub mod data;
+pub mod e2e;
+pub mod export;
+pub mod filter;
+pub mod groups;
+pub mod incoming;
 // pub mod db;
 pub mod jwt;
+pub mod keys;
+pub mod mesh;
 pub mod message;
+pub mod protocol;
+pub mod questions;
+pub mod reply;
+pub mod retention;
+pub mod rooms;
+pub mod search;
+pub mod sessions;
 pub mod signaling;
+pub mod sse;
 pub mod state;
+pub mod stun;
+pub mod trends;
+pub mod turn;
+pub mod typegen;
+pub mod util;
+pub mod webhooks;
+pub mod wire;
 pub mod pgdb;
@@ -1,12 +1,39 @@
-use khadga::{auth::login,
-             chat::{user_connected,
-                    Users},
-             config::Settings};
-use log::info;
-use std::{collections::HashMap,
-          net::SocketAddr,
-          sync::Arc};
-use tokio::sync::Mutex;
+use khadga::{auth::{authenticated,
+                   handle_rejection,
+                   login,
+                   Forbidden},
+             bots,
+             chat::{peer,
+                    user_connected,
+                    ChatState},
+             commands::Dispatcher,
+             config::Settings,
+             conversations,
+             corpus,
+             data::AccountKind,
+             e2e,
+             export,
+             filter::FilterChain,
+             groups,
+             incoming,
+             keys,
+             pgdb::pgdb,
+             questions::QuestionIndex,
+             retention,
+             search,
+             sessions,
+             signaling::{self,
+                         Calls},
+             sse,
+             state::Peer,
+             stun,
+             trends::{self,
+                      TrendTracker},
+             turn,
+             webhooks};
+use log::{error,
+          info};
+use std::net::SocketAddr;
 use warp::{http::{Response,
                   StatusCode},
            ws::Ws,
@@ -34,20 +61,89 @@ async fn main() {
         builder.status(StatusCode::OK).body("")
     });
 
-    let users: Users = Arc::new(Mutex::new(HashMap::new()));
-    let users2 = warp::any().map(move || users.clone());
+    let filters = FilterChain::from_config(&config.filters)
+        .unwrap_or_else(|e| panic!("Could not set up message filters: {}", e));
+    info!("Message filters: {:?}", filters.names());
+    let db = match pgdb::connect(&config.db.name).await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            error!("Could not connect to the database, nothing will be saved: {}", e);
+            None
+        }
+    };
+    let state = ChatState::new(filters, Dispatcher::with_builtins(), db);
+    *state.trends.lock().await = TrendTracker::new(config.trends.clone());
+    *state.questions.lock().await = QuestionIndex::new(config.questions.clone());
+    *state.calls.lock().await = Calls::new(config.calls.clone());
+    if let Some(db) = &state.db {
+        if let Err(e) = pgdb::make_table_messages(&config.db.tables.messages, db).await {
+            error!("Unable to create the {} table: {}", config.db.tables.messages, e);
+        }
+        if let Err(e) = pgdb::add_encrypted_column(&config.db.tables.messages, db).await {
+            error!("Unable to add the encrypted column to {}: {}", config.db.tables.messages, e);
+        }
+        if let Err(e) = pgdb::add_delivered_to_column(&config.db.tables.messages, db).await {
+            error!("Unable to add the delivered_to column to {}: {}", config.db.tables.messages, e);
+        }
+        if let Err(e) = pgdb::add_consent_columns(&config.db.tables.users, db).await {
+            error!("Unable to add the consent columns to {}: {}", config.db.tables.users, e);
+        }
+    }
+    bots::load(&state).await;
+    webhooks::load(&state).await;
+    incoming::load(&state).await;
+    retention::load(&state).await;
+    keys::load(&state).await;
+    e2e::load(&state).await;
+    groups::load(&state).await;
+    conversations::load(&state).await;
+    signaling::load(&state).await;
+    if let (Some(db), true) = (&state.db, config.retention.enabled) {
+        tokio::spawn(retention::run_periodically(db.clone(), config.retention.clone()));
+    }
+    if config.stun.enabled {
+        tokio::spawn(stun::run(config.stun.clone()));
+    }
+    if config.turn.enabled {
+        tokio::spawn(turn::run(config.turn.clone(), turn::CREDENTIALS.clone()));
+    }
+    let bot_routes = bots::routes(state.clone());
+    let webhook_routes = webhooks::routes(state.clone());
+    let incoming_routes = incoming::routes(state.clone());
+    let search_routes = search::routes(state.clone());
+    let export_routes = export::routes(state.clone());
+    let consent_routes = corpus::routes(state.clone());
+    let trend_routes = trends::routes(state.clone());
+    let retention_routes = retention::routes(state.clone());
+    let key_routes = keys::routes(state.clone());
+    let session_routes = sessions::routes(state.clone());
+    let sse_routes = sse::routes(state.clone());
+    let e2e_routes = e2e::routes(state.clone());
+    let group_routes = groups::routes(state.clone());
+    let conversation_routes = conversations::routes(state.clone());
+    let call_routes = signaling::routes(state.clone());
+    let state2 = warp::any().map(move || state.clone());
 
     // This is the main chat endpoint.  When the front end needs to perform chat, it will call
-    // this endpoint.
+    // this endpoint.  Who you are comes from the jwt cookie, and the name in the path has to match.
     let chat = warp::path("chat")
-        /* .and(warp::cookie("jwt")) */
+        .and(authenticated())
+        .and(warp::path::param())
+        .and_then(|user: String, username: String| async move {
+            if user == username {
+                Ok(user)
+            } else {
+                Err(warp::reject::custom(Forbidden))
+            }
+        })
         .and(warp::ws())
-        .and(warp::path::param().map(|username: String| username))
-        .and(users2)
-        .map(|/* cookie: String, */ ws: Ws, username: String, users: Users| {
-            /* info!("Cookie is: {}", cookie); */
-            info!("User {} starting chat", username);
-            ws.on_upgrade(move |socket| user_connected(socket, users, username))
+        .and(peer())
+        .and(state2)
+        .map(|username: String, ws: Ws, peer: Peer, state: ChatState| {
+            info!("User {} starting chat from {:?}", username, peer.remote_addr);
+            ws.on_upgrade(move |socket| {
+                user_connected(socket, state, username, AccountKind::User, peer)
+            })
         });
 
     // This is the main entry point to the application
@@ -62,12 +158,29 @@ async fn main() {
     let app = chat
         .or(health)
         .or(start)
+        .or(key_routes)
+        .or(e2e_routes)
+        .or(group_routes)
+        .or(conversation_routes)
+        .or(call_routes)
+        .or(turn::routes())
         .or(login())
+        .or(bot_routes)
+        .or(webhook_routes)
+        .or(incoming_routes)
+        .or(search_routes)
+        .or(export_routes)
+        .or(consent_routes)
+        .or(trend_routes)
+        .or(retention_routes)
+        .or(session_routes)
+        .or(sse_routes)
+        .recover(handle_rejection)
         .with(log);
 
     let host: SocketAddr = khadga_addr
         .parse()
-        .expect(&format!("Could not parse {}", khadga_addr));
+        .unwrap_or_else(|_| panic!("Could not parse {}", khadga_addr));
     info!("Starting up on {}", host);
     let warp_server = warp::serve(app);
 
@@ -0,0 +1,414 @@
+//! Calls with everybody in a room
+//!
+//! A room can have one call going on, with up to `calls.max_participants` people from the room in
+//! it.  It is a mesh: everybody has a peer connection to everybody else, and khadga only does the
+//! signaling for each pair.
+//!
+//! - Sending a `Call` command with the `Join` action and the message's `room` set joins that
+//!   room's call, starting it if there isn't one.  You have to be in the room, and not in another
+//!   call.
+//! - Everybody in the call then gets a `Mesh` event saying who joined.  The newcomer's has
+//!   `connect_to`, the people already there: the newcomer sends each of them an `SDPOffer`, and
+//!   they answer.
+//! - `SDPOffer`s, `SDPAnswer`s and `IceCandidate`s with the `room` set go to one other person in
+//!   that room's call, with the same checks as for a call between two people (see `signaling`)
+//! - The `Leave` action, leaving the room or disconnecting takes you out of the call, and
+//!   everybody left (and you) gets a `Mesh` event saying so.  The call is over once everybody has
+//!   left.
+
+use crate::{chat::{send_to,
+                   ChatState},
+            message::{Message as KMessage,
+                      MessageEvent},
+            rooms,
+            signaling::{check_request,
+                        forward,
+                        recipient,
+                        reject,
+                        CallAction,
+                        Signal},
+            util::random_id,
+            wire::{Envelope,
+                   Event}};
+use chrono::{DateTime,
+             Utc};
+use log::info;
+use serde::{Deserialize,
+            Serialize};
+use std::collections::{HashMap,
+                       HashSet};
+
+/// The body of a `Mesh` event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MeshEvent {
+    pub id: String,
+    pub room: String,
+    /// Everybody in the call, in the order they joined
+    pub participants: Vec<String>,
+    /// Who joined or left
+    pub user: String,
+    pub joined: bool,
+    /// Who to send offers to.  Only the newcomer gets these.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connect_to: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mesh {
+    pub id: String,
+    pub room: String,
+    /// In the order they joined
+    pub participants: Vec<String>,
+    /// Offers that haven't been answered yet, as (offerer, answerer)
+    offers: HashSet<(String, String)>,
+    pub started: DateTime<Utc>,
+}
+
+impl Mesh {
+    fn new(room: &str) -> Self {
+        Mesh {
+            id: random_id(8),
+            room: room.into(),
+            participants: vec![],
+            offers: HashSet::new(),
+            started: Utc::now(),
+        }
+    }
+
+    pub fn has(&self, user: &str) -> bool {
+        self.participants.iter().any(|p| p == user)
+    }
+
+    /// What a participant is told when somebody joins or leaves
+    pub fn event(&self, user: &str, joined: bool, to: &str) -> MeshEvent {
+        let connect_to = if joined && to == user {
+            self.participants.iter().filter(|p| *p != user).cloned().collect()
+        } else {
+            vec![]
+        };
+        MeshEvent {
+            id: self.id.clone(),
+            room: self.room.clone(),
+            participants: self.participants.clone(),
+            user: user.into(),
+            joined,
+            connect_to,
+        }
+    }
+}
+
+/// The room calls going on, and who is in which
+#[derive(Debug)]
+pub struct Meshes {
+    /// Keyed by room
+    meshes: HashMap<String, Mesh>,
+    /// The room each participant is in a call in
+    by_user: HashMap<String, String>,
+    pub max_participants: usize,
+}
+
+impl Meshes {
+    pub fn new(max_participants: usize) -> Self {
+        Meshes {
+            meshes: HashMap::new(),
+            by_user: HashMap::new(),
+            max_participants,
+        }
+    }
+
+    pub fn get(&self, room: &str) -> Option<&Mesh> {
+        self.meshes.get(room)
+    }
+
+    /// The room whose call the user is in, if any
+    pub fn room_of(&self, user: &str) -> Option<&str> {
+        self.by_user.get(user).map(String::as_str)
+    }
+
+    /// Adds the user to the room's call, starting it if need be
+    pub fn join(&mut self, user: &str, room: &str) -> Result<Mesh, String> {
+        match self.room_of(user) {
+            Some(r) if r == room => return Err("You are already in the call".into()),
+            Some(r) => return Err(format!("You are already in the call in {}", r)),
+            None => {}
+        }
+        let full = self.meshes.get(room).map_or(0, |m| m.participants.len());
+        if full >= self.max_participants {
+            let max = self.max_participants;
+            return Err(format!("The call in {} is full ({} people)", room, max));
+        }
+
+        let mesh = self.meshes.entry(room.into()).or_insert_with(|| Mesh::new(room));
+        mesh.participants.push(user.into());
+        self.by_user.insert(user.into(), room.into());
+        Ok(mesh.clone())
+    }
+
+    /// Takes the user out of whatever room's call they are in, returning what is left of it.  The
+    /// call is dropped once it is empty.
+    pub fn leave(&mut self, user: &str) -> Option<Mesh> {
+        let room = self.by_user.remove(user)?;
+        let mesh = self.meshes.get_mut(&room)?;
+        mesh.participants.retain(|p| p != user);
+        mesh.offers.retain(|(from, to)| from != user && to != user);
+        let mesh = mesh.clone();
+        if mesh.participants.is_empty() {
+            info!("The call in {} is over", room);
+            self.meshes.remove(&room);
+        }
+        Some(mesh)
+    }
+
+    /// Checks a signaling command between two people in a room's call, returning the call's id
+    pub fn signal(
+        &mut self,
+        room: &str,
+        from: &str,
+        to: &str,
+        signal: Signal,
+    ) -> Result<String, String> {
+        let mesh = match self.meshes.get_mut(room) {
+            Some(mesh) if mesh.has(from) => mesh,
+            _ => return Err(format!("You are not in the call in {}", room)),
+        };
+        if from == to || !mesh.has(to) {
+            return Err(format!("{} is not in the call in {}", to, room));
+        }
+        match signal {
+            Signal::Offer => {
+                mesh.offers.insert((from.into(), to.into()));
+            }
+            Signal::Answer => {
+                if !mesh.offers.remove(&(to.to_string(), from.to_string())) {
+                    return Err("There is no offer to answer".into());
+                }
+            }
+            Signal::Candidate => {}
+        }
+        Ok(mesh.id.clone())
+    }
+}
+
+/// Tells everybody in the call who joined or left.  Whoever left is told too.
+async fn announce(state: &ChatState, mesh: &Mesh, user: &str, joined: bool) {
+    let mut to = mesh.participants.clone();
+    if !joined {
+        to.push(user.into());
+    }
+    for participant in &to {
+        let event = mesh.event(user, joined, participant);
+        let mut mesg = KMessage::new("khadga".into(), to.clone(), MessageEvent::Mesh, event);
+        mesg.room = Some(mesh.room.clone());
+        send_to(&state.users, participant, &mesg).await;
+    }
+}
+
+/// Joins or leaves the call in the room a `Call` command was sent to
+pub async fn action(state: &ChatState, my_id: &str, envelope: &Envelope, action: CallAction) {
+    let room = match &envelope.room {
+        Some(room) => room,
+        None => {
+            let reason = "Room calls need the message's room".to_string();
+            return reject(state, my_id, envelope, reason).await;
+        }
+    };
+
+    if action != CallAction::Join {
+        if !left_room(state, room, my_id).await {
+            reject(state, my_id, envelope, format!("You are not in the call in {}", room)).await;
+        }
+        return;
+    }
+    let members = rooms::members(&state.rooms, room).await.unwrap_or_default();
+    if !members.iter().any(|m| m == my_id) {
+        return reject(state, my_id, envelope, format!("You are not in {}", room)).await;
+    }
+    let joined = state.calls.lock().await.join_mesh(my_id, room);
+    match joined {
+        Ok(mesh) => {
+            info!("{} joined the call in {} ({} people)", my_id, room, mesh.participants.len());
+            announce(state, &mesh, my_id, true).await;
+        }
+        Err(reason) => reject(state, my_id, envelope, reason).await,
+    }
+}
+
+/// Takes the user out of the room's call.  Returns false if they weren't in it.
+pub async fn left_room(state: &ChatState, room: &str, user: &str) -> bool {
+    let left = {
+        let mut calls = state.calls.lock().await;
+        if calls.meshes.room_of(user) != Some(room) {
+            return false;
+        }
+        calls.meshes.leave(user)
+    };
+    if let Some(mesh) = left {
+        info!("{} left the call in {}", user, room);
+        announce(state, &mesh, user, false).await;
+    }
+    true
+}
+
+/// Takes a user who disconnected out of whatever room's call they are in
+pub async fn leave(state: &ChatState, user: &str) {
+    let left = state.calls.lock().await.meshes.leave(user);
+    if let Some(mesh) = left {
+        announce(state, &mesh, user, false).await;
+    }
+}
+
+/// Checks a signaling command for somebody in a room's call, then relays it to them
+pub async fn handle(state: &ChatState, my_id: &str, envelope: &Envelope, room: &str) {
+    let request = match &envelope.event {
+        Event::CommandRequest(request) => request,
+        _ => return,
+    };
+    let checked = async {
+        let signal = check_request(request)?;
+        let to = recipient(state, envelope).await?;
+        state.calls.lock().await.meshes.signal(room, my_id, &to, signal)
+    };
+    match checked.await {
+        Ok(id) => forward(state, my_id, envelope, request, &id).await,
+        Err(reason) => reject(state, my_id, envelope, reason).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::user_text,
+                commands::Dispatcher,
+                filter::FilterChain,
+                state::UserInfo,
+                wire::{self,
+                       Request,
+                       SessionDescription}};
+    use tokio::sync::mpsc::{self,
+                            UnboundedReceiver};
+    use warp::ws::Message;
+
+    #[test]
+    fn test_meshes() {
+        let mut meshes = Meshes::new(3);
+        let first = meshes.join("stoner", "dnd").unwrap();
+        assert!(meshes.join("stoner", "dnd").is_err());
+        assert!(meshes.join("stoner", "lobby").is_err());
+        meshes.join("whammo", "dnd").unwrap();
+        let mesh = meshes.join("rubik", "dnd").unwrap();
+        assert_eq!(mesh.id, first.id);
+        assert_eq!(mesh.participants, vec!["stoner", "whammo", "rubik"]);
+        assert_eq!(mesh.event("rubik", true, "rubik").connect_to, vec!["stoner", "whammo"]);
+        assert!(mesh.event("rubik", true, "stoner").connect_to.is_empty());
+        assert_eq!(meshes.join("ken", "dnd").unwrap_err(), "The call in dnd is full (3 people)");
+        assert!(meshes.join("ken", "shadowrun").is_ok());
+
+        // Pairwise signaling only between people in the call
+        assert!(meshes.signal("dnd", "rubik", "stoner", Signal::Offer).is_ok());
+        assert!(meshes.signal("dnd", "rubik", "ken", Signal::Offer).is_err());
+        assert!(meshes.signal("dnd", "ken", "rubik", Signal::Candidate).is_err());
+        assert!(meshes.signal("dnd", "rubik", "rubik", Signal::Candidate).is_err());
+        assert!(meshes.signal("dnd", "whammo", "rubik", Signal::Answer).is_err());
+        assert!(meshes.signal("dnd", "stoner", "rubik", Signal::Answer).is_ok());
+        assert!(meshes.signal("dnd", "stoner", "rubik", Signal::Answer).is_err());
+
+        // Leaving drops the offers that involve the leaver
+        meshes.signal("dnd", "rubik", "whammo", Signal::Offer).unwrap();
+        let left = meshes.leave("rubik").unwrap();
+        assert_eq!(left.participants, vec!["stoner", "whammo"]);
+        assert!(left.offers.is_empty());
+        assert!(meshes.room_of("rubik").is_none());
+        assert!(meshes.join("ken", "dnd").is_err());
+        meshes.leave("stoner");
+        meshes.leave("whammo");
+        assert!(meshes.get("dnd").is_none());
+        assert!(meshes.leave("whammo").is_none());
+    }
+
+    type Peers = HashMap<&'static str, UnboundedReceiver<Result<Message, warp::Error>>>;
+
+    /// The next `Mesh` event for a peer
+    fn next_mesh(peers: &mut Peers, name: &str) -> Option<MeshEvent> {
+        let msg = peers.get_mut(name).unwrap().try_recv().ok()?.unwrap();
+        match wire::decode(msg.to_str().unwrap()).unwrap().event {
+            Event::Mesh(event) => Some(event),
+            other => panic!("Expected a Mesh event for {}, got {:?}", name, other),
+        }
+    }
+
+    fn command(from: &str, to: Vec<String>, request: Request) -> String {
+        let mut envelope = Envelope::new(from.into(), to, Event::CommandRequest(request));
+        envelope.room = Some("dnd".into());
+        serde_json::to_string(&envelope).unwrap()
+    }
+
+    fn join(from: &str) -> String {
+        command(from, vec![], Request::Call {
+            id: "".into(),
+            action: CallAction::Join,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_room_call() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        state.calls.lock().await.meshes.max_participants = 3;
+        let mut peers = HashMap::new();
+        for name in &["stoner", "whammo", "rubik", "ken"] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            state.users.lock().await.insert(name.to_string(), UserInfo::new(Some(tx)));
+            rooms::join(&state.rooms, "dnd", name).await;
+            peers.insert(*name, rx);
+        }
+
+        user_text("stoner".into(), &join("stoner"), &state).await;
+        assert!(next_mesh(&mut peers, "stoner").unwrap().connect_to.is_empty());
+        user_text("whammo".into(), &join("whammo"), &state).await;
+        assert_eq!(next_mesh(&mut peers, "whammo").unwrap().connect_to, vec!["stoner"]);
+        assert_eq!(next_mesh(&mut peers, "stoner").unwrap().user, "whammo");
+        user_text("rubik".into(), &join("rubik"), &state).await;
+        let joined = next_mesh(&mut peers, "stoner").unwrap();
+        assert_eq!((joined.user.as_str(), joined.participants.len()), ("rubik", 3));
+        assert!(joined.connect_to.is_empty());
+        assert_eq!(next_mesh(&mut peers, "whammo"), Some(joined));
+        assert_eq!(next_mesh(&mut peers, "rubik").unwrap().connect_to, vec!["stoner", "whammo"]);
+
+        // The call is full
+        user_text("ken".into(), &join("ken"), &state).await;
+        let msg = peers.get_mut("ken").unwrap().try_recv().unwrap().unwrap();
+        let rejected = wire::decode(msg.to_str().unwrap()).unwrap();
+        assert!(matches!(rejected.event, Event::Rejected(r) if r.reason.contains("full")));
+
+        // rubik offers to whammo, and only whammo gets it
+        let offer = Request::SDPOffer {
+            id: "".into(),
+            description: SessionDescription {
+                kind: "offer".into(),
+                sdp: "v=0 rubik".into(),
+            },
+        };
+        user_text("rubik".into(), &command("rubik", vec!["whammo".into()], offer), &state).await;
+        let msg = peers.get_mut("whammo").unwrap().try_recv().unwrap().unwrap();
+        let got = wire::decode(msg.to_str().unwrap()).unwrap();
+        let id = state.calls.lock().await.meshes.get("dnd").unwrap().id.clone();
+        assert!(matches!(got.event, Event::CommandRequest(Request::SDPOffer { id: ref i, .. })
+                         if *i == id));
+        assert!(next_mesh(&mut peers, "stoner").is_none());
+
+        // Leaving the room leaves the call
+        let leave = Request::Slash {
+            id: "1".into(),
+            line: "/leave dnd".into(),
+        };
+        user_text("whammo".into(), &command("whammo", vec![], leave), &state).await;
+        let left = next_mesh(&mut peers, "stoner").unwrap();
+        assert_eq!((left.user.as_str(), left.joined), ("whammo", false));
+        assert_eq!(left.participants, vec!["stoner", "rubik"]);
+        assert_eq!(next_mesh(&mut peers, "rubik"), Some(left.clone()));
+        assert_eq!(next_mesh(&mut peers, "whammo"), Some(left));
+
+        crate::signaling::leave(&state, "stoner").await;
+        crate::signaling::leave(&state, "rubik").await;
+        assert!(state.calls.lock().await.meshes.get("dnd").is_none());
+    }
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize,
             Serialize};
 use chrono::{Utc};
-use std::{fmt::{self, Display, Formatter},
+use std::{collections::HashMap,
+          fmt::{self, Display, Formatter},
           convert::{From}};
 
 /// Message that is sent to/from websocket
@@ -9,7 +10,7 @@ use std::{fmt::{self, Display, Formatter},
 /// {
 ///   sender: "stoner",
 ///   recipients: [
-/// 	  "whammo"
+///     "whammo"
 ///   ],
 ///   body: "How are you doing?",
 ///   event_type: MessageEvent::Message
@@ -18,7 +19,7 @@ use std::{fmt::{self, Display, Formatter},
 /// {
 ///   sender: "stoner",
 ///   recipients: [
-/// 	  "rubik", "whammo"
+///     "rubik", "whammo"
 ///   ],
 ///   body: {
 ///     ...
@@ -31,7 +32,24 @@ pub struct Message<T> {
     pub recipients: Vec<String>,
     pub body: T,
     pub event_type: MessageEvent,
-    pub time: i64
+    pub time: i64,
+    /// The room the message belongs to.  If this is not set, the message belongs to the lobby
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Set by khadga on every message sent by a bot account
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    /// Set by the client when the body is ciphertext for the recipients' devices (see `e2e`).
+    /// khadga relays these as they are, and only for direct messages.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    /// The direct message conversation this belongs to (see `conversations`).  khadga fills this in
+    /// for one to one messages, and the client sets it to post to a group conversation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<i32>,
+    /// Extra information added by the server side filters (see `filter::FilterChain`)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>
 }
 
 impl<T> Message<T> {
@@ -41,17 +59,28 @@ impl<T> Message<T> {
             recipients,
             body,
             event_type: evt_type,
-            time: Utc::now().timestamp_millis()
+            time: Utc::now().timestamp_millis(),
+            room: None,
+            bot: false,
+            encrypted: false,
+            conversation: None,
+            annotations: HashMap::new()
         }
     }
 
     pub fn from<R>(&self, body: R) -> Message<R> {
-        Message::new(
+        let mut msg = Message::new(
             self.sender.clone(),
             self.recipients.clone(),
             self.event_type.clone(),
             body,
-        )
+        );
+        msg.room = self.room.clone();
+        msg.bot = self.bot;
+        msg.encrypted = self.encrypted;
+        msg.conversation = self.conversation;
+        msg.annotations = self.annotations.clone();
+        msg
     }
 }
 
@@ -63,17 +92,37 @@ pub enum MessageEvent {
     CommandReply,
     Message,
     Data,
+    Rejected,
+    /// Something the admins should know about, eg a term that started trending
+    Alert,
+    /// Sent privately to someone whose question looks like one that was already answered
+    Suggestion,
+    /// The first thing a client sends: its protocol version and capabilities (see `protocol`)
+    Hello,
+    /// khadga's answer to a `Hello`, with what was negotiated
+    Welcome,
+    /// A call changed state, sent to both people in it (see `signaling`)
+    Call,
+    /// Somebody joined or left a room's call, sent to everybody in it (see `mesh`)
+    Mesh,
 }
 
 impl Display for MessageEvent {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         match self {
-            MessageEvent::Connect => write!(fmt, "{}", "Connect"),
-            MessageEvent::Disconnect => write!(fmt, "{}", "Disconnect"),
-            MessageEvent::CommandRequest => write!(fmt, "{}", "CommandRequest"),
-            MessageEvent::CommandReply => write!(fmt, "{}", "CommandReply"),
-            MessageEvent::Message => write!(fmt, "{}", "Message"),
-            MessageEvent::Data => write!(fmt, "{}", "Data")
+            MessageEvent::Connect => write!(fmt, "Connect"),
+            MessageEvent::Disconnect => write!(fmt, "Disconnect"),
+            MessageEvent::CommandRequest => write!(fmt, "CommandRequest"),
+            MessageEvent::CommandReply => write!(fmt, "CommandReply"),
+            MessageEvent::Message => write!(fmt, "Message"),
+            MessageEvent::Data => write!(fmt, "Data"),
+            MessageEvent::Rejected => write!(fmt, "Rejected"),
+            MessageEvent::Alert => write!(fmt, "Alert"),
+            MessageEvent::Suggestion => write!(fmt, "Suggestion"),
+            MessageEvent::Hello => write!(fmt, "Hello"),
+            MessageEvent::Welcome => write!(fmt, "Welcome"),
+            MessageEvent::Call => write!(fmt, "Call"),
+            MessageEvent::Mesh => write!(fmt, "Mesh")
         }
     }
 }
@@ -86,7 +135,14 @@ impl From<MessageEvent> for String {
             MessageEvent::CommandRequest => "CommandRequest".into(),
             MessageEvent::CommandReply => "CommandReply".into(),
             MessageEvent::Message => "Message".into(),
-            MessageEvent::Data => "Data".into()
+            MessageEvent::Data => "Data".into(),
+            MessageEvent::Rejected => "Rejected".into(),
+            MessageEvent::Alert => "Alert".into(),
+            MessageEvent::Suggestion => "Suggestion".into(),
+            MessageEvent::Hello => "Hello".into(),
+            MessageEvent::Welcome => "Welcome".into(),
+            MessageEvent::Call => "Call".into(),
+            MessageEvent::Mesh => "Mesh".into()
         }
     }
 }
@@ -100,6 +156,13 @@ impl From<&str> for MessageEvent {
             "CommandReply" => MessageEvent::CommandReply,
             "Message" => MessageEvent::Message,
             "Data" => MessageEvent::Data,
+            "Rejected" => MessageEvent::Rejected,
+            "Alert" => MessageEvent::Alert,
+            "Suggestion" => MessageEvent::Suggestion,
+            "Hello" => MessageEvent::Hello,
+            "Welcome" => MessageEvent::Welcome,
+            "Call" => MessageEvent::Call,
+            "Mesh" => MessageEvent::Mesh,
             _ => panic!("")
         }
     }
@@ -114,12 +177,19 @@ impl From<String> for MessageEvent {
             "CommandReply" => MessageEvent::CommandReply,
             "Message" => MessageEvent::Message,
             "Data" => MessageEvent::Data,
+            "Rejected" => MessageEvent::Rejected,
+            "Alert" => MessageEvent::Alert,
+            "Suggestion" => MessageEvent::Suggestion,
+            "Hello" => MessageEvent::Hello,
+            "Welcome" => MessageEvent::Welcome,
+            "Call" => MessageEvent::Call,
+            "Mesh" => MessageEvent::Mesh,
             _ => panic!("")
         }
     }
 } 
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ConnectionMsg {
     pub connected_users: Vec<String>,
 }
@@ -139,6 +209,11 @@ pub enum CommandTypes {
     SDPOffer,
     SDPAnswer,
     IceCandidate,
+    /// A slash command (eg `/who`) for khadga to run.  The args are the command line
+    Slash,
+    /// Invites someone to a call, or accepts, rejects or hangs up one.  The args are the
+    /// `CallAction`
+    Call,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1,2 +1,3 @@
+#[allow(clippy::module_inception)]
 pub mod pgdb;
 pub mod models;
\ No newline at end of file
@@ -1,4 +1,7 @@
 use chrono::{DateTime, Utc};
+use serde::{Deserialize,
+            Serialize};
+use std::collections::HashMap;
 
 pub struct User {
     pub user_id: i32,
@@ -8,6 +11,230 @@ pub struct User {
     pub email: String,
 }
 
+/// Who a user is, and whether they agreed to their messages being used as training data
+#[derive(Clone, Debug)]
+pub struct UserConsent {
+    pub username: String,
+    pub email: String,
+    pub consent: bool,
+}
+
+/// A bot account.  Only a hash of the API token is stored, the token itself is shown once
+#[derive(Clone, Debug)]
+pub struct Bot {
+    pub bot_id: i32,
+    pub name: String,
+    pub owner: String,
+    pub token_hash: String,
+    /// Rooms the bot joins as soon as it connects
+    pub rooms: Vec<String>,
+    pub created_on: DateTime<Utc>,
+}
+
+/// An outgoing webhook.  The secret is used to sign every request we send to the url.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub webhook_id: i32,
+    pub url: String,
+    /// None means every room
+    pub room: Option<String>,
+    /// Names of the `MessageEvent`s that are sent
+    pub events: Vec<String>,
+    /// Whether it also gets direct messages, which have no room
+    pub direct_messages: bool,
+    pub secret: String,
+    pub created_by: String,
+    pub created_on: DateTime<Utc>,
+}
+
+/// One attempt at delivering an event to a webhook
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub webhook_id: i32,
+    pub delivery: String,
+    pub event: String,
+    pub attempt: i32,
+    /// The HTTP status, if we got a response at all
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    pub sent_on: DateTime<Utc>,
+}
+
+/// An event that could not be delivered after all the attempts
+#[derive(Clone, Debug, Serialize)]
+pub struct DeadLetter {
+    pub webhook_id: i32,
+    pub delivery: String,
+    pub event: String,
+    pub payload: String,
+    pub last_error: String,
+    pub attempts: i32,
+    pub failed_on: DateTime<Utc>,
+}
+
+/// A saved chat message
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatMessage {
+    pub message_id: i64,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// Everybody it was sent to, with the addresses in `recipients` expanded (see `groups`)
+    pub delivered_to: Vec<String>,
+    /// None for the lobby and for direct messages
+    pub room: Option<String>,
+    pub body: String,
+    pub bot: bool,
+    /// The body is ciphertext (see `e2e`)
+    pub encrypted: bool,
+    pub annotations: HashMap<String, String>,
+    pub sent_on: DateTime<Utc>,
+    /// The direct message conversation, if it is in one (see `conversations`)
+    pub conversation_id: Option<i32>,
+}
+
+/// What to look for in the saved messages (see `pgdb::search_messages`)
+#[derive(Clone, Debug, Default)]
+pub struct MessageQuery {
+    /// Search terms, in the websearch syntax (eg `"exact phrase" -excluded or other`)
+    pub text: String,
+    /// Only messages in this room
+    pub room: Option<String>,
+    /// Only direct messages to or from this user
+    pub with: Option<String>,
+    pub sender: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+/// Which messages to export (see `pgdb::stream_messages`).  One of `room` or `dm` should be set.
+#[derive(Clone, Debug, Default)]
+pub struct ExportQuery {
+    /// Messages without a room count as being in the lobby
+    pub room: Option<String>,
+    /// The direct messages between these two users
+    pub dm: Option<(String, String)>,
+    /// If set, only messages this user sent or received
+    pub member: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// A message that matched a search, with the matching words wrapped in `<mark>` in the snippet
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchHit {
+    pub message: ChatMessage,
+    pub snippet: String,
+    pub rank: f32,
+}
+
+/// A token that lets another tool post into a room as `name`
+#[derive(Clone, Debug)]
+pub struct IncomingWebhook {
+    pub hook_id: i32,
+    pub name: String,
+    pub room: String,
+    pub token_hash: String,
+    pub created_by: String,
+    pub created_on: DateTime<Utc>,
+}
+
+/// An uploaded file.  The file itself is kept under `retention.upload_dir`.
+#[derive(Clone, Debug)]
+pub struct Upload {
+    pub upload_id: i32,
+    pub last_upload_date: DateTime<Utc>,
+    pub file_name: String,
+    pub user_id: i32,
+}
+
+/// Keeps a conversation from ever being purged (see `retention`)
+///
+/// Either `room` is set, or `members` has the two people whose direct messages are held.
+#[derive(Clone, Debug, Serialize)]
+pub struct LegalHold {
+    pub hold_id: i32,
+    pub room: Option<String>,
+    pub members: Vec<String>,
+    /// A conversation (see `conversations`), held whoever is in it
+    pub conversation_id: Option<i32>,
+    pub reason: String,
+    pub created_by: String,
+    pub created_on: DateTime<Utc>,
+}
+
+/// A public key a user can log in with (see `keys`)
+#[derive(Clone, Debug, Serialize)]
+pub struct PublicKey {
+    pub key_id: i32,
+    pub username: String,
+    /// `rsa` or `ed25519`
+    pub algorithm: String,
+    /// The key as it was registered, in PEM
+    pub public_key: String,
+    pub fingerprint: String,
+    pub created_on: DateTime<Utc>,
+    pub revoked_on: Option<DateTime<Utc>>,
+}
+
+/// One of a user's devices, with the public keys others need to encrypt to it (see `e2e`)
+#[derive(Clone, Debug, Serialize)]
+pub struct Device {
+    pub device_id: i32,
+    pub username: String,
+    pub name: String,
+    pub identity_key: String,
+    pub signed_prekey: String,
+    /// The signature of the signed pre-key, made with the identity key
+    pub prekey_signature: String,
+    pub created_on: DateTime<Utc>,
+    pub updated_on: DateTime<Utc>,
+}
+
+/// A one-time pre-key.  The `key_id` is the device's own id for it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PreKey {
+    pub key_id: i32,
+    pub public_key: String,
+}
+
+/// A named group of users, that messages can be addressed to (see `groups`)
+#[derive(Clone, Debug, Serialize)]
+pub struct UserGroup {
+    pub name: String,
+    pub owner: String,
+    pub members: Vec<String>,
+    pub created_on: DateTime<Utc>,
+}
+
+/// A direct message conversation, between two people or a small group
+#[derive(Clone, Debug, Serialize)]
+pub struct Conversation {
+    pub conversation_id: i32,
+    /// Sorted
+    pub members: Vec<String>,
+    /// A one to one conversation.  There is only ever one of these for each pair of people.
+    pub direct: bool,
+    pub created_by: String,
+    pub created_on: DateTime<Utc>,
+    pub last_activity: DateTime<Utc>,
+}
+
+/// A call that is over (see `signaling`)
+#[derive(Clone, Debug, Serialize)]
+pub struct CallRecord {
+    pub call_id: String,
+    pub caller: String,
+    pub callee: String,
+    /// How it ended: Rejected, Busy, Missed or Ended
+    pub state: String,
+    pub started: DateTime<Utc>,
+    pub accepted: Option<DateTime<Utc>>,
+    pub ended: DateTime<Utc>,
+    /// Seconds from when it was accepted to when it ended.  0 if it never was accepted.
+    pub duration: i64,
+}
+
 pub struct Post {
     pub post_id: i32,
     pub author_id: i32,
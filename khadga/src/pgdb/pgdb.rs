@@ -3,9 +3,11 @@
 use dotenv::dotenv;
 use std::env;
 use tokio_postgres::{
-    Connection, NoTls, Error, Socket, Client,
-    tls::{NoTlsStream}
+    Connection, NoTls, Error, Socket, Client, Row, RowStream,
+    tls::{NoTlsStream},
+    types::{Json, ToSql}
 };
+use std::sync::Arc;
 use tokio::{fs::{File},
             io::{AsyncReadExt}};
 use chrono::{Utc, DateTime};
@@ -14,6 +16,8 @@ use crate::pgdb::{models};
 
 pub type DbConnection = Connection<Socket, NoTlsStream>;
 pub type ConnectReturn = (Client, DbConnection);
+/// A client that can be shared by all the tasks that need the database
+pub type DbClient = Arc<Client>;
 
 
 /// Creates a connection to our postgres database
@@ -22,8 +26,8 @@ pub async fn establish_connection(dbname: &str) -> Result<ConnectReturn, Error>
 
     // If we're running under docker stack, we need to look for the secrets file
     // If we're running under kubernetes, we need to look at kubernetes secrets
-    match env::var("KHADGA_STACK") {
-        Ok(val) => if val.to_lowercase() == "true" { 
+    if let Ok(val) = env::var("KHADGA_STACK") {
+        if val.to_lowercase() == "true" {
             // Read the /run/secrets/postgres-secret file
             match File::open("/run/secrets/postgres-secret").await {
                 Ok(mut f) => {
@@ -37,9 +41,7 @@ pub async fn establish_connection(dbname: &str) -> Result<ConnectReturn, Error>
                     error!("Error while getting khadga database: {}", e)
                 }
             }
-            
-        },
-        Err(_) => { }
+        }
     };
 
     let config = format!(
@@ -57,15 +59,29 @@ pub async fn establish_connection(dbname: &str) -> Result<ConnectReturn, Error>
     Ok((client, connection))
 }
 
+/// Connects to the database and spawns the connection off to run on its own
+///
+/// This is what long running parts of khadga use.  The returned client can be shared, and it keeps
+/// working for as long as the connection task does.
+pub async fn connect(dbname: &str) -> Result<DbClient, Error> {
+    let (client, connection) = establish_connection(dbname).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("database connection error: {}", e);
+        }
+    });
+    Ok(Arc::new(client))
+}
+
 pub async fn drop_table(
     table: &str,
     client: &Client,
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     DROP TABLE IF EXISTS {};
     ", table)).await?;
 
-    Ok(res)
+    Ok(())
 }
 
 pub async fn make_table_posts(
@@ -73,7 +89,7 @@ pub async fn make_table_posts(
     refers_to: &str,
     client: &Client
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     CREATE TABLE {} (
         post_id SERIAL PRIMARY KEY,
         author_id INTEGER NOT NULL,
@@ -84,23 +100,38 @@ pub async fn make_table_posts(
         FOREIGN KEY (author_id) REFERENCES {}(user_id)
     )", table, refers_to)).await?;
 
-    Ok(res)
+    Ok(())
 }
 
 pub async fn make_table_users(
     table: &str,
     client: &Client
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     CREATE TABLE {} (
         user_id SERIAL PRIMARY KEY,
         first_name VARCHAR NOT NULL,
         last_name VARCHAR NOT NULL,
         username VARCHAR NOT NULL,
-        email VARCHAR NOT NULL
+        email VARCHAR NOT NULL,
+        consent BOOLEAN NOT NULL DEFAULT 'f',
+        consent_updated TIMESTAMPTZ
     )", table)).await?;
 
-    Ok(res)
+    Ok(())
+}
+
+/// Adds the consent columns to a users table made before they existed
+pub async fn add_consent_columns(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    ALTER TABLE {} ADD COLUMN IF NOT EXISTS consent BOOLEAN NOT NULL DEFAULT 'f',
+        ADD COLUMN IF NOT EXISTS consent_updated TIMESTAMPTZ
+    ", table)).await?;
+
+    Ok(())
 }
 
 pub async fn make_table_comments(
@@ -108,7 +139,7 @@ pub async fn make_table_comments(
     refers_to: &str,
     client: &Client
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     CREATE TABLE {} (
         comment_id SERIAL PRIMARY KEY,
         body TEXT NOT NULL,
@@ -121,7 +152,7 @@ pub async fn make_table_comments(
         FOREIGN KEY (ref_comment_id) REFERENCES {}(comment_id)
     )", table, refers_to)).await?;
 
-    Ok(res)
+    Ok(())
 }
 
 pub async fn make_table_accounts(
@@ -129,7 +160,7 @@ pub async fn make_table_accounts(
     refers_to: &str,
     client: &Client
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     CREATE TABLE {} (
         account_id SERIAL PRIMARY KEY,
         user_id INTEGER NOT NULL,
@@ -140,7 +171,7 @@ pub async fn make_table_accounts(
         FOREIGN KEY (user_id) REFERENCES {}(user_id) ON DELETE CASCADE
     )", table, refers_to)).await?;
 
-    Ok(res)
+    Ok(())
 }
 
 pub async fn make_table_uploads(
@@ -148,7 +179,7 @@ pub async fn make_table_uploads(
     refers_to: &str,
     client: &Client
 ) -> Result<(), Error> {
-    let res = client.batch_execute(&format!("
+    client.batch_execute(&format!("
     CREATE TABLE {} (
         upload_id SERIAL PRIMARY KEY,
         last_upload_date TIMESTAMPTZ NOT NULL,
@@ -157,7 +188,338 @@ pub async fn make_table_uploads(
         FOREIGN KEY (user_id) REFERENCES {}(user_id)
     )", table, refers_to)).await?;
 
-    Ok(res)
+    Ok(())
+}
+
+pub async fn make_table_bots(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        bot_id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL UNIQUE,
+        owner VARCHAR NOT NULL,
+        token_hash VARCHAR NOT NULL UNIQUE,
+        rooms TEXT[] NOT NULL DEFAULT '{{}}',
+        created_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_webhooks(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        webhook_id SERIAL PRIMARY KEY,
+        url VARCHAR NOT NULL,
+        room VARCHAR,
+        events TEXT[] NOT NULL,
+        direct_messages BOOLEAN NOT NULL DEFAULT 'f',
+        secret VARCHAR NOT NULL,
+        created_by VARCHAR NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
+}
+
+/// Adds the `direct_messages` column to a webhooks table made before webhooks could opt into them
+pub async fn add_direct_messages_column(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    ALTER TABLE {} ADD COLUMN IF NOT EXISTS direct_messages BOOLEAN NOT NULL DEFAULT 'f'
+    ", table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_webhook_deliveries(
+    table: &str,
+    webhooks: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        attempt_id SERIAL PRIMARY KEY,
+        webhook_id INTEGER NOT NULL,
+        delivery VARCHAR NOT NULL,
+        event VARCHAR NOT NULL,
+        attempt INTEGER NOT NULL,
+        status INTEGER,
+        error TEXT,
+        sent_on TIMESTAMPTZ NOT NULL,
+        FOREIGN KEY (webhook_id) REFERENCES {}(webhook_id) ON DELETE CASCADE
+    )", table, webhooks)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_dead_letters(
+    table: &str,
+    webhooks: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        letter_id SERIAL PRIMARY KEY,
+        webhook_id INTEGER NOT NULL,
+        delivery VARCHAR NOT NULL,
+        event VARCHAR NOT NULL,
+        payload TEXT NOT NULL,
+        last_error TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        failed_on TIMESTAMPTZ NOT NULL,
+        FOREIGN KEY (webhook_id) REFERENCES {}(webhook_id) ON DELETE CASCADE
+    )", table, webhooks)).await?;
+
+    Ok(())
+}
+
+/// The document that message searches run over.  The body is HTML escaped first, so the snippets
+/// are safe to show, and so that text like `<dice>` isn't skipped as a tag.
+const SEARCH_DOCUMENT: &str =
+    "to_tsvector('english', replace(replace(replace(body, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'))";
+
+pub async fn make_table_messages(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {table} (
+        message_id BIGSERIAL PRIMARY KEY,
+        sender VARCHAR NOT NULL,
+        recipients TEXT[] NOT NULL,
+        delivered_to TEXT[] NOT NULL DEFAULT '{{}}',
+        room VARCHAR,
+        body TEXT NOT NULL,
+        bot BOOLEAN NOT NULL DEFAULT 'f',
+        encrypted BOOLEAN NOT NULL DEFAULT 'f',
+        annotations JSONB NOT NULL DEFAULT '{{}}',
+        sent_on TIMESTAMPTZ NOT NULL,
+        conversation_id INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS {table}_body_fts ON {table} USING GIN (({document}));
+    CREATE INDEX IF NOT EXISTS {table}_conversation ON {table} (conversation_id, message_id);
+    ", table=table, document=SEARCH_DOCUMENT)).await?;
+
+    Ok(())
+}
+
+/// Adds the `encrypted` column to a messages table made before encrypted messages existed
+pub async fn add_encrypted_column(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    ALTER TABLE {} ADD COLUMN IF NOT EXISTS encrypted BOOLEAN NOT NULL DEFAULT 'f'
+    ", table)).await?;
+
+    Ok(())
+}
+
+/// Adds the `delivered_to` column to a messages table made before it existed
+///
+/// The messages already saved get their recipients, which is who they went to unless they were
+/// sent to an address.
+pub async fn add_delivered_to_column(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    DO $$
+    BEGIN
+        IF NOT EXISTS (SELECT 1 FROM information_schema.columns
+                       WHERE table_name = '{table}' AND column_name = 'delivered_to') THEN
+            ALTER TABLE {table} ADD COLUMN delivered_to TEXT[] NOT NULL DEFAULT '{{}}';
+            UPDATE {table} SET delivered_to = recipients;
+        END IF;
+    END
+    $$;
+    ", table=table)).await?;
+
+    Ok(())
+}
+
+/// Adds the `conversation_id` column to a messages table made before conversations existed
+pub async fn add_conversation_column(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    ALTER TABLE {table} ADD COLUMN IF NOT EXISTS conversation_id INTEGER;
+    CREATE INDEX IF NOT EXISTS {table}_conversation ON {table} (conversation_id, message_id);
+    ", table=table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_conversations(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {table} (
+        conversation_id SERIAL PRIMARY KEY,
+        members TEXT[] NOT NULL,
+        direct BOOLEAN NOT NULL,
+        created_by VARCHAR NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL,
+        last_activity TIMESTAMPTZ NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS {table}_direct ON {table} (members) WHERE direct;
+    CREATE INDEX IF NOT EXISTS {table}_members ON {table} USING GIN (members);
+    ", table=table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_calls(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {table} (
+        call_id VARCHAR PRIMARY KEY,
+        caller VARCHAR NOT NULL,
+        callee VARCHAR NOT NULL,
+        state VARCHAR NOT NULL,
+        started TIMESTAMPTZ NOT NULL,
+        accepted TIMESTAMPTZ,
+        ended TIMESTAMPTZ NOT NULL,
+        duration BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS {table}_caller ON {table} (caller, ended);
+    CREATE INDEX IF NOT EXISTS {table}_callee ON {table} (callee, ended);
+    ", table=table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_incoming_webhooks(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        hook_id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        room VARCHAR NOT NULL,
+        token_hash VARCHAR NOT NULL UNIQUE,
+        created_by VARCHAR NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_legal_holds(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        hold_id SERIAL PRIMARY KEY,
+        room VARCHAR,
+        members TEXT[] NOT NULL DEFAULT '{{}}',
+        conversation_id INTEGER,
+        reason TEXT NOT NULL,
+        created_by VARCHAR NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
+}
+
+/// Adds the `conversation_id` column to a legal holds table made before it held conversations
+pub async fn add_hold_conversation_column(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    ALTER TABLE {} ADD COLUMN IF NOT EXISTS conversation_id INTEGER
+    ", table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_public_keys(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        key_id SERIAL PRIMARY KEY,
+        username VARCHAR NOT NULL,
+        algorithm VARCHAR NOT NULL,
+        public_key TEXT NOT NULL,
+        fingerprint VARCHAR NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL,
+        revoked_on TIMESTAMPTZ,
+        CHECK (algorithm in ('rsa', 'ed25519'))
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS {}_active_fingerprint ON {} (fingerprint)
+        WHERE revoked_on IS NULL;
+    ", table, table, table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_devices(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        device_id SERIAL PRIMARY KEY,
+        username VARCHAR NOT NULL,
+        name VARCHAR NOT NULL,
+        identity_key TEXT NOT NULL,
+        signed_prekey TEXT NOT NULL,
+        prekey_signature TEXT NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL,
+        updated_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_prekeys(
+    table: &str,
+    devices: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        prekey_id SERIAL PRIMARY KEY,
+        device_id INTEGER NOT NULL,
+        key_id INTEGER NOT NULL,
+        public_key TEXT NOT NULL,
+        UNIQUE (device_id, key_id),
+        FOREIGN KEY (device_id) REFERENCES {}(device_id) ON DELETE CASCADE
+    )", table, devices)).await?;
+
+    Ok(())
+}
+
+pub async fn make_table_user_groups(
+    table: &str,
+    client: &Client
+) -> Result<(), Error> {
+    client.batch_execute(&format!("
+    CREATE TABLE IF NOT EXISTS {} (
+        name VARCHAR PRIMARY KEY,
+        owner VARCHAR NOT NULL,
+        members TEXT[] NOT NULL,
+        created_on TIMESTAMPTZ NOT NULL
+    )", table)).await?;
+
+    Ok(())
 }
 
 pub fn make_now() -> DateTime<Utc> {
@@ -205,98 +567,1021 @@ pub async fn lookup_user(
     Ok(userids)
 }
 
-/**
- * Inserts a post into the given table 
- */
-pub async fn insert_post(
+/// Finds a user by their username
+pub async fn find_user(
     client: &Client,
     table: &str,
-    title: &str,
-    body: &str,
-    author_id: i32
+    username: &str
+) -> Result<Option<models::User>, Error> {
+    let cmd = format!("
+    SELECT user_id, first_name, last_name, username, email FROM {}
+    WHERE username = $1;
+    ", table);
+    let row = client.query_opt(cmd.as_str(), &[&username]).await?;
+
+    Ok(row.map(|row| models::User {
+        user_id: row.get("user_id"),
+        first_name: row.get("first_name"),
+        last_name: row.get("last_name"),
+        username: row.get("username"),
+        email: row.get("email"),
+    }))
+}
+
+/// Records whether the user agrees to their messages being used as training data
+pub async fn set_consent(
+    client: &Client,
+    table: &str,
+    username: &str,
+    consent: bool
 ) -> Result<u64, Error> {
-    let now = make_now();
-    println!("Inserting post with time {}", now);
+    let cmd = format!("
+    UPDATE {} SET consent = $2, consent_updated = now() WHERE username = $1;
+    ", table);
+    client.execute(cmd.as_str(), &[&username, &consent]).await
+}
+
+/// Whether the user has consented, or None if there is no such user
+pub async fn get_consent(
+    client: &Client,
+    table: &str,
+    username: &str
+) -> Result<Option<bool>, Error> {
+    let cmd = format!("SELECT consent FROM {} WHERE username = $1;", table);
+    let rows = client.query(cmd.as_str(), &[&username]).await?;
+    Ok(rows.first().map(|row| row.get(0)))
+}
+
+pub async fn list_consents(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::UserConsent>, Error> {
+    let cmd = format!("SELECT username, email, consent FROM {};", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
+
+    Ok(rows.iter().map(|row| models::UserConsent {
+        username: row.get("username"),
+        email: row.get("email"),
+        consent: row.get("consent"),
+    }).collect())
+}
 
+pub async fn insert_bot(
+    client: &Client,
+    table: &str,
+    bot: &models::Bot
+) -> Result<i32, Error> {
     let cmd = format!("
-    INSERT INTO {} (title, body, created_on, author_id)
-    VALUES ($1, $2, $3, $4);
+    INSERT INTO {} (name, owner, token_hash, rooms, created_on)
+    VALUES ($1, $2, $3, $4, $5)
+    RETURNING bot_id;
     ", table);
-    println!("cmd is {}", cmd);
-    let rows = client.execute("
-    INSERT INTO test_posts (title, body, created_on, author_id)
-    VALUES ($1, $2, $3, $4);
-    ", &[&title, &body, &now, &author_id]).await?;
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&bot.name, &bot.owner, &bot.token_hash, &bot.rooms, &bot.created_on]
+    ).await?;
 
-    Ok(rows)
+    Ok(row.get(0))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub async fn delete_bot(
+    client: &Client,
+    table: &str,
+    name: &str
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE name = $1;", table);
+    client.execute(cmd.as_str(), &[&name]).await
+}
 
-    /**
-     * Inserts a post into the database
-     * 
-     * This should actually be an integration test, as it does and tests the following: 
-     * 
-     * - Drops the test_posts and test_users database (in that order)
-     * - Creates the test_users and test_posts database (in that order)
-     * - Inserts a user into test_users
-     * - Inserts a post into test_posts 
-     */
-    #[tokio::test]
-    async fn test_insert_post() -> Result<(), Error> {
-        let (client, connection) = establish_connection("test_db").await?;
-        let db_posts = "test_posts";
-        let db_users = "test_users";
+/// True if a bot is registered under the name
+pub async fn bot_exists(
+    client: &Client,
+    table: &str,
+    name: &str
+) -> Result<bool, Error> {
+    let cmd = format!("SELECT EXISTS (SELECT 1 FROM {} WHERE name = $1);", table);
+    let row = client.query_one(cmd.as_str(), &[&name]).await?;
 
-        // The connection object performs the actual communication with the database,
-        // so spawn it off to run on its own.
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("connection error: {}", e);
-            }
-        });
+    Ok(row.get(0))
+}
 
-        drop_table(db_posts, &client).await?;
-        drop_table(db_users, &client).await?;
-        
-        make_table_users(db_users, &client).await?;
-        println!("Created test_users table");
-        make_table_posts(db_posts, db_users, &client).await?;
-        println!("Created test_posts table");
+pub async fn list_bots(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::Bot>, Error> {
+    let cmd = format!("
+    SELECT bot_id, name, owner, token_hash, rooms, created_on FROM {};
+    ", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
 
-        // Create a user first, otherwise referential integrity won't hold
-        let user = models::User {
-            user_id: -1,
-            first_name: String::from("Sean"),
-            last_name: String::from("Toner"),
-            username: String::from("stoner"),
-            email: String::from("foo@bar.com")
-        };
-        let rows = insert_user(&client, db_users, &user).await?;
-        println!("Inserted test user: {}", rows);
+    Ok(rows.iter().map(|row| models::Bot {
+        bot_id: row.get("bot_id"),
+        name: row.get("name"),
+        owner: row.get("owner"),
+        token_hash: row.get("token_hash"),
+        rooms: row.get("rooms"),
+        created_on: row.get("created_on"),
+    }).collect())
+}
 
-        // Look up user
-        let userids = lookup_user(&client, db_users, &user).await?;
-        for id in userids.into_iter() {
-            println!("user id = {}", id);
-        }
+pub async fn insert_webhook(
+    client: &Client,
+    table: &str,
+    hook: &models::Webhook
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (url, room, events, direct_messages, secret, created_by, created_on)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    RETURNING webhook_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&hook.url, &hook.room, &hook.events, &hook.direct_messages, &hook.secret,
+          &hook.created_by, &hook.created_on]
+    ).await?;
 
-        // Create a post
-        let _res = insert_post(&client,
-            db_posts,
-            "Just a test",
-            r#"
-            <html>
-                <head>
-                </head>
-                <body>
-                    Just a simple html page
-                </body>
-            </html>"#, 1).await?;
-        println!("Inserted post");
+    Ok(row.get(0))
+}
+
+pub async fn delete_webhook(
+    client: &Client,
+    table: &str,
+    webhook_id: i32
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE webhook_id = $1;", table);
+    client.execute(cmd.as_str(), &[&webhook_id]).await
+}
+
+pub async fn list_webhooks(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::Webhook>, Error> {
+    let cmd = format!("
+    SELECT webhook_id, url, room, events, direct_messages, secret, created_by, created_on FROM {};
+    ", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
+
+    Ok(rows.iter().map(|row| models::Webhook {
+        webhook_id: row.get("webhook_id"),
+        url: row.get("url"),
+        room: row.get("room"),
+        events: row.get("events"),
+        direct_messages: row.get("direct_messages"),
+        secret: row.get("secret"),
+        created_by: row.get("created_by"),
+        created_on: row.get("created_on"),
+    }).collect())
+}
+
+pub async fn insert_delivery(
+    client: &Client,
+    table: &str,
+    delivery: &models::WebhookDelivery
+) -> Result<u64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (webhook_id, delivery, event, attempt, status, error, sent_on)
+    VALUES ($1, $2, $3, $4, $5, $6, $7);
+    ", table);
+    client.execute(
+        cmd.as_str(),
+        &[&delivery.webhook_id, &delivery.delivery, &delivery.event, &delivery.attempt,
+          &delivery.status, &delivery.error, &delivery.sent_on]
+    ).await
+}
+
+/// The most recent delivery attempts for a webhook, newest first
+pub async fn list_deliveries(
+    client: &Client,
+    table: &str,
+    webhook_id: i32,
+    limit: i64
+) -> Result<Vec<models::WebhookDelivery>, Error> {
+    let cmd = format!("
+    SELECT webhook_id, delivery, event, attempt, status, error, sent_on FROM {}
+    WHERE webhook_id = $1
+    ORDER BY attempt_id DESC
+    LIMIT $2;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&webhook_id, &limit]).await?;
+
+    Ok(rows.iter().map(|row| models::WebhookDelivery {
+        webhook_id: row.get("webhook_id"),
+        delivery: row.get("delivery"),
+        event: row.get("event"),
+        attempt: row.get("attempt"),
+        status: row.get("status"),
+        error: row.get("error"),
+        sent_on: row.get("sent_on"),
+    }).collect())
+}
+
+pub async fn insert_dead_letter(
+    client: &Client,
+    table: &str,
+    letter: &models::DeadLetter
+) -> Result<u64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (webhook_id, delivery, event, payload, last_error, attempts, failed_on)
+    VALUES ($1, $2, $3, $4, $5, $6, $7);
+    ", table);
+    client.execute(
+        cmd.as_str(),
+        &[&letter.webhook_id, &letter.delivery, &letter.event, &letter.payload,
+          &letter.last_error, &letter.attempts, &letter.failed_on]
+    ).await
+}
+
+/// The most recent dead letters, newest first
+pub async fn list_dead_letters(
+    client: &Client,
+    table: &str,
+    limit: i64
+) -> Result<Vec<models::DeadLetter>, Error> {
+    let cmd = format!("
+    SELECT webhook_id, delivery, event, payload, last_error, attempts, failed_on FROM {}
+    ORDER BY letter_id DESC
+    LIMIT $1;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&limit]).await?;
+
+    Ok(rows.iter().map(|row| models::DeadLetter {
+        webhook_id: row.get("webhook_id"),
+        delivery: row.get("delivery"),
+        event: row.get("event"),
+        payload: row.get("payload"),
+        last_error: row.get("last_error"),
+        attempts: row.get("attempts"),
+        failed_on: row.get("failed_on"),
+    }).collect())
+}
+
+pub async fn insert_message(
+    client: &Client,
+    table: &str,
+    msg: &models::ChatMessage
+) -> Result<i64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (sender, recipients, delivered_to, room, body, bot, encrypted, annotations,
+                    sent_on, conversation_id)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+    RETURNING message_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&msg.sender, &msg.recipients, &msg.delivered_to, &msg.room, &msg.body, &msg.bot,
+          &msg.encrypted, &Json(&msg.annotations), &msg.sent_on, &msg.conversation_id]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+/// Turns a row selected with all the columns of the messages table into a `ChatMessage`
+pub fn row_to_message(row: &Row) -> models::ChatMessage {
+    let Json(annotations) = row.get("annotations");
+    models::ChatMessage {
+        message_id: row.get("message_id"),
+        sender: row.get("sender"),
+        recipients: row.get("recipients"),
+        delivered_to: row.get("delivered_to"),
+        room: row.get("room"),
+        body: row.get("body"),
+        bot: row.get("bot"),
+        encrypted: row.get("encrypted"),
+        annotations,
+        sent_on: row.get("sent_on"),
+        conversation_id: row.get("conversation_id"),
+    }
+}
+
+/// Full text search over the messages the caller sent or received
+///
+/// Encrypted messages are never matched, their bodies are only ciphertext.
+/// The snippet is HTML escaped before the matches are wrapped in `<mark>`, so it is safe to show
+/// as HTML.
+pub async fn search_messages(
+    client: &Client,
+    table: &str,
+    caller: &str,
+    query: &models::MessageQuery
+) -> Result<Vec<models::SearchHit>, Error> {
+    let cmd = format!("
+    SELECT m.*,
+        ts_headline('english',
+            replace(replace(replace(m.body, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+            q, 'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=20, MinWords=5'
+        ) AS snippet,
+        ts_rank({document}, q) AS rank
+    FROM {table} m, websearch_to_tsquery('english', $1) q
+    WHERE {document} @@ q
+        AND NOT m.encrypted
+        AND (m.sender = $2 OR $2 = ANY(m.delivered_to))
+        AND ($3::VARCHAR IS NULL OR m.room = $3)
+        AND ($4::VARCHAR IS NULL
+             OR (m.room IS NULL AND (m.sender = $4 OR $4 = ANY(m.delivered_to))))
+        AND ($5::VARCHAR IS NULL OR m.sender = $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR m.sent_on >= $6)
+        AND ($7::TIMESTAMPTZ IS NULL OR m.sent_on < $7)
+    ORDER BY rank DESC, m.sent_on DESC
+    LIMIT $8;
+    ", table=table, document=SEARCH_DOCUMENT);
+    let rows = client.query(
+        cmd.as_str(),
+        &[&query.text, &caller, &query.room, &query.with, &query.sender, &query.since,
+          &query.until, &query.limit]
+    ).await?;
+
+    Ok(rows.iter().map(|row| models::SearchHit {
+        message: row_to_message(row),
+        snippet: row.get("snippet"),
+        rank: row.get("rank"),
+    }).collect())
+}
+
+/// Streams the messages of a conversation, oldest first
+///
+/// The rows come back as they are read, so this works for conversations of any size.  The lobby is
+/// the room-less messages that aren't in a direct message conversation.
+pub async fn stream_messages(
+    client: &Client,
+    table: &str,
+    query: &models::ExportQuery
+) -> Result<RowStream, Error> {
+    let cmd = format!("
+    SELECT * FROM {}
+    WHERE ($1::VARCHAR IS NULL
+           OR room = $1
+           OR ($1 = 'lobby' AND room IS NULL AND conversation_id IS NULL))
+        AND ($2::VARCHAR IS NULL
+             OR (room IS NULL
+                 AND ((sender = $2 AND $3 = ANY(delivered_to))
+                      OR (sender = $3 AND $2 = ANY(delivered_to)))))
+        AND ($4::VARCHAR IS NULL OR sender = $4 OR $4 = ANY(delivered_to))
+        AND ($5::TIMESTAMPTZ IS NULL OR sent_on >= $5)
+        AND ($6::TIMESTAMPTZ IS NULL OR sent_on < $6)
+    ORDER BY sent_on, message_id;
+    ", table);
+    let (first, second) = match &query.dm {
+        Some((a, b)) => (Some(a.clone()), Some(b.clone())),
+        None => (None, None),
+    };
+    let params: Vec<&(dyn ToSql + Sync)> =
+        vec![&query.room, &first, &second, &query.member, &query.since, &query.until];
+    client.query_raw(cmd.as_str(), params.into_iter().map(|p| p as &dyn ToSql)).await
+}
+
+/// Streams the messages people sent after consenting to training use, oldest first
+///
+/// Messages from bots are left out, as are encrypted messages and messages from users who have
+/// since withdrawn consent.  Consenting doesn't cover what was said before, so only messages sent
+/// since the consent was last given count.
+pub async fn stream_consented_messages(
+    client: &Client,
+    messages: &str,
+    users: &str
+) -> Result<RowStream, Error> {
+    let cmd = format!("
+    SELECT m.* FROM {} m
+    WHERE NOT m.bot
+        AND NOT m.encrypted
+        AND EXISTS (SELECT 1 FROM {} u
+                    WHERE u.username = m.sender
+                        AND u.consent
+                        AND m.sent_on >= u.consent_updated)
+    ORDER BY m.sent_on, m.message_id;
+    ", messages, users);
+    let params: Vec<&dyn ToSql> = vec![];
+    client.query_raw(cmd.as_str(), params).await
+}
+
+pub async fn insert_incoming_webhook(
+    client: &Client,
+    table: &str,
+    hook: &models::IncomingWebhook
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (name, room, token_hash, created_by, created_on)
+    VALUES ($1, $2, $3, $4, $5)
+    RETURNING hook_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&hook.name, &hook.room, &hook.token_hash, &hook.created_by, &hook.created_on]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn delete_incoming_webhook(
+    client: &Client,
+    table: &str,
+    hook_id: i32
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE hook_id = $1;", table);
+    client.execute(cmd.as_str(), &[&hook_id]).await
+}
+
+pub async fn list_incoming_webhooks(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::IncomingWebhook>, Error> {
+    let cmd = format!("
+    SELECT hook_id, name, room, token_hash, created_by, created_on FROM {};
+    ", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
+
+    Ok(rows.iter().map(|row| models::IncomingWebhook {
+        hook_id: row.get("hook_id"),
+        name: row.get("name"),
+        room: row.get("room"),
+        token_hash: row.get("token_hash"),
+        created_by: row.get("created_by"),
+        created_on: row.get("created_on"),
+    }).collect())
+}
+
+/// The messages sent before the cutoff, in batches ordered by id
+///
+/// Pass the last id of the previous batch as `after` (0 for the first batch).
+pub async fn list_messages_before(
+    client: &Client,
+    table: &str,
+    cutoff: &DateTime<Utc>,
+    after: i64,
+    limit: i64
+) -> Result<Vec<models::ChatMessage>, Error> {
+    let cmd = format!("
+    SELECT * FROM {}
+    WHERE sent_on < $1 AND message_id > $2
+    ORDER BY message_id
+    LIMIT $3;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[cutoff, &after, &limit]).await?;
+
+    Ok(rows.iter().map(row_to_message).collect())
+}
+
+pub async fn delete_messages(
+    client: &Client,
+    table: &str,
+    ids: &[i64]
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE message_id = ANY($1);", table);
+    client.execute(cmd.as_str(), &[&ids]).await
+}
+
+/// The uploads last touched before the cutoff, in batches ordered by id
+pub async fn list_uploads_before(
+    client: &Client,
+    table: &str,
+    cutoff: &DateTime<Utc>,
+    after: i32,
+    limit: i64
+) -> Result<Vec<models::Upload>, Error> {
+    let cmd = format!("
+    SELECT upload_id, last_upload_date, file_name, user_id FROM {}
+    WHERE last_upload_date < $1 AND upload_id > $2
+    ORDER BY upload_id
+    LIMIT $3;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[cutoff, &after, &limit]).await?;
+
+    Ok(rows.iter().map(|row| models::Upload {
+        upload_id: row.get("upload_id"),
+        last_upload_date: row.get("last_upload_date"),
+        file_name: row.get("file_name"),
+        user_id: row.get("user_id"),
+    }).collect())
+}
+
+pub async fn delete_uploads(
+    client: &Client,
+    table: &str,
+    ids: &[i32]
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE upload_id = ANY($1);", table);
+    client.execute(cmd.as_str(), &[&ids]).await
+}
+
+pub async fn insert_legal_hold(
+    client: &Client,
+    table: &str,
+    hold: &models::LegalHold
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (room, members, conversation_id, reason, created_by, created_on)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    RETURNING hold_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&hold.room, &hold.members, &hold.conversation_id, &hold.reason, &hold.created_by,
+          &hold.created_on]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn delete_legal_hold(
+    client: &Client,
+    table: &str,
+    hold_id: i32
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE hold_id = $1;", table);
+    client.execute(cmd.as_str(), &[&hold_id]).await
+}
+
+pub async fn list_legal_holds(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::LegalHold>, Error> {
+    let cmd = format!("
+    SELECT hold_id, room, members, conversation_id, reason, created_by, created_on FROM {}
+    ORDER BY hold_id;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
+
+    Ok(rows.iter().map(|row| models::LegalHold {
+        hold_id: row.get("hold_id"),
+        room: row.get("room"),
+        members: row.get("members"),
+        conversation_id: row.get("conversation_id"),
+        reason: row.get("reason"),
+        created_by: row.get("created_by"),
+        created_on: row.get("created_on"),
+    }).collect())
+}
+
+pub async fn insert_public_key(
+    client: &Client,
+    table: &str,
+    key: &models::PublicKey
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (username, algorithm, public_key, fingerprint, created_on)
+    VALUES ($1, $2, $3, $4, $5)
+    RETURNING key_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&key.username, &key.algorithm, &key.public_key, &key.fingerprint, &key.created_on]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+/// Revokes one of the user's keys.  Returns 0 if they have no such key, or it is already revoked.
+pub async fn revoke_public_key(
+    client: &Client,
+    table: &str,
+    username: &str,
+    key_id: i32
+) -> Result<u64, Error> {
+    let cmd = format!("
+    UPDATE {} SET revoked_on = $3
+    WHERE key_id = $1 AND username = $2 AND revoked_on IS NULL;
+    ", table);
+    client.execute(cmd.as_str(), &[&key_id, &username, &make_now()]).await
+}
+
+/// The user's keys, including the revoked ones, oldest first
+pub async fn list_public_keys(
+    client: &Client,
+    table: &str,
+    username: &str
+) -> Result<Vec<models::PublicKey>, Error> {
+    let cmd = format!("
+    SELECT * FROM {} WHERE username = $1 ORDER BY key_id;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&username]).await?;
+
+    Ok(rows.iter().map(|row| models::PublicKey {
+        key_id: row.get("key_id"),
+        username: row.get("username"),
+        algorithm: row.get("algorithm"),
+        public_key: row.get("public_key"),
+        fingerprint: row.get("fingerprint"),
+        created_on: row.get("created_on"),
+        revoked_on: row.get("revoked_on"),
+    }).collect())
+}
+
+pub async fn insert_device(
+    client: &Client,
+    table: &str,
+    device: &models::Device
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (username, name, identity_key, signed_prekey, prekey_signature, created_on,
+                    updated_on)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    RETURNING device_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&device.username, &device.name, &device.identity_key, &device.signed_prekey,
+          &device.prekey_signature, &device.created_on, &device.updated_on]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+/// Replaces the signed pre-key of one of the user's devices
+pub async fn update_signed_prekey(
+    client: &Client,
+    table: &str,
+    username: &str,
+    device_id: i32,
+    signed_prekey: &str,
+    signature: &str
+) -> Result<u64, Error> {
+    let cmd = format!("
+    UPDATE {} SET signed_prekey = $3, prekey_signature = $4, updated_on = $5
+    WHERE device_id = $1 AND username = $2;
+    ", table);
+    client.execute(
+        cmd.as_str(),
+        &[&device_id, &username, &signed_prekey, &signature, &make_now()]
+    ).await
+}
+
+pub async fn delete_device(
+    client: &Client,
+    table: &str,
+    username: &str,
+    device_id: i32
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE device_id = $1 AND username = $2;", table);
+    client.execute(cmd.as_str(), &[&device_id, &username]).await
+}
+
+/// The user's devices, with how many one-time pre-keys each has left
+pub async fn list_devices(
+    client: &Client,
+    devices: &str,
+    prekeys: &str,
+    username: &str
+) -> Result<Vec<(models::Device, i64)>, Error> {
+    let cmd = format!("
+    SELECT d.*, (SELECT COUNT(*) FROM {} p WHERE p.device_id = d.device_id) AS prekeys
+    FROM {} d
+    WHERE d.username = $1
+    ORDER BY d.device_id;
+    ", prekeys, devices);
+    let rows = client.query(cmd.as_str(), &[&username]).await?;
+
+    Ok(rows.iter().map(|row| (models::Device {
+        device_id: row.get("device_id"),
+        username: row.get("username"),
+        name: row.get("name"),
+        identity_key: row.get("identity_key"),
+        signed_prekey: row.get("signed_prekey"),
+        prekey_signature: row.get("prekey_signature"),
+        created_on: row.get("created_on"),
+        updated_on: row.get("updated_on"),
+    }, row.get("prekeys"))).collect())
+}
+
+/// Adds one-time pre-keys to a device.  Keys with an id the device already has are skipped.
+pub async fn insert_prekeys(
+    client: &Client,
+    table: &str,
+    device_id: i32,
+    keys: &[models::PreKey]
+) -> Result<u64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (device_id, key_id, public_key)
+    VALUES ($1, $2, $3)
+    ON CONFLICT DO NOTHING;
+    ", table);
+    let mut added = 0;
+    for key in keys {
+        added += client.execute(cmd.as_str(), &[&device_id, &key.key_id, &key.public_key]).await?;
+    }
+    Ok(added)
+}
+
+/// Removes and returns one of the device's one-time pre-keys, oldest first
+///
+/// Each pre-key is only ever handed out once, even with several requests at the same time.
+pub async fn take_prekey(
+    client: &Client,
+    table: &str,
+    device_id: i32
+) -> Result<Option<models::PreKey>, Error> {
+    let cmd = format!("
+    DELETE FROM {table} WHERE prekey_id = (
+        SELECT prekey_id FROM {table} WHERE device_id = $1
+        ORDER BY prekey_id LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING key_id, public_key;
+    ", table=table);
+    let row = client.query_opt(cmd.as_str(), &[&device_id]).await?;
+
+    Ok(row.map(|row| models::PreKey {
+        key_id: row.get("key_id"),
+        public_key: row.get("public_key"),
+    }))
+}
+
+/**
+ * Inserts a post into the given table 
+ */
+pub async fn insert_post(
+    client: &Client,
+    table: &str,
+    title: &str,
+    body: &str,
+    author_id: i32
+) -> Result<u64, Error> {
+    let now = make_now();
+    println!("Inserting post with time {}", now);
+
+    let cmd = format!("
+    INSERT INTO {} (title, body, created_on, author_id)
+    VALUES ($1, $2, $3, $4);
+    ", table);
+    println!("cmd is {}", cmd);
+    let rows = client.execute("
+    INSERT INTO test_posts (title, body, created_on, author_id)
+    VALUES ($1, $2, $3, $4);
+    ", &[&title, &body, &now, &author_id]).await?;
+
+    Ok(rows)
+}
+
+/// Saves a new group.  Returns 0 if there is already a group with that name.
+pub async fn insert_user_group(
+    client: &Client,
+    table: &str,
+    group: &models::UserGroup
+) -> Result<u64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (name, owner, members, created_on)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (name) DO NOTHING;
+    ", table);
+    client.execute(
+        cmd.as_str(),
+        &[&group.name, &group.owner, &group.members, &group.created_on]
+    ).await
+}
+
+pub async fn update_group_members(
+    client: &Client,
+    table: &str,
+    name: &str,
+    members: &[String]
+) -> Result<u64, Error> {
+    let cmd = format!("UPDATE {} SET members = $2 WHERE name = $1;", table);
+    client.execute(cmd.as_str(), &[&name, &members]).await
+}
+
+pub async fn delete_user_group(
+    client: &Client,
+    table: &str,
+    name: &str
+) -> Result<u64, Error> {
+    let cmd = format!("DELETE FROM {} WHERE name = $1;", table);
+    client.execute(cmd.as_str(), &[&name]).await
+}
+
+pub async fn list_user_groups(
+    client: &Client,
+    table: &str
+) -> Result<Vec<models::UserGroup>, Error> {
+    let cmd = format!("SELECT name, owner, members, created_on FROM {} ORDER BY name;", table);
+    let rows = client.query(cmd.as_str(), &[]).await?;
+
+    Ok(rows.iter().map(|row| models::UserGroup {
+        name: row.get("name"),
+        owner: row.get("owner"),
+        members: row.get("members"),
+        created_on: row.get("created_on"),
+    }).collect())
+}
+
+fn row_to_conversation(row: &Row) -> models::Conversation {
+    models::Conversation {
+        conversation_id: row.get("conversation_id"),
+        members: row.get("members"),
+        direct: row.get("direct"),
+        created_by: row.get("created_by"),
+        created_on: row.get("created_on"),
+        last_activity: row.get("last_activity"),
+    }
+}
+
+/// The one to one conversation between two people, made if they don't have one yet
+pub async fn direct_conversation(
+    client: &Client,
+    table: &str,
+    members: &[String],
+    created_by: &str,
+    time: &DateTime<Utc>
+) -> Result<models::Conversation, Error> {
+    // The update doesn't change anything, it is only there so the existing row is returned
+    let cmd = format!("
+    INSERT INTO {} (members, direct, created_by, created_on, last_activity)
+    VALUES ($1, 't', $2, $3, $3)
+    ON CONFLICT (members) WHERE direct DO UPDATE SET members = EXCLUDED.members
+    RETURNING *;
+    ", table);
+    let row = client.query_one(cmd.as_str(), &[&members, &created_by, time]).await?;
+
+    Ok(row_to_conversation(&row))
+}
+
+/// Saves a new group conversation, returning its id
+pub async fn insert_conversation(
+    client: &Client,
+    table: &str,
+    conversation: &models::Conversation
+) -> Result<i32, Error> {
+    let cmd = format!("
+    INSERT INTO {} (members, direct, created_by, created_on, last_activity)
+    VALUES ($1, $2, $3, $4, $5)
+    RETURNING conversation_id;
+    ", table);
+    let row = client.query_one(
+        cmd.as_str(),
+        &[&conversation.members, &conversation.direct, &conversation.created_by,
+          &conversation.created_on, &conversation.last_activity]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn get_conversation(
+    client: &Client,
+    table: &str,
+    conversation_id: i32
+) -> Result<Option<models::Conversation>, Error> {
+    let cmd = format!("SELECT * FROM {} WHERE conversation_id = $1;", table);
+    let row = client.query_opt(cmd.as_str(), &[&conversation_id]).await?;
+
+    Ok(row.as_ref().map(row_to_conversation))
+}
+
+/// The user's conversations, the most recently active first
+pub async fn list_conversations(
+    client: &Client,
+    table: &str,
+    username: &str,
+    limit: i64
+) -> Result<Vec<models::Conversation>, Error> {
+    let cmd = format!("
+    SELECT * FROM {} WHERE members @> ARRAY[$1::TEXT]
+    ORDER BY last_activity DESC, conversation_id DESC
+    LIMIT $2;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&username, &limit]).await?;
+
+    Ok(rows.iter().map(row_to_conversation).collect())
+}
+
+/// Moves the conversation's last activity up to `time`
+pub async fn touch_conversation(
+    client: &Client,
+    table: &str,
+    conversation_id: i32,
+    time: &DateTime<Utc>
+) -> Result<u64, Error> {
+    let cmd = format!("
+    UPDATE {} SET last_activity = GREATEST(last_activity, $2) WHERE conversation_id = $1;
+    ", table);
+    client.execute(cmd.as_str(), &[&conversation_id, time]).await
+}
+
+/// The messages in a conversation, newest first.  `before` is a message id, for paging back.
+pub async fn conversation_messages(
+    client: &Client,
+    table: &str,
+    conversation_id: i32,
+    before: Option<i64>,
+    limit: i64
+) -> Result<Vec<models::ChatMessage>, Error> {
+    let cmd = format!("
+    SELECT * FROM {}
+    WHERE conversation_id = $1 AND ($2::BIGINT IS NULL OR message_id < $2)
+    ORDER BY message_id DESC
+    LIMIT $3;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&conversation_id, &before, &limit]).await?;
+
+    Ok(rows.iter().map(row_to_message).collect())
+}
+
+/// Saves a call that is over
+pub async fn insert_call(
+    client: &Client,
+    table: &str,
+    call: &models::CallRecord
+) -> Result<u64, Error> {
+    let cmd = format!("
+    INSERT INTO {} (call_id, caller, callee, state, started, accepted, ended, duration)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    ON CONFLICT (call_id) DO NOTHING;
+    ", table);
+    client.execute(
+        cmd.as_str(),
+        &[&call.call_id, &call.caller, &call.callee, &call.state, &call.started, &call.accepted,
+          &call.ended, &call.duration]
+    ).await
+}
+
+/// The calls the user made or got, the most recent first
+pub async fn list_calls(
+    client: &Client,
+    table: &str,
+    username: &str,
+    limit: i64
+) -> Result<Vec<models::CallRecord>, Error> {
+    let cmd = format!("
+    SELECT * FROM {} WHERE caller = $1 OR callee = $1
+    ORDER BY ended DESC
+    LIMIT $2;
+    ", table);
+    let rows = client.query(cmd.as_str(), &[&username, &limit]).await?;
+
+    Ok(rows.iter().map(|row| models::CallRecord {
+        call_id: row.get("call_id"),
+        caller: row.get("caller"),
+        callee: row.get("callee"),
+        state: row.get("state"),
+        started: row.get("started"),
+        accepted: row.get("accepted"),
+        ended: row.get("ended"),
+        duration: row.get("duration"),
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Inserts a post into the database
+     * 
+     * This should actually be an integration test, as it does and tests the following: 
+     * 
+     * - Drops the test_posts and test_users database (in that order)
+     * - Creates the test_users and test_posts database (in that order)
+     * - Inserts a user into test_users
+     * - Inserts a post into test_posts 
+     */
+    #[tokio::test]
+    async fn test_insert_post() -> Result<(), Error> {
+        let (client, connection) = establish_connection("test_db").await?;
+        let db_posts = "test_posts";
+        let db_users = "test_users";
+
+        // The connection object performs the actual communication with the database,
+        // so spawn it off to run on its own.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("connection error: {}", e);
+            }
+        });
+
+        drop_table(db_posts, &client).await?;
+        drop_table(db_users, &client).await?;
+        
+        make_table_users(db_users, &client).await?;
+        println!("Created test_users table");
+        make_table_posts(db_posts, db_users, &client).await?;
+        println!("Created test_posts table");
+
+        // Create a user first, otherwise referential integrity won't hold
+        let user = models::User {
+            user_id: -1,
+            first_name: String::from("Sean"),
+            last_name: String::from("Toner"),
+            username: String::from("stoner"),
+            email: String::from("foo@bar.com")
+        };
+        let rows = insert_user(&client, db_users, &user).await?;
+        println!("Inserted test user: {}", rows);
+
+        // Look up user
+        let userids = lookup_user(&client, db_users, &user).await?;
+        for id in userids.into_iter() {
+            println!("user id = {}", id);
+        }
+
+        // Create a post
+        let _res = insert_post(&client,
+            db_posts,
+            "Just a test",
+            r#"
+            <html>
+                <head>
+                </head>
+                <body>
+                    Just a simple html page
+                </body>
+            </html>"#, 1).await?;
+        println!("Inserted post");
 
         // Now, make a query for this
         let rows = client.query("
@@ -318,4 +1603,639 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_bots() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let table = "test_bots_crud";
+
+        drop_table(table, &client).await?;
+        make_table_bots(table, &client).await?;
+
+        let bot = models::Bot {
+            bot_id: -1,
+            name: "dicebot".into(),
+            owner: "stoner".into(),
+            token_hash: "abc".into(),
+            rooms: vec!["dnd".into(), "lobby".into()],
+            created_on: make_now(),
+        };
+        let id = insert_bot(&client, table, &bot).await?;
+        assert!(id > 0);
+        assert!(insert_bot(&client, table, &bot).await.is_err(), "bot names are unique");
+
+        let bots = list_bots(&client, table).await?;
+        assert_eq!(bots.len(), 1);
+        assert_eq!(bots[0].rooms, bot.rooms);
+        assert!(bot_exists(&client, table, "dicebot").await?);
+        assert!(!bot_exists(&client, table, "stoner").await?);
+
+        assert_eq!(delete_bot(&client, table, "dicebot").await?, 1);
+        assert!(list_bots(&client, table).await?.is_empty());
+        drop_table(table, &client).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_webhook_logs() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let (hooks, deliveries, letters) =
+            ("test_webhooks_crud", "test_deliveries_crud", "test_dead_letters_crud");
+
+        drop_table(letters, &client).await?;
+        drop_table(deliveries, &client).await?;
+        drop_table(hooks, &client).await?;
+        make_table_webhooks(hooks, &client).await?;
+        make_table_webhook_deliveries(deliveries, hooks, &client).await?;
+        make_table_dead_letters(letters, hooks, &client).await?;
+
+        let hook = models::Webhook {
+            webhook_id: -1,
+            url: "http://localhost:9999/hook".into(),
+            room: None,
+            events: vec!["Message".into()],
+            direct_messages: true,
+            secret: "shh".into(),
+            created_by: "stoner".into(),
+            created_on: make_now(),
+        };
+        let id = insert_webhook(&client, hooks, &hook).await?;
+        let listed = list_webhooks(&client, hooks).await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].room, None);
+        assert!(listed[0].direct_messages);
+
+        for attempt in 1..=2 {
+            let delivery = models::WebhookDelivery {
+                webhook_id: id,
+                delivery: "abc".into(),
+                event: "Message".into(),
+                attempt,
+                status: Some(500),
+                error: Some("HTTP 500".into()),
+                sent_on: make_now(),
+            };
+            insert_delivery(&client, deliveries, &delivery).await?;
+        }
+        let letter = models::DeadLetter {
+            webhook_id: id,
+            delivery: "abc".into(),
+            event: "Message".into(),
+            payload: "{}".into(),
+            last_error: "HTTP 500".into(),
+            attempts: 2,
+            failed_on: make_now(),
+        };
+        insert_dead_letter(&client, letters, &letter).await?;
+
+        let logged = list_deliveries(&client, deliveries, id, 10).await?;
+        assert_eq!(logged.iter().map(|d| d.attempt).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(list_dead_letters(&client, letters, 10).await?.len(), 1);
+
+        // Deleting the webhook takes its logs with it
+        assert_eq!(delete_webhook(&client, hooks, id).await?, 1);
+        assert!(list_deliveries(&client, deliveries, id, 10).await?.is_empty());
+        assert!(list_dead_letters(&client, letters, 10).await?.is_empty());
+
+        drop_table(letters, &client).await?;
+        drop_table(deliveries, &client).await?;
+        drop_table(hooks, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let table = "test_messages_crud";
+
+        drop_table(table, &client).await?;
+        make_table_messages(table, &client).await?;
+
+        let mut annotations = std::collections::HashMap::new();
+        annotations.insert("integration".to_string(), "ci".to_string());
+        let msg = models::ChatMessage {
+            message_id: -1,
+            sender: "ci".into(),
+            recipients: vec!["stoner".into()],
+            delivered_to: vec!["stoner".into()],
+            room: Some("builds".into()),
+            body: "Build 42 passed".into(),
+            bot: false,
+            encrypted: false,
+            annotations,
+            sent_on: make_now(),
+            conversation_id: None,
+        };
+        let id = insert_message(&client, table, &msg).await?;
+
+        let cmd = format!("SELECT * FROM {} WHERE message_id = $1", table);
+        let row = client.query_one(cmd.as_str(), &[&id]).await?;
+        let saved = row_to_message(&row);
+        assert_eq!(saved.body, msg.body);
+        assert_eq!(saved.room, msg.room);
+        assert_eq!(saved.annotations, msg.annotations);
+
+        drop_table(table, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let table = "test_messages_search";
+
+        drop_table(table, &client).await?;
+        make_table_messages(table, &client).await?;
+
+        let msg = |sender: &str, to: &[&str], room: Option<&str>, body: &str| models::ChatMessage {
+            message_id: -1,
+            sender: sender.into(),
+            recipients: to.iter().map(|s| s.to_string()).collect(),
+            delivered_to: to.iter().map(|s| s.to_string()).collect(),
+            room: room.map(String::from),
+            body: body.into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: make_now(),
+            conversation_id: None,
+        };
+        let saved = [
+            msg("stoner", &["whammo"], Some("dnd"), "Who is bringing the <dice> tonight?"),
+            msg("whammo", &["stoner"], Some("dnd"), "I have the dice"),
+            msg("whammo", &["rubik"], None, "Secret dice plans"),
+            msg("rubik", &["stoner"], None, "Unrelated message"),
+        ];
+        for m in saved.iter() {
+            insert_message(&client, table, m).await?;
+        }
+        // Sent to a group, so only who it was delivered to says rubik got it
+        let mut grouped = msg("whammo", &["@group:mods"], None, "Mod dice rules");
+        grouped.delivered_to = vec!["rubik".into()];
+        insert_message(&client, table, &grouped).await?;
+        // Encrypted bodies are never searched, even if they happen to look like words
+        let mut encrypted = msg("rubik", &["stoner"], None, "dice");
+        encrypted.encrypted = true;
+        insert_message(&client, table, &encrypted).await?;
+
+        let query = |text: &str| models::MessageQuery {
+            text: text.into(),
+            limit: 10,
+            ..Default::default()
+        };
+
+        // stoner wasn't part of the conversation between whammo and rubik
+        let hits = search_messages(&client, table, "stoner", &query("dice")).await?;
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().any(|h| h.snippet.contains("&lt;<mark>dice</mark>&gt;")));
+        let hits = search_messages(&client, table, "rubik", &query("dice")).await?;
+        assert_eq!(hits.len(), 2);
+
+        let mut by_sender = query("dice");
+        by_sender.sender = Some("whammo".into());
+        assert_eq!(search_messages(&client, table, "stoner", &by_sender).await?.len(), 1);
+
+        let mut dms = query("dice");
+        dms.with = Some("rubik".into());
+        assert_eq!(search_messages(&client, table, "whammo", &dms).await?.len(), 2);
+
+        let mut later = query("dice");
+        later.since = Some(make_now() + chrono::Duration::hours(1));
+        assert!(search_messages(&client, table, "stoner", &later).await?.is_empty());
+
+        drop_table(table, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_messages() -> Result<(), Error> {
+        use futures::TryStreamExt;
+
+        let client = connect("test_db").await?;
+        let table = "test_messages_export";
+
+        drop_table(table, &client).await?;
+        make_table_messages(table, &client).await?;
+
+        let msg = |sender: &str, to: &str, room: Option<&str>, body: &str| models::ChatMessage {
+            message_id: -1,
+            sender: sender.into(),
+            recipients: vec![to.into()],
+            delivered_to: vec![to.into()],
+            room: room.map(String::from),
+            body: body.into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: make_now(),
+            // Direct messages are in a conversation (see `conversations::attach`)
+            conversation_id: if room.is_none() { Some(1) } else { None },
+        };
+        let saved = [
+            msg("stoner", "whammo", Some("dnd"), "one"),
+            msg("whammo", "stoner", Some("dnd"), "two"),
+            msg("whammo", "stoner", None, "psst"),
+            msg("stoner", "whammo", None, "what"),
+            msg("rubik", "whammo", None, "hey"),
+            models::ChatMessage { conversation_id: None, ..msg("stoner", "rubik", None, "hi all") },
+        ];
+        for m in saved.iter() {
+            insert_message(&client, table, m).await?;
+        }
+
+        let export = |query: models::ExportQuery| {
+            let client = client.clone();
+            async move {
+                let rows: Vec<Row> = stream_messages(&client, table, &query).await?.try_collect().await?;
+                Ok::<_, Error>(rows.iter().map(|r| row_to_message(r).body).collect::<Vec<_>>())
+            }
+        };
+
+        let room = models::ExportQuery { room: Some("dnd".into()), ..Default::default() };
+        assert_eq!(export(room).await?, vec!["one", "two"]);
+        let dm = models::ExportQuery {
+            dm: Some(("stoner".into(), "whammo".into())),
+            ..Default::default()
+        };
+        assert_eq!(export(dm).await?, vec!["psst", "what"]);
+        let lobby = models::ExportQuery {
+            room: Some("lobby".into()),
+            member: Some("stoner".into()),
+            ..Default::default()
+        };
+        assert_eq!(export(lobby).await?, vec!["hi all"]);
+
+        drop_table(table, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_consent() -> Result<(), Error> {
+        use futures::TryStreamExt;
+
+        let client = connect("test_db").await?;
+        let (users, messages) = ("test_users_consent", "test_messages_consent");
+
+        drop_table(messages, &client).await?;
+        drop_table(users, &client).await?;
+        make_table_users(users, &client).await?;
+        add_consent_columns(users, &client).await?;
+        make_table_messages(messages, &client).await?;
+
+        for name in &["stoner", "whammo"] {
+            let user = models::User {
+                user_id: -1,
+                first_name: String::new(),
+                last_name: String::new(),
+                username: name.to_string(),
+                email: format!("{}@example.com", name),
+            };
+            insert_user(&client, users, &user).await?;
+            let msg = models::ChatMessage {
+                message_id: -1,
+                sender: name.to_string(),
+                recipients: vec![],
+                delivered_to: vec![],
+                room: None,
+                body: format!("hi from {}", name),
+                bot: false,
+                encrypted: false,
+                annotations: Default::default(),
+                sent_on: make_now(),
+                conversation_id: None,
+            };
+            insert_message(&client, messages, &msg).await?;
+        }
+
+        assert_eq!(get_consent(&client, users, "stoner").await?, Some(false));
+        assert_eq!(set_consent(&client, users, "stoner", true).await?, 1);
+        assert_eq!(get_consent(&client, users, "stoner").await?, Some(true));
+        assert_eq!(get_consent(&client, users, "nobody").await?, None);
+        assert_eq!(list_consents(&client, users).await?.iter().filter(|u| u.consent).count(), 1);
+
+        // Only what stoner said after consenting counts
+        let later = models::ChatMessage {
+            message_id: -1,
+            sender: "stoner".into(),
+            recipients: vec![],
+            delivered_to: vec![],
+            room: None,
+            body: "hi again".into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: make_now() + chrono::Duration::seconds(1),
+            conversation_id: None,
+        };
+        insert_message(&client, messages, &later).await?;
+        let rows: Vec<Row> =
+            stream_consented_messages(&client, messages, users).await?.try_collect().await?;
+        let bodies: Vec<String> = rows.iter().map(|r| row_to_message(r).body).collect();
+        assert_eq!(bodies, vec!["hi again"]);
+
+        drop_table(messages, &client).await?;
+        drop_table(users, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retention_queries() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let (messages, holds) = ("test_messages_retention", "test_legal_holds");
+
+        drop_table(messages, &client).await?;
+        drop_table(holds, &client).await?;
+        make_table_messages(messages, &client).await?;
+        make_table_legal_holds(holds, &client).await?;
+
+        let now = make_now();
+        let mut ids = vec![];
+        for days in 0..5 {
+            let msg = models::ChatMessage {
+                message_id: -1,
+                sender: "stoner".into(),
+                recipients: vec!["whammo".into()],
+                delivered_to: vec!["whammo".into()],
+                room: None,
+                body: format!("{} days old", days),
+                bot: false,
+                encrypted: false,
+                annotations: Default::default(),
+                sent_on: now - chrono::Duration::days(days),
+                conversation_id: None,
+            };
+            ids.push(insert_message(&client, messages, &msg).await?);
+        }
+
+        let cutoff = now - chrono::Duration::days(1);
+        let first = list_messages_before(&client, messages, &cutoff, 0, 2).await?;
+        assert_eq!(first.len(), 2);
+        let rest = list_messages_before(&client, messages, &cutoff, first[1].message_id, 2).await?;
+        assert_eq!(rest.len(), 1);
+        assert_eq!(delete_messages(&client, messages, &[first[0].message_id]).await?, 1);
+        assert_eq!(list_messages_before(&client, messages, &cutoff, 0, 10).await?.len(), 2);
+
+        let hold = models::LegalHold {
+            hold_id: -1,
+            room: None,
+            members: vec!["stoner".into(), "whammo".into()],
+            conversation_id: None,
+            reason: "case 42".into(),
+            created_by: "admin".into(),
+            created_on: now,
+        };
+        let id = insert_legal_hold(&client, holds, &hold).await?;
+        let by_conversation = models::LegalHold {
+            members: vec![],
+            conversation_id: Some(7),
+            ..hold.clone()
+        };
+        insert_legal_hold(&client, holds, &by_conversation).await?;
+        let listed = list_legal_holds(&client, holds).await?;
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].members, hold.members);
+        assert_eq!(listed[1].conversation_id, Some(7));
+        assert_eq!(delete_legal_hold(&client, holds, id).await?, 1);
+
+        drop_table(messages, &client).await?;
+        drop_table(holds, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_public_keys() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let (users, keys) = ("test_users_keys", "test_public_keys");
+
+        drop_table(keys, &client).await?;
+        drop_table(users, &client).await?;
+        make_table_users(users, &client).await?;
+        make_table_public_keys(keys, &client).await?;
+
+        let user = models::User {
+            user_id: -1,
+            first_name: String::new(),
+            last_name: String::new(),
+            username: "stoner".into(),
+            email: "stoner@example.com".into(),
+        };
+        insert_user(&client, users, &user).await?;
+        let found = find_user(&client, users, "stoner").await?.expect("stoner was added");
+        assert_eq!(found.email, "stoner@example.com");
+        assert!(find_user(&client, users, "nobody").await?.is_none());
+
+        let key = models::PublicKey {
+            key_id: -1,
+            username: "stoner".into(),
+            algorithm: "ed25519".into(),
+            public_key: "-----BEGIN PUBLIC KEY-----".into(),
+            fingerprint: "SHA256:abc".into(),
+            created_on: make_now(),
+            revoked_on: None,
+        };
+        let id = insert_public_key(&client, keys, &key).await?;
+        // The same key can't be active twice
+        assert!(insert_public_key(&client, keys, &key).await.is_err());
+
+        assert_eq!(revoke_public_key(&client, keys, "whammo", id).await?, 0);
+        assert_eq!(revoke_public_key(&client, keys, "stoner", id).await?, 1);
+        assert_eq!(revoke_public_key(&client, keys, "stoner", id).await?, 0);
+        // Once revoked, it can be registered again
+        insert_public_key(&client, keys, &key).await?;
+
+        let listed = list_public_keys(&client, keys, "stoner").await?;
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].revoked_on.is_some() && listed[1].revoked_on.is_none());
+
+        drop_table(keys, &client).await?;
+        drop_table(users, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_devices() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let (devices, prekeys) = ("test_devices", "test_prekeys");
+
+        drop_table(prekeys, &client).await?;
+        drop_table(devices, &client).await?;
+        make_table_devices(devices, &client).await?;
+        make_table_prekeys(prekeys, devices, &client).await?;
+
+        let device = models::Device {
+            device_id: -1,
+            username: "stoner".into(),
+            name: "laptop".into(),
+            identity_key: "aWRlbnRpdHk=".into(),
+            signed_prekey: "c3Br".into(),
+            prekey_signature: "c2ln".into(),
+            created_on: make_now(),
+            updated_on: make_now(),
+        };
+        let id = insert_device(&client, devices, &device).await?;
+        let keys: Vec<models::PreKey> = (1..=3).map(|key_id| models::PreKey {
+            key_id,
+            public_key: format!("key{}", key_id),
+        }).collect();
+        assert_eq!(insert_prekeys(&client, prekeys, id, &keys).await?, 3);
+        // The same ids again are skipped
+        assert_eq!(insert_prekeys(&client, prekeys, id, &keys[..1]).await?, 0);
+
+        let listed = list_devices(&client, devices, prekeys, "stoner").await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].1, 3);
+
+        assert_eq!(take_prekey(&client, prekeys, id).await?.map(|k| k.key_id), Some(1));
+        assert_eq!(take_prekey(&client, prekeys, id).await?.map(|k| k.key_id), Some(2));
+        assert_eq!(list_devices(&client, devices, prekeys, "stoner").await?[0].1, 1);
+
+        assert_eq!(update_signed_prekey(&client, devices, "whammo", id, "x", "y").await?, 0);
+        assert_eq!(update_signed_prekey(&client, devices, "stoner", id, "x", "y").await?, 1);
+        let listed = list_devices(&client, devices, prekeys, "stoner").await?;
+        assert_eq!(listed[0].0.signed_prekey, "x");
+
+        assert_eq!(delete_device(&client, devices, "whammo", id).await?, 0);
+        assert_eq!(delete_device(&client, devices, "stoner", id).await?, 1);
+        // The pre-keys go with the device
+        assert!(take_prekey(&client, prekeys, id).await?.is_none());
+
+        drop_table(prekeys, &client).await?;
+        drop_table(devices, &client).await?;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_user_groups() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let table = "test_user_groups";
+
+        drop_table(table, &client).await?;
+        make_table_user_groups(table, &client).await?;
+
+        let group = models::UserGroup {
+            name: "mods".into(),
+            owner: "stoner".into(),
+            members: vec!["stoner".into(), "whammo".into()],
+            created_on: make_now(),
+        };
+        assert_eq!(insert_user_group(&client, table, &group).await?, 1);
+        assert_eq!(insert_user_group(&client, table, &group).await?, 0);
+
+        let members = vec!["rubik".to_string()];
+        assert_eq!(update_group_members(&client, table, "mods", &members).await?, 1);
+        assert_eq!(update_group_members(&client, table, "nobody", &members).await?, 0);
+        let groups = list_user_groups(&client, table).await?;
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].members, members);
+        assert_eq!(groups[0].owner, "stoner");
+
+        assert_eq!(delete_user_group(&client, table, "mods").await?, 1);
+        assert!(list_user_groups(&client, table).await?.is_empty());
+
+        drop_table(table, &client).await?;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_conversations() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let (conversations, messages) = ("test_conversations_crud", "test_messages_conversations");
+
+        drop_table(conversations, &client).await?;
+        drop_table(messages, &client).await?;
+        make_table_conversations(conversations, &client).await?;
+        make_table_messages(messages, &client).await?;
+        add_conversation_column(messages, &client).await?;
+
+        // The same pair of people always get the same one to one conversation
+        let pair = vec!["stoner".to_string(), "whammo".to_string()];
+        let earlier = make_now() - chrono::Duration::hours(1);
+        let direct = direct_conversation(&client, conversations, &pair, "stoner", &earlier).await?;
+        let now = make_now();
+        let again = direct_conversation(&client, conversations, &pair, "whammo", &now).await?;
+        assert_eq!(direct.conversation_id, again.conversation_id);
+        assert_eq!(again.created_by, "stoner");
+
+        let group = models::Conversation {
+            conversation_id: -1,
+            members: vec!["rubik".into(), "stoner".into(), "whammo".into()],
+            direct: false,
+            created_by: "rubik".into(),
+            created_on: earlier,
+            last_activity: earlier,
+        };
+        let group_id = insert_conversation(&client, conversations, &group).await?;
+        assert!(get_conversation(&client, conversations, group_id).await?.is_some());
+        assert!(get_conversation(&client, conversations, -5).await?.is_none());
+
+        // The group was active last, so it comes first
+        assert_eq!(touch_conversation(&client, conversations, group_id, &now).await?, 1);
+        let listed = list_conversations(&client, conversations, "stoner", 10).await?;
+        let ids: Vec<i32> = listed.iter().map(|c| c.conversation_id).collect();
+        assert_eq!(ids, vec![group_id, direct.conversation_id]);
+        assert_eq!(list_conversations(&client, conversations, "rubik", 10).await?.len(), 1);
+        assert!(list_conversations(&client, conversations, "nobody", 10).await?.is_empty());
+
+        let msg = |body: &str, conversation_id| models::ChatMessage {
+            message_id: -1,
+            sender: "stoner".into(),
+            recipients: vec!["whammo".into()],
+            delivered_to: vec!["whammo".into()],
+            room: None,
+            body: body.into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: make_now(),
+            conversation_id,
+        };
+        let id = direct.conversation_id;
+        let first = insert_message(&client, messages, &msg("hi", Some(id))).await?;
+        insert_message(&client, messages, &msg("yo", Some(id))).await?;
+        insert_message(&client, messages, &msg("elsewhere", None)).await?;
+        let page = conversation_messages(&client, messages, id, None, 10).await?;
+        let bodies: Vec<&str> = page.iter().map(|m| m.body.as_str()).collect();
+        assert_eq!(bodies, vec!["yo", "hi"]);
+        assert_eq!(page[0].conversation_id, Some(id));
+        let older = conversation_messages(&client, messages, id, Some(first), 10).await?;
+        assert!(older.is_empty());
+
+        drop_table(messages, &client).await?;
+        drop_table(conversations, &client).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_calls() -> Result<(), Error> {
+        let client = connect("test_db").await?;
+        let table = "test_calls_crud";
+
+        drop_table(table, &client).await?;
+        make_table_calls(table, &client).await?;
+
+        let started = make_now() - chrono::Duration::minutes(5);
+        let call = |id: &str, callee: &str, state: &str, minutes| models::CallRecord {
+            call_id: id.into(),
+            caller: "stoner".into(),
+            callee: callee.into(),
+            state: state.into(),
+            started,
+            accepted: if minutes > 0 { Some(started) } else { None },
+            ended: started + chrono::Duration::minutes(minutes),
+            duration: minutes * 60,
+        };
+        assert_eq!(insert_call(&client, table, &call("a1", "whammo", "Ended", 3)).await?, 1);
+        assert_eq!(insert_call(&client, table, &call("a1", "whammo", "Ended", 3)).await?, 0);
+        insert_call(&client, table, &call("b2", "rubik", "Missed", 0)).await?;
+
+        let calls = list_calls(&client, table, "stoner", 10).await?;
+        let ids: Vec<&str> = calls.iter().map(|c| c.call_id.as_str()).collect();
+        assert_eq!(ids, vec!["a1", "b2"]);
+        assert_eq!((calls[0].duration, calls[1].accepted), (180, None));
+        let whammo = list_calls(&client, table, "whammo", 10).await?;
+        assert_eq!(whammo.len(), 1);
+        assert!(list_calls(&client, table, "nobody", 10).await?.is_empty());
+
+        drop_table(table, &client).await?;
+        Ok(())
+    }
 }
\ No newline at end of file
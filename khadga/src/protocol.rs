@@ -0,0 +1,241 @@
+//! Protocol versions and capabilities
+//!
+//! So that `message` can change without breaking older vision builds, a client starts by saying
+//! what it speaks.  The first message on the websocket is a `Hello` event whose body is a `Hello`:
+//!
+//! ```json
+//! {"version": 2, "min_version": 1, "codecs": ["json"], "features": ["rooms", "e2e"]}
+//! ```
+//!
+//! khadga answers with a `Welcome`: its own version range, codecs and features, and what the two
+//! have in common.  The negotiated version is the newest one both speak, the codec is the first of
+//! the client's that khadga knows, and the features are the ones both listed.  If there is no
+//! version or codec in common, the websocket is closed with code 4001 and a reason saying why,
+//! and the client never shows up as connected.
+//!
+//! Events that belong to a feature are only sent to clients that negotiated it: `Alert` needs
+//! `alerts`, `Suggestion` needs `suggestions`, and `Call` and `Mesh` need `calls`.
+//!
+//! Clients from before the handshake don't send a `Hello`.  If the first message is something else,
+//! or nothing comes within `HELLO_TIMEOUT_MS`, the connection carries on as version 0 with every
+//! feature, which is how it always worked.
+//!
+//! The versions so far:
+//!
+//! 1. the original `message::Message` format, with the handshake
+//! 2. the tagged format in `wire`.  A client can send either format whatever it negotiated, but
+//!    what khadga sends follows the version.
+//!
+//! The `Hello` itself can be in either format.  The SSE transport (see `sse`) takes the same thing
+//! as query parameters instead: `/sse/events?version=2&codecs=json&features=rooms,e2e`.
+
+use crate::{message::MessageEvent,
+            wire::{self,
+                   Event}};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::Value;
+
+/// The newest protocol version khadga speaks
+pub const VERSION: u32 = 2;
+/// The oldest protocol version khadga still speaks
+pub const MIN_VERSION: u32 = 1;
+/// How message bodies can be encoded, in khadga's order of preference
+pub const CODECS: &[&str] = &["json"];
+/// What khadga can do beyond plain messages
+pub const FEATURES: &[&str] = &["alerts", "calls", "commands", "e2e", "rooms", "suggestions"];
+/// How long to wait for a `Hello` before treating the client as one from before the handshake
+pub const HELLO_TIMEOUT_MS: u64 = 3000;
+/// The websocket close code for a failed handshake
+pub const CLOSE_UNSUPPORTED: u16 = 4001;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    /// The oldest version the client can still speak.  Only `version` if not given.
+    #[serde(default)]
+    pub min_version: Option<u32>,
+    /// In order of preference
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// What both sides agreed on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub codec: String,
+    pub features: Vec<String>,
+}
+
+impl Negotiated {
+    /// A client from before the handshake
+    pub fn legacy() -> Self {
+        Negotiated {
+            version: 0,
+            codec: CODECS[0].to_string(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Whether the client can be sent the event, ie it negotiated the feature the event needs
+    pub fn accepts(&self, event: &MessageEvent) -> bool {
+        match event {
+            MessageEvent::Alert => self.has("alerts"),
+            MessageEvent::Suggestion => self.has("suggestions"),
+            MessageEvent::Call | MessageEvent::Mesh => self.has("calls"),
+            _ => true,
+        }
+    }
+}
+
+/// The body of a `Welcome`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub version: u32,
+    pub min_version: u32,
+    pub codecs: Vec<String>,
+    pub features: Vec<String>,
+    pub negotiated: Negotiated,
+}
+
+impl Welcome {
+    pub fn new(negotiated: Negotiated) -> Self {
+        Welcome {
+            version: VERSION,
+            min_version: MIN_VERSION,
+            codecs: CODECS.iter().map(|c| c.to_string()).collect(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            negotiated,
+        }
+    }
+}
+
+/// Works out what khadga and the client have in common, or why they can't talk
+pub fn negotiate(hello: &Hello) -> Result<Negotiated, String> {
+    let client_min = hello.min_version.unwrap_or(hello.version).min(hello.version);
+    let version = hello.version.min(VERSION);
+    if version < client_min.max(MIN_VERSION) {
+        return Err(format!(
+            "Unsupported protocol version {}: khadga speaks {} to {}",
+            hello.version, MIN_VERSION, VERSION
+        ));
+    }
+
+    let codec = if hello.codecs.is_empty() {
+        CODECS[0]
+    } else {
+        match hello.codecs.iter().find_map(|c| CODECS.iter().find(|k| *k == c)) {
+            Some(codec) => *codec,
+            None => {
+                return Err(format!(
+                    "No codec in common: khadga speaks {}",
+                    CODECS.join(", ")
+                ))
+            }
+        }
+    };
+
+    let features = FEATURES
+        .iter()
+        .filter(|f| hello.features.iter().any(|h| h == *f))
+        .map(|f| f.to_string())
+        .collect();
+
+    Ok(Negotiated {
+        version,
+        codec: codec.to_string(),
+        features,
+    })
+}
+
+/// Reads the `Hello` out of a client's first message
+///
+/// None if the message isn't a `Hello` at all, ie the client is from before the handshake.  A
+/// `Hello` with a body that doesn't parse is an error, since the client meant to negotiate.
+pub fn parse_hello(text: &str) -> Option<Result<Hello, String>> {
+    let value: Value = serde_json::from_str(text).ok()?;
+    let event = value.get("event").or_else(|| value.get("event_type"))?;
+    if event != "Hello" {
+        return None;
+    }
+    match wire::decode(text) {
+        Ok(wire::Envelope { event: Event::Hello(hello), .. }) => Some(Ok(hello)),
+        Ok(_) => None,
+        Err(e) => Some(Err(format!("Invalid Hello: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(version: u32, min_version: Option<u32>) -> Hello {
+        Hello {
+            version,
+            min_version,
+            codecs: vec!["msgpack".into(), "json".into()],
+            features: vec!["e2e".into(), "rooms".into(), "holograms".into()],
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let got = negotiate(&hello(1, None)).unwrap();
+        assert_eq!(got.version, 1);
+        assert_eq!(got.codec, "json");
+        assert_eq!(got.features, vec!["e2e", "rooms"]);
+        assert!(got.has("rooms") && !got.has("holograms"));
+        assert!(got.accepts(&MessageEvent::Message) && !got.accepts(&MessageEvent::Alert));
+        assert!(Negotiated::legacy().accepts(&MessageEvent::Call));
+
+        // A newer client that can still speak our version
+        assert_eq!(negotiate(&hello(5, Some(1))).unwrap().version, VERSION);
+        // One that can't
+        let e = negotiate(&hello(5, Some(3))).unwrap_err();
+        assert!(e.contains("Unsupported protocol version 5"), "{}", e);
+        assert!(negotiate(&hello(0, None)).is_err());
+
+        let no_codec = Hello {
+            codecs: vec!["cbor".into()],
+            ..hello(1, None)
+        };
+        assert!(negotiate(&no_codec).unwrap_err().contains("codec"));
+        let no_codecs = Hello {
+            codecs: vec![],
+            ..hello(1, None)
+        };
+        assert_eq!(negotiate(&no_codecs).unwrap().codec, "json");
+    }
+
+    #[test]
+    fn test_parse_hello() {
+        use crate::message::{Message as KMessage,
+                             MessageEvent};
+
+        let body = serde_json::to_string(&hello(1, None)).unwrap();
+        let mesg = KMessage::new("stoner".into(), vec![], MessageEvent::Hello, body);
+        let text = serde_json::to_string(&mesg).unwrap();
+        assert_eq!(parse_hello(&text), Some(Ok(hello(1, None))));
+
+        let mesg = KMessage::new("stoner".into(), vec![], MessageEvent::Hello, "{}".to_string());
+        let text = serde_json::to_string(&mesg).unwrap();
+        assert!(matches!(parse_hello(&text), Some(Err(_))));
+
+        let mesg = KMessage::new("stoner".into(), vec![], MessageEvent::Message, "hi".to_string());
+        assert_eq!(parse_hello(&serde_json::to_string(&mesg).unwrap()), None);
+        assert_eq!(parse_hello("not json"), None);
+
+        // The tagged format
+        let tagged = serde_json::json!({
+            "sender": "stoner", "event": "Hello", "payload": hello(2, Some(1))
+        });
+        assert_eq!(parse_hello(&tagged.to_string()), Some(Ok(hello(2, Some(1)))));
+    }
+}
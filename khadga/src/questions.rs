@@ -0,0 +1,396 @@
+//! Similar questions
+//!
+//! People keep asking the same things.  khadga notices messages that look like questions and
+//! remembers them, along with the replies that answer them.  When somebody asks something close to
+//! a question that already has answers, they are privately sent a `Suggestion` event pointing them
+//! at the earlier thread.  Everybody else in the room sees their question as usual.
+//!
+//! It is all done in process, with no model or outside service:
+//!
+//! - a message is a question if it ends with a `?`, or starts with a question word like "how" or
+//!   "does" and is long enough to be a real question
+//! - questions are indexed as TF-IDF vectors over the same terms `trends` uses (words and pairs of
+//!   words), and compared with cosine similarity
+//! - khadga messages don't say what they are replying to, so the answers to a question are the
+//!   messages from other people in the same room during the next `answer_window_secs`, until
+//!   somebody asks something else
+//!
+//! Suggestions only come from the same room, so nobody is pointed at a conversation they couldn't
+//! have seen.  The sender has to be in that room, so naming a room you aren't in gets you nothing.
+//! Messages that don't name a room may be direct messages, so they are left out altogether.  Bots don't ask questions, but their replies count as answers.
+
+use crate::{chat::{send_to,
+                   ChatState},
+            config::QuestionCfg,
+            message::{Message as KMessage,
+                      MessageEvent},
+            rooms,
+            trends::terms};
+use serde::{Deserialize,
+            Serialize};
+use std::{collections::{HashMap,
+                        VecDeque},
+          sync::Arc};
+use tokio::sync::Mutex;
+
+pub type Questions = Arc<Mutex<QuestionIndex>>;
+
+const QUESTION_WORDS: &[&str] = &[
+    "how", "what", "why", "when", "where", "who", "which", "can", "could", "does", "do", "is",
+    "are", "should", "would", "anyone", "anybody",
+];
+
+/// Whether the message looks like a question
+pub fn is_question(text: &str) -> bool {
+    let text = text.trim();
+    if text.ends_with('?') {
+        return text.split_whitespace().count() >= 2;
+    }
+    let mut words = text.split_whitespace();
+    let first = words.next().unwrap_or("").to_lowercase();
+    QUESTION_WORDS.contains(&first.as_str()) && words.count() >= 3
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub sender: String,
+    pub body: String,
+    pub time: i64,
+}
+
+/// A question and what people answered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Question {
+    pub id: u64,
+    pub room: String,
+    pub asker: String,
+    pub body: String,
+    pub time: i64,
+    pub answers: Vec<Answer>,
+    #[serde(skip)]
+    terms: Vec<String>,
+}
+
+/// The body of a `Suggestion` event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub question: Question,
+    /// Cosine similarity to the new question, from 0 to 1
+    pub similarity: f64,
+}
+
+/// The questions of every room, oldest first
+#[derive(Debug)]
+pub struct QuestionIndex {
+    cfg: QuestionCfg,
+    questions: VecDeque<Question>,
+    /// How many questions each term shows up in
+    doc_freq: HashMap<String, usize>,
+    next_id: u64,
+}
+
+impl QuestionIndex {
+    pub fn new(cfg: QuestionCfg) -> Self {
+        QuestionIndex {
+            cfg,
+            questions: VecDeque::new(),
+            doc_freq: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn config(&self) -> &QuestionCfg {
+        &self.cfg
+    }
+
+    pub fn len(&self) -> usize {
+        self.questions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.questions.is_empty()
+    }
+
+    fn idf(&self, term: &str) -> f64 {
+        let n = self.questions.len() as f64;
+        let df = *self.doc_freq.get(term).unwrap_or(&0) as f64;
+        ((n + 1.0) / (df + 1.0)).ln() + 1.0
+    }
+
+    /// The unit TF-IDF vector of the terms.  Each term is in a message once, so tf is 0 or 1.
+    fn vector(&self, terms: &[String]) -> HashMap<String, f64> {
+        let mut vector: HashMap<String, f64> =
+            terms.iter().map(|t| (t.clone(), self.idf(t))).collect();
+        let norm = vector.values().map(|w| w * w).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vector.values_mut().for_each(|w| *w /= norm);
+        }
+        vector
+    }
+
+    /// The answered question in the room that is most like the text, if it is close enough
+    pub fn most_similar(&self, room: &str, text: &str) -> Option<Suggestion> {
+        let mut terms: Vec<String> = terms(text).into_iter().collect();
+        terms.sort();
+        let query = self.vector(&terms);
+        if query.is_empty() {
+            return None;
+        }
+
+        self.questions
+            .iter()
+            .filter(|q| q.room == room && !q.answers.is_empty())
+            .map(|q| {
+                let other = self.vector(&q.terms);
+                let similarity = query
+                    .iter()
+                    .filter_map(|(term, w)| other.get(term).map(|o| w * o))
+                    .sum::<f64>();
+                (q, similarity)
+            })
+            .filter(|(_, similarity)| *similarity >= self.cfg.threshold)
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.time.cmp(&b.0.time)))
+            .map(|(q, similarity)| Suggestion {
+                question: q.clone(),
+                similarity,
+            })
+    }
+
+    /// Indexes a question and returns its id
+    pub fn add_question(&mut self, room: &str, asker: &str, body: &str, time: i64) -> u64 {
+        let mut terms: Vec<String> = terms(body).into_iter().collect();
+        terms.sort();
+        for term in &terms {
+            *self.doc_freq.entry(term.clone()).or_insert(0) += 1;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.questions.push_back(Question {
+            id,
+            room: room.to_string(),
+            asker: asker.to_string(),
+            body: body.to_string(),
+            time,
+            answers: vec![],
+            terms,
+        });
+
+        while self.questions.len() > self.cfg.max_questions {
+            if let Some(old) = self.questions.pop_front() {
+                for term in &old.terms {
+                    if let Some(df) = self.doc_freq.get_mut(term) {
+                        *df -= 1;
+                        if *df == 0 {
+                            self.doc_freq.remove(term);
+                        }
+                    }
+                }
+            }
+        }
+        id
+    }
+
+    /// Links the message as an answer to the room's latest question, if it is still open
+    ///
+    /// Returns the id of the question it answered.
+    pub fn add_answer(&mut self, room: &str, sender: &str, body: &str, time: i64) -> Option<u64> {
+        let window = self.cfg.answer_window_secs as i64 * 1000;
+        let max_answers = self.cfg.max_answers;
+        let question = self.questions.iter_mut().rev().find(|q| q.room == room)?;
+        if question.asker == sender
+            || time - question.time > window
+            || question.answers.len() >= max_answers
+        {
+            return None;
+        }
+        question.answers.push(Answer {
+            sender: sender.to_string(),
+            body: body.to_string(),
+            time,
+        });
+        Some(question.id)
+    }
+
+    /// Handles a chat message in a room, returning the suggestion for its sender if there is one
+    ///
+    /// `time` is in milliseconds, like `Message::time`
+    pub fn observe(
+        &mut self,
+        room: &str,
+        sender: &str,
+        body: &str,
+        bot: bool,
+        time: i64,
+    ) -> Option<Suggestion> {
+        if bot || !is_question(body) {
+            self.add_answer(room, sender, body, time);
+            return None;
+        }
+        let suggestion = self.most_similar(room, body);
+        self.add_question(room, sender, body, time);
+        suggestion
+    }
+}
+
+/// Indexes a chat message, and privately suggests an earlier thread if it asks a known question
+pub async fn observe(state: &ChatState, mesg: &KMessage<String>) {
+    let room = match (&mesg.room, mesg.conversation) {
+        (Some(room), None) => room.clone(),
+        // Private, and nobody else's business
+        _ => return,
+    };
+    let members = rooms::members(&state.rooms, &room).await.unwrap_or_default();
+    if !members.contains(&mesg.sender) {
+        return;
+    }
+    let suggestion = {
+        let mut index = state.questions.lock().await;
+        if !index.config().enabled {
+            return;
+        }
+        index.observe(&room, &mesg.sender, &mesg.body, mesg.bot, mesg.time)
+    };
+
+    if let Some(suggestion) = suggestion {
+        let mut reply = KMessage::new(
+            "khadga".into(),
+            vec![mesg.sender.clone()],
+            MessageEvent::Suggestion,
+            &suggestion,
+        );
+        reply.room = Some(room);
+        send_to(&state.users, &mesg.sender, &reply).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> QuestionCfg {
+        QuestionCfg {
+            enabled: true,
+            threshold: 0.4,
+            answer_window_secs: 300,
+            max_answers: 3,
+            max_questions: 100,
+        }
+    }
+
+    #[test]
+    fn test_is_question() {
+        assert!(is_question("How do I roll for initiative"));
+        assert!(is_question("initiative rolls?"));
+        assert!(!is_question("?"));
+        assert!(!is_question("how so"));
+        assert!(!is_question("I rolled a 20"));
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let mut index = QuestionIndex::new(config());
+        let t = 1_600_000_000_000;
+
+        assert!(index.observe("dnd", "stoner", "How do I roll for initiative?", false, t).is_none());
+        index.observe("dnd", "whammo", "Roll a d20 and add your dex modifier", false, t + 1000);
+        index.observe("dnd", "dicebot", "d20+2 = 15", true, t + 2000);
+        // Too late to be an answer
+        index.observe("dnd", "rubik", "anyway", false, t + 600_000);
+        assert_eq!(index.questions[0].answers.len(), 2);
+
+        // Unrelated questions don't get suggestions, but are indexed
+        let got = index.observe("dnd", "rubik", "Where is the tavern?", false, t + 700_000);
+        assert!(got.is_none());
+        assert_eq!(index.len(), 2);
+
+        let got = index
+            .observe("dnd", "rubik", "how do you roll initiative?", false, t + 800_000)
+            .expect("Should suggest the initiative question");
+        assert_eq!(got.question.asker, "stoner");
+        assert_eq!(got.question.answers[0].sender, "whammo");
+        assert!(got.similarity >= 0.4 && got.similarity <= 1.0 + 1e-9);
+
+        // Only from the same room
+        assert!(index.most_similar("lobby", "How do I roll for initiative?").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages_are_private() {
+        use crate::{commands::Dispatcher,
+                    filter::FilterChain,
+                    state::UserInfo};
+        use tokio::sync::mpsc;
+
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        *state.questions.lock().await = QuestionIndex::new(config());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.users.lock().await.insert("rubik".into(), UserInfo::new(Some(tx)));
+
+        let t = 1_600_000_000_000;
+        let message = |sender: &str, to: &str, body: &str, time: i64| {
+            let mut mesg =
+                KMessage::new(sender.into(), vec![to.into()], MessageEvent::Message, body.into());
+            mesg.time = time;
+            mesg
+        };
+        let question = "How do I roll for initiative?";
+        observe(&state, &message("stoner", "whammo", question, t)).await;
+        observe(&state, &message("whammo", "stoner", "d20 plus dex, keep it quiet", t + 1)).await;
+        assert_eq!(state.questions.lock().await.len(), 0);
+
+        let mut asked = message("rubik", "", question, t + 2);
+        asked.room = Some("lobby".into());
+        observe(&state, &asked).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_members() {
+        use crate::{commands::Dispatcher,
+                    filter::FilterChain,
+                    state::UserInfo};
+        use tokio::sync::mpsc;
+
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        *state.questions.lock().await = QuestionIndex::new(config());
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.users.lock().await.insert("rubik".into(), UserInfo::new(Some(tx)));
+        for user in &["stoner", "whammo"] {
+            rooms::join(&state.rooms, "dnd", user).await;
+        }
+
+        let t = 1_600_000_000_000;
+        let message = |sender: &str, body: &str, time: i64| {
+            let mut mesg = KMessage::new(sender.into(), vec![], MessageEvent::Message, body.into());
+            mesg.room = Some("dnd".into());
+            mesg.time = time;
+            mesg
+        };
+        let question = "How do I roll for initiative?";
+        observe(&state, &message("stoner", question, t)).await;
+        observe(&state, &message("whammo", "d20 plus dex", t + 1)).await;
+        assert_eq!(state.questions.lock().await.len(), 1);
+
+        // rubik isn't in dnd, so asks nothing there and learns nothing from it
+        observe(&state, &message("rubik", question, t + 2)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(state.questions.lock().await.len(), 1);
+
+        rooms::join(&state.rooms, "dnd", "rubik").await;
+        observe(&state, &message("rubik", question, t + 3)).await;
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cfg = config();
+        cfg.max_questions = 2;
+        let mut index = QuestionIndex::new(cfg);
+        index.add_question("dnd", "stoner", "Which spells can a paladin learn?", 0);
+        index.add_question("dnd", "stoner", "Which spells can a wizard learn?", 1);
+        index.add_question("dnd", "stoner", "Which spells can a rogue learn?", 2);
+        assert_eq!(index.len(), 2);
+        assert!(!index.doc_freq.contains_key("paladin"));
+        assert_eq!(index.doc_freq["spells"], 2);
+    }
+}
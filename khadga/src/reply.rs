@@ -0,0 +1,26 @@
+//! Error replies
+//!
+//! The REST endpoints answer a bad request with a status and a JSON body of `{"error": "..."}`.
+//! The ones that return a file instead of JSON, like `/export`, answer with the message as plain
+//! text.
+
+use serde_json::json;
+use warp::{http::{Response,
+                  StatusCode},
+           hyper::Body,
+           reply::{self,
+                   Json,
+                   WithStatus}};
+
+/// A JSON error reply with the status
+pub fn error_reply(status: StatusCode, message: &str) -> WithStatus<Json> {
+    reply::with_status(reply::json(&json!({ "error": message })), status)
+}
+
+/// A plain text error reply with the status
+pub fn text_error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .expect("Unable to create HTTP Response")
+}
@@ -0,0 +1,624 @@
+//! Message retention
+//!
+//! Without this, saved chat messages and uploads are kept forever.  The `retention` section of the
+//! settings says how long to keep them:
+//!
+//! ```yaml
+//! retention:
+//!   enabled: true
+//!   dry_run: false
+//!   message_days: 365
+//!   rooms:
+//!     random: 30
+//!   types:
+//!     bot: 7
+//!   upload_days: 90
+//!   archive_dir: /var/lib/khadga/archive
+//! ```
+//!
+//! The most specific rule wins: a message in `random` is kept 30 days even if a bot sent it.  The
+//! type of a message is `bot`, `integration` (from an incoming webhook), `emote`, `topic` or
+//! plain `message`.
+//!
+//! When `enabled` is set, a background job runs every `interval_secs` and purges what has expired
+//! in batches of `batch_size`.  With an `archive_dir`, purged messages are first appended to a
+//! JSON Lines file there, and purged uploads are moved there, instead of being deleted outright.
+//!
+//! Conversations under a legal hold are never purged.  Admins manage holds with
+//! `POST /retention/holds`, `GET /retention/holds` and `DELETE /retention/holds/<id>`.  A hold is
+//! on a room (`{"room": "dnd", "reason": "..."}`), on a conversation, group or direct
+//! (`{"conversation": 12, "reason": "..."}`), or on the direct messages between some people
+//! (`{"members": ["stoner", "whammo"], "reason": "..."}`).  Uploads don't belong to a
+//! conversation, so holds don't cover them.
+//!
+//! `GET /retention/report` does a dry run and returns what would be purged right now.  Setting
+//! `dry_run` in the settings makes the background job do the same, and only log the report.
+
+use crate::{auth::{admin,
+                   CONFIG},
+            chat::ChatState,
+            config::RetentionCfg,
+            export::Format,
+            pgdb::{models::{ChatMessage,
+                            LegalHold},
+                   pgdb::{self,
+                          DbClient}},
+            reply::error_reply,
+            rooms::LOBBY};
+use chrono::{DateTime,
+             Duration,
+             Utc};
+use log::{error,
+          info};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::json;
+use std::{collections::HashMap,
+          convert::Infallible,
+          fs::{self,
+               OpenOptions},
+          io::Write,
+          path::Path};
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// What kind of message this is, for the `types` rules
+pub fn message_type(msg: &ChatMessage) -> &'static str {
+    if msg.annotations.contains_key("integration") {
+        "integration"
+    } else if msg.bot {
+        "bot"
+    } else if msg.annotations.contains_key("emote") {
+        "emote"
+    } else if msg.annotations.contains_key("topic") {
+        "topic"
+    } else {
+        "message"
+    }
+}
+
+/// How many days the message is kept, or None to keep it forever
+pub fn retention_days(cfg: &RetentionCfg, msg: &ChatMessage) -> Option<u32> {
+    let room = msg.room.as_deref().unwrap_or(LOBBY);
+    cfg.rooms
+        .get(room)
+        .or_else(|| cfg.types.get(message_type(msg)))
+        .copied()
+        .or(cfg.message_days)
+}
+
+/// The shortest period of any rule.  Nothing newer than this can have expired.
+fn shortest_days(cfg: &RetentionCfg) -> Option<u32> {
+    cfg.rooms
+        .values()
+        .chain(cfg.types.values())
+        .chain(cfg.message_days.iter())
+        .min()
+        .copied()
+}
+
+/// Whether a legal hold covers the message
+pub fn is_held(holds: &[LegalHold], msg: &ChatMessage) -> bool {
+    holds.iter().any(|hold| match (&hold.room, hold.conversation_id) {
+        (Some(room), _) => msg.room.as_deref().unwrap_or(LOBBY) == room,
+        (None, Some(id)) => msg.conversation_id == Some(id),
+        (None, None) => {
+            msg.room.is_none()
+                && hold.members.contains(&msg.sender)
+                && hold.members.iter().any(|m| *m != msg.sender && msg.delivered_to.contains(m))
+        }
+    })
+}
+
+/// What a purge did, or would do in a dry run
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub dry_run: bool,
+    pub time: DateTime<Utc>,
+    pub messages: u64,
+    /// Messages per room, with the lobby for messages without one
+    pub by_room: HashMap<String, u64>,
+    /// Expired messages kept because of a legal hold
+    pub held: u64,
+    pub uploads: u64,
+    pub upload_bytes: u64,
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    pub fn new(dry_run: bool, time: DateTime<Utc>) -> Self {
+        Report {
+            dry_run,
+            time,
+            messages: 0,
+            by_room: HashMap::new(),
+            held: 0,
+            uploads: 0,
+            upload_bytes: 0,
+            errors: vec![],
+        }
+    }
+}
+
+/// Appends the messages to the day's archive file
+fn archive_messages(dir: &Path, time: DateTime<Utc>, msgs: &[ChatMessage]) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("messages-{}.jsonl", time.format("%Y-%m-%d")));
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for msg in msgs {
+        file.write_all(Format::Jsonl.format(msg).as_bytes())?;
+    }
+    file.sync_all()
+}
+
+/// Purges the expired messages that aren't on hold
+pub async fn purge_messages(
+    db: &DbClient,
+    table: &str,
+    cfg: &RetentionCfg,
+    holds: &[LegalHold],
+    report: &mut Report,
+) -> Result<(), String> {
+    let shortest = match shortest_days(cfg) {
+        Some(days) => days,
+        None => return Ok(()),
+    };
+    let cutoff = report.time - Duration::days(shortest as i64);
+    let mut after = 0;
+    loop {
+        let batch = pgdb::list_messages_before(db, table, &cutoff, after, cfg.batch_size)
+            .await
+            .map_err(|e| format!("Unable to list messages: {}", e))?;
+        let last = match batch.last() {
+            Some(msg) => msg.message_id,
+            None => return Ok(()),
+        };
+
+        let mut expired = vec![];
+        for msg in batch {
+            let days = match retention_days(cfg, &msg) {
+                Some(days) => days,
+                None => continue,
+            };
+            if msg.sent_on >= report.time - Duration::days(days as i64) {
+                continue;
+            }
+            if is_held(holds, &msg) {
+                report.held += 1;
+                continue;
+            }
+            expired.push(msg);
+        }
+
+        if !report.dry_run && !expired.is_empty() {
+            if let Some(dir) = &cfg.archive_dir {
+                archive_messages(Path::new(dir), report.time, &expired)
+                    .map_err(|e| format!("Unable to archive messages to {}: {}", dir, e))?;
+            }
+            let ids: Vec<i64> = expired.iter().map(|m| m.message_id).collect();
+            pgdb::delete_messages(db, table, &ids)
+                .await
+                .map_err(|e| format!("Unable to delete messages: {}", e))?;
+        }
+        for msg in &expired {
+            let room = msg.room.clone().unwrap_or_else(|| LOBBY.to_string());
+            *report.by_room.entry(room).or_insert(0) += 1;
+        }
+        report.messages += expired.len() as u64;
+        after = last;
+    }
+}
+
+/// Purges the expired uploads and their files
+pub async fn purge_uploads(
+    db: &DbClient,
+    table: &str,
+    cfg: &RetentionCfg,
+    report: &mut Report,
+) -> Result<(), String> {
+    let days = match cfg.upload_days {
+        Some(days) => days,
+        None => return Ok(()),
+    };
+    let cutoff = report.time - Duration::days(days as i64);
+    let upload_dir = Path::new(&cfg.upload_dir);
+    let mut after = 0;
+    loop {
+        let batch = pgdb::list_uploads_before(db, table, &cutoff, after, cfg.batch_size)
+            .await
+            .map_err(|e| format!("Unable to list uploads: {}", e))?;
+        let last = match batch.last() {
+            Some(upload) => upload.upload_id,
+            None => return Ok(()),
+        };
+
+        let mut purged = vec![];
+        for upload in &batch {
+            let path = upload_dir.join(&upload.file_name);
+            report.upload_bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            if !report.dry_run && path.exists() {
+                let result = match &cfg.archive_dir {
+                    Some(dir) => {
+                        let to = Path::new(dir).join("uploads").join(&upload.file_name);
+                        to.parent()
+                            .map_or(Ok(()), fs::create_dir_all)
+                            .and_then(|_| fs::rename(&path, &to))
+                    }
+                    None => fs::remove_file(&path),
+                };
+                if let Err(e) = result {
+                    // Keep the row, so the file isn't lost track of
+                    report.errors.push(format!("Unable to purge {}: {}", path.display(), e));
+                    continue;
+                }
+            }
+            purged.push(upload.upload_id);
+        }
+
+        if !report.dry_run && !purged.is_empty() {
+            pgdb::delete_uploads(db, table, &purged)
+                .await
+                .map_err(|e| format!("Unable to delete uploads: {}", e))?;
+        }
+        report.uploads += purged.len() as u64;
+        after = last;
+    }
+}
+
+/// Runs a purge of the configured tables
+pub async fn run(db: &DbClient, cfg: &RetentionCfg, dry_run: bool) -> Report {
+    let tables = &CONFIG.db.tables;
+    let mut report = Report::new(dry_run, Utc::now());
+
+    let holds = match pgdb::list_legal_holds(db, &tables.legal_holds).await {
+        Ok(holds) => holds,
+        Err(e) => {
+            // Without the holds we can't tell what is safe to delete
+            report.errors.push(format!("Unable to load legal holds: {}", e));
+            return report;
+        }
+    };
+    if let Err(e) = purge_messages(db, &tables.messages, cfg, &holds, &mut report).await {
+        report.errors.push(e);
+    }
+    if let Err(e) = purge_uploads(db, &tables.uploads, cfg, &mut report).await {
+        report.errors.push(e);
+    }
+    report
+}
+
+/// Creates the legal hold table
+pub async fn load(state: &ChatState) {
+    if let Some(db) = &state.db {
+        let table = &CONFIG.db.tables.legal_holds;
+        let created = async {
+            pgdb::make_table_legal_holds(table, db).await?;
+            pgdb::add_hold_conversation_column(table, db).await
+        };
+        if let Err(e) = created.await {
+            error!("Unable to create the {} table: {}", table, e);
+        }
+    }
+}
+
+/// Runs the purge job every `interval_secs`, forever
+pub async fn run_periodically(db: DbClient, cfg: RetentionCfg) {
+    let interval = std::time::Duration::from_secs(cfg.interval_secs.max(60));
+    loop {
+        tokio::time::delay_for(interval).await;
+        let report = run(&db, &cfg, cfg.dry_run).await;
+        let verb = if report.dry_run { "Would purge" } else { "Purged" };
+        info!(
+            "{} {} messages ({} held) and {} uploads ({} bytes)",
+            verb, report.messages, report.held, report.uploads, report.upload_bytes
+        );
+        for e in &report.errors {
+            error!("Retention: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewHold {
+    pub room: Option<String>,
+    pub conversation: Option<i32>,
+    #[serde(default)]
+    pub members: Vec<String>,
+    pub reason: String,
+}
+
+fn no_db() -> WithStatus<Json> {
+    error_reply(StatusCode::SERVICE_UNAVAILABLE, "The database is not available")
+}
+
+pub async fn create_hold(
+    user: String,
+    new_hold: NewHold,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let targets = [
+        new_hold.room.is_some(),
+        new_hold.conversation.is_some(),
+        !new_hold.members.is_empty(),
+    ];
+    if targets.iter().filter(|given| **given).count() != 1 {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "Give one of a room, a conversation or the members of a conversation",
+        ));
+    }
+    if !new_hold.members.is_empty() && new_hold.members.len() < 2 {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "A conversation has at least two members"));
+    }
+    if new_hold.reason.trim().is_empty() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "No reason given"));
+    }
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    if let Some(id) = new_hold.conversation {
+        match pgdb::get_conversation(db, &CONFIG.db.tables.conversations, id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(error_reply(StatusCode::NOT_FOUND, "No such conversation")),
+            Err(e) => {
+                error!("Unable to look up conversation {}: {}", id, e);
+                return Ok(error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to look up the conversation",
+                ));
+            }
+        }
+    }
+
+    let mut hold = LegalHold {
+        hold_id: -1,
+        room: new_hold.room,
+        members: new_hold.members,
+        conversation_id: new_hold.conversation,
+        reason: new_hold.reason,
+        created_by: user,
+        created_on: Utc::now(),
+    };
+    match pgdb::insert_legal_hold(db, &CONFIG.db.tables.legal_holds, &hold).await {
+        Ok(id) => hold.hold_id = id,
+        Err(e) => {
+            error!("Unable to save legal hold: {}", e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save legal hold"));
+        }
+    }
+    info!("{} put legal hold {} in place: {}", hold.created_by, hold.hold_id, hold.reason);
+    Ok(reply::with_status(reply::json(&hold), StatusCode::CREATED))
+}
+
+pub async fn list_holds(_user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::list_legal_holds(db, &CONFIG.db.tables.legal_holds).await {
+        Ok(holds) => Ok(reply::with_status(reply::json(&holds), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to list legal holds: {}", e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to list legal holds"))
+        }
+    }
+}
+
+pub async fn delete_hold(
+    id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(no_db()),
+    };
+    match pgdb::delete_legal_hold(db, &CONFIG.db.tables.legal_holds, id).await {
+        Ok(0) => Ok(error_reply(StatusCode::NOT_FOUND, "No such legal hold")),
+        Ok(_) => {
+            info!("{} lifted legal hold {}", user, id);
+            Ok(reply::with_status(reply::json(&json!({ "deleted": id })), StatusCode::OK))
+        }
+        Err(e) => {
+            error!("Unable to delete legal hold {}: {}", id, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to delete legal hold"))
+        }
+    }
+}
+
+/// `GET /retention/report`: a dry run of the purge
+pub async fn report(_user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    match &state.db {
+        Some(db) => {
+            let report = run(db, &CONFIG.retention, true).await;
+            Ok(reply::with_status(reply::json(&report), StatusCode::OK))
+        }
+        None => Ok(no_db()),
+    }
+}
+
+/// The retention endpoints, all for admins only
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path!("retention" / "holds"))
+        .and(admin())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_hold);
+
+    let list = warp::get()
+        .and(warp::path!("retention" / "holds"))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(list_holds);
+
+    let delete = warp::delete()
+        .and(warp::path!("retention" / "holds" / i32))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(delete_hold);
+
+    let report = warp::get()
+        .and(warp::path!("retention" / "report"))
+        .and(admin())
+        .and(with_state)
+        .and_then(report);
+
+    create
+        .or(list)
+        .or(delete)
+        .or(report)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(room: Option<&str>, sender: &str, recipients: &[&str]) -> ChatMessage {
+        ChatMessage {
+            message_id: 1,
+            sender: sender.into(),
+            recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            delivered_to: recipients.iter().map(|r| r.to_string()).collect(),
+            room: room.map(String::from),
+            body: "hi".into(),
+            bot: false,
+            encrypted: false,
+            annotations: Default::default(),
+            sent_on: Utc.ymd(2020, 9, 30).and_hms(12, 0, 0),
+            conversation_id: None,
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut cfg = RetentionCfg::default();
+        let mut msg = message(Some("dnd"), "stoner", &["whammo"]);
+        assert_eq!(retention_days(&cfg, &msg), None);
+        assert_eq!(shortest_days(&cfg), None);
+
+        cfg.message_days = Some(365);
+        cfg.types.insert("bot".into(), 7);
+        cfg.rooms.insert("random".into(), 30);
+        assert_eq!(retention_days(&cfg, &msg), Some(365));
+        msg.bot = true;
+        assert_eq!(message_type(&msg), "bot");
+        assert_eq!(retention_days(&cfg, &msg), Some(7));
+        // The room wins over the type
+        msg.room = Some("random".into());
+        assert_eq!(retention_days(&cfg, &msg), Some(30));
+        assert_eq!(shortest_days(&cfg), Some(7));
+
+        msg.annotations.insert("integration".into(), "ci".into());
+        assert_eq!(message_type(&msg), "integration");
+    }
+
+    #[test]
+    fn test_holds() {
+        let hold = |room: Option<&str>, members: &[&str]| LegalHold {
+            hold_id: 1,
+            room: room.map(String::from),
+            members: members.iter().map(|m| m.to_string()).collect(),
+            conversation_id: None,
+            reason: "case 42".into(),
+            created_by: "admin".into(),
+            created_on: Utc::now(),
+        };
+        let room_hold = [hold(Some("dnd"), &[])];
+        assert!(is_held(&room_hold, &message(Some("dnd"), "stoner", &["whammo"])));
+        assert!(!is_held(&room_hold, &message(Some("random"), "stoner", &["whammo"])));
+        let lobby_hold = [hold(Some("lobby"), &[])];
+        assert!(is_held(&lobby_hold, &message(None, "stoner", &["whammo"])));
+
+        let dm_hold = [hold(None, &["stoner", "whammo"])];
+        assert!(is_held(&dm_hold, &message(None, "whammo", &["stoner"])));
+        assert!(!is_held(&dm_hold, &message(None, "whammo", &["rubik"])));
+        let mut grouped = message(None, "whammo", &["@group:mods"]);
+        grouped.delivered_to = vec!["stoner".into()];
+        assert!(is_held(&dm_hold, &grouped));
+        assert!(!is_held(&dm_hold, &message(Some("dnd"), "whammo", &["stoner"])));
+
+        // A group conversation is held by its id, whoever was in it
+        let group_hold = [LegalHold {
+            conversation_id: Some(12),
+            ..hold(None, &[])
+        }];
+        let mut in_group = message(None, "rubik", &["stoner", "whammo"]);
+        in_group.conversation_id = Some(12);
+        assert!(is_held(&group_hold, &in_group));
+        in_group.conversation_id = Some(13);
+        assert!(!is_held(&group_hold, &in_group));
+        assert!(!is_held(&group_hold, &message(None, "whammo", &["stoner"])));
+    }
+
+    #[test]
+    fn test_archive() {
+        let dir = std::env::temp_dir().join(format!("khadga-archive-{}", std::process::id()));
+        let time = Utc.ymd(2020, 10, 1).and_hms(0, 0, 0);
+        let msgs = [message(None, "stoner", &[]), message(Some("dnd"), "whammo", &[])];
+        archive_messages(&dir, time, &msgs[..1]).unwrap();
+        archive_messages(&dir, time, &msgs[1..]).unwrap();
+
+        let text = fs::read_to_string(dir.join("messages-2020-10-01.jsonl")).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purge() -> Result<(), tokio_postgres::Error> {
+        let db = pgdb::connect("test_db").await?;
+        let table = "test_messages_purge";
+        pgdb::drop_table(table, &db).await?;
+        pgdb::make_table_messages(table, &db).await?;
+
+        let now = Utc::now();
+        let ages = [(Some("dnd"), 40), (Some("random"), 40), (Some("random"), 10), (None, 40)];
+        for (room, days) in &ages {
+            let mut msg = message(*room, "stoner", &["whammo"]);
+            msg.sent_on = now - Duration::days(*days);
+            pgdb::insert_message(&db, table, &msg).await?;
+        }
+
+        let mut cfg = RetentionCfg {
+            batch_size: 1,
+            ..Default::default()
+        };
+        cfg.rooms.insert("random".into(), 30);
+        cfg.rooms.insert("lobby".into(), 30);
+        let holds = [LegalHold {
+            hold_id: 1,
+            room: Some("lobby".into()),
+            members: vec![],
+            conversation_id: None,
+            reason: "case 42".into(),
+            created_by: "admin".into(),
+            created_on: now,
+        }];
+
+        let mut report = Report::new(true, now);
+        purge_messages(&db, table, &cfg, &holds, &mut report).await.unwrap();
+        assert_eq!((report.messages, report.held), (1, 1));
+        assert_eq!(report.by_room["random"], 1);
+        let left = pgdb::list_messages_before(&db, table, &now, 0, 10).await?;
+        assert_eq!(left.len(), 4);
+
+        let mut report = Report::new(false, now);
+        purge_messages(&db, table, &cfg, &holds, &mut report).await.unwrap();
+        assert_eq!(report.messages, 1);
+        let left = pgdb::list_messages_before(&db, table, &now, 0, 10).await?;
+        assert_eq!(left.len(), 3);
+
+        pgdb::drop_table(table, &db).await?;
+        Ok(())
+    }
+}
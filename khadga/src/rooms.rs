@@ -0,0 +1,131 @@
+//! Named chat rooms
+//!
+//! A room is a named group of connected users with an optional topic.  Every user is put in the
+//! `lobby` room when they connect, and removed from all their rooms when they disconnect.  A
+//! `message::Message` says which room it belongs to with its `room` field, and if it doesn't name
+//! one, it belongs to the lobby.
+
+use chrono::{DateTime,
+             Utc};
+use serde::{Deserialize,
+            Serialize};
+use std::{collections::{HashMap,
+                        HashSet},
+          sync::Arc};
+use tokio::sync::Mutex;
+
+/// The room everybody is in
+pub const LOBBY: &str = "lobby";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Room {
+    pub name: String,
+    pub topic: Option<Topic>,
+    pub members: HashSet<String>,
+}
+
+impl Room {
+    pub fn new(name: &str) -> Self {
+        Room {
+            name: name.into(),
+            topic: None,
+            members: HashSet::new(),
+        }
+    }
+}
+
+/// All the rooms that currently exist, keyed by name
+pub type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+
+pub fn new_rooms() -> Rooms {
+    let mut rooms = HashMap::new();
+    rooms.insert(LOBBY.to_string(), Room::new(LOBBY));
+    Arc::new(Mutex::new(rooms))
+}
+
+/// Adds the user to a room, creating the room if it doesn't exist yet
+pub async fn join(rooms: &Rooms, room: &str, user: &str) {
+    let mut rooms = rooms.lock().await;
+    rooms
+        .entry(room.to_string())
+        .or_insert_with(|| Room::new(room))
+        .members
+        .insert(user.to_string());
+}
+
+/// Removes the user from one room, returning false if they weren't in it.  If that leaves the room
+/// empty (and it isn't the lobby), the room is dropped.
+pub async fn leave(rooms: &Rooms, room: &str, user: &str) -> bool {
+    let mut rooms = rooms.lock().await;
+    let (left, empty) = match rooms.get_mut(room) {
+        Some(r) => (r.members.remove(user), r.members.is_empty()),
+        None => return false,
+    };
+    if empty && room != LOBBY {
+        rooms.remove(room);
+    }
+    left
+}
+
+/// Removes the user from every room they are in.  Empty rooms (other than the lobby) are dropped.
+pub async fn leave_all(rooms: &Rooms, user: &str) {
+    let mut rooms = rooms.lock().await;
+    for room in rooms.values_mut() {
+        room.members.remove(user);
+    }
+    rooms.retain(|name, room| name == LOBBY || !room.members.is_empty());
+}
+
+/// Returns the sorted member list of the room, or None if there is no such room
+pub async fn members(rooms: &Rooms, room: &str) -> Option<Vec<String>> {
+    let rooms = rooms.lock().await;
+    rooms.get(room).map(|r| {
+        let mut members: Vec<String> = r.members.iter().cloned().collect();
+        members.sort();
+        members
+    })
+}
+
+/// Returns the sorted names of the rooms the user is in
+pub async fn rooms_of(rooms: &Rooms, user: &str) -> Vec<String> {
+    let rooms = rooms.lock().await;
+    let mut names: Vec<String> = rooms
+        .values()
+        .filter(|r| r.members.contains(user))
+        .map(|r| r.name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_join_and_leave() {
+        let rooms = new_rooms();
+        join(&rooms, LOBBY, "stoner").await;
+        join(&rooms, "dnd", "stoner").await;
+        join(&rooms, "dnd", "whammo").await;
+
+        assert_eq!(members(&rooms, "dnd").await, Some(vec!["stoner".into(), "whammo".into()]));
+        assert_eq!(rooms_of(&rooms, "stoner").await, vec!["dnd".to_string(), LOBBY.to_string()]);
+
+        assert!(leave(&rooms, "dnd", "whammo").await);
+        assert!(!leave(&rooms, "dnd", "whammo").await);
+        join(&rooms, "dnd", "whammo").await;
+
+        leave_all(&rooms, "stoner").await;
+        leave_all(&rooms, "whammo").await;
+        assert_eq!(members(&rooms, "dnd").await, None);
+        assert_eq!(members(&rooms, LOBBY).await, Some(vec![]));
+    }
+}
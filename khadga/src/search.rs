@@ -0,0 +1,219 @@
+//! Searching the chat history
+//!
+//! The saved messages (see `chat::post`) can be searched with `GET /search` or with the `/search`
+//! slash command.  Both use Postgres full text search, so the terms can use the websearch syntax:
+//! `"exact phrase"`, `-excluded` and `or`.
+//!
+//! People can only find messages they sent or received.  Each saved message keeps who it was
+//! delivered to, so this holds even for rooms they have since left.  The slash command searches as
+//! the user the chat connection belongs to, which the `jwt` cookie vouches for (see `chat`).
+//!
+//! The REST endpoint takes these query parameters:
+//!
+//! - `q`: the search terms
+//! - `room`: only messages in this room
+//! - `with`: only direct messages with this user
+//! - `sender`: only messages from this user
+//! - `since` and `until`: a date (`2020-09-30`) or an RFC 3339 time
+//! - `limit`: the most hits to return, 20 by default
+//!
+//! The slash command takes the same filters as `name:value` words, eg
+//! `/search room:dnd sender:whammo since:2020-09-01 dice`
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::ChatState,
+            commands::{CommandContext,
+                       SlashCommand},
+            pgdb::{models::{MessageQuery,
+                            SearchHit},
+                   pgdb},
+            reply::error_reply};
+use async_trait::async_trait;
+use chrono::{DateTime,
+             NaiveDate,
+             TimeZone,
+             Utc};
+use log::error;
+use serde::Deserialize;
+use serde_json::{json,
+                 Value};
+use std::convert::Infallible;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+/// Parses a date (which means midnight UTC) or an RFC 3339 time
+pub fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+        .map_err(|_| format!("{} is not a date (like 2020-09-30) or an RFC 3339 time", text))
+}
+
+/// The query string of `GET /search`
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchParams {
+    pub q: String,
+    pub room: Option<String>,
+    pub with: Option<String>,
+    pub sender: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<i64>,
+}
+
+impl SearchParams {
+    /// Checks the parameters and turns them into a `MessageQuery`
+    pub fn to_query(&self) -> Result<MessageQuery, String> {
+        if self.q.trim().is_empty() {
+            return Err("Nothing to search for".into());
+        }
+        Ok(MessageQuery {
+            text: self.q.clone(),
+            room: self.room.clone(),
+            with: self.with.clone(),
+            sender: self.sender.clone(),
+            since: self.since.as_deref().map(parse_time).transpose()?,
+            until: self.until.as_deref().map(parse_time).transpose()?,
+            limit: self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        })
+    }
+
+    /// Builds the parameters from the words of a `/search` command
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut params = SearchParams::default();
+        let mut terms = vec![];
+        for arg in args {
+            let (name, value) = match arg.find(':') {
+                Some(i) => (&arg[..i], arg[i + 1..].to_string()),
+                None => ("", arg.clone()),
+            };
+            match name {
+                "room" => params.room = Some(value),
+                "with" => params.with = Some(value),
+                "sender" => params.sender = Some(value),
+                "since" => params.since = Some(value),
+                "until" => params.until = Some(value),
+                "limit" => {
+                    params.limit = Some(value.parse().map_err(|_| format!("Bad limit {}", value))?)
+                }
+                // Anything else, including words that just happen to have a : in them
+                _ => terms.push(arg.clone()),
+            }
+        }
+        params.q = terms.join(" ");
+        Ok(params)
+    }
+}
+
+/// Runs the search for the caller
+pub async fn search(
+    state: &ChatState,
+    caller: &str,
+    query: &MessageQuery,
+) -> Result<Vec<SearchHit>, String> {
+    let db = state.db.as_ref().ok_or("Chat history is not available")?;
+    pgdb::search_messages(db, &CONFIG.db.tables.messages, caller, query)
+        .await
+        .map_err(|e| {
+            error!("Search for {} failed: {}", caller, e);
+            "Search failed".to_string()
+        })
+}
+
+/// `GET /search`
+pub async fn search_handler(
+    caller: String,
+    params: SearchParams,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let query = match params.to_query() {
+        Ok(query) => query,
+        Err(e) => return Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    };
+    match search(&state, &caller, &query).await {
+        Ok(hits) => Ok(reply::with_status(reply::json(&hits), StatusCode::OK)),
+        Err(e) => Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, &e)),
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    warp::get()
+        .and(warp::path("search"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::query::<SearchParams>())
+        .and(with_state)
+        .and_then(search_handler)
+        .boxed()
+}
+
+/// Searches the chat history from the chat window
+pub struct Search {}
+
+#[async_trait]
+impl SlashCommand for Search {
+    fn name(&self) -> &'static str {
+        "search"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/search [room:<room>] [with:<user>] [sender:<user>] [since:<date>] [until:<date>] <terms>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Search the messages you have sent and received"
+    }
+
+    fn arity(&self) -> (usize, Option<usize>) {
+        (1, None)
+    }
+
+    async fn run(&self, ctx: &CommandContext<'_>, args: Vec<String>) -> Result<Value, String> {
+        let query = SearchParams::from_args(&args)?.to_query()?;
+        let hits = search(ctx.state, ctx.caller, &query).await?;
+        Ok(json!({ "hits": hits }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params() {
+        let args: Vec<String> = vec!["room:dnd", "sender:whammo", "since:2020-09-01", "the", "dice"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let query = SearchParams::from_args(&args).unwrap().to_query().unwrap();
+        assert_eq!(query.text, "the dice");
+        assert_eq!(query.room, Some("dnd".into()));
+        assert_eq!(query.sender, Some("whammo".into()));
+        assert_eq!(query.since, Some(Utc.ymd(2020, 9, 1).and_hms(0, 0, 0)));
+        assert_eq!(query.limit, DEFAULT_LIMIT);
+
+        let args = vec!["limit:1000".to_string(), "dice".to_string()];
+        assert_eq!(SearchParams::from_args(&args).unwrap().to_query().unwrap().limit, MAX_LIMIT);
+
+        let args = vec!["room:dnd".to_string()];
+        assert!(SearchParams::from_args(&args).unwrap().to_query().is_err());
+        let args = vec!["until:yesterday".to_string(), "dice".to_string()];
+        assert!(SearchParams::from_args(&args).unwrap().to_query().is_err());
+
+        let time = parse_time("2020-09-30T12:00:00+02:00").unwrap();
+        assert_eq!(time, Utc.ymd(2020, 9, 30).and_hms(10, 0, 0));
+    }
+}
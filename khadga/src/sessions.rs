@@ -0,0 +1,178 @@
+//! Live connections, for the admins
+//!
+//! Everybody connected to the chat (people and bots) has a session, keyed by their name.  These
+//! endpoints are for the `admins` in the config only:
+//!
+//! - `GET /admin/sessions` lists the sessions, oldest login first
+//! - `GET /admin/sessions/<user>` gets one
+//! - `DELETE /admin/sessions/<user>` closes the connection.  The others are told the user left, the
+//!   same as when they close the tab.  Nothing stops them from connecting again.
+//!
+//! A session has the login time, when the user last sent something, how many bytes of messages
+//! they have sent, and the remote address and user agent of the connection.  Behind a proxy, the
+//! remote address is the proxy's.
+
+use crate::{auth::admin,
+            chat::ChatState,
+            reply::error_reply,
+            state::{Session,
+                    Users}};
+use log::info;
+use serde_json::json;
+use std::convert::Infallible;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           ws::Message,
+           Filter,
+           Reply};
+
+/// Every connected session
+pub async fn list_sessions(users: &Users) -> Vec<Session> {
+    let mut sessions: Vec<Session> = users
+        .lock()
+        .await
+        .iter()
+        .map(|(name, info)| info.session(name))
+        .collect();
+    sessions.sort_by(|a, b| a.login_time.cmp(&b.login_time).then(a.username.cmp(&b.username)));
+    sessions
+}
+
+pub async fn get_session(users: &Users, username: &str) -> Option<Session> {
+    users.lock().await.get(username).map(|info| info.session(username))
+}
+
+/// Ends the user's connection, telling them why.  Returns false if they aren't connected.
+///
+/// The client is sent a close frame, and the connection stops reading from it right away, so it
+/// doesn't matter whether the client answers.
+pub async fn disconnect(users: &Users, username: &str, reason: &str) -> bool {
+    let mut list = users.lock().await;
+    let info = match list.get_mut(username) {
+        Some(info) => info,
+        None => return false,
+    };
+    if let Some(tx) = &info.sender {
+        let _ = tx.send(Ok(Message::close_with(1008u16, reason.to_string())));
+    }
+    match info.disconnect.take() {
+        Some(disconnect) => {
+            let _ = disconnect.send(());
+        }
+        // Not a websocket we are reading from, so dropping it is all there is to do
+        None => {
+            list.remove(username);
+        }
+    }
+    true
+}
+
+/// `GET /admin/sessions`
+pub async fn sessions(_admin: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let sessions = list_sessions(&state.users).await;
+    Ok(reply::with_status(reply::json(&sessions), StatusCode::OK))
+}
+
+/// `GET /admin/sessions/<user>`
+pub async fn session(
+    username: String,
+    _admin: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    match get_session(&state.users, &username).await {
+        Some(session) => Ok(reply::with_status(reply::json(&session), StatusCode::OK)),
+        None => Ok(error_reply(StatusCode::NOT_FOUND, "No such session")),
+    }
+}
+
+/// `DELETE /admin/sessions/<user>`
+pub async fn end_session(
+    username: String,
+    admin: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if disconnect(&state.users, &username, "Disconnected by an admin").await {
+        info!("{} disconnected {}", admin, username);
+        let body = json!({ "disconnected": username });
+        Ok(reply::with_status(reply::json(&body), StatusCode::OK))
+    } else {
+        Ok(error_reply(StatusCode::NOT_FOUND, "No such session"))
+    }
+}
+
+/// The session endpoints, all for admins only
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let list = warp::get()
+        .and(warp::path!("admin" / "sessions"))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(sessions);
+
+    let get = warp::get()
+        .and(warp::path!("admin" / "sessions" / String))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(session);
+
+    let delete = warp::delete()
+        .and(warp::path!("admin" / "sessions" / String))
+        .and(admin())
+        .and(with_state)
+        .and_then(end_session);
+
+    list.or(get)
+        .or(delete)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{MessageInventory,
+                       Peer,
+                       UserInfo};
+    use std::{collections::HashMap,
+              sync::Arc};
+    use tokio::sync::{mpsc,
+                      oneshot,
+                      Mutex};
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let users: Users = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let mut info = UserInfo::new(Some(tx));
+        info.peer = Peer {
+            remote_addr: Some("10.0.0.7:51234".parse().unwrap()),
+            user_agent: Some("Firefox".into()),
+        };
+        info.disconnect = Some(disconnect_tx);
+        let info = info + MessageInventory::new(42, None);
+        users.lock().await.insert("stoner".into(), info);
+        users.lock().await.insert("whammo".into(), UserInfo::new(None));
+
+        let sessions = list_sessions(&users).await;
+        assert_eq!(sessions.len(), 2);
+        let stoner = get_session(&users, "stoner").await.expect("stoner is connected");
+        assert_eq!(stoner.bytes_sent, 42);
+        assert_eq!(stoner.user_agent.as_deref(), Some("Firefox"));
+        assert_eq!(stoner.remote_addr.map(|a| a.port()), Some(51234));
+        assert!(get_session(&users, "rubik").await.is_none());
+
+        assert!(disconnect(&users, "stoner", "Bye").await);
+        assert!(rx.recv().await.unwrap().unwrap().is_close());
+        assert_eq!(disconnect_rx.await, Ok(()));
+        // The connection cleans up after itself once it stops reading
+        assert!(get_session(&users, "stoner").await.is_some());
+
+        assert!(disconnect(&users, "whammo", "Bye").await);
+        assert!(get_session(&users, "whammo").await.is_none());
+        assert!(!disconnect(&users, "rubik", "Bye").await);
+    }
+}
@@ -1,7 +1,879 @@
 //! Code for getting the signaling server to work.  The signaling server acts as a rendezvous point
 //! and relay for 2 remote systems to discover each other and communicate peer to peer.  This is
-//! needed due to some systems possibly being behind a NAT or other firewall.  For now, we will not
-//! implement a TURN server, and only implement STUN.  This means that the remote systems must allow
-//! a remote system to connect to it, even if it has not connected with the other remote system first.
+//! needed due to some systems possibly being behind a NAT or other firewall.  khadga can also be
+//! the STUN server the clients use to find their own addresses (see `stun`), and the TURN relay
+//! for the ones who can't reach each other directly (see `turn`).
+//!
+//! A call is between two people, and goes through these states:
+//!
+//! - `Ringing`: the caller sent a `Call` command with the `Invite` action to the callee (or, like
+//!   older clients do, just sent them an `SDPOffer`).  If the callee is already in a call, the call
+//!   is `Busy` instead, and ends right away.
+//! - `Accepted`: the callee sent the `Accept` action (or an `SDPAnswer` to the caller's offer)
+//! - `Rejected`: the callee sent the `Reject` action instead
+//! - `Missed`: nobody answered before the ring timeout (`calls.ring_timeout_secs` in the settings)
+//! - `Ended`: either of them sent the `HangUp` action, or disconnected
+//!
+//! Every change goes to both of them as a `Call` event (a busy call only goes to the caller).  The
+//! `Accept`, `Reject` and `HangUp` actions are for the call the sender is in, and the command's
+//! `id` can be left empty.  Calls that are over are saved, with how long they lasted.
+//!
+//! While the call is ringing or accepted, the two of them (and only them) can send each other
+//! `SDPOffer`s, `SDPAnswer`s and `IceCandidate`s.  Every signaling command is checked before it is
+//! relayed:
+//!
+//! - it has to be for exactly one other person, who is connected
+//! - an offer that starts a call can't come from somebody already in a call
+//! - anything else has to be between the two people in the call, and an answer has to come from
+//!   whoever got the last offer
+//! - the session description has to be the right type (`offer` or `answer`), and neither it nor
+//!   the ICE candidate can be bigger than `MAX_SDP_LEN`
+//!
+//! khadga gives each call an id, which goes out as the `id` of every command relayed for it.  A
+//! command that fails the checks isn't relayed, and the sender gets a `Rejected` event from the
+//! `signaling` filter instead.
+//!
+//! Calls with everybody in a room work differently, see `mesh`.  You can only be in one call at a
+//! time, of either kind.
+//!
+//! `GET /calls` lists the calls you made or got that are over, the most recent first.
 
-pub struct ChatWS {}
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::{relay,
+                   send_to,
+                   ChatState},
+            config::CallCfg,
+            filter::Rejection,
+            mesh::{self,
+                   Meshes},
+            message::{Message as KMessage,
+                      MessageEvent},
+            pgdb::{models::CallRecord,
+                   pgdb},
+            reply::error_reply,
+            util::random_id,
+            wire::{Envelope,
+                   Event,
+                   Request}};
+use chrono::{DateTime,
+             Utc};
+use log::{debug,
+          error,
+          info};
+use serde::{Deserialize,
+            Serialize};
+use std::{collections::HashMap,
+          convert::Infallible,
+          fmt::{self,
+                Display,
+                Formatter},
+          sync::Arc,
+          time::Duration};
+use tokio::{sync::Mutex,
+            time::delay_for};
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// The biggest session description or ICE candidate that is relayed, in bytes
+pub const MAX_SDP_LEN: usize = 64 * 1024;
+/// How many calls `GET /calls` returns
+const LIST_LIMIT: i64 = 100;
+
+/// The kinds of signaling command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Offer,
+    Answer,
+    Candidate,
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Signal::Offer => write!(f, "SDPOffer"),
+            Signal::Answer => write!(f, "SDPAnswer"),
+            Signal::Candidate => write!(f, "IceCandidate"),
+        }
+    }
+}
+
+/// What somebody can do with a call, as the args of a `Call` command
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CallAction {
+    /// Calls the (one) recipient
+    Invite,
+    Accept,
+    Reject,
+    HangUp,
+    /// Joins the call in the message's room (see `mesh`)
+    Join,
+    /// Leaves the call in the message's room
+    Leave,
+}
+
+/// Where a call is at.  The first two are while it is going on, the rest are how it ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CallState {
+    Ringing,
+    Accepted,
+    Rejected,
+    /// The callee was already in a call
+    Busy,
+    /// Nobody answered before the ring timeout
+    Missed,
+    /// One of them hung up or disconnected
+    Ended,
+}
+
+impl CallState {
+    pub fn is_over(self) -> bool {
+        !matches!(self, CallState::Ringing | CallState::Accepted)
+    }
+}
+
+/// The body of a `Call` event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CallEvent {
+    pub id: String,
+    pub caller: String,
+    pub callee: String,
+    pub state: CallState,
+    /// When the caller called, in milliseconds
+    pub started: i64,
+    /// Seconds from when the call was accepted to when it ended, once it is over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<i64>,
+    /// Why it ended, eg "whammo hung up"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub id: String,
+    pub caller: String,
+    pub callee: String,
+    pub state: CallState,
+    /// Who has to answer the outstanding offer, if there is one
+    pub answerer: Option<String>,
+    pub started: DateTime<Utc>,
+    pub accepted: Option<DateTime<Utc>>,
+    pub ended: Option<DateTime<Utc>>,
+    pub reason: Option<String>,
+}
+
+impl Call {
+    fn new(caller: &str, callee: &str) -> Self {
+        Call {
+            id: random_id(8),
+            caller: caller.into(),
+            callee: callee.into(),
+            state: CallState::Ringing,
+            answerer: None,
+            started: Utc::now(),
+            accepted: None,
+            ended: None,
+            reason: None,
+        }
+    }
+
+    pub fn has(&self, user: &str) -> bool {
+        self.caller == user || self.callee == user
+    }
+
+    /// The other person in the call
+    pub fn other(&self, user: &str) -> &str {
+        if self.caller == user {
+            &self.callee
+        } else {
+            &self.caller
+        }
+    }
+
+    fn accept(&mut self) {
+        self.state = CallState::Accepted;
+        self.accepted = Some(Utc::now());
+    }
+
+    fn finish(&mut self, state: CallState, reason: String) {
+        self.state = state;
+        self.ended = Some(Utc::now());
+        self.reason = Some(reason);
+    }
+
+    /// Seconds from when it was accepted to when it ended, once it is over
+    pub fn duration(&self) -> Option<i64> {
+        let ended = self.ended?;
+        Some(self.accepted.map_or(0, |accepted| (ended - accepted).num_seconds()))
+    }
+
+    pub fn event(&self) -> CallEvent {
+        CallEvent {
+            id: self.id.clone(),
+            caller: self.caller.clone(),
+            callee: self.callee.clone(),
+            state: self.state,
+            started: self.started.timestamp_millis(),
+            duration: self.duration(),
+            reason: self.reason.clone(),
+        }
+    }
+
+    pub fn record(&self) -> CallRecord {
+        CallRecord {
+            call_id: self.id.clone(),
+            caller: self.caller.clone(),
+            callee: self.callee.clone(),
+            state: format!("{:?}", self.state),
+            started: self.started,
+            accepted: self.accepted,
+            ended: self.ended.unwrap_or_else(Utc::now),
+            duration: self.duration().unwrap_or(0),
+        }
+    }
+}
+
+/// The calls going on, and who is in which
+#[derive(Debug)]
+pub struct Calls {
+    calls: HashMap<String, Call>,
+    by_user: HashMap<String, String>,
+    /// How long a call rings before it is missed
+    pub ring_timeout: Duration,
+    /// The calls with everybody in a room
+    pub meshes: Meshes,
+}
+
+pub type CallTable = Arc<Mutex<Calls>>;
+
+impl Calls {
+    pub fn new(cfg: CallCfg) -> Self {
+        Calls {
+            calls: HashMap::new(),
+            by_user: HashMap::new(),
+            ring_timeout: Duration::from_secs(cfg.ring_timeout_secs),
+            meshes: Meshes::new(cfg.max_participants),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Call> {
+        self.calls.get(id)
+    }
+
+    /// The call the user is in, if any
+    pub fn call_of(&self, user: &str) -> Option<&Call> {
+        self.by_user.get(user).and_then(|id| self.calls.get(id))
+    }
+
+    /// Whether the user is in a call of either kind
+    pub fn in_call(&self, user: &str) -> bool {
+        self.by_user.contains_key(user) || self.meshes.room_of(user).is_some()
+    }
+
+    /// Adds the user to the room's call, unless they are in a call with one person
+    pub fn join_mesh(&mut self, user: &str, room: &str) -> Result<mesh::Mesh, String> {
+        if self.by_user.contains_key(user) {
+            return Err("You are already in a call".into());
+        }
+        self.meshes.join(user, room)
+    }
+
+    /// Starts a call.  If the callee is busy, the call comes back already over.
+    pub fn invite(&mut self, from: &str, to: &str) -> Result<Call, String> {
+        if from == to {
+            return Err("You can't call yourself".into());
+        }
+        if self.in_call(from) {
+            return Err("You are already in a call".into());
+        }
+        let mut call = Call::new(from, to);
+        if self.in_call(to) {
+            call.finish(CallState::Busy, format!("{} is in another call", to));
+            return Ok(call);
+        }
+        info!("{} is calling {} (call {})", from, to, call.id);
+        self.by_user.insert(from.into(), call.id.clone());
+        self.by_user.insert(to.into(), call.id.clone());
+        self.calls.insert(call.id.clone(), call.clone());
+        Ok(call)
+    }
+
+    /// Accepts, rejects or hangs up the user's call.  `id` is the call's id, or empty.
+    pub fn act(&mut self, user: &str, id: &str, action: CallAction) -> Result<Call, String> {
+        let call = match self.call_of(user) {
+            Some(call) if id.is_empty() || call.id == id => call,
+            _ => return Err("You are not in that call".into()),
+        };
+        let ringing_me = call.state == CallState::Ringing && call.callee == user;
+        let id = call.id.clone();
+        match action {
+            CallAction::Accept if ringing_me => {
+                let call = self.calls.get_mut(&id).expect("Calls and by_user agree");
+                call.accept();
+                Ok(call.clone())
+            }
+            CallAction::Reject if ringing_me => {
+                let reason = format!("{} rejected the call", user);
+                Ok(self.end(&id, CallState::Rejected, reason).expect("The call exists"))
+            }
+            CallAction::Accept | CallAction::Reject => Err("There is no call to answer".into()),
+            CallAction::HangUp => {
+                let reason = format!("{} hung up", user);
+                Ok(self.end(&id, CallState::Ended, reason).expect("The call exists"))
+            }
+            CallAction::Invite => Err("You are already in a call".into()),
+            CallAction::Join | CallAction::Leave => Err("That is for room calls".into()),
+        }
+    }
+
+    /// Checks a signaling command from `from` to `to`, and updates the call it is for
+    ///
+    /// An offer to somebody you aren't in a call with invites them.  Returns the call, or why the
+    /// command can't be relayed.
+    pub fn signal(&mut self, from: &str, to: &str, signal: Signal) -> Result<Call, String> {
+        let id = match self.call_of(from) {
+            Some(call) if call.has(to) && from != to => call.id.clone(),
+            Some(_) => return Err("You are already in a call".into()),
+            None if signal == Signal::Offer && self.meshes.room_of(from).is_some() => {
+                return Err("You are already in a call".into())
+            }
+            None if signal == Signal::Offer => {
+                let call = self.invite(from, to)?;
+                if call.state.is_over() {
+                    return Ok(call);
+                }
+                call.id
+            }
+            None => return Err(format!("You are not in a call with {}", to)),
+        };
+
+        let call = self.calls.get_mut(&id).expect("Calls and by_user agree");
+        match signal {
+            // Either side can renegotiate
+            Signal::Offer => call.answerer = Some(to.into()),
+            Signal::Answer if call.answerer.as_deref() == Some(from) => {
+                call.answerer = None;
+                if call.state == CallState::Ringing && call.callee == from {
+                    call.accept();
+                }
+            }
+            Signal::Answer => return Err("There is no offer to answer".into()),
+            Signal::Candidate => {}
+        }
+        Ok(call.clone())
+    }
+
+    fn end(&mut self, id: &str, state: CallState, reason: String) -> Option<Call> {
+        let mut call = self.calls.remove(id)?;
+        self.by_user.remove(&call.caller);
+        self.by_user.remove(&call.callee);
+        call.finish(state, reason);
+        Some(call)
+    }
+
+    /// Misses the call, if it is still ringing
+    pub fn time_out(&mut self, id: &str) -> Option<Call> {
+        match self.calls.get(id)?.state {
+            CallState::Ringing => self.end(id, CallState::Missed, "Nobody answered".into()),
+            _ => None,
+        }
+    }
+
+    /// Ends whatever call the user is in, eg because they disconnected
+    pub fn leave(&mut self, user: &str) -> Option<Call> {
+        let id = self.by_user.get(user)?.clone();
+        self.end(&id, CallState::Ended, format!("{} disconnected", user))
+    }
+}
+
+/// What kind of signaling command this is, after checking what it carries
+pub fn check_request(request: &Request) -> Result<Signal, String> {
+    let (signal, kind, len) = match request {
+        Request::SDPOffer { description, .. } => {
+            (Signal::Offer, Some((&description.kind, "offer")), description.sdp.len())
+        }
+        Request::SDPAnswer { description, .. } => {
+            (Signal::Answer, Some((&description.kind, "answer")), description.sdp.len())
+        }
+        Request::IceCandidate { candidate, .. } => {
+            (Signal::Candidate, None, candidate.candidate.len())
+        }
+        _ => return Err("Not a signaling command".into()),
+    };
+    match kind {
+        Some((kind, expected)) if kind != expected => {
+            Err(format!("A {} needs a session description of type {}", signal, expected))
+        }
+        _ if len > MAX_SDP_LEN => Err(format!("The {} is too big", signal)),
+        _ => Ok(signal),
+    }
+}
+
+/// The same command, with the call's id
+fn with_call_id(request: &Request, call_id: &str) -> Request {
+    let mut request = request.clone();
+    match &mut request {
+        Request::SDPOffer { id, .. }
+        | Request::SDPAnswer { id, .. }
+        | Request::IceCandidate { id, .. } => *id = call_id.to_string(),
+        _ => {}
+    }
+    request
+}
+
+/// The one person a command is for, if they are connected
+pub async fn recipient(state: &ChatState, envelope: &Envelope) -> Result<String, String> {
+    let to = match envelope.recipients.as_slice() {
+        [to] => to,
+        _ => return Err("Calls are with exactly one other person".into()),
+    };
+    if !state.users.lock().await.contains_key(to) {
+        return Err(format!("{} is not connected", to));
+    }
+    Ok(to.clone())
+}
+
+/// Tells the sender why their command wasn't relayed
+pub async fn reject(state: &ChatState, my_id: &str, envelope: &Envelope, reason: String) {
+    info!("{}: signaling rejected: {}", my_id, reason);
+    let rejection = Rejection::new("signaling", reason, &envelope.message(String::new()));
+    let to = vec![my_id.to_string()];
+    let reply = KMessage::new("khadga".into(), to, MessageEvent::Rejected, rejection);
+    send_to(&state.users, my_id, &reply).await;
+}
+
+/// Sends the call's state to both people in it, and saves it if it is over
+async fn announce(state: &ChatState, call: &Call) {
+    let mut to = vec![call.caller.clone()];
+    if call.state != CallState::Busy {
+        to.push(call.callee.clone());
+    }
+    let mesg = KMessage::new("khadga".into(), to.clone(), MessageEvent::Call, call.event());
+    for user in &to {
+        send_to(&state.users, user, &mesg).await;
+    }
+
+    if let (true, Some(db)) = (call.state.is_over(), &state.db) {
+        info!("Call {} is over: {:?}", call.id, call.reason);
+        if let Err(e) = pgdb::insert_call(db, &CONFIG.db.tables.calls, &call.record()).await {
+            error!("Unable to save call {}: {}", call.id, e);
+        }
+    }
+}
+
+/// Tells both people about their call if its state changed, and starts it ringing if it is new
+async fn changed(state: &ChatState, before: Option<CallState>, call: &Call) {
+    if before == Some(call.state) {
+        return;
+    }
+    announce(state, call).await;
+    if before.is_none() && call.state == CallState::Ringing {
+        let timeout = state.calls.lock().await.ring_timeout;
+        let (state, id) = (state.clone(), call.id.clone());
+        tokio::spawn(async move {
+            delay_for(timeout).await;
+            let missed = state.calls.lock().await.time_out(&id);
+            if let Some(call) = missed {
+                announce(&state, &call).await;
+            }
+        });
+    }
+}
+
+/// Relays a signaling command that passed the checks, with the call's id
+pub async fn forward(
+    state: &ChatState,
+    my_id: &str,
+    envelope: &Envelope,
+    request: &Request,
+    call_id: &str,
+) {
+    debug!("{}: relaying signaling for call {}", my_id, call_id);
+    let mut relayed = envelope.clone();
+    relayed.sender = my_id.to_string();
+    relayed.event = Event::CommandRequest(with_call_id(request, call_id));
+    relay(state, &relayed.to_legacy()).await;
+}
+
+/// Checks a signaling command from a connected user, then relays it to the other side of the call
+pub async fn handle(state: &ChatState, my_id: &str, envelope: &Envelope) {
+    let request = match &envelope.event {
+        Event::CommandRequest(request) => request,
+        _ => return,
+    };
+    if let Some(room) = &envelope.room {
+        return mesh::handle(state, my_id, envelope, room).await;
+    }
+
+    let checked = async {
+        let signal = check_request(request)?;
+        let to = recipient(state, envelope).await?;
+        let mut calls = state.calls.lock().await;
+        let before = calls.call_of(my_id).filter(|call| call.has(&to)).map(|call| call.state);
+        calls.signal(my_id, &to, signal).map(|call| (before, call))
+    };
+
+    match checked.await {
+        Ok((before, call)) => {
+            changed(state, before, &call).await;
+            if call.state.is_over() {
+                return;
+            }
+            forward(state, my_id, envelope, request, &call.id).await;
+        }
+        Err(reason) => reject(state, my_id, envelope, reason).await,
+    }
+}
+
+/// Runs a `Call` command from a connected user
+pub async fn call(
+    state: &ChatState,
+    my_id: &str,
+    envelope: &Envelope,
+    id: &str,
+    action: CallAction,
+) {
+    let result = match action {
+        CallAction::Join | CallAction::Leave => {
+            return mesh::action(state, my_id, envelope, action).await
+        }
+        CallAction::Invite => match recipient(state, envelope).await {
+            Ok(to) => state.calls.lock().await.invite(my_id, &to).map(|call| (None, call)),
+            Err(e) => Err(e),
+        },
+        action => {
+            let mut calls = state.calls.lock().await;
+            let before = calls.call_of(my_id).map(|call| call.state);
+            calls.act(my_id, id, action).map(|call| (before, call))
+        }
+    };
+
+    match result {
+        Ok((before, call)) => changed(state, before, &call).await,
+        Err(reason) => reject(state, my_id, envelope, reason).await,
+    }
+}
+
+/// Ends the call of a user who left, or takes them out of their room's call
+pub async fn leave(state: &ChatState, user: &str) {
+    mesh::leave(state, user).await;
+    let left = state.calls.lock().await.leave(user);
+    if let Some(call) = left {
+        announce(state, &call).await;
+    }
+}
+
+/// Creates the table the calls are saved in
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let table = &CONFIG.db.tables.calls;
+    if let Err(e) = pgdb::make_table_calls(table, db).await {
+        error!("Unable to create the {} table: {}", table, e);
+    }
+}
+
+/// `GET /calls`
+pub async fn list_calls(user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")),
+    };
+    match pgdb::list_calls(db, &CONFIG.db.tables.calls, &user, LIST_LIMIT).await {
+        Ok(list) => Ok(reply::with_status(reply::json(&list), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to list the calls of {}: {}", user, e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read calls"))
+        }
+    }
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    warp::get()
+        .and(warp::path!("calls"))
+        .and(authenticated())
+        .and(with_state)
+        .and_then(list_calls)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chat::user_text,
+                commands::Dispatcher,
+                filter::FilterChain,
+                state::UserInfo,
+                wire::{self,
+                       IceCandidate,
+                       SessionDescription}};
+    use tokio::sync::mpsc::{self,
+                            UnboundedReceiver};
+    use warp::ws::Message;
+
+    #[test]
+    fn test_calls() {
+        let mut calls = Calls::new(Default::default());
+        assert!(calls.signal("stoner", "whammo", Signal::Answer).is_err());
+        assert!(calls.signal("stoner", "whammo", Signal::Candidate).is_err());
+        assert!(calls.signal("stoner", "stoner", Signal::Offer).is_err());
+
+        let call = calls.signal("stoner", "whammo", Signal::Offer).unwrap();
+        assert_eq!(call.state, CallState::Ringing);
+        assert_eq!(calls.call_of("whammo").map(|c| &c.id), Some(&call.id));
+        // Both can trickle candidates before the answer, but only whammo can answer
+        assert!(calls.signal("stoner", "whammo", Signal::Candidate).is_ok());
+        assert!(calls.signal("whammo", "stoner", Signal::Candidate).is_ok());
+        assert!(calls.signal("stoner", "whammo", Signal::Answer).is_err());
+        let answered = calls.signal("whammo", "stoner", Signal::Answer).unwrap();
+        assert_eq!(answered.state, CallState::Accepted);
+        assert!(calls.signal("whammo", "stoner", Signal::Answer).is_err());
+
+        // Nobody else gets in
+        let busy = calls.signal("rubik", "stoner", Signal::Offer).unwrap();
+        assert_eq!(busy.state, CallState::Busy);
+        assert_eq!(busy.reason.as_deref(), Some("stoner is in another call"));
+        assert!(calls.call_of("rubik").is_none());
+        assert!(calls.signal("rubik", "whammo", Signal::Candidate).is_err());
+        assert!(calls.signal("stoner", "rubik", Signal::Offer).is_err());
+
+        // Renegotiating goes the other way this time
+        assert!(calls.signal("whammo", "stoner", Signal::Offer).is_ok());
+        assert!(calls.signal("stoner", "whammo", Signal::Answer).is_ok());
+
+        let ended = calls.leave("whammo").unwrap();
+        assert_eq!((&ended.id, ended.state), (&call.id, CallState::Ended));
+        assert_eq!(ended.duration(), Some(0));
+        assert!(calls.call_of("stoner").is_none());
+        assert!(calls.signal("rubik", "stoner", Signal::Offer).is_ok());
+    }
+
+    #[test]
+    fn test_call_actions() {
+        let mut calls = Calls::new(Default::default());
+        let call = calls.invite("stoner", "whammo").unwrap();
+        assert!(calls.invite("stoner", "rubik").is_err());
+        assert!(calls.act("stoner", "", CallAction::Accept).is_err());
+        assert!(calls.act("rubik", "", CallAction::Accept).is_err());
+        assert!(calls.act("whammo", "nope", CallAction::Accept).is_err());
+        assert_eq!(calls.act("whammo", &call.id, CallAction::Accept).unwrap().state,
+                   CallState::Accepted);
+        assert!(calls.act("whammo", "", CallAction::Reject).is_err());
+        // Accepted calls don't time out
+        assert!(calls.time_out(&call.id).is_none());
+        let ended = calls.act("stoner", "", CallAction::HangUp).unwrap();
+        assert_eq!(ended.reason.as_deref(), Some("stoner hung up"));
+        assert!(ended.accepted.is_some() && ended.ended.is_some());
+        assert!(calls.get(&call.id).is_none());
+
+        let call = calls.invite("stoner", "whammo").unwrap();
+        let rejected = calls.act("whammo", "", CallAction::Reject).unwrap();
+        assert_eq!((rejected.state, rejected.duration()), (CallState::Rejected, Some(0)));
+        assert_eq!(rejected.record().state, "Rejected");
+        assert!(calls.call_of("stoner").is_none());
+
+        let call2 = calls.invite("stoner", "whammo").unwrap();
+        assert_ne!(call.id, call2.id);
+        assert_eq!(calls.time_out(&call2.id).unwrap().state, CallState::Missed);
+        assert!(calls.call_of("whammo").is_none());
+    }
+
+    #[test]
+    fn test_check_request() {
+        let description = |kind: &str| SessionDescription {
+            kind: kind.into(),
+            sdp: "v=0".into(),
+        };
+        let offer = |kind| Request::SDPOffer {
+            id: "".into(),
+            description: description(kind),
+        };
+        assert_eq!(check_request(&offer("offer")), Ok(Signal::Offer));
+        assert!(check_request(&offer("answer")).is_err());
+        let answer = Request::SDPAnswer {
+            id: "".into(),
+            description: description("answer"),
+        };
+        assert_eq!(check_request(&answer), Ok(Signal::Answer));
+        let huge = Request::IceCandidate {
+            id: "".into(),
+            candidate: IceCandidate {
+                candidate: "a".repeat(MAX_SDP_LEN + 1),
+                sdp_mid: None,
+                sdp_mline_index: None,
+            },
+        };
+        assert!(check_request(&huge).is_err());
+        let ping = Request::Ping {
+            id: "".into(),
+            args: vec![],
+        };
+        assert!(check_request(&ping).is_err());
+    }
+
+    type Peers = HashMap<&'static str, UnboundedReceiver<Result<Message, warp::Error>>>;
+
+    async fn connect(state: &ChatState) -> Peers {
+        let mut peers = HashMap::new();
+        for name in &["stoner", "whammo", "rubik"] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            state.users.lock().await.insert(name.to_string(), UserInfo::new(Some(tx)));
+            peers.insert(*name, rx);
+        }
+        peers
+    }
+
+    /// The next message for a peer, decoded
+    fn next(peers: &mut Peers, name: &str) -> Option<Envelope> {
+        let msg = peers.get_mut(name).unwrap().try_recv().ok()?.unwrap();
+        Some(wire::decode(msg.to_str().unwrap()).unwrap())
+    }
+
+    /// The next message for a peer, which has to be a `Call` event
+    fn next_call(peers: &mut Peers, name: &str) -> CallEvent {
+        match next(peers, name).map(|envelope| envelope.event) {
+            Some(Event::Call(event)) => event,
+            other => panic!("Expected a Call event for {}, got {:?}", name, other),
+        }
+    }
+
+    fn command(from: &str, to: &str, request: Request) -> String {
+        let envelope = Envelope::new(from.into(), vec![to.into()], Event::CommandRequest(request));
+        serde_json::to_string(&envelope).unwrap()
+    }
+
+    fn action(from: &str, to: &str, action: CallAction) -> String {
+        command(from, to, Request::Call {
+            id: "".into(),
+            action,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_two_peers() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        let mut peers = connect(&state).await;
+        let description = |kind: &str, sdp: &str| SessionDescription {
+            kind: kind.into(),
+            sdp: sdp.into(),
+        };
+        let candidate = |c: &str| IceCandidate {
+            candidate: c.into(),
+            sdp_mid: Some("0".into()),
+            sdp_mline_index: Some(0),
+        };
+
+        let offer = Request::SDPOffer {
+            id: "".into(),
+            description: description("offer", "v=0 stoner"),
+        };
+        user_text("stoner".into(), &command("stoner", "whammo", offer), &state).await;
+        assert_eq!(next_call(&mut peers, "stoner").state, CallState::Ringing);
+        assert_eq!(next_call(&mut peers, "whammo").state, CallState::Ringing);
+        let got = next(&mut peers, "whammo").expect("whammo gets the offer");
+        let call_id = match got.event {
+            Event::CommandRequest(Request::SDPOffer { id, description }) => {
+                assert_eq!(description.sdp, "v=0 stoner");
+                id
+            }
+            other => panic!("Expected an offer, got {:?}", other),
+        };
+        assert!(!call_id.is_empty());
+
+        // rubik can't butt in
+        let sneaky = Request::IceCandidate {
+            id: call_id.clone(),
+            candidate: candidate("candidate:evil"),
+        };
+        user_text("rubik".into(), &command("rubik", "stoner", sneaky), &state).await;
+        assert!(next(&mut peers, "stoner").is_none());
+        let rejected = next(&mut peers, "rubik").expect("rubik is told why");
+        assert!(matches!(rejected.event, Event::Rejected(r) if r.filter == "signaling"));
+
+        let answer = Request::SDPAnswer {
+            id: "".into(),
+            description: description("answer", "v=0 whammo"),
+        };
+        user_text("whammo".into(), &command("whammo", "stoner", answer), &state).await;
+        assert_eq!(next_call(&mut peers, "stoner").state, CallState::Accepted);
+        assert_eq!(next_call(&mut peers, "whammo").state, CallState::Accepted);
+        let got = next(&mut peers, "stoner").expect("stoner gets the answer");
+        assert!(matches!(got.event, Event::CommandRequest(Request::SDPAnswer { id, .. })
+                         if id == call_id));
+
+        let ice = Request::IceCandidate {
+            id: "".into(),
+            candidate: candidate("candidate:1 1 udp 2122260223 10.0.0.7 54321 typ host"),
+        };
+        user_text("stoner".into(), &command("stoner", "whammo", ice.clone()), &state).await;
+        user_text("whammo".into(), &command("whammo", "stoner", ice), &state).await;
+        assert!(next(&mut peers, "whammo").is_some());
+        assert!(next(&mut peers, "stoner").is_some());
+
+        leave(&state, "whammo").await;
+        let ended = next_call(&mut peers, "stoner");
+        assert_eq!((ended.state, ended.reason.as_deref()), (CallState::Ended,
+                                                            Some("whammo disconnected")));
+        assert!(state.calls.lock().await.call_of("stoner").is_none());
+        next_call(&mut peers, "whammo");
+        assert!(["stoner", "whammo", "rubik"].iter().all(|name| next(&mut peers, name).is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_call_lifecycle() {
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        state.calls.lock().await.ring_timeout = Duration::from_millis(50);
+        let mut peers = connect(&state).await;
+
+        user_text("stoner".into(), &action("stoner", "whammo", CallAction::Invite), &state).await;
+        let ringing = next_call(&mut peers, "whammo");
+        assert_eq!((ringing.caller.as_str(), ringing.state), ("stoner", CallState::Ringing));
+        assert_eq!(next_call(&mut peers, "stoner"), ringing);
+
+        // Only the caller hears that the callee is busy
+        user_text("rubik".into(), &action("rubik", "whammo", CallAction::Invite), &state).await;
+        assert_eq!(next_call(&mut peers, "rubik").state, CallState::Busy);
+        assert!(next(&mut peers, "whammo").is_none());
+
+        user_text("whammo".into(), &action("whammo", "stoner", CallAction::Accept), &state).await;
+        assert_eq!(next_call(&mut peers, "stoner").state, CallState::Accepted);
+        assert_eq!(next_call(&mut peers, "whammo").state, CallState::Accepted);
+        // Long enough that the call would have been missed if it was still ringing
+        delay_for(Duration::from_millis(100)).await;
+        user_text("stoner".into(), &action("stoner", "whammo", CallAction::HangUp), &state).await;
+        let ended = next_call(&mut peers, "whammo");
+        assert_eq!((ended.state, ended.duration), (CallState::Ended, Some(0)));
+        assert_eq!(next_call(&mut peers, "stoner"), ended);
+
+        user_text("stoner".into(), &action("stoner", "rubik", CallAction::Invite), &state).await;
+        next_call(&mut peers, "stoner");
+        next_call(&mut peers, "rubik");
+        user_text("rubik".into(), &action("rubik", "stoner", CallAction::Reject), &state).await;
+        assert_eq!(next_call(&mut peers, "stoner").state, CallState::Rejected);
+        assert_eq!(next_call(&mut peers, "rubik").state, CallState::Rejected);
+
+        user_text("stoner".into(), &action("stoner", "whammo", CallAction::Invite), &state).await;
+        next_call(&mut peers, "stoner");
+        next_call(&mut peers, "whammo");
+        delay_for(Duration::from_millis(100)).await;
+        let missed = next_call(&mut peers, "whammo");
+        assert_eq!((missed.state, missed.reason.as_deref()), (CallState::Missed,
+                                                              Some("Nobody answered")));
+        assert_eq!(next_call(&mut peers, "stoner"), missed);
+
+        // There is nothing left to hang up
+        user_text("stoner".into(), &action("stoner", "whammo", CallAction::HangUp), &state).await;
+        let rejected = next(&mut peers, "stoner").expect("stoner is told why");
+        assert!(matches!(rejected.event, Event::Rejected(r) if r.filter == "signaling"));
+    }
+}
@@ -0,0 +1,249 @@
+//! Chat without websockets
+//!
+//! Some proxies kill websocket upgrades, so the chat also works over plain HTTP.  Both endpoints
+//! need the `jwt` cookie, and speak the same `message::Message` JSON as the websocket:
+//!
+//! - `GET /sse/events` is a stream of Server-Sent Events.  Opening it connects you, just like
+//!   opening the websocket does: you join the lobby and everybody gets a `Connect` event.  Each
+//!   event's `data` is one message.  Closing it disconnects you.
+//! - `POST /sse/messages` with a message sends it.  It goes through exactly what a message from the
+//!   websocket goes through (filters, commands, rooms and so on).  The reply is `202 Accepted`, and
+//!   anything khadga has to say back (a `Rejected` event, a command reply) comes on the stream.
+//!
+//! There is one session per name, so opening the stream while you are connected some other way is
+//! refused with a `409`.  Sending only works while your stream is open, and the `sender` must be
+//! you.  Besides the chat's
+//! own pings, the stream gets a comment every 15 seconds so proxies don't time it out.
+//!
+//! The protocol handshake (see `protocol`) is done with query parameters on the stream, eg
+//! `/sse/events?version=2&codecs=json&features=rooms,e2e`.  The first event is then the `Welcome`.
+//! If the versions don't match, the reply is a `400` with the reason instead of a stream.
+
+use crate::{auth::authenticated,
+            chat::{close_session,
+                   open_session,
+                   peer,
+                   user_text,
+                   ChatState},
+            data::AccountKind,
+            message::{Message as KMessage,
+                      MessageEvent},
+            protocol::{self,
+                       Hello,
+                       Negotiated,
+                       Welcome},
+            reply::error_reply,
+            state::Peer,
+            wire::{self,
+                   Encoded}};
+use futures::{future,
+              StreamExt};
+use log::{debug,
+          info};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           hyper::body::Bytes,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           sse,
+           ws::Message,
+           Filter,
+           Reply};
+
+/// Closes the session once the event stream is dropped, ie when the client goes away
+struct SessionGuard {
+    state: ChatState,
+    username: String,
+    session_id: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        info!("{} closed their event stream", self.username);
+        let state = self.state.clone();
+        let username = self.username.clone();
+        let session_id = self.session_id.clone();
+        tokio::spawn(async move { close_session(&state, &username, &session_id).await });
+    }
+}
+
+/// The handshake, as query parameters.  Lists are comma separated.
+#[derive(Deserialize, Debug, Default)]
+pub struct HelloQuery {
+    pub version: Option<u32>,
+    pub min_version: Option<u32>,
+    pub codecs: Option<String>,
+    pub features: Option<String>,
+}
+
+impl HelloQuery {
+    /// None for clients from before the handshake
+    pub fn hello(&self) -> Option<Hello> {
+        let list = |l: &Option<String>| -> Vec<String> {
+            l.as_deref()
+                .unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        self.version.map(|version| Hello {
+            version,
+            min_version: self.min_version,
+            codecs: list(&self.codecs),
+            features: list(&self.features),
+        })
+    }
+}
+
+/// `GET /sse/events`
+pub async fn events(
+    user: String,
+    peer: Peer,
+    query: HelloQuery,
+    state: ChatState,
+) -> Result<Box<dyn Reply>, Infallible> {
+    info!("{} starting chat over SSE from {:?}", user, peer.remote_addr);
+    let negotiated = match query.hello().map(|hello| protocol::negotiate(&hello)) {
+        Some(Ok(negotiated)) => Some(negotiated),
+        Some(Err(reason)) => {
+            info!("{}: handshake failed: {}", user, reason);
+            return Ok(Box::new(error_reply(StatusCode::BAD_REQUEST, &reason)));
+        }
+        None => None,
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    if let Some(negotiated) = &negotiated {
+        let welcome = Welcome::new(negotiated.clone());
+        let to = vec![user.clone()];
+        let welcome = KMessage::new("khadga".into(), to, MessageEvent::Welcome, welcome);
+        let welcome = Encoded::new(&welcome);
+        let _ = tx.send(Ok(Message::text(welcome.for_version(negotiated.version))));
+    }
+    let negotiated = negotiated.unwrap_or_else(Negotiated::legacy);
+    // An admin disconnecting the session also sends a close frame, which ends the stream below,
+    // so there is no need to wait on the receiver
+    let opened = open_session(&state, &user, AccountKind::User, peer, negotiated, tx).await;
+    let session_id = match opened {
+        Ok((session_id, _)) => session_id,
+        Err(reason) => {
+            info!("{}: {}", user, reason);
+            return Ok(Box::new(error_reply(StatusCode::CONFLICT, &reason)));
+        }
+    };
+    let guard = SessionGuard {
+        state,
+        username: user,
+        session_id,
+    };
+
+    let stream = rx
+        .take_while(|msg| future::ready(matches!(msg, Ok(msg) if !msg.is_close())))
+        .filter_map(move |msg| {
+            // Keeps the guard alive for as long as the stream is
+            let _ = &guard;
+            future::ready(match msg {
+                Ok(msg) => msg.to_str().ok().map(|text| Ok(sse::data(text.to_string()))),
+                Err(e) => Some(Err(e)),
+            })
+        });
+    Ok(Box::new(sse::reply(sse::keep_alive().stream(stream))))
+}
+
+/// Checks a message posted by the user, in either format (see `wire`), returning its JSON
+pub fn check_message(user: &str, body: &[u8]) -> Result<String, (StatusCode, &'static str)> {
+    let text = std::str::from_utf8(body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "The message must be UTF-8"))?;
+    let mesg = wire::decode(text)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "The body is not a message"))?;
+    if mesg.sender != user {
+        return Err((StatusCode::FORBIDDEN, "You can only send messages as yourself"));
+    }
+    Ok(text.to_string())
+}
+
+/// `POST /sse/messages`
+pub async fn send(
+    user: String,
+    body: Bytes,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let text = match check_message(&user, &body) {
+        Ok(text) => text,
+        Err((status, e)) => return Ok(error_reply(status, e)),
+    };
+    if !state.users.lock().await.contains_key(&user) {
+        let msg = "Not connected.  Open /sse/events first";
+        return Ok(error_reply(StatusCode::CONFLICT, msg));
+    }
+    debug!("{} sent a message over HTTP", user);
+    user_text(user, &text, &state).await;
+    Ok(reply::with_status(reply::json(&json!({ "accepted": true })), StatusCode::ACCEPTED))
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let events = warp::get()
+        .and(warp::path!("sse" / "events"))
+        .and(authenticated())
+        .and(peer())
+        .and(warp::query())
+        .and(with_state.clone())
+        .and_then(events);
+
+    let send = warp::post()
+        .and(warp::path!("sse" / "messages"))
+        .and(authenticated())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and(with_state)
+        .and_then(send);
+
+    events.or(send).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageEvent;
+
+    #[test]
+    fn test_check_message() {
+        let mesg = KMessage::new(
+            "stoner".to_string(),
+            vec![],
+            MessageEvent::Message,
+            "hi".to_string(),
+        );
+        let json = serde_json::to_string(&mesg).unwrap();
+
+        assert_eq!(check_message("stoner", json.as_bytes()).unwrap(), json);
+        assert_eq!(check_message("whammo", json.as_bytes()).unwrap_err().0, StatusCode::FORBIDDEN);
+        assert_eq!(check_message("stoner", b"{}").unwrap_err().0, StatusCode::BAD_REQUEST);
+        assert_eq!(check_message("stoner", &[0xff, 0xfe]).unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_hello_query() {
+        assert!(HelloQuery::default().hello().is_none());
+
+        let query = HelloQuery {
+            version: Some(1),
+            codecs: Some("msgpack, json".into()),
+            features: Some("rooms,,e2e".into()),
+            ..Default::default()
+        };
+        let hello = query.hello().unwrap();
+        assert_eq!(hello.codecs, vec!["msgpack", "json"]);
+        assert_eq!(hello.features, vec!["rooms", "e2e"]);
+        assert_eq!(protocol::negotiate(&hello).unwrap().codec, "json");
+    }
+}
@@ -1,12 +1,18 @@
 use chrono::{DateTime,
              Utc};
+use serde::Serialize;
 use std::{collections::HashMap,
           fmt::{self,
                 Display,
                 Formatter},
+          net::SocketAddr,
           sync::Arc,
           ops::{Add}};
+use crate::{data::AccountKind,
+            protocol::Negotiated,
+            util::random_id};
 use tokio::sync::{mpsc,
+                  oneshot,
                   Mutex};
 use warp::ws::Message;
 
@@ -32,26 +38,82 @@ impl MessageInventory {
     }
 }
 
+/// Where a connection came from
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Peer {
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// What the admins see of a connection (see `sessions`)
+#[derive(Serialize, Debug, Clone)]
+pub struct Session {
+    pub username: String,
+    pub kind: AccountKind,
+    pub login_time: DateTime<Utc>,
+    pub last_message: DateTime<Utc>,
+    /// Bytes of messages the user has sent
+    pub bytes_sent: usize,
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    /// 0 for clients from before the handshake
+    pub protocol_version: u32,
+}
+
 pub struct UserInfo {
+    /// Tells this connection apart from an earlier or later one under the same name
+    pub session_id: String,
     pub sender: Option<Sender>,
+    pub kind: AccountKind,
     data_usage: usize,
     last_message: DateTime<Utc>,
     pub login_time: DateTime<Utc>,
+    pub peer: Peer,
+    /// What was agreed in the handshake (see `protocol`)
+    pub protocol: Negotiated,
+    /// Firing this ends the connection
+    pub disconnect: Option<oneshot::Sender<()>>,
 }
 
 impl UserInfo {
     pub fn new(sender: Option<Sender>) -> Self {
         UserInfo {
+            session_id: random_id(8),
             sender,
+            kind: AccountKind::User,
             data_usage: 0,
             last_message: Utc::now(),
             login_time: Utc::now(),
+            peer: Peer::default(),
+            protocol: Negotiated::legacy(),
+            disconnect: None,
         }
     }
 
     pub fn clear_data_usage(&mut self) {
         self.data_usage = 0;
     }
+
+    pub fn data_usage(&self) -> usize {
+        self.data_usage
+    }
+
+    pub fn last_message(&self) -> DateTime<Utc> {
+        self.last_message
+    }
+
+    pub fn session(&self, username: &str) -> Session {
+        Session {
+            username: username.to_string(),
+            kind: self.kind,
+            login_time: self.login_time,
+            last_message: self.last_message,
+            bytes_sent: self.data_usage,
+            remote_addr: self.peer.remote_addr,
+            user_agent: self.peer.user_agent.clone(),
+            protocol_version: self.protocol.version,
+        }
+    }
 }
 
 impl Display for UserInfo {
@@ -62,8 +124,14 @@ impl Display for UserInfo {
     data_usage: {},
     last_message: {},
     login_time: {},
+    remote_addr: {:?},
+    user_agent: {:?},
 }}"#,
-            self.data_usage, self.last_message, self.login_time
+            self.data_usage,
+            self.last_message,
+            self.login_time,
+            self.peer.remote_addr,
+            self.peer.user_agent
         )
     }
 }
@@ -99,5 +167,6 @@ mod tests {
         user = user + sent_msg;
 
         println!("{}", user);
+        assert_eq!(user.session("stoner").bytes_sent, 1024);
     }
 }
@@ -0,0 +1,541 @@
+//! A STUN server, so clients don't have to depend on public ones
+//!
+//! WebRTC clients behind a NAT ask a STUN server what address their packets come from, and then
+//! offer that address to the other side as a candidate.  This answers RFC 5389 Binding requests
+//! over UDP with the address the request came from, in an XOR-MAPPED-ADDRESS.  Nothing else
+//! (TCP, TLS, authentication, the old RFC 3489 format) is supported.
+//!
+//! It is off by default.  With `stun.enabled` set in the settings it runs inside khadga, listening
+//! on `stun.listen` (port 3478 is the standard one).  Clients then use `stun:<khadga host>:3478`
+//! as an ICE server.
+//!
+//! The message format is shared with the TURN server, so it lives here too.
+
+use crate::config::StunCfg;
+use log::{debug,
+          error,
+          info};
+use ring::hmac;
+use std::{convert::TryInto,
+          fmt::{self,
+                Display,
+                Formatter},
+          net::{IpAddr,
+                Ipv4Addr,
+                Ipv6Addr,
+                SocketAddr}};
+use tokio::net::UdpSocket;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+/// The biggest datagram we bother reading
+pub const MAX_DATAGRAM: usize = 1500;
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// The only method a plain STUN server has
+pub const BINDING: u16 = 0x001;
+
+pub const MAPPED_ADDRESS: u16 = 0x0001;
+pub const USERNAME: u16 = 0x0006;
+pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ERROR_CODE: u16 = 0x0009;
+pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+pub const REALM: u16 = 0x0014;
+pub const NONCE: u16 = 0x0015;
+pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// ICE sends these, and we may as well understand them
+pub const PRIORITY: u16 = 0x0024;
+pub const USE_CANDIDATE: u16 = 0x0025;
+pub const SOFTWARE: u16 = 0x8022;
+pub const FINGERPRINT: u16 = 0x8028;
+
+/// The comprehension-required attributes (the ones below 0x8000) that a Binding request can have
+const KNOWN: [u16; 10] = [
+    MAPPED_ADDRESS,
+    USERNAME,
+    MESSAGE_INTEGRITY,
+    ERROR_CODE,
+    UNKNOWN_ATTRIBUTES,
+    REALM,
+    NONCE,
+    XOR_MAPPED_ADDRESS,
+    PRIORITY,
+    USE_CANDIDATE,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StunError {
+    TooShort,
+    /// The first two bits or the magic cookie are wrong, so it isn't STUN at all
+    NotStun,
+    BadLength,
+    BadAttribute(u16),
+    BadFingerprint,
+}
+
+impl Display for StunError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StunError::TooShort => write!(f, "The message is too short"),
+            StunError::NotStun => write!(f, "Not a STUN message"),
+            StunError::BadLength => write!(f, "The length doesn't match the message"),
+            StunError::BadAttribute(kind) => write!(f, "Attribute {:#06x} is malformed", kind),
+            StunError::BadFingerprint => write!(f, "The fingerprint is wrong"),
+        }
+    }
+}
+
+/// One STUN message
+#[derive(Debug, Clone, PartialEq)]
+pub struct StunMessage {
+    pub class: Class,
+    pub method: u16,
+    pub transaction_id: [u8; 12],
+    /// Type and value, in the order they appear.  The FINGERPRINT isn't kept.
+    pub attributes: Vec<(u16, Vec<u8>)>,
+}
+
+/// The CRC-32 that FINGERPRINT uses (the same one as zip and ethernet)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+impl StunMessage {
+    pub fn new(class: Class, method: u16, transaction_id: [u8; 12]) -> Self {
+        StunMessage {
+            class,
+            method,
+            transaction_id,
+            attributes: vec![],
+        }
+    }
+
+    /// A response to this request, with the same method and transaction
+    pub fn response(&self, class: Class) -> Self {
+        StunMessage::new(class, self.method, self.transaction_id)
+    }
+
+    /// An error response, eg 420 Unknown Attribute
+    pub fn error(&self, code: u16, reason: &str) -> Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.response(Class::Error).with(ERROR_CODE, value)
+    }
+
+    /// Adds an attribute
+    pub fn with(mut self, kind: u16, value: Vec<u8>) -> Self {
+        self.attributes.push((kind, value));
+        self
+    }
+
+    /// The value of the first attribute of that type
+    pub fn get(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes.iter().find(|(k, _)| *k == kind).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn get_str(&self, kind: u16) -> Option<&str> {
+        self.get(kind).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// The comprehension-required attributes that aren't in `known`
+    pub fn unknown(&self, known: &[u16]) -> Vec<u16> {
+        self.attributes
+            .iter()
+            .map(|(kind, _)| *kind)
+            .filter(|kind| *kind < 0x8000 && !known.contains(kind))
+            .collect()
+    }
+
+    fn message_type(&self) -> u16 {
+        let class = match self.class {
+            Class::Request => 0b00,
+            Class::Indication => 0b01,
+            Class::Success => 0b10,
+            Class::Error => 0b11,
+        };
+        let m = self.method;
+        (m & 0x000F) | ((m & 0x0070) << 1) | ((m & 0x0F80) << 2) | ((class & 1) << 4)
+            | ((class & 2) << 7)
+    }
+
+    /// The message, without a FINGERPRINT
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_DATAGRAM);
+        buf.extend_from_slice(&self.message_type().to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+        for (kind, value) in &self.attributes {
+            buf.extend_from_slice(&kind.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            buf.resize(padded(buf.len()), 0);
+        }
+        set_length(&mut buf, 0);
+        buf
+    }
+
+    /// The message, with a FINGERPRINT on the end
+    pub fn encode_with_fingerprint(&self) -> Vec<u8> {
+        let mut buf = self.encode();
+        add_fingerprint(&mut buf);
+        buf
+    }
+
+    /// The message, with a MESSAGE-INTEGRITY made with the key and then a FINGERPRINT
+    pub fn encode_with_integrity(&self, key: &[u8]) -> Vec<u8> {
+        let mut buf = self.encode();
+        add_integrity(&mut buf, key);
+        add_fingerprint(&mut buf);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<StunMessage, StunError> {
+        if buf.len() < HEADER_LEN {
+            return Err(StunError::TooShort);
+        }
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        let cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if kind & 0xC000 != 0 || cookie != MAGIC_COOKIE {
+            return Err(StunError::NotStun);
+        }
+        if length % 4 != 0 || HEADER_LEN + length != buf.len() {
+            return Err(StunError::BadLength);
+        }
+        let class = match ((kind >> 4) & 1) | ((kind >> 7) & 2) {
+            0b00 => Class::Request,
+            0b01 => Class::Indication,
+            0b10 => Class::Success,
+            _ => Class::Error,
+        };
+        let method = (kind & 0x000F) | ((kind >> 1) & 0x0070) | ((kind >> 2) & 0x0F80);
+        let transaction_id = buf[8..HEADER_LEN].try_into().expect("12 bytes");
+
+        let mut attributes = vec![];
+        let mut at = HEADER_LEN;
+        while at < buf.len() {
+            if at + 4 > buf.len() {
+                return Err(StunError::BadLength);
+            }
+            let kind = u16::from_be_bytes([buf[at], buf[at + 1]]);
+            let len = usize::from(u16::from_be_bytes([buf[at + 2], buf[at + 3]]));
+            let start = at + 4;
+            if start + len > buf.len() {
+                return Err(StunError::BadAttribute(kind));
+            }
+            if kind == FINGERPRINT {
+                // The fingerprint covers everything before it, and nothing can come after it
+                if len != 4 || start + len != buf.len() {
+                    return Err(StunError::BadAttribute(kind));
+                }
+                let expected = crc32(&buf[..at]) ^ FINGERPRINT_XOR;
+                if buf[start..] != expected.to_be_bytes() {
+                    return Err(StunError::BadFingerprint);
+                }
+                break;
+            }
+            attributes.push((kind, buf[start..start + len].to_vec()));
+            at = start + padded(len);
+        }
+
+        Ok(StunMessage {
+            class,
+            method,
+            transaction_id,
+            attributes,
+        })
+    }
+}
+
+/// Sets the length in the header to what is in the buffer, plus `extra` bytes still to come
+pub fn set_length(buf: &mut [u8], extra: usize) {
+    let length = (buf.len() + extra - HEADER_LEN) as u16;
+    buf[2..4].copy_from_slice(&length.to_be_bytes());
+}
+
+/// Adds a FINGERPRINT to an encoded message
+pub fn add_fingerprint(buf: &mut Vec<u8>) {
+    set_length(buf, 8);
+    let crc = crc32(buf) ^ FINGERPRINT_XOR;
+    buf.extend_from_slice(&FINGERPRINT.to_be_bytes());
+    buf.extend_from_slice(&4u16.to_be_bytes());
+    buf.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Adds a MESSAGE-INTEGRITY to an encoded message.  It has to come before any FINGERPRINT.
+pub fn add_integrity(buf: &mut Vec<u8>, key: &[u8]) {
+    set_length(buf, 24);
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key), buf);
+    buf.extend_from_slice(&MESSAGE_INTEGRITY.to_be_bytes());
+    buf.extend_from_slice(&20u16.to_be_bytes());
+    buf.extend_from_slice(tag.as_ref());
+}
+
+/// Whether a received message has a MESSAGE-INTEGRITY, and it was made with the key
+///
+/// This works on the raw datagram, because the HMAC covers the exact bytes before the attribute.
+pub fn check_integrity(packet: &[u8], key: &[u8]) -> bool {
+    let mut at = HEADER_LEN;
+    while at + 4 <= packet.len() {
+        let kind = u16::from_be_bytes([packet[at], packet[at + 1]]);
+        let len = usize::from(u16::from_be_bytes([packet[at + 2], packet[at + 3]]));
+        if kind == MESSAGE_INTEGRITY {
+            if len != 20 || at + 24 > packet.len() {
+                return false;
+            }
+            let mut signed = packet[..at].to_vec();
+            set_length(&mut signed, 24);
+            let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+            return hmac::verify(&key, &signed, &packet[at + 4..at + 24]).is_ok();
+        }
+        at += 4 + padded(len);
+    }
+    false
+}
+
+/// The cookie and transaction id, which addresses are XORed with
+fn xor_key(transaction_id: &[u8; 12]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(transaction_id);
+    key
+}
+
+/// The value of an XOR-MAPPED-ADDRESS (or any other XOR-ed address) attribute
+pub fn xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let key = xor_key(transaction_id);
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, ip): (u8, Vec<u8>) = match addr.ip() {
+        IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(ip.iter().zip(key.iter()).map(|(a, b)| a ^ b));
+    value
+}
+
+pub fn parse_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    let key = xor_key(transaction_id);
+    if value.len() < 4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip: Vec<u8> = value[4..].iter().zip(key.iter()).map(|(a, b)| a ^ b).collect();
+    let ip = match (value[1], ip.len()) {
+        (1, 4) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+        (2, 16) => {
+            let octets: [u8; 16] = ip.as_slice().try_into().ok()?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// The answer to one datagram, if it needs one
+pub fn answer(packet: &[u8], from: SocketAddr, software: Option<&str>) -> Option<Vec<u8>> {
+    let request = match StunMessage::decode(packet) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Ignoring a datagram from {}: {}", from, e);
+            return None;
+        }
+    };
+    // Indications and stray responses don't get an answer
+    if request.class != Class::Request {
+        return None;
+    }
+
+    let reply = if request.method != BINDING {
+        request.error(400, "Only Binding is supported")
+    } else {
+        match request.unknown(&KNOWN) {
+            unknown if !unknown.is_empty() => {
+                let value = unknown.iter().flat_map(|kind| kind.to_be_bytes().to_vec()).collect();
+                request.error(420, "Unknown Attribute").with(UNKNOWN_ATTRIBUTES, value)
+            }
+            _ => {
+                let mapped = xor_address(from, &request.transaction_id);
+                request.response(Class::Success).with(XOR_MAPPED_ADDRESS, mapped)
+            }
+        }
+    };
+    let reply = match software {
+        Some(software) => reply.with(SOFTWARE, software.as_bytes().to_vec()),
+        None => reply,
+    };
+    Some(reply.encode_with_fingerprint())
+}
+
+/// Answers Binding requests on the socket, forever
+pub async fn serve(mut socket: UdpSocket, software: Option<String>) {
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // eg an ICMP port unreachable from an earlier answer
+                debug!("STUN receive failed: {}", e);
+                continue;
+            }
+        };
+        if let Some(reply) = answer(&buf[..len], from, software.as_deref()) {
+            if let Err(e) = socket.send_to(&reply, &from).await {
+                debug!("Unable to answer {}: {}", from, e);
+            }
+        }
+    }
+}
+
+/// Starts the STUN server
+pub async fn run(cfg: StunCfg) {
+    match UdpSocket::bind(&cfg.listen).await {
+        Ok(socket) => {
+            info!("STUN server listening on {}", cfg.listen);
+            serve(socket, cfg.software).await
+        }
+        Err(e) => error!("Unable to start the STUN server on {}: {}", cfg.listen, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION: [u8; 12] =
+        [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    /// The IPv4 response from RFC 5769, section 2.2
+    const RFC5769_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    #[test]
+    fn test_rfc5769() {
+        let response = StunMessage::decode(&RFC5769_RESPONSE).unwrap();
+        assert_eq!((response.class, response.method), (Class::Success, BINDING));
+        assert_eq!(response.transaction_id, TRANSACTION);
+        assert_eq!(response.get_str(SOFTWARE), Some("test vector"));
+        let mapped = parse_xor_address(response.get(XOR_MAPPED_ADDRESS).unwrap(), &TRANSACTION);
+        assert_eq!(mapped, Some("192.0.2.1:32853".parse().unwrap()));
+
+        let mut corrupt = RFC5769_RESPONSE;
+        corrupt[30] ^= 1;
+        assert_eq!(StunMessage::decode(&corrupt), Err(StunError::BadFingerprint));
+
+        // The vectors use short-term credentials, so the password is the key
+        let key = b"VOkJxbRl1RmTxUk/WvJxBt";
+        assert!(check_integrity(&RFC5769_RESPONSE, key));
+        assert!(!check_integrity(&RFC5769_RESPONSE, b"wrong"));
+        assert!(!check_integrity(&corrupt, key));
+        let rebuilt = StunMessage::new(Class::Success, BINDING, TRANSACTION)
+            .with(SOFTWARE, b"test vector".to_vec())
+            .with(XOR_MAPPED_ADDRESS, response.get(XOR_MAPPED_ADDRESS).unwrap().to_vec());
+        let mut bytes = rebuilt.encode();
+        add_integrity(&mut bytes, key);
+        assert!(check_integrity(&bytes, key));
+        assert_eq!(bytes.len(), 72);
+        assert!(!check_integrity(&rebuilt.encode_with_fingerprint(), key));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let v6: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+        let message = StunMessage::new(Class::Error, BINDING, TRANSACTION)
+            .with(XOR_MAPPED_ADDRESS, xor_address(v6, &TRANSACTION))
+            .with(SOFTWARE, b"odd".to_vec());
+        let bytes = message.encode_with_fingerprint();
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(StunMessage::decode(&bytes), Ok(message.clone()));
+        assert_eq!(StunMessage::decode(&message.encode()), Ok(message.clone()));
+        let mapped = parse_xor_address(message.get(XOR_MAPPED_ADDRESS).unwrap(), &TRANSACTION);
+        assert_eq!(mapped, Some(v6));
+
+        assert_eq!(StunMessage::decode(&bytes[..12]), Err(StunError::TooShort));
+        assert_eq!(StunMessage::decode(&bytes[..bytes.len() - 4]), Err(StunError::BadLength));
+        let mut not_stun = bytes.clone();
+        not_stun[4] = 0;
+        assert_eq!(StunMessage::decode(&not_stun), Err(StunError::NotStun));
+    }
+
+    #[test]
+    fn test_answer() {
+        let from: SocketAddr = "203.0.113.7:51000".parse().unwrap();
+        let request = StunMessage::new(Class::Request, BINDING, TRANSACTION);
+        let reply = answer(&request.encode(), from, Some("khadga")).unwrap();
+        let reply = StunMessage::decode(&reply).unwrap();
+        assert_eq!(reply.class, Class::Success);
+        let mapped = parse_xor_address(reply.get(XOR_MAPPED_ADDRESS).unwrap(), &TRANSACTION);
+        assert_eq!(mapped, Some(from));
+        assert_eq!(reply.get_str(SOFTWARE), Some("khadga"));
+
+        let odd = request.clone().with(0x0042, vec![1]).with(0x8042, vec![2]);
+        let reply = StunMessage::decode(&answer(&odd.encode(), from, None).unwrap()).unwrap();
+        assert_eq!(reply.class, Class::Error);
+        assert_eq!(reply.get(ERROR_CODE).unwrap()[2..4], [4, 20]);
+        assert_eq!(reply.get(UNKNOWN_ATTRIBUTES), Some(&[0x00, 0x42][..]));
+
+        let indication = StunMessage::new(Class::Indication, BINDING, TRANSACTION);
+        assert!(answer(&indication.encode(), from, None).is_none());
+        assert!(answer(b"GET / HTTP/1.1\r\n\r\n", from, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(serve(server, Some("khadga".into())));
+
+        let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        for n in 0..3u8 {
+            let mut transaction = TRANSACTION;
+            transaction[0] = n;
+            let request = StunMessage::new(Class::Request, BINDING, transaction);
+            client.send_to(&request.encode_with_fingerprint(), &server_addr).await.unwrap();
+
+            let mut buf = [0u8; MAX_DATAGRAM];
+            let wait = std::time::Duration::from_secs(5);
+            let (len, from) =
+                tokio::time::timeout(wait, client.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert_eq!(from, server_addr);
+            let reply = StunMessage::decode(&buf[..len]).unwrap();
+            assert_eq!((reply.class, reply.transaction_id), (Class::Success, transaction));
+            let mapped = parse_xor_address(reply.get(XOR_MAPPED_ADDRESS).unwrap(), &transaction);
+            assert_eq!(mapped, Some(client_addr));
+        }
+        // Garbage is ignored, and the server keeps going
+        client.send_to(b"hello", &server_addr).await.unwrap();
+        let request = StunMessage::new(Class::Request, BINDING, TRANSACTION);
+        client.send_to(&request.encode(), &server_addr).await.unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(StunMessage::decode(&buf[..len]).unwrap().transaction_id, TRANSACTION);
+    }
+}
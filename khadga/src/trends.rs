@@ -0,0 +1,456 @@
+//! Trending terms
+//!
+//! Every chat message that goes through `chat::post` is also counted here, so khadga always knows
+//! what each room is talking about right now.  The words of a message (and the pairs of words next
+//! to each other, so that "critical hit" counts as a topic of its own) are counted in buckets of
+//! `bucket_secs` seconds.  For each room we keep:
+//!
+//! - the window: the last `window_buckets` buckets, which is "right now"
+//! - the baseline: up to `baseline_buckets` buckets before that, which is "normal"
+//!
+//! A term is trending when it shows up in the window a lot more than the baseline says it should.
+//! Word counts are treated as Poisson, so the score is how many standard deviations the window
+//! count is above the expected count: `(count - expected) / sqrt(expected + 1)`.  The `+ 1` keeps
+//! brand new words from scoring infinitely high just because nobody said them before.  A term also
+//! needs at least `min_count` mentions in the window, so a single message can't start a trend.
+//!
+//! Only messages in a room are counted.  Messages that don't name one may be direct messages, which
+//! aren't anybody else's business.  The counts of a room are dropped once the room is gone, or once
+//! nobody has said anything in it for the window and the baseline both, so a server that sees a lot
+//! of short lived rooms doesn't keep them all forever.
+//!
+//! Trends can be fetched with `GET /trends` (optionally `?room=<room>`).  You only get the trends
+//! of the rooms you are in, unless you are an admin.  If `alert` is set, the admins that are
+//! connected are also sent an `Alert` event the first time a term starts trending in a room (and
+//! again after `alert_cooldown_secs`).
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            chat::{send_to,
+                   ChatState},
+            config::TrendCfg,
+            message::{Message as KMessage,
+                      MessageEvent},
+            reply::error_reply,
+            rooms};
+use chrono::Utc;
+use serde::{Deserialize,
+            Serialize};
+use std::{collections::{HashMap,
+                        HashSet,
+                        VecDeque},
+          convert::Infallible,
+          sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+pub type Trends = Arc<Mutex<TrendTracker>>;
+
+/// Words that are too common to ever be interesting
+const STOPWORDS: &[&str] = &[
+    "about", "after", "again", "all", "also", "and", "any", "are", "because", "been", "before",
+    "but", "can", "could", "did", "does", "doing", "don", "for", "from", "get", "got", "had", "has",
+    "have", "her", "here", "him", "his", "how", "its", "just", "like", "more", "not", "now", "off",
+    "one", "only", "our", "out", "really", "she", "should", "some", "than", "that", "the", "their",
+    "them", "then", "there", "these", "they", "this", "too", "was", "well", "were", "what", "when",
+    "where", "which", "who", "why", "will", "with", "would", "yeah", "yes", "you", "your",
+];
+
+/// The terms of a message: its interesting words and the pairs of them next to each other
+pub fn terms(text: &str) -> HashSet<String> {
+    let lower = text.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !(c.is_alphanumeric() || c == '\''))
+        .map(|w| w.trim_matches('\''))
+        .filter(|w| {
+            w.chars().count() >= 3
+                && !w.chars().all(|c| c.is_ascii_digit())
+                && !STOPWORDS.contains(w)
+        })
+        .collect();
+
+    // Counting each term once per message means one person repeating themselves isn't a trend
+    let mut terms: HashSet<String> = words.iter().map(|w| w.to_string()).collect();
+    for pair in words.windows(2) {
+        terms.insert(format!("{} {}", pair[0], pair[1]));
+    }
+    terms
+}
+
+/// A term that is trending in a room
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trend {
+    pub term: String,
+    /// Mentions in the window
+    pub count: u32,
+    /// The mentions the baseline says the window should have
+    pub expected: f64,
+    pub score: f64,
+}
+
+/// The body of an `Alert` event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrendAlert {
+    pub room: String,
+    pub trends: Vec<Trend>,
+}
+
+#[derive(Debug, Default)]
+struct RoomCounts {
+    /// The first bucket this room was seen in, so a young room doesn't have a baseline of zeros
+    first: i64,
+    /// (bucket, term counts), oldest first
+    buckets: VecDeque<(i64, HashMap<String, u32>)>,
+}
+
+/// The sliding windows of every room
+#[derive(Debug)]
+pub struct TrendTracker {
+    cfg: TrendCfg,
+    rooms: HashMap<String, RoomCounts>,
+    /// When we last alerted about a (room, term)
+    alerted: HashMap<(String, String), i64>,
+}
+
+impl TrendTracker {
+    pub fn new(cfg: TrendCfg) -> Self {
+        TrendTracker {
+            cfg,
+            rooms: HashMap::new(),
+            alerted: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &TrendCfg {
+        &self.cfg
+    }
+
+    fn bucket(&self, now: i64) -> i64 {
+        now / self.cfg.bucket_secs.max(1) as i64
+    }
+
+    /// Counts the message and returns the terms of it that just started trending
+    ///
+    /// `now` is in seconds.  Nothing is returned unless alerts are turned on.
+    pub fn observe(&mut self, room: &str, text: &str, now: i64) -> Vec<Trend> {
+        let bucket = self.bucket(now);
+        let oldest = bucket - (self.cfg.window_buckets + self.cfg.baseline_buckets) as i64;
+        let counts = self.rooms.entry(room.to_string()).or_insert_with(|| RoomCounts {
+            first: bucket,
+            buckets: VecDeque::new(),
+        });
+        if counts.buckets.back().is_none_or(|(b, _)| *b != bucket) {
+            counts.buckets.push_back((bucket, HashMap::new()));
+        }
+        let (_, current) = counts.buckets.back_mut().expect("Just pushed a bucket");
+        let seen = terms(text);
+        for term in &seen {
+            *current.entry(term.clone()).or_insert(0) += 1;
+        }
+
+        // Old buckets are of no more use, and neither is a room with nothing but old buckets
+        for counts in self.rooms.values_mut() {
+            while counts.buckets.front().is_some_and(|(b, _)| *b <= oldest) {
+                counts.buckets.pop_front();
+            }
+        }
+        self.rooms.retain(|_, counts| !counts.buckets.is_empty());
+
+        if !self.cfg.alert {
+            return vec![];
+        }
+        let cooldown = self.cfg.alert_cooldown_secs as i64;
+        let mut fresh = vec![];
+        for trend in self.trending(room, now) {
+            if !seen.contains(&trend.term) {
+                continue;
+            }
+            let key = (room.to_string(), trend.term.clone());
+            if self.alerted.get(&key).is_some_and(|last| now - last < cooldown) {
+                continue;
+            }
+            self.alerted.insert(key, now);
+            fresh.push(trend);
+        }
+        self.alerted.retain(|_, last| now - *last < cooldown);
+        fresh
+    }
+
+    /// The terms trending in the room, highest score first
+    pub fn trending(&self, room: &str, now: i64) -> Vec<Trend> {
+        let counts = match self.rooms.get(room) {
+            Some(counts) => counts,
+            None => return vec![],
+        };
+        let bucket = self.bucket(now);
+        let window_start = bucket - self.cfg.window_buckets as i64 + 1;
+        let baseline_start = window_start - self.cfg.baseline_buckets as i64;
+        // How much baseline this room really has
+        let baseline_len = window_start - baseline_start.max(counts.first);
+        if baseline_len <= 0 {
+            return vec![];
+        }
+
+        let mut window: HashMap<&str, u32> = HashMap::new();
+        let mut baseline: HashMap<&str, u32> = HashMap::new();
+        for (b, terms) in &counts.buckets {
+            let totals = if *b >= window_start && *b <= bucket {
+                &mut window
+            } else if *b >= baseline_start && *b < window_start {
+                &mut baseline
+            } else {
+                continue;
+            };
+            for (term, n) in terms {
+                *totals.entry(term).or_insert(0) += n;
+            }
+        }
+
+        let mut trends: Vec<Trend> = window
+            .into_iter()
+            .filter(|(_, count)| *count >= self.cfg.min_count)
+            .filter_map(|(term, count)| {
+                let rate = *baseline.get(term).unwrap_or(&0) as f64 / baseline_len as f64;
+                let expected = rate * self.cfg.window_buckets as f64;
+                let score = (count as f64 - expected) / (expected + 1.0).sqrt();
+                if score >= self.cfg.threshold {
+                    Some(Trend {
+                        term: term.to_string(),
+                        count,
+                        expected,
+                        score,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        trends.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap().then(a.term.cmp(&b.term)));
+        trends
+    }
+
+    /// Drops the counts of every room that `keep` says no to
+    pub fn retain_rooms<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.rooms.retain(|room, _| keep(room));
+        self.alerted.retain(|(room, _), _| keep(room));
+    }
+
+    /// The trends of every room that has any
+    pub fn all_trending(&self, now: i64) -> HashMap<String, Vec<Trend>> {
+        self.rooms
+            .keys()
+            .map(|room| (room.clone(), self.trending(room, now)))
+            .filter(|(_, trends)| !trends.is_empty())
+            .collect()
+    }
+}
+
+/// Counts a chat message, and tells the connected admins about anything that just started trending
+pub async fn observe(state: &ChatState, mesg: &KMessage<String>) {
+    let room = match (&mesg.room, mesg.conversation) {
+        (Some(room), None) => room.clone(),
+        _ => return,
+    };
+    let existing: HashSet<String> = state.rooms.lock().await.keys().cloned().collect();
+    if !existing.contains(&room) {
+        return;
+    }
+    let fresh = {
+        let mut tracker = state.trends.lock().await;
+        if !tracker.config().enabled {
+            return;
+        }
+        tracker.retain_rooms(|room| existing.contains(room));
+        tracker.observe(&room, &mesg.body, Utc::now().timestamp())
+    };
+    if fresh.is_empty() {
+        return;
+    }
+
+    let alert = TrendAlert { room, trends: fresh };
+    for admin in CONFIG.admins.iter() {
+        let mesg = KMessage::new("khadga".into(), vec![admin.clone()], MessageEvent::Alert, &alert);
+        send_to(&state.users, admin, &mesg).await;
+    }
+}
+
+/// The query string of `GET /trends`
+#[derive(Deserialize, Debug)]
+pub struct TrendParams {
+    pub room: Option<String>,
+}
+
+/// `GET /trends`
+pub async fn trends_handler(
+    user: String,
+    params: TrendParams,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let is_admin = CONFIG.admins.contains(&user);
+    let mine = rooms::rooms_of(&state.rooms, &user).await;
+    let tracker = state.trends.lock().await;
+    let now = Utc::now().timestamp();
+    let body = match params.room {
+        Some(room) => {
+            if !is_admin && !mine.contains(&room) {
+                let msg = "You can only see the trends of rooms you are in";
+                return Ok(error_reply(StatusCode::FORBIDDEN, msg));
+            }
+            let trends = tracker.trending(&room, now);
+            reply::json(&TrendAlert { room, trends })
+        }
+        None => {
+            let mut all = tracker.all_trending(now);
+            if !is_admin {
+                all.retain(|room, _| mine.contains(room));
+            }
+            reply::json(&all)
+        }
+    };
+    Ok(reply::with_status(body, StatusCode::OK))
+}
+
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    warp::get()
+        .and(warp::path("trends"))
+        .and(warp::path::end())
+        .and(authenticated())
+        .and(warp::query::<TrendParams>())
+        .and(with_state)
+        .and_then(trends_handler)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TrendCfg {
+        TrendCfg {
+            enabled: true,
+            bucket_secs: 60,
+            window_buckets: 5,
+            baseline_buckets: 60,
+            min_count: 3,
+            threshold: 3.0,
+            alert: true,
+            alert_cooldown_secs: 600,
+        }
+    }
+
+    #[test]
+    fn test_terms() {
+        let got = terms("The dragon's hoard!  The DRAGON's hoard, 2020 of it");
+        assert!(got.contains("dragon's"));
+        assert!(got.contains("hoard"));
+        assert!(got.contains("dragon's hoard"));
+        assert!(!got.contains("the"));
+        assert!(!got.contains("2020"));
+        assert!(!got.contains("it"));
+    }
+
+    #[test]
+    fn test_spikes() {
+        let mut tracker = TrendTracker::new(config());
+        let start = 1_600_000_000;
+
+        // An hour of the usual: dice come up once a minute
+        for minute in 0..60 {
+            let alerts = tracker.observe("dnd", "roll the dice", start + minute * 60);
+            assert!(alerts.is_empty(), "minute {}: {:?}", minute, alerts);
+        }
+
+        // Then everybody starts talking about the dragon
+        let now = start + 60 * 60;
+        let mut alerts = vec![];
+        for i in 0..6 {
+            alerts.extend(tracker.observe("dnd", "a red dragon appears", now + i));
+            tracker.observe("dnd", "roll the dice", now + i);
+        }
+        let alerted: Vec<&str> = alerts.iter().map(|t| t.term.as_str()).collect();
+        assert!(alerted.contains(&"dragon"));
+        assert!(alerted.contains(&"red dragon"));
+        // Alerts only happen once per term
+        assert_eq!(alerted.iter().filter(|t| **t == "dragon").count(), 1);
+
+        let trending = tracker.trending("dnd", now + 10);
+        assert!(trending.iter().any(|t| t.term == "dragon" && t.count == 6));
+        // Dice are up a bit, but that's not significant against the baseline
+        assert!(trending.iter().all(|t| t.term != "dice"));
+        // Other rooms are separate
+        assert!(tracker.trending("lobby", now + 10).is_empty());
+
+        // Once the window moves on, the dragon is old news
+        assert!(tracker.trending("dnd", now + 10 * 60).is_empty());
+    }
+
+    #[test]
+    fn test_new_room() {
+        // Without any history there is no baseline to compare against
+        let mut tracker = TrendTracker::new(config());
+        for i in 0..10 {
+            assert!(tracker.observe("new", "hello dragon", 1_600_000_000 + i).is_empty());
+        }
+        assert!(tracker.trending("new", 1_600_000_010).is_empty());
+    }
+
+    #[test]
+    fn test_idle_rooms() {
+        let mut tracker = TrendTracker::new(config());
+        let start = 1_600_000_000;
+        tracker.observe("oneshot", "anybody here", start);
+        tracker.observe("dnd", "roll the dice", start);
+        assert_eq!(tracker.rooms.len(), 2);
+
+        // An hour and more later, only the room that is still talking is kept
+        tracker.observe("dnd", "roll the dice", start + 65 * 60);
+        assert_eq!(tracker.rooms.keys().collect::<Vec<_>>(), vec!["dnd"]);
+
+        tracker.retain_rooms(|room| room != "dnd");
+        assert!(tracker.rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_privacy() {
+        use crate::{commands::Dispatcher,
+                    filter::FilterChain};
+
+        let state = ChatState::new(FilterChain::new(), Dispatcher::with_builtins(), None);
+        *state.trends.lock().await = TrendTracker::new(config());
+        let dm = KMessage::new(
+            "stoner".into(),
+            vec!["whammo".into()],
+            MessageEvent::Message,
+            "the secret plan".to_string(),
+        );
+        observe(&state, &dm).await;
+        assert!(state.trends.lock().await.rooms.is_empty());
+
+        // Nor are rooms that don't exist
+        let mut ghost =
+            KMessage::new("stoner".into(), vec![], MessageEvent::Message, "boo".to_string());
+        ghost.room = Some("ghost".into());
+        observe(&state, &ghost).await;
+        assert!(state.trends.lock().await.rooms.is_empty());
+
+        rooms::join(&state.rooms, "dnd", "stoner").await;
+        let ask = |room: Option<&str>| TrendParams {
+            room: room.map(String::from),
+        };
+        let status = |reply: WithStatus<Json>| reply.into_response().status();
+        let got = trends_handler("stoner".into(), ask(Some("dnd")), state.clone()).await;
+        assert_eq!(status(got.unwrap()), StatusCode::OK);
+        let got = trends_handler("whammo".into(), ask(Some("dnd")), state.clone()).await;
+        assert_eq!(status(got.unwrap()), StatusCode::FORBIDDEN);
+        let got = trends_handler("whammo".into(), ask(None), state.clone()).await;
+        assert_eq!(status(got.unwrap()), StatusCode::OK);
+    }
+}
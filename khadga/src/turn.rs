@@ -0,0 +1,1275 @@
+//! A TURN relay, for the people who can't connect to each other directly
+//!
+//! When both sides of a call are behind symmetric NATs (or firewalls that only let connections
+//! out), the addresses STUN finds don't work, and the media has to go through a server both of
+//! them can reach.  This is an RFC 5766 TURN server, over UDP:
+//!
+//! - an `Allocate` request gets the client a relayed address, a UDP port on khadga, for
+//!   `turn.default_lifetime_secs` (or as long as it asks for, up to `turn.max_lifetime_secs`).  A
+//!   `Refresh` keeps it alive, or deletes it when the lifetime asked for is 0.
+//! - `CreatePermission` lets a peer's IP send to the relayed address for 5 minutes.  Anything else
+//!   that arrives there is dropped.
+//! - data goes out to a peer in a `Send` indication, and comes back in a `Data` indication.  A
+//!   `ChannelBind` gives the peer a channel number instead, and then the data goes both ways with
+//!   a 4 byte header (ChannelData) rather than a whole STUN message.  Channels last 10 minutes.
+//! - each allocation can relay `turn.bandwidth_bytes_per_sec`, both ways together, and whatever
+//!   goes over that is dropped, like it would be on a congested link.  Nobody can have more than
+//!   `turn.max_allocations_per_user` allocations at once.
+//! - peers in `turn.denied_peers` (by default the loopback, private, link-local and multicast
+//!   ranges) get 403 Forbidden, and so does khadga itself.  The exception is another relayed
+//!   address, so two people who both need the relay can still reach each other.
+//!
+//! Relaying over TCP, DONT-FRAGMENT, EVEN-PORT and reservations aren't supported.
+//!
+//! Every request needs the long-term credentials of a khadga user.  khadga doesn't have passwords,
+//! so a logged in user gets credentials from `GET /turn/credentials`, which returns an ICE server
+//! (`urls`, `username` and `credential`) the browser can use as is.  The username is when they
+//! expire and who they are for (`1600000000:stoner`), and the password is an HMAC of the username,
+//! so the relay can check them without keeping anything.  They work for
+//! `turn.credential_ttl_secs`.
+//!
+//! It is off by default, and `turn.enabled` in the settings turns it on.  It answers Binding
+//! requests too, so it can stand in for the STUN server.
+
+use crate::{auth::{authenticated,
+                   CONFIG},
+            config::TurnCfg,
+            reply::error_reply,
+            stun::{self,
+                   parse_xor_address,
+                   xor_address,
+                   Class,
+                   StunMessage,
+                   BINDING,
+                   MAX_DATAGRAM,
+                   MESSAGE_INTEGRITY,
+                   NONCE,
+                   REALM,
+                   SOFTWARE,
+                   UNKNOWN_ATTRIBUTES,
+                   USERNAME,
+                   XOR_MAPPED_ADDRESS},
+            util::{random_bytes,
+                   to_hex}};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{debug,
+          error,
+          info};
+use ring::hmac;
+use serde_json::json;
+use std::{collections::HashMap,
+          convert::{Infallible,
+                    TryInto},
+          io,
+          net::{IpAddr,
+                SocketAddr},
+          sync::Arc,
+          time::{Duration,
+                 Instant}};
+use tokio::{net::{udp::{RecvHalf,
+                        SendHalf},
+                  UdpSocket},
+            sync::{oneshot,
+                   Mutex},
+            time::delay_for};
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+pub const ALLOCATE: u16 = 0x003;
+pub const REFRESH: u16 = 0x004;
+pub const SEND_INDICATION: u16 = 0x006;
+pub const DATA_INDICATION: u16 = 0x007;
+pub const CREATE_PERMISSION: u16 = 0x008;
+pub const CHANNEL_BIND: u16 = 0x009;
+
+pub const CHANNEL_NUMBER: u16 = 0x000C;
+pub const LIFETIME: u16 = 0x000D;
+pub const XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const DATA: u16 = 0x0013;
+pub const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const REQUESTED_TRANSPORT: u16 = 0x0019;
+
+/// The protocol number REQUESTED-TRANSPORT has for UDP, the only one we relay
+pub const UDP: u8 = 17;
+pub const FIRST_CHANNEL: u16 = 0x4000;
+pub const LAST_CHANNEL: u16 = 0x7FFF;
+
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+/// How long a nonce is good for.  After that, requests get 438 Stale Nonce, and need a new one.
+const NONCE_LIFETIME: i64 = 3600;
+
+/// The comprehension-required attributes that TURN requests can have
+const KNOWN: [u16; 9] = [
+    USERNAME,
+    MESSAGE_INTEGRITY,
+    REALM,
+    NONCE,
+    CHANNEL_NUMBER,
+    LIFETIME,
+    XOR_PEER_ADDRESS,
+    DATA,
+    REQUESTED_TRANSPORT,
+];
+
+lazy_static! {
+    pub static ref CREDENTIALS: Credentials = Credentials::from_config(&CONFIG.turn);
+}
+
+/// MD5, which the long-term credential mechanism makes its keys with.  ring doesn't have it.
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    ::md5::compute(data).0
+}
+
+/// Makes and checks the credentials handed out to khadga users, and the nonces
+#[derive(Clone)]
+pub struct Credentials {
+    secret: Vec<u8>,
+    pub realm: String,
+    /// How many seconds the credentials work for
+    pub ttl: i64,
+}
+
+impl Credentials {
+    pub fn new(secret: &[u8], realm: &str, ttl: i64) -> Self {
+        Credentials {
+            secret: secret.to_vec(),
+            realm: realm.into(),
+            ttl,
+        }
+    }
+
+    pub fn from_config(cfg: &TurnCfg) -> Self {
+        let secret = match &cfg.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => random_bytes::<[u8; 32]>().to_vec(),
+        };
+        Credentials::new(&secret, &cfg.realm, cfg.credential_ttl_secs)
+    }
+
+    fn sign(&self, data: &str) -> hmac::Tag {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &self.secret), data.as_bytes())
+    }
+
+    pub fn password(&self, username: &str) -> String {
+        base64::encode(self.sign(username))
+    }
+
+    /// A username and password for a khadga user, that work until `now` plus the ttl
+    pub fn issue(&self, user: &str, now: i64) -> (String, String) {
+        let username = format!("{}:{}", now + self.ttl, user);
+        let password = self.password(&username);
+        (username, password)
+    }
+
+    /// The khadga user a username is for, unless it has expired
+    pub fn user<'a>(&self, username: &'a str, now: i64) -> Option<&'a str> {
+        let mut parts = username.splitn(2, ':');
+        let expires: i64 = parts.next()?.parse().ok()?;
+        let user = parts.next().filter(|user| !user.is_empty())?;
+        if expires < now {
+            None
+        } else {
+            Some(user)
+        }
+    }
+
+    /// The long-term key, MD5(username:realm:password)
+    pub fn key(&self, username: &str) -> [u8; 16] {
+        md5(format!("{}:{}:{}", username, self.realm, self.password(username)).as_bytes())
+    }
+
+    /// A nonce has when it was made and a signature, so it can be checked without keeping it
+    pub fn nonce(&self, now: i64) -> String {
+        let made = format!("{:x}", now);
+        format!("{}-{}", made, to_hex(&self.sign(&made).as_ref()[..8]))
+    }
+
+    /// Whether we made the nonce, not too long ago
+    pub fn fresh(&self, nonce: &str, now: i64) -> bool {
+        let made = nonce.split('-').next().and_then(|made| i64::from_str_radix(made, 16).ok());
+        match made {
+            Some(made) => self.nonce(made) == nonce && made <= now && now - made < NONCE_LIFETIME,
+            None => false,
+        }
+    }
+}
+
+/// A range of addresses, like 10.0.0.0/8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    ip: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    /// Parses a range, or a single address
+    pub fn parse(text: &str) -> Option<IpRange> {
+        let mut parts = text.splitn(2, '/');
+        let ip: IpAddr = parts.next()?.trim().parse().ok()?;
+        let bits = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(IpRange { ip, prefix })
+    }
+
+    /// Whether the address is in the range.  IPv4 addresses mapped into IPv6 count as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            _ => ip,
+        };
+        let (net, ip, bits) = match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (net ^ ip) >> (bits - self.prefix) == 0
+    }
+}
+
+/// Rate limits an allocation.  It fills up at the allowed rate, and holds up to a second's worth.
+struct Bucket {
+    rate: f64,
+    bytes: f64,
+    filled: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Bucket {
+            rate: bytes_per_sec as f64,
+            bytes: bytes_per_sec as f64,
+            filled: now,
+        }
+    }
+
+    /// Whether `len` more bytes can go through now
+    fn take(&mut self, len: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.filled).as_secs_f64();
+        self.filled = self.filled.max(now);
+        self.bytes = (self.bytes + elapsed * self.rate).min(self.rate);
+        if self.bytes < len as f64 {
+            return false;
+        }
+        self.bytes -= len as f64;
+        true
+    }
+}
+
+/// The peers an allocation can talk to
+#[derive(Default)]
+struct Grants {
+    /// When the permission for each IP expires
+    permissions: HashMap<IpAddr, Instant>,
+    /// The peer each channel is bound to, and when the binding expires
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+}
+
+impl Grants {
+    fn permit(&mut self, ip: IpAddr, now: Instant) {
+        self.permissions.insert(ip, now + PERMISSION_LIFETIME);
+    }
+
+    fn permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|expires| *expires > now)
+    }
+
+    /// Binds a channel to a peer, or refreshes the binding.  A channel can only be for one peer,
+    /// and a peer can only have one channel.  Binding also gives the peer a permission.
+    fn bind(&mut self, channel: u16, peer: SocketAddr, now: Instant) -> bool {
+        let taken = self.channels.iter().any(|(number, (bound, _))| {
+            (*number == channel && *bound != peer) || (*number != channel && *bound == peer)
+        });
+        if taken {
+            return false;
+        }
+        self.channels.insert(channel, (peer, now + CHANNEL_LIFETIME));
+        self.permit(peer.ip(), now);
+        true
+    }
+
+    fn peer(&self, channel: u16, now: Instant) -> Option<SocketAddr> {
+        match self.channels.get(&channel) {
+            Some((peer, expires)) if *expires > now => Some(*peer),
+            _ => None,
+        }
+    }
+
+    fn channel(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (bound, expires))| *bound == peer && *expires > now)
+            .map(|(number, _)| *number)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.permissions.retain(|_, expires| *expires > now);
+        self.channels.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+/// A relayed address, and what it is allowed to do
+struct Allocation {
+    /// The credentials it was made with, which the requests for it have to use too
+    username: String,
+    user: String,
+    /// The Allocate request's, so a retransmission gets the same answer
+    transaction_id: [u8; 12],
+    relayed: SocketAddr,
+    expires: Instant,
+    grants: Grants,
+    bucket: Bucket,
+    to_peers: Arc<Mutex<SendHalf>>,
+    /// Dropped along with the allocation, which stops the task reading from the peers
+    _stop: oneshot::Sender<()>,
+}
+
+impl Allocation {
+    fn alive(&self, now: Instant) -> bool {
+        self.expires > now
+    }
+}
+
+/// The LIFETIME a request asked for
+fn requested_lifetime(request: &StunMessage) -> Option<u32> {
+    request
+        .get(LIFETIME)
+        .and_then(|value| value.try_into().ok())
+        .map(u32::from_be_bytes)
+}
+
+/// A ChannelData message: the channel, the length, then the data.  It isn't padded, because it
+/// goes over UDP.
+pub fn channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 4);
+    frame.extend_from_slice(&channel.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// The channel and data in a ChannelData message
+pub fn parse_channel_data(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let channel = u16::from_be_bytes([packet[0], packet[1]]);
+    let len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if !(FIRST_CHANNEL..=LAST_CHANNEL).contains(&channel) || packet.len() < len + 4 {
+        return None;
+    }
+    Some((channel, &packet[4..len + 4]))
+}
+
+fn data_indication(peer: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let transaction_id: [u8; 12] = random_bytes();
+    StunMessage::new(Class::Indication, DATA_INDICATION, transaction_id)
+        .with(XOR_PEER_ADDRESS, xor_address(peer, &transaction_id))
+        .with(DATA, data.to_vec())
+        .encode()
+}
+
+/// The peer addresses in a request, if there are any and they all make sense
+fn peers(request: &StunMessage) -> Option<Vec<SocketAddr>> {
+    let peers: Option<Vec<SocketAddr>> = request
+        .attributes
+        .iter()
+        .filter(|(kind, _)| *kind == XOR_PEER_ADDRESS)
+        .map(|(_, value)| parse_xor_address(value, &request.transaction_id))
+        .collect();
+    peers.filter(|peers| !peers.is_empty())
+}
+
+pub struct TurnServer {
+    cfg: TurnCfg,
+    credentials: Credentials,
+    /// By the client's address.  Everything comes in on one UDP socket, so that is the whole
+    /// 5-tuple.
+    allocations: Mutex<HashMap<SocketAddr, Allocation>>,
+    to_clients: Mutex<SendHalf>,
+    /// `cfg.denied_peers`, parsed
+    denied: Vec<IpRange>,
+    /// The addresses khadga itself has, which peers can't be either
+    own: Vec<IpAddr>,
+}
+
+impl TurnServer {
+    /// Answers the requests that come in, forever
+    async fn serve(self: Arc<Self>, mut from_clients: RecvHalf) {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match from_clients.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // eg an ICMP port unreachable from an earlier answer
+                    debug!("TURN receive failed: {}", e);
+                    continue;
+                }
+            };
+            if let Some(reply) = self.handle(&buf[..len], from).await {
+                self.send_to_client(from, &reply).await;
+            }
+        }
+    }
+
+    async fn send_to_client(&self, client: SocketAddr, data: &[u8]) {
+        if let Err(e) = self.to_clients.lock().await.send_to(data, &client).await {
+            debug!("Unable to send to {}: {}", client, e);
+        }
+    }
+
+    /// Drops the allocations, permissions and channels that have run out, every second
+    async fn expire_periodically(self: Arc<Self>) {
+        loop {
+            delay_for(Duration::from_secs(1)).await;
+            let now = Instant::now();
+            let mut allocations = self.allocations.lock().await;
+            allocations.retain(|client, allocation| {
+                if !allocation.alive(now) {
+                    info!("The relay for {} ({}) expired", allocation.user, client);
+                }
+                allocation.alive(now)
+            });
+            for allocation in allocations.values_mut() {
+                allocation.grants.expire(now);
+            }
+        }
+    }
+
+    /// The answer to one datagram from a client, if it needs one
+    async fn handle(self: &Arc<Self>, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        // ChannelData starts with 01, and STUN messages with 00
+        if packet.first().is_some_and(|b| b & 0xC0 == 0x40) {
+            if let Some((channel, data)) = parse_channel_data(packet) {
+                self.to_peer(from, data, |grants, now| grants.peer(channel, now)).await;
+            }
+            return None;
+        }
+        let request = match StunMessage::decode(packet) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Ignoring a datagram from {}: {}", from, e);
+                return None;
+            }
+        };
+        match (request.class, request.method) {
+            (Class::Request, BINDING) => {
+                return stun::answer(packet, from, self.cfg.software.as_deref());
+            }
+            (Class::Indication, SEND_INDICATION) => {
+                let peer = request
+                    .get(XOR_PEER_ADDRESS)
+                    .and_then(|value| parse_xor_address(value, &request.transaction_id));
+                if let (Some(peer), Some(data)) = (peer, request.get(DATA)) {
+                    self.to_peer(from, data, |_, _| Some(peer)).await;
+                }
+                return None;
+            }
+            (Class::Request, _) => {}
+            _ => return None,
+        }
+
+        let (reply, key) = match self.authenticate(&request, packet, Utc::now().timestamp()) {
+            Err(reply) => (reply, None),
+            Ok((username, user, key)) => {
+                let unknown = request.unknown(&KNOWN);
+                let reply = if !unknown.is_empty() {
+                    let value = unknown.iter().flat_map(|kind| kind.to_be_bytes().to_vec());
+                    let value = value.collect();
+                    request.error(420, "Unknown Attribute").with(UNKNOWN_ATTRIBUTES, value)
+                } else {
+                    match request.method {
+                        ALLOCATE => self.allocate(&request, from, username, user).await,
+                        REFRESH => self.refresh(&request, from, &username).await,
+                        CREATE_PERMISSION => {
+                            self.create_permission(&request, from, &username).await
+                        }
+                        CHANNEL_BIND => self.channel_bind(&request, from, &username).await,
+                        _ => request.error(400, "Unsupported method"),
+                    }
+                };
+                (reply, Some(key))
+            }
+        };
+        let reply = match &self.cfg.software {
+            Some(software) => reply.with(SOFTWARE, software.as_bytes().to_vec()),
+            None => reply,
+        };
+        Some(match key {
+            Some(key) => reply.encode_with_integrity(&key),
+            None => reply.encode_with_fingerprint(),
+        })
+    }
+
+    /// Checks the long-term credentials of a request.  If they are good, this gives the username,
+    /// the khadga user, and the key to sign the answer with.  Otherwise, it gives the answer.
+    fn authenticate(
+        &self,
+        request: &StunMessage,
+        packet: &[u8],
+        now: i64,
+    ) -> Result<(String, String, [u8; 16]), StunMessage> {
+        let challenge = |code: u16, reason: &str| {
+            request
+                .error(code, reason)
+                .with(REALM, self.credentials.realm.as_bytes().to_vec())
+                .with(NONCE, self.credentials.nonce(now).into_bytes())
+        };
+        if request.get(MESSAGE_INTEGRITY).is_none() {
+            return Err(challenge(401, "Unauthorized"));
+        }
+        let (username, realm, nonce) =
+            match (request.get_str(USERNAME), request.get_str(REALM), request.get_str(NONCE)) {
+                (Some(username), Some(realm), Some(nonce)) => (username, realm, nonce),
+                _ => return Err(request.error(400, "Missing USERNAME, REALM or NONCE")),
+            };
+        if !self.credentials.fresh(nonce, now) {
+            return Err(challenge(438, "Stale Nonce"));
+        }
+        let user = match self.credentials.user(username, now) {
+            Some(user) if realm == self.credentials.realm => user,
+            _ => return Err(challenge(401, "Unauthorized")),
+        };
+        let key = self.credentials.key(username);
+        if !stun::check_integrity(packet, &key) {
+            return Err(challenge(401, "Unauthorized"));
+        }
+        Ok((username.into(), user.into(), key))
+    }
+
+    /// The lifetime to give an allocation, when the client asked for `requested`
+    fn lifetime(&self, requested: Option<u32>) -> u32 {
+        let lifetime = requested.unwrap_or(self.cfg.default_lifetime_secs);
+        lifetime.max(self.cfg.default_lifetime_secs).min(self.cfg.max_lifetime_secs)
+    }
+
+    async fn relay_socket(&self) -> io::Result<(UdpSocket, SocketAddr)> {
+        let parse = |ip: &str| {
+            ip.parse::<IpAddr>()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Bad IP {}", ip)))
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(parse(&self.cfg.relay_ip)?, 0)).await?;
+        let mut relayed = socket.local_addr()?;
+        if let Some(external) = &self.cfg.external_ip {
+            relayed.set_ip(parse(external)?);
+        }
+        Ok((socket, relayed))
+    }
+
+    fn allocated(
+        request: &StunMessage,
+        client: SocketAddr,
+        allocation: &Allocation,
+        now: Instant,
+    ) -> StunMessage {
+        let lifetime = allocation.expires.saturating_duration_since(now).as_secs() as u32;
+        let transaction_id = &request.transaction_id;
+        request
+            .response(Class::Success)
+            .with(XOR_RELAYED_ADDRESS, xor_address(allocation.relayed, transaction_id))
+            .with(LIFETIME, lifetime.to_be_bytes().to_vec())
+            .with(XOR_MAPPED_ADDRESS, xor_address(client, transaction_id))
+    }
+
+    async fn allocate(
+        self: &Arc<Self>,
+        request: &StunMessage,
+        client: SocketAddr,
+        username: String,
+        user: String,
+    ) -> StunMessage {
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().await;
+        if let Some(existing) = allocations.get(&client).filter(|a| a.alive(now)) {
+            // The client didn't get our answer, and asked again
+            if existing.transaction_id == request.transaction_id && existing.username == username {
+                return TurnServer::allocated(request, client, existing, now);
+            }
+            return request.error(437, "Allocation Mismatch");
+        }
+        match request.get(REQUESTED_TRANSPORT) {
+            None => return request.error(400, "Missing REQUESTED-TRANSPORT"),
+            Some(value) if value.first() != Some(&UDP) => {
+                return request.error(442, "Unsupported Transport Protocol");
+            }
+            _ => {}
+        }
+        let mine = allocations.values().filter(|a| a.user == user && a.alive(now)).count();
+        if mine >= self.cfg.max_allocations_per_user {
+            return request.error(486, "Allocation Quota Reached");
+        }
+        if allocations.len() >= self.cfg.max_allocations {
+            return request.error(508, "Insufficient Capacity");
+        }
+        let (socket, relayed) = match self.relay_socket().await {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!("Unable to open a relay socket on {}: {}", self.cfg.relay_ip, e);
+                return request.error(508, "Insufficient Capacity");
+            }
+        };
+
+        let (from_peers, to_peers) = socket.split();
+        let (stop, stopped) = oneshot::channel();
+        let lifetime = self.lifetime(requested_lifetime(request));
+        let allocation = Allocation {
+            username,
+            user,
+            transaction_id: request.transaction_id,
+            relayed,
+            expires: now + Duration::from_secs(lifetime.into()),
+            grants: Grants::default(),
+            bucket: Bucket::new(self.cfg.bandwidth_bytes_per_sec, now),
+            to_peers: Arc::new(Mutex::new(to_peers)),
+            _stop: stop,
+        };
+        info!("Relaying for {} ({}) on {}", allocation.user, client, relayed);
+        let reply = TurnServer::allocated(request, client, &allocation, now);
+        allocations.insert(client, allocation);
+        tokio::spawn(self.clone().relay_from_peers(client, from_peers, stopped));
+        reply
+    }
+
+    /// Whether nobody can relay to the peer: it is in a denied range, or it is khadga itself.
+    /// Other allocations' relayed addresses are fine, since that is how two people behind the
+    /// relay reach each other.
+    fn forbidden(
+        &self,
+        peer: SocketAddr,
+        allocations: &HashMap<SocketAddr, Allocation>,
+        now: Instant,
+    ) -> bool {
+        if self.denied.iter().any(|range| range.contains(peer.ip())) {
+            return true;
+        }
+        self.own.contains(&peer.ip())
+            && !allocations.values().any(|a| a.alive(now) && a.relayed == peer)
+    }
+
+    /// The client's allocation, as long as the request used the credentials it was made with
+    fn owned<'a>(
+        allocations: &'a mut HashMap<SocketAddr, Allocation>,
+        request: &StunMessage,
+        client: SocketAddr,
+        username: &str,
+        now: Instant,
+    ) -> Result<&'a mut Allocation, StunMessage> {
+        match allocations.get_mut(&client) {
+            Some(allocation) if allocation.alive(now) => {
+                if allocation.username == username {
+                    Ok(allocation)
+                } else {
+                    Err(request.error(441, "Wrong Credentials"))
+                }
+            }
+            _ => Err(request.error(437, "Allocation Mismatch")),
+        }
+    }
+
+    async fn refresh(
+        &self,
+        request: &StunMessage,
+        client: SocketAddr,
+        username: &str,
+    ) -> StunMessage {
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().await;
+        let allocation = match TurnServer::owned(&mut allocations, request, client, username, now) {
+            Ok(allocation) => allocation,
+            Err(reply) => return reply,
+        };
+        let lifetime = match requested_lifetime(request) {
+            Some(0) => 0,
+            requested => self.lifetime(requested),
+        };
+        if lifetime == 0 {
+            info!("{} ({}) is done with {}", allocation.user, client, allocation.relayed);
+            allocations.remove(&client);
+        } else {
+            allocation.expires = now + Duration::from_secs(lifetime.into());
+        }
+        request.response(Class::Success).with(LIFETIME, lifetime.to_be_bytes().to_vec())
+    }
+
+    async fn create_permission(
+        &self,
+        request: &StunMessage,
+        client: SocketAddr,
+        username: &str,
+    ) -> StunMessage {
+        let peers = match peers(request) {
+            Some(peers) => peers,
+            None => return request.error(400, "Missing or bad XOR-PEER-ADDRESS"),
+        };
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().await;
+        if peers.iter().any(|peer| self.forbidden(*peer, &allocations, now)) {
+            return request.error(403, "Forbidden");
+        }
+        let allocation = match TurnServer::owned(&mut allocations, request, client, username, now) {
+            Ok(allocation) => allocation,
+            Err(reply) => return reply,
+        };
+        if peers.iter().any(|peer| peer.is_ipv4() != allocation.relayed.is_ipv4()) {
+            return request.error(443, "Peer Address Family Mismatch");
+        }
+        for peer in peers {
+            allocation.grants.permit(peer.ip(), now);
+        }
+        request.response(Class::Success)
+    }
+
+    async fn channel_bind(
+        &self,
+        request: &StunMessage,
+        client: SocketAddr,
+        username: &str,
+    ) -> StunMessage {
+        let channel = request
+            .get(CHANNEL_NUMBER)
+            .filter(|value| value.len() == 4)
+            .map(|value| u16::from_be_bytes([value[0], value[1]]))
+            .filter(|channel| (FIRST_CHANNEL..=LAST_CHANNEL).contains(channel));
+        let peer = request
+            .get(XOR_PEER_ADDRESS)
+            .and_then(|value| parse_xor_address(value, &request.transaction_id));
+        let (channel, peer) = match (channel, peer) {
+            (Some(channel), Some(peer)) => (channel, peer),
+            _ => return request.error(400, "Missing or bad CHANNEL-NUMBER or XOR-PEER-ADDRESS"),
+        };
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().await;
+        if self.forbidden(peer, &allocations, now) {
+            return request.error(403, "Forbidden");
+        }
+        let allocation = match TurnServer::owned(&mut allocations, request, client, username, now) {
+            Ok(allocation) => allocation,
+            Err(reply) => return reply,
+        };
+        if peer.is_ipv4() != allocation.relayed.is_ipv4() {
+            return request.error(443, "Peer Address Family Mismatch");
+        }
+        if !allocation.grants.bind(channel, peer, now) {
+            return request.error(400, "The channel or the peer is already bound");
+        }
+        request.response(Class::Success)
+    }
+
+    /// Sends data from a client to the peer `pick` chooses, if it has a permission, isn't
+    /// forbidden, and the allocation has the bandwidth left
+    async fn to_peer<F>(&self, client: SocketAddr, data: &[u8], pick: F)
+    where
+        F: FnOnce(&Grants, Instant) -> Option<SocketAddr>,
+    {
+        let now = Instant::now();
+        let (to_peers, peer) = {
+            let mut allocations = self.allocations.lock().await;
+            let peer = match allocations.get(&client) {
+                Some(allocation) if allocation.alive(now) => pick(&allocation.grants, now),
+                _ => return,
+            };
+            // Permissions are by IP, so a permission for another relayed address doesn't mean
+            // every port on khadga is fair game
+            let peer = match peer {
+                Some(peer) if !self.forbidden(peer, &allocations, now) => peer,
+                _ => return,
+            };
+            let allocation = allocations.get_mut(&client).expect("checked above");
+            let permitted = allocation.grants.permitted(peer.ip(), now);
+            if !permitted || !allocation.bucket.take(data.len(), now) {
+                return;
+            }
+            (allocation.to_peers.clone(), peer)
+        };
+        let sent = to_peers.lock().await.send_to(data, &peer).await;
+        if let Err(e) = sent {
+            debug!("Unable to relay to {}: {}", peer, e);
+        }
+    }
+
+    /// Passes on what peers send to the relayed address to the client, until the allocation is gone
+    async fn relay_from_peers(
+        self: Arc<Self>,
+        client: SocketAddr,
+        mut from_peers: RecvHalf,
+        mut stopped: oneshot::Receiver<()>,
+    ) {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = tokio::select! {
+                received = from_peers.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("Relay receive for {} failed: {}", client, e);
+                        continue;
+                    }
+                },
+                _ = &mut stopped => return,
+            };
+            let now = Instant::now();
+            let frame = {
+                let mut allocations = self.allocations.lock().await;
+                let allocation = match allocations.get_mut(&client) {
+                    Some(allocation) if allocation.alive(now) => allocation,
+                    _ => continue,
+                };
+                if !allocation.grants.permitted(peer.ip(), now) || !allocation.bucket.take(len, now)
+                {
+                    continue;
+                }
+                match allocation.grants.channel(peer, now) {
+                    Some(channel) => channel_data(channel, &buf[..len]),
+                    None => data_indication(peer, &buf[..len]),
+                }
+            };
+            self.send_to_client(client, &frame).await;
+        }
+    }
+}
+
+/// Starts the relay, and gives the address it is listening on
+pub async fn start(cfg: TurnCfg, credentials: Credentials) -> io::Result<SocketAddr> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, what.to_string());
+    let denied = cfg
+        .denied_peers
+        .iter()
+        .map(|range| IpRange::parse(range).ok_or_else(|| invalid(&format!("Bad range {}", range))))
+        .collect::<io::Result<Vec<IpRange>>>()?;
+    let socket = UdpSocket::bind(&cfg.listen).await?;
+    let addr = socket.local_addr()?;
+    let mut own = vec![];
+    for ip in std::iter::once(&cfg.relay_ip).chain(cfg.external_ip.iter()) {
+        own.push(ip.parse::<IpAddr>().map_err(|_| invalid(&format!("Bad IP {}", ip)))?);
+    }
+    if !addr.ip().is_unspecified() {
+        own.push(addr.ip());
+    }
+
+    let (from_clients, to_clients) = socket.split();
+    let server = Arc::new(TurnServer {
+        cfg,
+        credentials,
+        allocations: Mutex::new(HashMap::new()),
+        to_clients: Mutex::new(to_clients),
+        denied,
+        own,
+    });
+    tokio::spawn(server.clone().serve(from_clients));
+    tokio::spawn(server.expire_periodically());
+    Ok(addr)
+}
+
+/// Starts the TURN relay
+pub async fn run(cfg: TurnCfg, credentials: Credentials) {
+    let listen = cfg.listen.clone();
+    match start(cfg, credentials).await {
+        Ok(addr) => info!("TURN relay listening on {}", addr),
+        Err(e) => error!("Unable to start the TURN relay on {}: {}", listen, e),
+    }
+}
+
+/// `GET /turn/credentials`
+pub async fn credentials(user: String) -> Result<WithStatus<Json>, Infallible> {
+    if !CONFIG.turn.enabled {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "The TURN relay isn't enabled"));
+    }
+    let (username, password) = CREDENTIALS.issue(&user, Utc::now().timestamp());
+    let server = json!({
+        "urls": CONFIG.turn.urls,
+        "username": username,
+        "credential": password,
+        "ttl": CREDENTIALS.ttl,
+    });
+    Ok(reply::with_status(reply::json(&server), StatusCode::OK))
+}
+
+pub fn routes() -> BoxedFilter<(impl Reply,)> {
+    warp::get()
+        .and(warp::path!("turn" / "credentials"))
+        .and(authenticated())
+        .and_then(credentials)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stun::ERROR_CODE;
+
+    const PEER_TRANSACTION: [u8; 12] = [9; 12];
+
+    fn test_cfg() -> TurnCfg {
+        TurnCfg {
+            enabled: true,
+            listen: "127.0.0.1:0".into(),
+            relay_ip: "127.0.0.1".into(),
+            // The peers are on loopback too
+            denied_peers: vec![],
+            ..TurnCfg::default()
+        }
+    }
+
+    fn code(reply: &StunMessage) -> Option<u16> {
+        reply.get(ERROR_CODE).map(|value| u16::from(value[2]) * 100 + u16::from(value[3]))
+    }
+
+    async fn receive(socket: &mut UdpSocket) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let wait = Duration::from_millis(500);
+        match tokio::time::timeout(wait, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, from))) => Some((buf[..len].to_vec(), from)),
+            _ => None,
+        }
+    }
+
+    /// A socket, and the credentials it signs its requests with
+    struct Client {
+        socket: UdpSocket,
+        server: SocketAddr,
+        username: String,
+        key: [u8; 16],
+        nonce: String,
+    }
+
+    impl Client {
+        async fn new(server: SocketAddr) -> Self {
+            Client {
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                server,
+                username: String::new(),
+                key: [0; 16],
+                nonce: String::new(),
+            }
+        }
+
+        fn login(&mut self, credentials: &Credentials, user: &str, now: i64) {
+            let (username, password) = credentials.issue(user, now);
+            self.key = md5(format!("{}:khadga:{}", username, password).as_bytes());
+            self.username = username;
+            self.nonce = credentials.nonce(now);
+        }
+
+        async fn send(&mut self, packet: &[u8]) {
+            self.socket.send_to(packet, &self.server).await.unwrap();
+        }
+
+        /// Sends the request as it is, and waits for the answer
+        async fn exchange(&mut self, packet: &[u8]) -> (StunMessage, Vec<u8>) {
+            self.send(packet).await;
+            let (answer, _) = receive(&mut self.socket).await.expect("no answer");
+            (StunMessage::decode(&answer).unwrap(), answer)
+        }
+
+        /// Signs the request, and waits for the answer
+        async fn ask(&mut self, request: StunMessage) -> StunMessage {
+            let packet = request
+                .with(USERNAME, self.username.as_bytes().to_vec())
+                .with(REALM, b"khadga".to_vec())
+                .with(NONCE, self.nonce.as_bytes().to_vec())
+                .encode_with_integrity(&self.key);
+            let (answer, raw) = self.exchange(&packet).await;
+            assert!(stun::check_integrity(&raw, &self.key) || answer.class == Class::Error);
+            answer
+        }
+    }
+
+    fn request(method: u16, n: u8) -> StunMessage {
+        StunMessage::new(Class::Request, method, [n; 12])
+    }
+
+    fn allocate(n: u8) -> StunMessage {
+        request(ALLOCATE, n).with(REQUESTED_TRANSPORT, vec![UDP, 0, 0, 0])
+    }
+
+    fn refresh(n: u8, lifetime: u32) -> StunMessage {
+        request(REFRESH, n).with(LIFETIME, lifetime.to_be_bytes().to_vec())
+    }
+
+    fn with_peer(method: u16, n: u8, peer: SocketAddr) -> StunMessage {
+        request(method, n).with(XOR_PEER_ADDRESS, xor_address(peer, &[n; 12]))
+    }
+
+    fn relayed(reply: &StunMessage) -> SocketAddr {
+        parse_xor_address(reply.get(XOR_RELAYED_ADDRESS).unwrap(), &reply.transaction_id).unwrap()
+    }
+
+    #[test]
+    fn test_md5() {
+        assert_eq!(to_hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(to_hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        let long = "1234567890".repeat(8);
+        assert_eq!(to_hex(&md5(long.as_bytes())), "57edf4a22be3c955ac49da2e2107b67a");
+        // The example from RFC 5389, section 15.4
+        let key = md5("user:realm:pass".as_bytes());
+        assert_eq!(to_hex(&key), "8493fbc53ba582fb4c044c456bdc40eb");
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials::new(b"secret", "khadga", 100);
+        let (username, password) = credentials.issue("stoner", 1000);
+        assert_eq!(username, "1100:stoner");
+        assert_eq!(credentials.password(&username), password);
+        assert_ne!(Credentials::new(b"other", "khadga", 100).password(&username), password);
+        assert_eq!(credentials.user(&username, 1050), Some("stoner"));
+        assert_eq!(credentials.user(&username, 1101), None);
+        assert_eq!(credentials.user("1100:odd:name", 1000), Some("odd:name"));
+        assert_eq!(credentials.user("stoner", 1000), None);
+        assert_eq!(credentials.user("1100:", 1000), None);
+
+        let nonce = credentials.nonce(1000);
+        assert!(credentials.fresh(&nonce, 1000));
+        assert!(credentials.fresh(&nonce, 1000 + NONCE_LIFETIME - 1));
+        assert!(!credentials.fresh(&nonce, 1000 + NONCE_LIFETIME));
+        assert!(!credentials.fresh(&nonce.replace("3e8", "3e9"), 1001));
+        assert!(!credentials.fresh("garbage", 1000));
+    }
+
+    #[test]
+    fn test_grants() {
+        let now = Instant::now();
+        let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let other: SocketAddr = "203.0.113.8:5000".parse().unwrap();
+        let mut grants = Grants::default();
+        assert!(!grants.permitted(peer.ip(), now));
+        grants.permit(peer.ip(), now);
+        assert!(grants.permitted(peer.ip(), now + Duration::from_secs(299)));
+        assert!(!grants.permitted(peer.ip(), now + PERMISSION_LIFETIME));
+
+        assert!(grants.bind(0x4000, other, now));
+        assert!(grants.permitted(other.ip(), now));
+        assert_eq!(grants.peer(0x4000, now), Some(other));
+        assert_eq!(grants.channel(other, now), Some(0x4000));
+        // Taken channels and peers that already have one can't be bound again, but can be refreshed
+        assert!(!grants.bind(0x4000, peer, now));
+        assert!(!grants.bind(0x4001, other, now));
+        let later = now + Duration::from_secs(500);
+        assert!(grants.bind(0x4000, other, later));
+        assert_eq!(grants.peer(0x4000, now + CHANNEL_LIFETIME), Some(other));
+
+        grants.expire(later + CHANNEL_LIFETIME);
+        assert!(grants.permissions.is_empty() && grants.channels.is_empty());
+        assert!(grants.bind(0x4000, peer, now));
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let private = IpRange::parse("172.16.0.0/12").unwrap();
+        assert!(private.contains("172.31.255.1".parse().unwrap()));
+        assert!(!private.contains("172.32.0.1".parse().unwrap()));
+        assert!(private.contains("::ffff:172.16.0.1".parse().unwrap()));
+        assert!(!private.contains("fc00::1".parse().unwrap()));
+        let one = IpRange::parse("::1").unwrap();
+        assert!(one.contains("::1".parse().unwrap()) && !one.contains("::2".parse().unwrap()));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(IpRange::parse("lan").is_none());
+        let denied: Vec<IpRange> =
+            TurnCfg::default().denied_peers.iter().map(|r| IpRange::parse(r).unwrap()).collect();
+        assert!(denied.iter().any(|range| range.contains("169.254.169.254".parse().unwrap())));
+        assert!(!denied.iter().any(|range| range.contains("203.0.113.7".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn test_denied_peers() {
+        let credentials = Credentials::new(b"secret", "khadga", 3600);
+        let cfg = TurnCfg {
+            denied_peers: TurnCfg::default().denied_peers,
+            ..test_cfg()
+        };
+        let server = start(cfg, credentials.clone()).await.unwrap();
+        let mut client = Client::new(server).await;
+        client.login(&credentials, "stoner", Utc::now().timestamp());
+        assert_eq!(client.ask(allocate(1)).await.class, Class::Success);
+        let inside = ["127.0.0.2:5432", "10.1.2.3:53", "169.254.169.254:80"];
+        for (n, peer) in inside.iter().enumerate() {
+            let peer = peer.parse().unwrap();
+            let reply = client.ask(with_peer(CREATE_PERMISSION, n as u8 + 2, peer)).await;
+            assert_eq!(code(&reply), Some(403));
+            let bind = with_peer(CHANNEL_BIND, n as u8 + 10, peer)
+                .with(CHANNEL_NUMBER, vec![0x40, 0, 0, 0]);
+            assert_eq!(code(&client.ask(bind).await), Some(403));
+        }
+        let public = "203.0.113.7:5000".parse().unwrap();
+        let reply = client.ask(with_peer(CREATE_PERMISSION, 20, public)).await;
+        assert_eq!(reply.class, Class::Success);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1000, now);
+        assert!(bucket.take(600, now));
+        assert!(!bucket.take(600, now));
+        assert!(bucket.take(400, now));
+        assert!(!bucket.take(1, now));
+        assert!(bucket.take(500, now + Duration::from_millis(500)));
+        // It doesn't fill up past a second's worth
+        assert!(!bucket.take(1001, now + Duration::from_secs(10)));
+        assert!(bucket.take(1000, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_channel_data() {
+        let frame = channel_data(0x4001, b"hello");
+        assert_eq!(frame[..4], [0x40, 0x01, 0, 5]);
+        assert_eq!(parse_channel_data(&frame), Some((0x4001, &b"hello"[..])));
+        assert_eq!(parse_channel_data(&frame[..6]), None);
+        assert_eq!(parse_channel_data(&channel_data(0x8000, b"hi")), None);
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let credentials = Credentials::new(b"secret", "khadga", 3600);
+        let server = start(test_cfg(), credentials.clone()).await.unwrap();
+        let mut client = Client::new(server).await;
+        let client_addr = client.socket.local_addr().unwrap();
+        // Not on the relay's IP, which is khadga's own
+        let mut peer = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let now = Utc::now().timestamp();
+
+        // Without credentials, we get told the realm and a nonce
+        let (reply, _) = client.exchange(&allocate(1).encode()).await;
+        assert_eq!(code(&reply), Some(401));
+        assert_eq!(reply.get_str(REALM), Some("khadga"));
+        assert!(credentials.fresh(reply.get_str(NONCE).unwrap(), now));
+
+        client.login(&credentials, "stoner", now);
+        let key = client.key;
+        client.key = md5(format!("{}:khadga:nope", client.username).as_bytes());
+        assert_eq!(code(&client.ask(allocate(1)).await), Some(401));
+        client.key = key;
+        let nonce = std::mem::replace(&mut client.nonce, credentials.nonce(now - NONCE_LIFETIME));
+        assert_eq!(code(&client.ask(allocate(1)).await), Some(438));
+        client.nonce = nonce;
+
+        let reply = client.ask(allocate(1)).await;
+        assert_eq!(reply.class, Class::Success);
+        let relayed = relayed(&reply);
+        assert_eq!(relayed.ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_ne!(relayed, server);
+        assert_eq!(reply.get(LIFETIME), Some(&600u32.to_be_bytes()[..]));
+        let mapped = parse_xor_address(reply.get(XOR_MAPPED_ADDRESS).unwrap(), &[1; 12]);
+        assert_eq!(mapped, Some(client_addr));
+        // A retransmission gets the same answer, but a new Allocate doesn't
+        let again = client.ask(allocate(1)).await;
+        assert_eq!(again.get(XOR_RELAYED_ADDRESS), reply.get(XOR_RELAYED_ADDRESS));
+        assert_eq!(code(&client.ask(allocate(2)).await), Some(437));
+
+        // Nothing gets through without a permission
+        let send = |data: &[u8]| {
+            StunMessage::new(Class::Indication, SEND_INDICATION, PEER_TRANSACTION)
+                .with(XOR_PEER_ADDRESS, xor_address(peer_addr, &PEER_TRANSACTION))
+                .with(DATA, data.to_vec())
+                .encode()
+        };
+        client.send(&send(b"hello")).await;
+        assert!(receive(&mut peer).await.is_none());
+        peer.send_to(b"hi", &relayed).await.unwrap();
+        assert!(receive(&mut client.socket).await.is_none());
+
+        // khadga itself is off limits
+        assert_eq!(code(&client.ask(with_peer(CREATE_PERMISSION, 3, server)).await), Some(403));
+        let reply = client.ask(with_peer(CREATE_PERMISSION, 3, peer_addr)).await;
+        assert_eq!(reply.class, Class::Success);
+        client.send(&send(b"hello")).await;
+        assert_eq!(receive(&mut peer).await, Some((b"hello".to_vec(), relayed)));
+        peer.send_to(b"hi", &relayed).await.unwrap();
+        let (packet, _) = receive(&mut client.socket).await.unwrap();
+        let data = StunMessage::decode(&packet).unwrap();
+        assert_eq!((data.class, data.method), (Class::Indication, DATA_INDICATION));
+        let from = parse_xor_address(data.get(XOR_PEER_ADDRESS).unwrap(), &data.transaction_id);
+        assert_eq!(from, Some(peer_addr));
+        assert_eq!(data.get(DATA), Some(&b"hi"[..]));
+
+        // Once there is a channel, the data goes with just a channel number
+        let bind = |n: u8, channel: u16| {
+            let number = channel.to_be_bytes();
+            let value = vec![number[0], number[1], 0, 0];
+            with_peer(CHANNEL_BIND, n, peer_addr).with(CHANNEL_NUMBER, value)
+        };
+        assert_eq!(client.ask(bind(4, 0x4000)).await.class, Class::Success);
+        assert_eq!(code(&client.ask(bind(5, 0x4001)).await), Some(400));
+        client.send(&channel_data(0x4000, b"fast")).await;
+        assert_eq!(receive(&mut peer).await, Some((b"fast".to_vec(), relayed)));
+        peer.send_to(b"back", &relayed).await.unwrap();
+        let (packet, _) = receive(&mut client.socket).await.unwrap();
+        assert_eq!(parse_channel_data(&packet), Some((0x4000, &b"back"[..])));
+
+        // Somebody else can't use it, even from the same address
+        client.login(&credentials, "whammo", now);
+        assert_eq!(code(&client.ask(refresh(6, 60)).await), Some(441));
+        client.login(&credentials, "stoner", now);
+
+        // Refreshing stays within the limits, and a lifetime of 0 deletes the allocation
+        let reply = client.ask(refresh(7, 99_999)).await;
+        assert_eq!(reply.get(LIFETIME), Some(&3600u32.to_be_bytes()[..]));
+        let reply = client.ask(refresh(8, 0)).await;
+        assert_eq!((reply.class, reply.get(LIFETIME)), (Class::Success, Some(&[0u8; 4][..])));
+        peer.send_to(b"anyone?", &relayed).await.unwrap();
+        assert!(receive(&mut client.socket).await.is_none());
+        assert_eq!(code(&client.ask(refresh(9, 60)).await), Some(437));
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let credentials = Credentials::new(b"secret", "khadga", 3600);
+        let cfg = TurnCfg {
+            max_allocations_per_user: 1,
+            bandwidth_bytes_per_sec: 1000,
+            ..test_cfg()
+        };
+        let server = start(cfg, credentials.clone()).await.unwrap();
+        let now = Utc::now().timestamp();
+        let mut first = Client::new(server).await;
+        let mut second = Client::new(server).await;
+        // Not on the relay's IP, which is khadga's own
+        let mut peer = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        first.login(&credentials, "stoner", now);
+        second.login(&credentials, "stoner", now);
+        let reply = first.ask(allocate(1)).await;
+        assert_eq!(reply.class, Class::Success);
+        let relayed = relayed(&reply);
+        assert_eq!(code(&second.ask(allocate(2)).await), Some(486));
+
+        // Expired credentials and other transports don't work
+        second.login(&credentials, "whammo", now - 7200);
+        second.nonce = credentials.nonce(now);
+        assert_eq!(code(&second.ask(allocate(3)).await), Some(401));
+        second.login(&credentials, "whammo", now);
+        let tcp = request(ALLOCATE, 4).with(REQUESTED_TRANSPORT, vec![6, 0, 0, 0]);
+        assert_eq!(code(&second.ask(tcp).await), Some(442));
+        let reply = second.ask(allocate(5)).await;
+        assert_eq!(reply.class, Class::Success);
+        // Other relayed addresses are khadga's too, but people behind the relay can reach them
+        let other = parse_xor_address(reply.get(XOR_RELAYED_ADDRESS).unwrap(), &[5; 12]).unwrap();
+        let reply = first.ask(with_peer(CREATE_PERMISSION, 7, other)).await;
+        assert_eq!(reply.class, Class::Success);
+
+        // Only a second's worth of bandwidth goes through at once
+        let reply = first.ask(with_peer(CREATE_PERMISSION, 6, peer_addr)).await;
+        assert_eq!(reply.class, Class::Success);
+        for _ in 0..3 {
+            peer.send_to(&[7u8; 600], &relayed).await.unwrap();
+        }
+        assert!(receive(&mut first.socket).await.is_some());
+        assert!(receive(&mut first.socket).await.is_none());
+
+        // Binding requests get answered like the STUN server does
+        let (reply, _) = first.exchange(&request(BINDING, 8).encode()).await;
+        let mapped = parse_xor_address(reply.get(XOR_MAPPED_ADDRESS).unwrap(), &[8; 12]);
+        assert_eq!(mapped, first.socket.local_addr().ok());
+    }
+}
@@ -0,0 +1,1062 @@
+//! TypeScript and JSON Schema for the protocol types
+//!
+//! vision used to declare `message::Message` and friends by hand, and the two drifted.  Instead,
+//! the definitions are worked out from the Rust types themselves: `Registry::add` runs a type's
+//! `Deserialize` impl against a `Tracer`, which records the names and shapes serde asks for
+//! instead of parsing anything.  So whatever serde accepts (renames, defaults and all) is what ends
+//! up in the output.  A field is optional if the type still deserializes without it, ie it is an
+//! `Option` or has `#[serde(default)]`.
+//!
+//! Generic fields (eg the `body` of a `Message<T>`) are traced with `Param`, and come out as a
+//! type parameter.  Only structs and enums of unit variants can be traced.  The tagged enums of the
+//! `wire` format, and the `Envelope` they are flattened into, are written out by hand instead (see
+//! `Describe`), with the shapes of their fields still traced.  The `khadga-typegen` binary writes
+//! the output into vision, as `TS_PATH` and `SCHEMA_PATH`, and a test fails if those are out of
+//! date.
+
+use crate::{commands::{CommandError,
+                       CommandResponse},
+            filter::Rejection,
+            mesh::MeshEvent,
+            message::{CommandReplyMsg,
+                      CommandRequestMsg,
+                      ConnectionMsg,
+                      Message},
+            protocol::{Hello,
+                       Welcome},
+            questions::Suggestion,
+            signaling::{CallAction,
+                        CallEvent},
+            trends::TrendAlert,
+            wire::{Envelope,
+                   Event,
+                   IceCandidate,
+                   Reply,
+                   Request,
+                   SessionDescription}};
+use serde::de::{self,
+                value::StrDeserializer,
+                DeserializeOwned,
+                DeserializeSeed,
+                EnumAccess,
+                IntoDeserializer,
+                MapAccess,
+                SeqAccess,
+                VariantAccess,
+                Visitor};
+use serde_json::{json,
+                 Map,
+                 Value};
+use std::{collections::HashMap,
+          fmt::{self,
+                Display,
+                Formatter}};
+
+/// What the TypeScript is generated into, relative to the khadga directory
+pub const TS_PATH: &str = "../vision/src/state/protocol.ts";
+/// What the JSON Schema is generated into, relative to the khadga directory
+pub const SCHEMA_PATH: &str = "../vision/src/state/protocol.schema.json";
+
+const HEADER: &str = "// Generated by khadga-typegen from the khadga message types.  Do not edit,\n\
+                      // run `cargo run --bin khadga-typegen` in khadga instead.\n";
+
+/// Stands in for a type parameter.  Use it as the `T` of the type being traced.
+#[derive(Debug)]
+pub struct Param;
+
+const PARAM: &str = "$T";
+
+impl<'de> de::Deserialize<'de> for Param {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ParamVisitor;
+
+        impl<'de> Visitor<'de> for ParamVisitor {
+            type Value = Param;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                write!(f, "a type parameter")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Param, E> {
+                Ok(Param)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(PARAM, ParamVisitor)
+    }
+}
+
+/// The shape of a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    /// Not traced yet
+    Unknown,
+    /// Anything at all, eg a `serde_json::Value`
+    Any,
+    Null,
+    Bool,
+    Integer,
+    Number,
+    String,
+    /// The type parameter
+    Param,
+    Optional(Box<Shape>),
+    List(Box<Shape>),
+    /// An object with string keys
+    Map(Box<Shape>),
+    /// A struct or enum in the registry
+    Named(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub shape: Shape,
+    pub optional: bool,
+}
+
+fn field(name: &'static str, shape: Shape) -> Field {
+    Field {
+        name,
+        shape,
+        optional: false,
+    }
+}
+
+fn optional(name: &'static str, shape: Shape) -> Field {
+    Field {
+        name,
+        shape,
+        optional: true,
+    }
+}
+
+/// Where serde puts the name of an enum's variant
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tagging {
+    /// `{"<variant>": <payload>}`
+    External,
+    /// In the given field, next to the fields of the variant
+    Internal(&'static str),
+    /// `{"<tag>": "<variant>", "<content>": <payload>}`
+    Adjacent(&'static str, &'static str),
+}
+
+/// What a variant of a tagged enum holds
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Newtype(Shape),
+    Struct(Vec<Field>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Def {
+    /// Still being traced
+    Pending,
+    Struct(Vec<Field>),
+    /// An enum of unit variants, ie one of some strings
+    Variants(&'static [&'static str]),
+    /// An enum whose variants hold something
+    Tagged(Tagging, Vec<(&'static str, Payload)>),
+    /// A struct with a tagged enum flattened into it
+    Flattened(Vec<Field>, &'static str),
+}
+
+/// A type the tracer can't see into, described by hand
+///
+/// serde reads tagged enums, and structs with a `#[serde(flatten)]` field, into a buffer first and
+/// then deserializes that, so their fields never reach a `Tracer`.  A description only names the
+/// variants and fields: their shapes still come from the Rust types, with `Registry::shape`.  The
+/// tests check every description against what serde actually accepts.
+pub trait Describe {
+    const NAME: &'static str;
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError>;
+}
+
+#[derive(Debug)]
+pub struct TraceError(String);
+
+impl Display for TraceError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: Display>(msg: T) -> Self {
+        TraceError(msg.to_string())
+    }
+}
+
+/// The types traced so far, in the order they were first seen
+#[derive(Debug, Default)]
+pub struct Registry {
+    pub defs: Vec<(&'static str, Def)>,
+    /// A (struct, field) to leave out while tracing, to see whether the field is optional
+    omit: Option<(&'static str, &'static str)>,
+    omitted: bool,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Def> {
+        self.defs.iter().find(|(n, _)| *n == name).map(|(_, def)| def)
+    }
+
+    fn set(&mut self, name: &'static str, def: Def) {
+        match self.defs.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = def,
+            None => self.defs.push((name, def)),
+        }
+    }
+
+    /// Traces `T` and every type it uses
+    pub fn add<T: DeserializeOwned>(&mut self) -> Result<(), TraceError> {
+        self.shape::<T>().map(|_| ())
+    }
+
+    /// Adds a type that can't be traced, from its description
+    pub fn describe<T: Describe>(&mut self) -> Result<Shape, TraceError> {
+        if self.get(T::NAME).is_none() {
+            self.set(T::NAME, Def::Pending);
+            let def = T::describe(self)?;
+            self.set(T::NAME, def);
+        }
+        Ok(Shape::Named(T::NAME))
+    }
+
+    /// Traces `T` and every type it uses, and returns the shape of a `T`
+    pub fn shape<T: DeserializeOwned>(&mut self) -> Result<Shape, TraceError> {
+        let mut shape = Shape::Unknown;
+        T::deserialize(Tracer {
+            reg: self,
+            shape: &mut shape,
+        })?;
+
+        // Now try again without each field in turn
+        let fields: Vec<(&'static str, &'static str)> = self
+            .defs
+            .iter()
+            .flat_map(|(name, def)| match def {
+                Def::Struct(fields) => fields.iter().map(|f| (*name, f.name)).collect(),
+                _ => vec![],
+            })
+            .collect();
+        for (name, field) in fields {
+            self.omit = Some((name, field));
+            self.omitted = false;
+            let ok = T::deserialize(Tracer {
+                reg: self,
+                shape: &mut Shape::Unknown,
+            })
+            .is_ok();
+            // Types added earlier but not used by T don't learn anything from this
+            if ok && self.omitted {
+                if let Some((_, Def::Struct(fields))) =
+                    self.defs.iter_mut().find(|(n, _)| *n == name)
+                {
+                    fields.iter_mut().filter(|f| f.name == field).for_each(|f| f.optional = true);
+                }
+            }
+        }
+        self.omit = None;
+        Ok(shape)
+    }
+
+    pub fn typescript(&self) -> String {
+        let mut out = String::from(HEADER);
+        for (name, def) in &self.defs {
+            out.push('\n');
+            match def {
+                Def::Variants(variants) => {
+                    let quoted = variants.iter().map(|v| format!("\"{}\"", v)).collect();
+                    out.push_str(&ts_union(name, quoted));
+                }
+                Def::Tagged(tagging, variants) => {
+                    let alternatives = variants
+                        .iter()
+                        .map(|(variant, payload)| ts_variant(tagging, variant, payload))
+                        .collect();
+                    out.push_str(&ts_union(name, alternatives));
+                }
+                Def::Flattened(fields, flattened) => {
+                    let lines: Vec<String> =
+                        fields.iter().map(|f| format!("  {}", ts_field(f))).collect();
+                    out.push_str(&format!("export type {} = {{\n", name));
+                    out.push_str(&lines.join(",\n"));
+                    out.push_str(&format!("\n}} & {};\n", flattened));
+                }
+                Def::Struct(fields) => {
+                    let generic = fields.iter().any(|f| uses_param(&f.shape));
+                    out.push_str(&format!(
+                        "export interface {}{} {{\n",
+                        name,
+                        if generic { "<T>" } else { "" }
+                    ));
+                    let lines: Vec<String> =
+                        fields.iter().map(|f| format!("  {}", ts_field(f))).collect();
+                    out.push_str(&lines.join(",\n"));
+                    out.push_str("\n}\n");
+                }
+                Def::Pending => {}
+            }
+        }
+        out
+    }
+
+    /// A draft-07 schema with every type under `definitions`
+    pub fn json_schema(&self) -> Value {
+        let mut defs = Map::new();
+        for (name, def) in &self.defs {
+            let schema = match def {
+                Def::Variants(variants) => json!({ "type": "string", "enum": variants }),
+                Def::Struct(fields) => struct_schema(fields),
+                Def::Tagged(tagging, variants) => {
+                    let alternatives: Vec<Value> = variants
+                        .iter()
+                        .map(|(variant, payload)| variant_schema(tagging, variant, payload))
+                        .collect();
+                    json!({ "oneOf": alternatives })
+                }
+                Def::Flattened(fields, flattened) => json!({
+                    "allOf": [struct_schema(fields), schema_type(&Shape::Named(flattened))]
+                }),
+                Def::Pending => continue,
+            };
+            defs.insert(name.to_string(), schema);
+        }
+        json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": "khadga protocol",
+            "definitions": defs,
+        })
+    }
+}
+
+/// `export type <name> = <alternative> | ...`, one alternative per line
+fn ts_union(name: &str, alternatives: Vec<String>) -> String {
+    let head = format!("export type {} = ", name);
+    let indent = " ".repeat(head.len() - 2);
+    let mut out = head;
+    for (i, alternative) in alternatives.iter().enumerate() {
+        if i > 0 {
+            out.push_str(&format!("{}| ", indent));
+        }
+        out.push_str(&format!("{}\n", alternative));
+    }
+    out.push_str(&format!("{};\n", indent));
+    out
+}
+
+fn ts_field(field: &Field) -> String {
+    let optional = if field.optional { "?" } else { "" };
+    format!("{}{}: {}", field.name, optional, ts_type(&field.shape))
+}
+
+fn ts_variant(tagging: &Tagging, variant: &str, payload: &Payload) -> String {
+    let fields = |fields: &[Field]| fields.iter().map(ts_field).collect::<Vec<_>>().join(", ");
+    let inner = match payload {
+        Payload::Newtype(shape) => ts_type(shape),
+        Payload::Struct(fs) => format!("{{ {} }}", fields(fs)),
+    };
+    match (tagging, payload) {
+        (Tagging::External, _) => format!("{{ {}: {} }}", variant, inner),
+        (Tagging::Adjacent(tag, content), _) => {
+            format!("{{ {}: \"{}\", {}: {} }}", tag, variant, content, inner)
+        }
+        (Tagging::Internal(tag), Payload::Struct(fs)) if fs.is_empty() => {
+            format!("{{ {}: \"{}\" }}", tag, variant)
+        }
+        (Tagging::Internal(tag), Payload::Struct(fs)) => {
+            format!("{{ {}: \"{}\", {} }}", tag, variant, fields(fs))
+        }
+        (Tagging::Internal(tag), Payload::Newtype(_)) => {
+            format!("{{ {}: \"{}\" }} & {}", tag, variant, inner)
+        }
+    }
+}
+
+fn struct_schema(fields: &[Field]) -> Value {
+    let properties: Map<String, Value> =
+        fields.iter().map(|f| (f.name.to_string(), schema_type(&f.shape))).collect();
+    let required: Vec<&str> = fields.iter().filter(|f| !f.optional).map(|f| f.name).collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn variant_schema(tagging: &Tagging, variant: &str, payload: &Payload) -> Value {
+    let inner = match payload {
+        Payload::Newtype(shape) => schema_type(shape),
+        Payload::Struct(fields) => struct_schema(fields),
+    };
+    let mut properties = Map::new();
+    let required: Vec<&str> = match tagging {
+        Tagging::External => {
+            properties.insert(variant.to_string(), inner);
+            vec![variant]
+        }
+        Tagging::Adjacent(tag, content) => {
+            properties.insert(tag.to_string(), json!({ "const": variant }));
+            properties.insert(content.to_string(), inner);
+            vec![tag, content]
+        }
+        Tagging::Internal(tag) => {
+            properties.insert(tag.to_string(), json!({ "const": variant }));
+            let tagged = json!({ "type": "object", "properties": properties, "required": [tag] });
+            return json!({ "allOf": [tagged, inner] });
+        }
+    };
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+fn uses_param(shape: &Shape) -> bool {
+    match shape {
+        Shape::Param => true,
+        Shape::Optional(s) | Shape::List(s) | Shape::Map(s) => uses_param(s),
+        _ => false,
+    }
+}
+
+fn ts_type(shape: &Shape) -> String {
+    match shape {
+        Shape::Unknown | Shape::Any => "any".into(),
+        Shape::Null => "null".into(),
+        Shape::Bool => "boolean".into(),
+        Shape::Integer | Shape::Number => "number".into(),
+        Shape::String => "string".into(),
+        Shape::Param => "T".into(),
+        Shape::Optional(s) => format!("{} | null", ts_type(s)),
+        Shape::List(s) => match **s {
+            Shape::Optional(_) => format!("({})[]", ts_type(s)),
+            _ => format!("{}[]", ts_type(s)),
+        },
+        Shape::Map(s) => format!("{{ [key: string]: {} }}", ts_type(s)),
+        Shape::Named(name) => name.to_string(),
+    }
+}
+
+fn schema_type(shape: &Shape) -> Value {
+    match shape {
+        Shape::Unknown | Shape::Any | Shape::Param => json!({}),
+        Shape::Null => json!({ "type": "null" }),
+        Shape::Bool => json!({ "type": "boolean" }),
+        Shape::Integer => json!({ "type": "integer" }),
+        Shape::Number => json!({ "type": "number" }),
+        Shape::String => json!({ "type": "string" }),
+        Shape::Optional(s) => json!({ "anyOf": [schema_type(s), { "type": "null" }] }),
+        Shape::List(s) => json!({ "type": "array", "items": schema_type(s) }),
+        Shape::Map(s) => json!({ "type": "object", "additionalProperties": schema_type(s) }),
+        Shape::Named(name) => json!({ "$ref": format!("#/definitions/{}", name) }),
+    }
+}
+
+impl Describe for CommandResponse {
+    const NAME: &'static str = "CommandResponse";
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError> {
+        Ok(Def::Tagged(Tagging::External, vec![
+            ("Output", Payload::Newtype(reg.shape::<Value>()?)),
+            ("Error", Payload::Newtype(reg.shape::<CommandError>()?)),
+        ]))
+    }
+}
+
+impl Describe for Request {
+    const NAME: &'static str = "Request";
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError> {
+        let id = field("id", reg.shape::<String>()?);
+        let description = field("description", reg.shape::<SessionDescription>()?);
+        let args = optional("args", reg.shape::<Vec<String>>()?);
+        let candidate = field("candidate", reg.shape::<IceCandidate>()?);
+        let line = field("line", reg.shape::<String>()?);
+        let action = field("action", reg.shape::<CallAction>()?);
+        Ok(Def::Tagged(Tagging::Internal("op"), vec![
+            ("Ping", Payload::Struct(vec![id.clone(), args])),
+            ("SDPOffer", Payload::Struct(vec![id.clone(), description.clone()])),
+            ("SDPAnswer", Payload::Struct(vec![id.clone(), description])),
+            ("IceCandidate", Payload::Struct(vec![id.clone(), candidate])),
+            ("Slash", Payload::Struct(vec![id.clone(), line])),
+            ("Call", Payload::Struct(vec![id, action])),
+        ]))
+    }
+}
+
+impl Describe for Reply {
+    const NAME: &'static str = "Reply";
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError> {
+        let id = field("id", reg.shape::<String>()?);
+        let args = optional("args", reg.shape::<Vec<String>>()?);
+        let response = field("response", reg.describe::<CommandResponse>()?);
+        Ok(Def::Tagged(Tagging::Internal("op"), vec![
+            ("Pong", Payload::Struct(vec![id.clone(), args])),
+            ("Slash", Payload::Struct(vec![id, response])),
+        ]))
+    }
+}
+
+impl Describe for Event {
+    const NAME: &'static str = "Event";
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError> {
+        let connection = Payload::Newtype(reg.shape::<ConnectionMsg>()?);
+        Ok(Def::Tagged(Tagging::Adjacent("event", "payload"), vec![
+            ("Connect", connection.clone()),
+            ("Disconnect", connection),
+            ("Message", Payload::Newtype(reg.shape::<String>()?)),
+            ("Data", Payload::Newtype(reg.shape::<Value>()?)),
+            ("CommandRequest", Payload::Newtype(reg.describe::<Request>()?)),
+            ("CommandReply", Payload::Newtype(reg.describe::<Reply>()?)),
+            ("Rejected", Payload::Newtype(reg.shape::<Rejection>()?)),
+            ("Alert", Payload::Newtype(reg.shape::<TrendAlert>()?)),
+            ("Suggestion", Payload::Newtype(reg.shape::<Suggestion>()?)),
+            ("Hello", Payload::Newtype(reg.shape::<Hello>()?)),
+            ("Welcome", Payload::Newtype(reg.shape::<Welcome>()?)),
+            ("Call", Payload::Newtype(reg.shape::<CallEvent>()?)),
+            ("Mesh", Payload::Newtype(reg.shape::<MeshEvent>()?)),
+        ]))
+    }
+}
+
+impl Describe for Envelope {
+    const NAME: &'static str = "Envelope";
+
+    fn describe(reg: &mut Registry) -> Result<Def, TraceError> {
+        let fields = vec![
+            field("sender", reg.shape::<String>()?),
+            optional("recipients", reg.shape::<Vec<String>>()?),
+            optional("time", reg.shape::<i64>()?),
+            optional("room", reg.shape::<Option<String>>()?),
+            optional("bot", reg.shape::<bool>()?),
+            optional("encrypted", reg.shape::<bool>()?),
+            optional("conversation", reg.shape::<Option<i32>>()?),
+            optional("annotations", reg.shape::<HashMap<String, String>>()?),
+        ];
+        reg.describe::<Event>()?;
+        Ok(Def::Flattened(fields, Event::NAME))
+    }
+}
+
+/// Every type the legacy format, the handshake and the `wire` format use
+pub fn protocol() -> Result<Registry, TraceError> {
+    let mut reg = Registry::new();
+    reg.add::<Message<Param>>()?;
+    reg.add::<ConnectionMsg>()?;
+    reg.add::<CommandRequestMsg<Param>>()?;
+    reg.add::<CommandReplyMsg<Param>>()?;
+    reg.add::<Hello>()?;
+    reg.add::<Welcome>()?;
+    reg.add::<CallAction>()?;
+    reg.add::<CallEvent>()?;
+    reg.add::<MeshEvent>()?;
+    reg.add::<Rejection>()?;
+    reg.add::<TrendAlert>()?;
+    reg.add::<Suggestion>()?;
+    reg.describe::<CommandResponse>()?;
+    reg.describe::<Envelope>()?;
+    Ok(reg)
+}
+
+/// A `Deserializer` that makes up a value for whatever it is asked for, and writes down what that
+/// was
+struct Tracer<'a> {
+    reg: &'a mut Registry,
+    shape: &'a mut Shape,
+}
+
+impl<'a> Tracer<'a> {
+    /// Traces a nested value into `shape`
+    fn nested<'b>(reg: &'b mut Registry, shape: &'b mut Shape) -> Tracer<'b> {
+        Tracer { reg, shape }
+    }
+}
+
+macro_rules! trace_primitive {
+    ($($method:ident => $shape:expr, $visit:ident($($value:expr)?);)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                *self.shape = $shape;
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracer<'a> {
+    type Error = TraceError;
+
+    trace_primitive! {
+        deserialize_bool => Shape::Bool, visit_bool(false);
+        deserialize_i8 => Shape::Integer, visit_i8(0);
+        deserialize_i16 => Shape::Integer, visit_i16(0);
+        deserialize_i32 => Shape::Integer, visit_i32(0);
+        deserialize_i64 => Shape::Integer, visit_i64(0);
+        deserialize_u8 => Shape::Integer, visit_u8(0);
+        deserialize_u16 => Shape::Integer, visit_u16(0);
+        deserialize_u32 => Shape::Integer, visit_u32(0);
+        deserialize_u64 => Shape::Integer, visit_u64(0);
+        deserialize_f32 => Shape::Number, visit_f32(0.0);
+        deserialize_f64 => Shape::Number, visit_f64(0.0);
+        deserialize_char => Shape::String, visit_char('a');
+        deserialize_str => Shape::String, visit_str("");
+        deserialize_string => Shape::String, visit_str("");
+        deserialize_identifier => Shape::String, visit_str("");
+        deserialize_unit => Shape::Null, visit_unit();
+        deserialize_any => Shape::Any, visit_unit();
+        deserialize_ignored_any => Shape::Any, visit_unit();
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.shape = Shape::List(Box::new(Shape::Integer));
+        visitor.visit_bytes(&[])
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut inner = Shape::Unknown;
+        let value = visitor.visit_some(Tracer::nested(self.reg, &mut inner))?;
+        *self.shape = Shape::Optional(Box::new(inner));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        if name == PARAM {
+            *self.shape = Shape::Param;
+            return visitor.visit_unit();
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut item = Shape::Unknown;
+        let value = visitor.visit_seq(OneItem {
+            reg: self.reg,
+            item: &mut item,
+            done: false,
+        })?;
+        *self.shape = Shape::List(Box::new(item));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(de::Error::custom("tuples are not supported"))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(de::Error::custom(format!("tuple structs are not supported ({})", name)))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut item = Shape::Unknown;
+        let value = visitor.visit_map(OneEntry {
+            reg: self.reg,
+            item: &mut item,
+            state: 0,
+        })?;
+        *self.shape = Shape::Map(Box::new(item));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        if self.reg.get(name) == Some(&Def::Pending) {
+            return Err(de::Error::custom(format!("{} is recursive", name)));
+        }
+        let tracing = self.reg.omit.is_none();
+        let previous = self.reg.get(name).cloned();
+        if tracing {
+            self.reg.set(name, Def::Pending);
+        }
+
+        let mut access = Fields {
+            reg: self.reg,
+            name,
+            fields: fields.iter(),
+            current: None,
+            traced: vec![],
+        };
+        let value = visitor.visit_map(&mut access);
+        let traced = access.traced;
+        if tracing {
+            match &value {
+                Ok(_) => self.reg.set(name, Def::Struct(traced)),
+                Err(_) => self.reg.set(name, previous.unwrap_or(Def::Pending)),
+            }
+        }
+        *self.shape = Shape::Named(name);
+        value
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let first = variants
+            .first()
+            .ok_or_else(|| de::Error::custom(format!("{} has no variants", name)))?;
+        let value = visitor.visit_enum(FirstVariant(first))?;
+        if self.reg.omit.is_none() {
+            self.reg.set(name, Def::Variants(variants));
+        }
+        *self.shape = Shape::Named(name);
+        Ok(value)
+    }
+}
+
+/// A sequence of one item
+struct OneItem<'a> {
+    reg: &'a mut Registry,
+    item: &'a mut Shape,
+    done: bool,
+}
+
+impl<'de, 'a> SeqAccess<'de> for OneItem<'a> {
+    type Error = TraceError;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, TraceError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        seed.deserialize(Tracer::nested(self.reg, self.item)).map(Some)
+    }
+}
+
+/// A map of one entry, with an empty key
+struct OneEntry<'a> {
+    reg: &'a mut Registry,
+    item: &'a mut Shape,
+    state: u8,
+}
+
+impl<'de, 'a> MapAccess<'de> for OneEntry<'a> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        if self.state > 0 {
+            return Ok(None);
+        }
+        self.state = 1;
+        let key: StrDeserializer<TraceError> = "".into_deserializer();
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, TraceError> {
+        seed.deserialize(Tracer::nested(self.reg, self.item))
+    }
+}
+
+/// The fields of a struct, minus the one being left out
+struct Fields<'a> {
+    reg: &'a mut Registry,
+    name: &'static str,
+    fields: std::slice::Iter<'static, &'static str>,
+    current: Option<&'static str>,
+    traced: Vec<Field>,
+}
+
+impl<'de, 'a, 'b> MapAccess<'de> for &'b mut Fields<'a> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        for field in self.fields.by_ref() {
+            if self.reg.omit == Some((self.name, field)) {
+                self.reg.omitted = true;
+                continue;
+            }
+            self.current = Some(field);
+            let key: StrDeserializer<TraceError> = field.into_deserializer();
+            return seed.deserialize(key).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, TraceError> {
+        let mut shape = Shape::Unknown;
+        let value = seed.deserialize(Tracer::nested(self.reg, &mut shape))?;
+        if let Some(name) = self.current.take() {
+            self.traced.push(Field {
+                name,
+                shape,
+                optional: false,
+            });
+        }
+        Ok(value)
+    }
+}
+
+/// Picks the first variant of an enum, which has to be a unit variant
+struct FirstVariant(&'static str);
+
+impl<'de> EnumAccess<'de> for FirstVariant {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        seed: S,
+    ) -> Result<(S::Value, Self), TraceError> {
+        let key: StrDeserializer<TraceError> = self.0.into_deserializer();
+        Ok((seed.deserialize(key)?, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for FirstVariant {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(
+        self,
+        _seed: S,
+    ) -> Result<S::Value, TraceError> {
+        Err(de::Error::custom(format!("only unit variants are supported ({})", self.0)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(de::Error::custom(format!("only unit variants are supported ({})", self.0)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(de::Error::custom(format!("only unit variants are supported ({})", self.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{CommandTypes,
+                         MessageEvent};
+
+    #[test]
+    fn test_trace() {
+        let reg = protocol().expect("The protocol types can be traced");
+
+        let fields = match reg.get("Message") {
+            Some(Def::Struct(fields)) => fields.clone(),
+            other => panic!("Message is not a struct: {:?}", other),
+        };
+        let field = |name: &str| fields.iter().find(|f| f.name == name).unwrap().clone();
+        assert_eq!(field("body").shape, Shape::Param);
+        assert_eq!(field("event_type").shape, Shape::Named("MessageEvent"));
+        assert_eq!(field("recipients").shape, Shape::List(Box::new(Shape::String)));
+        assert!(!field("sender").optional && !field("time").optional);
+        assert!(field("room").optional && field("annotations").optional);
+
+        // Every variant is there, and the names are what serde reads
+        match reg.get("MessageEvent") {
+            Some(Def::Variants(variants)) => {
+                for variant in variants.iter() {
+                    let event: MessageEvent = serde_json::from_value(json!(variant)).unwrap();
+                    assert_eq!(&event.to_string(), variant);
+                }
+                assert!(variants.contains(&"Welcome"));
+            }
+            other => panic!("MessageEvent is not an enum: {:?}", other),
+        }
+        match reg.get("CommandTypes") {
+            Some(Def::Variants(variants)) => {
+                let op: CommandTypes = serde_json::from_value(json!(variants[0])).unwrap();
+                assert!(matches!(op, CommandTypes::Ping));
+            }
+            other => panic!("CommandTypes is not an enum: {:?}", other),
+        }
+        assert!(matches!(reg.get("Negotiated"), Some(Def::Struct(_))));
+    }
+
+    #[test]
+    fn test_typescript() {
+        let ts = protocol().unwrap().typescript();
+        assert!(ts.contains("export interface Message<T> {\n  sender: string,\n"));
+        assert!(ts.contains("  room?: string | null,\n"));
+        assert!(ts.contains("  annotations?: { [key: string]: string }\n}\n"));
+        assert!(ts.contains("export interface Command {\n  op: CommandTypes,\n"));
+
+        let schema = protocol().unwrap().json_schema();
+        let message = &schema["definitions"]["Message"];
+        assert_eq!(message["properties"]["event_type"]["$ref"], "#/definitions/MessageEvent");
+        assert!(message["required"].as_array().unwrap().contains(&json!("sender")));
+        assert!(!message["required"].as_array().unwrap().contains(&json!("room")));
+    }
+
+    /// A value of the shape, with every field filled in
+    fn example(reg: &Registry, shape: &Shape) -> Value {
+        match shape {
+            Shape::Unknown | Shape::Any | Shape::Null | Shape::Param => Value::Null,
+            Shape::Bool => json!(false),
+            Shape::Integer | Shape::Number => json!(0),
+            Shape::String => json!(""),
+            Shape::Optional(s) => example(reg, s),
+            Shape::List(s) => json!([example(reg, s)]),
+            Shape::Map(s) => json!({ "key": example(reg, s) }),
+            Shape::Named(name) => examples(reg, name).remove(0).0,
+        }
+    }
+
+    fn object(reg: &Registry, fields: &[Field]) -> Map<String, Value> {
+        fields.iter().map(|f| (f.name.to_string(), example(reg, &f.shape))).collect()
+    }
+
+    /// A value for each variant of the type (just the one for a struct), with the fields that sit
+    /// at the top of it
+    fn examples(reg: &Registry, name: &str) -> Vec<(Value, Vec<Field>)> {
+        match reg.get(name) {
+            Some(Def::Struct(fields)) => vec![(Value::Object(object(reg, fields)), fields.clone())],
+            Some(Def::Variants(variants)) => variants.iter().map(|v| (json!(v), vec![])).collect(),
+            Some(Def::Tagged(tagging, variants)) => variants
+                .iter()
+                .map(|(variant, payload)| {
+                    let (inner, fields) = match payload {
+                        Payload::Newtype(shape) => (example(reg, shape), vec![]),
+                        Payload::Struct(fields) => {
+                            (Value::Object(object(reg, fields)), fields.clone())
+                        }
+                    };
+                    let mut value = Map::new();
+                    match tagging {
+                        Tagging::External => {
+                            value.insert(variant.to_string(), inner);
+                            (Value::Object(value), vec![])
+                        }
+                        Tagging::Adjacent(tag, content) => {
+                            value.insert(tag.to_string(), json!(variant));
+                            value.insert(content.to_string(), inner);
+                            (Value::Object(value), vec![])
+                        }
+                        Tagging::Internal(tag) => {
+                            let mut value = inner;
+                            value[*tag] = json!(variant);
+                            (value, fields)
+                        }
+                    }
+                })
+                .collect(),
+            Some(Def::Flattened(fields, flattened)) => examples(reg, flattened)
+                .into_iter()
+                .map(|(mut value, _)| {
+                    for (key, field) in object(reg, fields) {
+                        value[key] = field;
+                    }
+                    (value, fields.clone())
+                })
+                .collect(),
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    /// Checks a description against serde: every variant parses, only the optional fields can be
+    /// left out, and the variants are the ones serde lists for `unknown`
+    fn check<T: DeserializeOwned + Describe>(reg: &Registry, unknown: Option<Value>) {
+        let parses = |value: &Value| serde_json::from_value::<T>(value.clone()).is_ok();
+        for (value, fields) in examples(reg, T::NAME) {
+            assert!(parses(&value), "{} is not a {}", value, T::NAME);
+            for field in fields {
+                let mut without = value.clone();
+                without.as_object_mut().unwrap().remove(field.name);
+                assert_eq!(parses(&without), field.optional, "{}.{}", T::NAME, field.name);
+            }
+        }
+
+        if let Some(unknown) = unknown {
+            let error = serde_json::from_value::<T>(unknown).err().unwrap().to_string();
+            let expected: Vec<&str> =
+                error.split("expected").nth(1).unwrap().split('`').skip(1).step_by(2).collect();
+            match reg.get(T::NAME) {
+                Some(Def::Tagged(_, variants)) => {
+                    let described: Vec<&str> = variants.iter().map(|(v, _)| *v).collect();
+                    assert_eq!(described, expected, "the variants of {}", T::NAME);
+                }
+                other => panic!("{} is {:?}", T::NAME, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_descriptions() {
+        let reg = protocol().unwrap();
+        check::<CommandResponse>(&reg, Some(json!({ "Nope": null })));
+        check::<Request>(&reg, Some(json!({ "op": "Nope" })));
+        check::<Reply>(&reg, Some(json!({ "op": "Nope" })));
+        check::<Event>(&reg, Some(json!({ "event": "Nope", "payload": null })));
+        check::<Envelope>(&reg, None);
+
+        let ts = reg.typescript();
+        let ping = "export type Request = { op: \"Ping\", id: string, args?: string[] }\n";
+        assert!(ts.contains(ping));
+        assert!(ts.contains("| { event: \"CommandRequest\", payload: Request }\n"));
+        assert!(ts.contains("  annotations?: { [key: string]: string }\n} & Event;\n"));
+    }
+
+    /// vision's copy has to be regenerated whenever the types change
+    #[test]
+    fn test_generated_files() {
+        let reg = protocol().unwrap();
+        let stale = "is out of date.  Run `cargo run --bin khadga-typegen` in khadga";
+        assert!(reg.typescript() == include_str!("../../vision/src/state/protocol.ts"),
+                "{} {}", TS_PATH, stale);
+        let schema = serde_json::to_string_pretty(&reg.json_schema()).unwrap() + "\n";
+        assert!(schema == include_str!("../../vision/src/state/protocol.schema.json"),
+                "{} {}", SCHEMA_PATH, stale);
+    }
+}
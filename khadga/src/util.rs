@@ -0,0 +1,60 @@
+//! Small helpers shared by the other modules
+//!
+//! Random ids and bytes for tokens, calls, meshes and the TURN relay, and the rule for the names
+//! people give to bots and integrations.
+
+use ring::rand::{SecureRandom,
+                 SystemRandom};
+
+/// Random bytes from the system's secure generator
+pub fn random_bytes<T: AsMut<[u8]> + Default>() -> T {
+    let mut bytes = T::default();
+    SystemRandom::new()
+        .fill(bytes.as_mut())
+        .expect("Unable to generate random bytes");
+    bytes
+}
+
+/// A random id of `len` bytes, in hex
+pub fn random_id(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Unable to generate random bytes");
+    to_hex(&bytes)
+}
+
+/// The bytes in lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether the name is fine for a bot or an incoming webhook: letters, digits, `-` and `_`, up to
+/// 32 of them
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_id() {
+        let id = random_id(8);
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, random_id(8));
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("ci-bot_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("no spaces"));
+        assert!(!valid_name(&"a".repeat(33)));
+    }
+}
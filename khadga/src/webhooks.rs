@@ -0,0 +1,635 @@
+//! Outgoing webhooks
+//!
+//! An admin can register a url that khadga will POST chat events to.  A webhook can be for one room
+//! or for every room, and it says which `MessageEvent`s it wants (eg just `Message`).
+//!
+//! Messages without a room are direct messages, and only go to webhooks registered with
+//! `direct_messages` set.  Encrypted messages and call signaling are never sent to any webhook.
+//!
+//! Every request has these headers:
+//!
+//! - `X-Khadga-Event`: the name of the event
+//! - `X-Khadga-Delivery`: an id for the delivery, which stays the same across retries
+//! - `X-Khadga-Timestamp`: seconds since the epoch when the attempt was made
+//! - `X-Khadga-Signature`: `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>`, keyed with
+//!   the webhook's secret.  Receivers should check this with `verify` (or their own version of it)
+//!
+//! A delivery that doesn't get a 2xx back is retried with exponential backoff, as set in the
+//! `webhooks` section of the config.  Each attempt is logged to the webhook_deliveries table, and a
+//! delivery that fails every attempt is moved to the dead_letters table.
+
+use crate::{auth::{admin,
+                   CONFIG},
+            chat::ChatState,
+            config::WebhookCfg,
+            message::{Message as KMessage,
+                      MessageEvent},
+            pgdb::{models,
+                   pgdb::{self,
+                          DbClient}},
+            reply::error_reply,
+            rooms,
+            util::{random_id,
+                   to_hex}};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{error,
+          info,
+          warn};
+use ring::{constant_time,
+           hmac};
+use serde::{Deserialize,
+            Serialize};
+use serde_json::{json,
+                 Value};
+use std::{collections::HashMap,
+          convert::Infallible,
+          sync::Arc};
+use tokio::{sync::Mutex,
+            time::Duration};
+use warp::{filters::BoxedFilter,
+           http::StatusCode,
+           reply::{self,
+                   Json,
+                   WithStatus},
+           Filter,
+           Reply};
+
+/// Registered webhooks, keyed by id
+pub type Webhooks = Arc<Mutex<HashMap<i32, models::Webhook>>>;
+
+/// The events a webhook can ask for
+pub const EVENTS: [&str; 7] = [
+    "Connect",
+    "Disconnect",
+    "Message",
+    "Data",
+    "CommandRequest",
+    "CommandReply",
+    "Rejected",
+];
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// Signs the body of a request, returning the value for the `X-Khadga-Signature` header
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", to_hex(tag.as_ref()))
+}
+
+/// Checks a signature made by `sign`
+pub fn verify(secret: &str, timestamp: i64, body: &str, signature: &str) -> bool {
+    let expected = sign(secret, timestamp, body);
+    constant_time::verify_slices_are_equal(expected.as_bytes(), signature.as_bytes()).is_ok()
+}
+
+/// How long to wait after the given (1 based) attempt failed
+pub fn backoff(cfg: &WebhookCfg, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+    Duration::from_millis(cfg.backoff_ms.saturating_mul(factor).min(cfg.max_backoff_ms))
+}
+
+/// True if the webhook wants this event from this room.  `room` is None for direct messages.
+pub fn wants(hook: &models::Webhook, event: &str, room: Option<&str>) -> bool {
+    let from_room = match room {
+        Some(room) => hook.room.as_ref().is_none_or(|r| r == room),
+        None => hook.direct_messages,
+    };
+    hook.events.iter().any(|e| e == event) && from_room
+}
+
+/// The commands that carry the details of somebody's call
+const SIGNALING: [&str; 4] = ["SDPOffer", "SDPAnswer", "IceCandidate", "Call"];
+
+/// True for messages that must never leave khadga: ciphertext, and anything about calls
+fn private(event: &MessageEvent, message: &Value) -> bool {
+    let signaling = match event {
+        MessageEvent::Call | MessageEvent::Mesh => true,
+        MessageEvent::CommandRequest => message["body"]["cmd"]["op"]
+            .as_str()
+            .is_some_and(|op| SIGNALING.contains(&op)),
+        _ => false,
+    };
+    signaling || message["encrypted"] == json!(true)
+}
+
+/// One event on its way to one webhook
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook: models::Webhook,
+    pub event: String,
+    pub payload: String,
+}
+
+impl Delivery {
+    pub fn new(webhook: models::Webhook, event: &str, payload: String) -> Self {
+        Delivery {
+            id: random_id(16),
+            webhook,
+            event: event.into(),
+            payload,
+        }
+    }
+}
+
+/// Makes a single attempt.  On failure, returns the status (if there was a response) and why.
+async fn send(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    cfg: &WebhookCfg,
+) -> Result<u16, (Option<u16>, String)> {
+    let timestamp = Utc::now().timestamp();
+    let signature = sign(&delivery.webhook.secret, timestamp, &delivery.payload);
+
+    let response = client
+        .post(&delivery.webhook.url)
+        .timeout(Duration::from_secs(cfg.timeout_secs))
+        .header("Content-Type", "application/json")
+        .header("X-Khadga-Event", delivery.event.as_str())
+        .header("X-Khadga-Delivery", delivery.id.as_str())
+        .header("X-Khadga-Timestamp", timestamp.to_string())
+        .header("X-Khadga-Signature", signature)
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(resp) if resp.status().is_success() => Ok(resp.status().as_u16()),
+        Ok(resp) => Err((Some(resp.status().as_u16()), format!("HTTP {}", resp.status()))),
+        Err(e) => Err((None, e.to_string())),
+    }
+}
+
+async fn log_attempt(
+    db: Option<&DbClient>,
+    delivery: &Delivery,
+    attempt: u32,
+    result: &Result<u16, (Option<u16>, String)>,
+) {
+    let (status, err) = match result {
+        Ok(status) => (Some(*status as i32), None),
+        Err((status, e)) => (status.map(i32::from), Some(e.clone())),
+    };
+    if let Some(e) = &err {
+        warn!(
+            "Webhook {} delivery {} attempt {} failed: {}",
+            delivery.webhook.webhook_id, delivery.id, attempt, e
+        );
+    }
+
+    if let Some(db) = db {
+        let row = models::WebhookDelivery {
+            webhook_id: delivery.webhook.webhook_id,
+            delivery: delivery.id.clone(),
+            event: delivery.event.clone(),
+            attempt: attempt as i32,
+            status,
+            error: err,
+            sent_on: Utc::now(),
+        };
+        if let Err(e) = pgdb::insert_delivery(db, &CONFIG.db.tables.webhook_deliveries, &row).await
+        {
+            error!("Unable to log webhook delivery: {}", e);
+        }
+    }
+}
+
+/// Delivers an event, retrying until it works or we run out of attempts
+///
+/// Returns the status of the successful attempt, or the last error if every attempt failed.  In
+/// that case the delivery has been put in the dead letter table.
+pub async fn deliver(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    cfg: &WebhookCfg,
+    db: Option<&DbClient>,
+) -> Result<u16, String> {
+    let mut last_error = String::new();
+    for attempt in 1..=cfg.max_attempts {
+        let result = send(client, delivery, cfg).await;
+        log_attempt(db, delivery, attempt, &result).await;
+        match result {
+            Ok(status) => return Ok(status),
+            Err((_, e)) => last_error = e,
+        }
+        if attempt < cfg.max_attempts {
+            tokio::time::delay_for(backoff(cfg, attempt)).await;
+        }
+    }
+
+    error!(
+        "Giving up on webhook {} delivery {} after {} attempts",
+        delivery.webhook.webhook_id, delivery.id, cfg.max_attempts
+    );
+    if let Some(db) = db {
+        let letter = models::DeadLetter {
+            webhook_id: delivery.webhook.webhook_id,
+            delivery: delivery.id.clone(),
+            event: delivery.event.clone(),
+            payload: delivery.payload.clone(),
+            last_error: last_error.clone(),
+            attempts: cfg.max_attempts as i32,
+            failed_on: Utc::now(),
+        };
+        if let Err(e) = pgdb::insert_dead_letter(db, &CONFIG.db.tables.dead_letters, &letter).await
+        {
+            error!("Unable to save dead letter: {}", e);
+        }
+    }
+    Err(last_error)
+}
+
+/// Sends the message to every webhook that wants it.  Deliveries happen in the background.
+pub async fn notify<T: Serialize>(state: &ChatState, mesg: &KMessage<T>) {
+    let message = json!(mesg);
+    if private(&mesg.event_type, &message) {
+        return;
+    }
+    let event = mesg.event_type.to_string();
+    // Connecting and disconnecting happen in the lobby, anything else without a room is private
+    let room = match (&mesg.event_type, &mesg.room, mesg.conversation) {
+        (MessageEvent::Connect, ..) | (MessageEvent::Disconnect, ..) => Some(rooms::LOBBY),
+        (_, Some(room), None) => Some(room.as_str()),
+        _ => None,
+    };
+    let hooks: Vec<models::Webhook> = state
+        .webhooks
+        .lock()
+        .await
+        .values()
+        .filter(|hook| wants(hook, &event, room))
+        .cloned()
+        .collect();
+    if hooks.is_empty() {
+        return;
+    }
+
+    let payload = json!({ "event": event, "room": room, "message": message }).to_string();
+    for hook in hooks {
+        let delivery = Delivery::new(hook, &event, payload.clone());
+        let db = state.db.clone();
+        tokio::spawn(async move {
+            let _ = deliver(&CLIENT, &delivery, &CONFIG.webhooks, db.as_ref()).await;
+        });
+    }
+}
+
+/// Creates the webhook tables and loads the registered webhooks
+pub async fn load(state: &ChatState) {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return,
+    };
+    let tables = &CONFIG.db.tables;
+
+    let created = async {
+        pgdb::make_table_webhooks(&tables.webhooks, db).await?;
+        pgdb::add_direct_messages_column(&tables.webhooks, db).await?;
+        pgdb::make_table_webhook_deliveries(&tables.webhook_deliveries, &tables.webhooks, db)
+            .await?;
+        pgdb::make_table_dead_letters(&tables.dead_letters, &tables.webhooks, db).await
+    };
+    if let Err(e) = created.await {
+        error!("Unable to create the webhook tables: {}", e);
+        return;
+    }
+    match pgdb::list_webhooks(db, &tables.webhooks).await {
+        Ok(list) => {
+            info!("Loaded {} webhooks", list.len());
+            let mut hooks = state.webhooks.lock().await;
+            for hook in list {
+                hooks.insert(hook.webhook_id, hook);
+            }
+        }
+        Err(e) => error!("Unable to load webhooks: {}", e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewWebhook {
+    pub url: String,
+    /// Leave this out to get events from every room
+    pub room: Option<String>,
+    pub events: Vec<String>,
+    /// Set this to also get direct messages
+    #[serde(default)]
+    pub direct_messages: bool,
+    /// If not given, one is generated
+    pub secret: Option<String>,
+}
+
+/// What we show about a webhook.  The secret is only returned when the webhook is created.
+#[derive(Serialize, Debug)]
+pub struct WebhookInfo {
+    pub id: i32,
+    pub url: String,
+    pub room: Option<String>,
+    pub events: Vec<String>,
+    pub direct_messages: bool,
+    pub created_by: String,
+    pub created_on: chrono::DateTime<Utc>,
+}
+
+impl From<&models::Webhook> for WebhookInfo {
+    fn from(hook: &models::Webhook) -> Self {
+        WebhookInfo {
+            id: hook.webhook_id,
+            url: hook.url.clone(),
+            room: hook.room.clone(),
+            events: hook.events.clone(),
+            direct_messages: hook.direct_messages,
+            created_by: hook.created_by.clone(),
+            created_on: hook.created_on,
+        }
+    }
+}
+
+/// Registers a webhook
+pub async fn create_webhook(
+    user: String,
+    new_hook: NewWebhook,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    if !(new_hook.url.starts_with("http://") || new_hook.url.starts_with("https://")) {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "The url must be http or https"));
+    }
+    if new_hook.events.is_empty() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "No events given"));
+    }
+    if let Some(bad) = new_hook.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+        let msg = format!("Unknown event {}, expected one of {}", bad, EVENTS.join(", "));
+        return Ok(error_reply(StatusCode::BAD_REQUEST, &msg));
+    }
+
+    let mut hook = models::Webhook {
+        webhook_id: -1,
+        url: new_hook.url,
+        room: new_hook.room,
+        events: new_hook.events,
+        direct_messages: new_hook.direct_messages,
+        secret: new_hook.secret.unwrap_or_else(|| format!("whsec_{}", random_id(24))),
+        created_by: user,
+        created_on: Utc::now(),
+    };
+
+    let mut hooks = state.webhooks.lock().await;
+    match &state.db {
+        Some(db) => match pgdb::insert_webhook(db, &CONFIG.db.tables.webhooks, &hook).await {
+            Ok(id) => hook.webhook_id = id,
+            Err(e) => {
+                error!("Unable to save webhook: {}", e);
+                return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to save webhook"));
+            }
+        },
+        None => hook.webhook_id = hooks.keys().max().map_or(1, |id| id + 1),
+    }
+    info!("{} registered webhook {} for {}", hook.created_by, hook.webhook_id, hook.url);
+
+    let mut body = json!(WebhookInfo::from(&hook));
+    body["secret"] = json!(hook.secret);
+    hooks.insert(hook.webhook_id, hook);
+    Ok(reply::with_status(reply::json(&body), StatusCode::CREATED))
+}
+
+pub async fn list_webhooks(_user: String, state: ChatState) -> Result<Json, Infallible> {
+    let hooks = state.webhooks.lock().await;
+    let mut all: Vec<WebhookInfo> = hooks.values().map(WebhookInfo::from).collect();
+    all.sort_by_key(|h| h.id);
+    Ok(reply::json(&all))
+}
+
+pub async fn delete_webhook(
+    id: i32,
+    user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let mut hooks = state.webhooks.lock().await;
+    if !hooks.contains_key(&id) {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "No such webhook"));
+    }
+    if let Some(db) = &state.db {
+        if let Err(e) = pgdb::delete_webhook(db, &CONFIG.db.tables.webhooks, id).await {
+            error!("Unable to delete webhook {}: {}", id, e);
+            return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to delete webhook"));
+        }
+    }
+    hooks.remove(&id);
+    info!("{} deleted webhook {}", user, id);
+    Ok(reply::with_status(reply::json(&json!({ "deleted": id })), StatusCode::OK))
+}
+
+/// How many log rows the endpoints return
+const LOG_LIMIT: i64 = 100;
+
+/// The latest delivery attempts for a webhook
+pub async fn deliveries(
+    id: i32,
+    _user: String,
+    state: ChatState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")),
+    };
+    let table = &CONFIG.db.tables.webhook_deliveries;
+    match pgdb::list_deliveries(db, table, id, LOG_LIMIT).await {
+        Ok(rows) => Ok(reply::with_status(reply::json(&rows), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to read webhook deliveries: {}", e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read deliveries"))
+        }
+    }
+}
+
+/// The latest deliveries that failed for good
+pub async fn dead_letters(_user: String, state: ChatState) -> Result<WithStatus<Json>, Infallible> {
+    let db = match &state.db {
+        Some(db) => db,
+        None => return Ok(error_reply(StatusCode::SERVICE_UNAVAILABLE, "No database")),
+    };
+    match pgdb::list_dead_letters(db, &CONFIG.db.tables.dead_letters, LOG_LIMIT).await {
+        Ok(rows) => Ok(reply::with_status(reply::json(&rows), StatusCode::OK)),
+        Err(e) => {
+            error!("Unable to read dead letters: {}", e);
+            Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Unable to read dead letters"))
+        }
+    }
+}
+
+/// The webhook admin endpoints
+pub fn routes(state: ChatState) -> BoxedFilter<(impl Reply,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let create = warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(admin())
+        .and(warp::body::json())
+        .and(with_state.clone())
+        .and_then(create_webhook);
+
+    let list = warp::get()
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(list_webhooks);
+
+    let delete = warp::delete()
+        .and(warp::path!("webhooks" / i32))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(delete_webhook);
+
+    let logs = warp::get()
+        .and(warp::path!("webhooks" / i32 / "deliveries"))
+        .and(admin())
+        .and(with_state.clone())
+        .and_then(deliveries);
+
+    let dead = warp::get()
+        .and(warp::path!("webhooks" / "dead_letters"))
+        .and(admin())
+        .and(with_state)
+        .and_then(dead_letters);
+
+    create
+        .or(list)
+        .or(dead)
+        .or(delete)
+        .or(logs)
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::SocketAddr,
+              sync::atomic::{AtomicUsize,
+                             Ordering}};
+    use warp::http::HeaderMap;
+
+    type Received = Arc<std::sync::Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// Starts a local stand-in for a webhook receiver.  The first `failures` requests get a 500.
+    fn receiver(failures: usize) -> (SocketAddr, Received) {
+        let received: Received = Arc::new(std::sync::Mutex::new(vec![]));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let store = received.clone();
+
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers: HeaderMap, body: warp::hyper::body::Bytes| {
+                let body = String::from_utf8_lossy(&body).to_string();
+                store.lock().unwrap().push((headers, body));
+                let status = if hits.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                };
+                reply::with_status("", status)
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    fn hook(addr: SocketAddr) -> models::Webhook {
+        models::Webhook {
+            webhook_id: 1,
+            url: format!("http://{}/hook", addr),
+            room: Some("dnd".into()),
+            events: vec!["Message".into()],
+            direct_messages: false,
+            secret: "whsec_test".into(),
+            created_by: "stoner".into(),
+            created_on: Utc::now(),
+        }
+    }
+
+    fn cfg(max_attempts: u32) -> WebhookCfg {
+        WebhookCfg {
+            max_attempts,
+            backoff_ms: 10,
+            max_backoff_ms: 40,
+            timeout_secs: 5,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_match() {
+        let sig = sign("secret", 1600000000, "{}");
+        assert!(sig.starts_with("sha256="));
+        assert_eq!(sig.len(), "sha256=".len() + 64);
+        assert!(verify("secret", 1600000000, "{}", &sig));
+        assert!(!verify("secret", 1600000001, "{}", &sig));
+        assert!(!verify("other", 1600000000, "{}", &sig));
+
+        let backoffs: Vec<u64> =
+            (1..=5).map(|a| backoff(&cfg(5), a).as_millis() as u64).collect();
+        assert_eq!(backoffs, vec![10, 20, 40, 40, 40]);
+
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut h = hook(addr);
+        assert!(wants(&h, "Message", Some("dnd")));
+        assert!(!wants(&h, "Message", Some("lobby")));
+        assert!(!wants(&h, "Connect", Some("dnd")));
+        h.room = None;
+        assert!(wants(&h, "Message", Some("lobby")));
+        assert!(!wants(&h, "Message", None));
+        h.direct_messages = true;
+        assert!(wants(&h, "Message", None));
+    }
+
+    #[test]
+    fn test_private() {
+        let mut mesg = KMessage::new("rubik".into(), vec![], MessageEvent::Message, "hi");
+        assert!(!private(&mesg.event_type, &json!(mesg)));
+        mesg.encrypted = true;
+        assert!(private(&mesg.event_type, &json!(mesg)));
+
+        let command = |op: &str| json!({ "cmd": { "op": op, "ack": true, "id": "" }, "args": "" });
+        for (op, secret) in &[("SDPOffer", true), ("IceCandidate", true), ("Ping", false)] {
+            let event = MessageEvent::CommandRequest;
+            let mesg = KMessage::new("rubik".into(), vec![], event, command(op));
+            assert_eq!(private(&mesg.event_type, &json!(mesg)), *secret);
+        }
+        let call = KMessage::new("rubik".into(), vec![], MessageEvent::Call, json!({}));
+        assert!(private(&call.event_type, &json!(call)));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_with_retries() {
+        let (addr, received) = receiver(2);
+        let delivery = Delivery::new(hook(addr), "Message", r#"{"body":"hi"}"#.into());
+
+        let result = deliver(&CLIENT, &delivery, &cfg(3), None).await;
+        assert_eq!(result, Ok(200));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        for (headers, body) in received.iter() {
+            let header = |name: &str| headers[name].to_str().unwrap().to_string();
+            assert_eq!(body, r#"{"body":"hi"}"#);
+            assert_eq!(header("x-khadga-event"), "Message");
+            assert_eq!(header("x-khadga-delivery"), delivery.id);
+            let timestamp: i64 = header("x-khadga-timestamp").parse().unwrap();
+            assert!(verify("whsec_test", timestamp, body, &header("x-khadga-signature")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up() {
+        let (addr, received) = receiver(usize::MAX);
+        let delivery = Delivery::new(hook(addr), "Message", "{}".into());
+
+        let result = deliver(&CLIENT, &delivery, &cfg(2), None).await;
+        assert_eq!(result, Err("HTTP 500 Internal Server Error".into()));
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,639 @@
+//! The typed wire format
+//!
+//! Originally every event was a `message::Message<String>`, and the `body` of anything that wasn't
+//! a chat message was usually another JSON document in a string, which handlers parsed again (and
+//! panicked on).  From protocol version 2 (see `protocol`) a message on the wire is an `Envelope`:
+//! the same sender, recipients, room and so on, plus an `event` tag and a typed `payload`:
+//!
+//! ```json
+//! {"sender": "stoner", "recipients": ["whammo"], "time": 1600000000000,
+//!  "event": "CommandRequest",
+//!  "payload": {"op": "SDPOffer", "id": "call-1",
+//!              "description": {"type": "offer", "sdp": "v=0..."}}}
+//! ```
+//!
+//! Clients that negotiated an older version (or none) still get the old format, and `decode` still
+//! understands it, whichever version the client said it speaks.  That compatibility is for one
+//! release: after that, `protocol::MIN_VERSION` goes up to 2 and the legacy half of this module
+//! goes away.
+//!
+//! Inside khadga, chat messages are still a `Message<String>` (the body of a chat message really
+//! is a string), so filters, bots, webhooks and the database don't change.  Everything is decoded
+//! here once, at the edge.
+
+use crate::{commands::CommandResponse,
+            filter::Rejection,
+            mesh::MeshEvent,
+            message::{ConnectionMsg,
+                      Message as KMessage,
+                      MessageEvent},
+            protocol::{Hello,
+                       Welcome},
+            questions::Suggestion,
+            signaling::{CallAction,
+                        CallEvent},
+            trends::TrendAlert};
+use serde::{de::DeserializeOwned,
+            Deserialize,
+            Serialize};
+use serde_json::{json,
+                 Value};
+use std::collections::HashMap;
+
+/// The first protocol version that speaks this format
+pub const TAGGED_VERSION: u32 = 2;
+
+/// One message on the wire
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub sender: String,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub time: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<i32>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Every event, with its payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", content = "payload")]
+pub enum Event {
+    Connect(ConnectionMsg),
+    Disconnect(ConnectionMsg),
+    /// A chat message.  Ciphertext if the envelope is `encrypted`.
+    Message(String),
+    /// Anything at all, passed along as is
+    Data(Value),
+    CommandRequest(Request),
+    CommandReply(Reply),
+    Rejected(Rejection),
+    Alert(TrendAlert),
+    Suggestion(Suggestion),
+    Hello(Hello),
+    Welcome(Welcome),
+    Call(CallEvent),
+    Mesh(MeshEvent),
+}
+
+/// A WebRTC session description, as `RTCSessionDescription.toJSON()` gives it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sdp: String,
+}
+
+/// A WebRTC ICE candidate, as `RTCIceCandidate.toJSON()` gives it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IceCandidate {
+    pub candidate: String,
+    #[serde(default, rename = "sdpMid", skip_serializing_if = "Option::is_none")]
+    pub sdp_mid: Option<String>,
+    #[serde(default, rename = "sdpMLineIndex", skip_serializing_if = "Option::is_none")]
+    pub sdp_mline_index: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum Request {
+    /// khadga checking the client is still there
+    Ping {
+        id: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    SDPOffer {
+        id: String,
+        description: SessionDescription,
+    },
+    SDPAnswer {
+        id: String,
+        description: SessionDescription,
+    },
+    IceCandidate {
+        id: String,
+        candidate: IceCandidate,
+    },
+    /// A slash command for khadga to run (see `commands`)
+    Slash {
+        id: String,
+        line: String,
+    },
+    /// Invites someone to a call, or answers or ends one (see `signaling`)
+    Call {
+        id: String,
+        action: CallAction,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum Reply {
+    Pong {
+        id: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    Slash {
+        id: String,
+        response: CommandResponse,
+    },
+}
+
+impl Event {
+    pub fn event_type(&self) -> MessageEvent {
+        match self {
+            Event::Connect(_) => MessageEvent::Connect,
+            Event::Disconnect(_) => MessageEvent::Disconnect,
+            Event::Message(_) => MessageEvent::Message,
+            Event::Data(_) => MessageEvent::Data,
+            Event::CommandRequest(_) => MessageEvent::CommandRequest,
+            Event::CommandReply(_) => MessageEvent::CommandReply,
+            Event::Rejected(_) => MessageEvent::Rejected,
+            Event::Alert(_) => MessageEvent::Alert,
+            Event::Suggestion(_) => MessageEvent::Suggestion,
+            Event::Hello(_) => MessageEvent::Hello,
+            Event::Welcome(_) => MessageEvent::Welcome,
+            Event::Call(_) => MessageEvent::Call,
+            Event::Mesh(_) => MessageEvent::Mesh,
+        }
+    }
+}
+
+/// The `cmd` of an old style command.  The op is a string because clients sent "pong" as well as
+/// "Pong".  The `ack` flag meant nothing to khadga, so it is dropped.
+#[derive(Deserialize, Debug)]
+struct LegacyCommand {
+    op: String,
+    #[serde(default)]
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LegacyCommandBody {
+    cmd: LegacyCommand,
+    #[serde(default)]
+    args: Value,
+    #[serde(default)]
+    response: Value,
+}
+
+/// The JSON of the value, as a string
+fn json_string<T: Serialize>(value: &T) -> Value {
+    Value::String(serde_json::to_string(value).expect("Unable to serialize"))
+}
+
+/// Old style args were sometimes left out when there were none
+fn or_empty(args: Value) -> Value {
+    if args.is_null() {
+        Value::Array(vec![])
+    } else {
+        args
+    }
+}
+
+/// Reads an old style body, which may be the JSON itself or a string holding it
+fn legacy_body<T: DeserializeOwned>(body: Value) -> Result<T, String> {
+    let parsed = match body {
+        Value::String(text) => {
+            serde_json::from_str(&text).or_else(|_| serde_json::from_value(Value::String(text)))
+        }
+        body => serde_json::from_value(body),
+    };
+    parsed.map_err(|e| e.to_string())
+}
+
+fn legacy_request(body: LegacyCommandBody) -> Result<Request, String> {
+    let LegacyCommandBody { cmd, args, .. } = body;
+    let id = cmd.id;
+    match cmd.op.as_str() {
+        "Ping" | "ping" => Ok(Request::Ping {
+            id,
+            args: legacy_body(or_empty(args))?,
+        }),
+        "SDPOffer" => Ok(Request::SDPOffer {
+            id,
+            description: legacy_body(args)?,
+        }),
+        "SDPAnswer" => Ok(Request::SDPAnswer {
+            id,
+            description: legacy_body(args)?,
+        }),
+        "IceCandidate" => Ok(Request::IceCandidate {
+            id,
+            candidate: legacy_body(args)?,
+        }),
+        "Slash" => match args {
+            Value::String(line) => Ok(Request::Slash { id, line }),
+            _ => Err("The args of a Slash command must be the command line".into()),
+        },
+        "Call" => Ok(Request::Call {
+            id,
+            action: legacy_body(args)?,
+        }),
+        op => Err(format!("Unknown command request {}", op)),
+    }
+}
+
+fn legacy_reply(body: LegacyCommandBody) -> Result<Reply, String> {
+    let LegacyCommandBody { cmd, args, response } = body;
+    let id = cmd.id;
+    match cmd.op.as_str() {
+        "Pong" | "pong" => Ok(Reply::Pong {
+            id,
+            args: legacy_body(or_empty(args))?,
+        }),
+        "Slash" => Ok(Reply::Slash {
+            id,
+            response: legacy_body(response)?,
+        }),
+        op => Err(format!("Unknown command reply {}", op)),
+    }
+}
+
+impl Envelope {
+    pub fn new(sender: String, recipients: Vec<String>, event: Event) -> Self {
+        Envelope {
+            sender,
+            recipients,
+            time: chrono::Utc::now().timestamp_millis(),
+            room: None,
+            bot: false,
+            encrypted: false,
+            conversation: None,
+            annotations: HashMap::new(),
+            event,
+        }
+    }
+
+    /// A `Message` with the same sender, recipients, room and so on, but the given body
+    pub fn message<T>(&self, body: T) -> KMessage<T> {
+        let event_type = self.event.event_type();
+        let recipients = self.recipients.clone();
+        let mut mesg = KMessage::new(self.sender.clone(), recipients, event_type, body);
+        mesg.time = self.time;
+        mesg.room = self.room.clone();
+        mesg.bot = self.bot;
+        mesg.encrypted = self.encrypted;
+        mesg.conversation = self.conversation;
+        mesg.annotations = self.annotations.clone();
+        mesg
+    }
+
+    /// Converts an old style message
+    pub fn from_legacy(mesg: KMessage<Value>) -> Result<Envelope, String> {
+        let body = mesg.body.clone();
+        let event = match mesg.event_type {
+            MessageEvent::Connect => Event::Connect(legacy_body(body)?),
+            MessageEvent::Disconnect => Event::Disconnect(legacy_body(body)?),
+            MessageEvent::Message => match body {
+                Value::String(text) => Event::Message(text),
+                _ => return Err("The body of a Message must be a string".into()),
+            },
+            MessageEvent::Data => Event::Data(body),
+            MessageEvent::CommandRequest => {
+                Event::CommandRequest(legacy_request(legacy_body(body)?)?)
+            }
+            MessageEvent::CommandReply => Event::CommandReply(legacy_reply(legacy_body(body)?)?),
+            MessageEvent::Rejected => Event::Rejected(legacy_body(body)?),
+            MessageEvent::Alert => Event::Alert(legacy_body(body)?),
+            MessageEvent::Suggestion => Event::Suggestion(legacy_body(body)?),
+            MessageEvent::Hello => Event::Hello(legacy_body(body)?),
+            MessageEvent::Welcome => Event::Welcome(legacy_body(body)?),
+            MessageEvent::Call => Event::Call(legacy_body(body)?),
+            MessageEvent::Mesh => Event::Mesh(legacy_body(body)?),
+        };
+        Ok(Envelope {
+            sender: mesg.sender,
+            recipients: mesg.recipients,
+            time: mesg.time,
+            room: mesg.room,
+            bot: mesg.bot,
+            encrypted: mesg.encrypted,
+            conversation: mesg.conversation,
+            annotations: mesg.annotations,
+            event,
+        })
+    }
+
+    /// Converts any message khadga sends, eg `Message<Suggestion>`
+    pub fn from_message<T: Serialize>(mesg: &KMessage<T>) -> Result<Envelope, String> {
+        let value = serde_json::to_value(mesg).map_err(|e| e.to_string())?;
+        Envelope::from_legacy(serde_json::from_value(value).map_err(|e| e.to_string())?)
+    }
+
+    /// The old style message, for clients that don't speak the tagged format
+    ///
+    /// Commands come out the way the vision client has always sent them: the `args` of signaling
+    /// commands are a JSON string.
+    pub fn to_legacy(&self) -> KMessage<Value> {
+        let command = |op: &str, ack: bool, id: &str, args: Value| {
+            json!({ "cmd": { "op": op, "ack": ack, "id": id }, "args": args })
+        };
+        let body = match &self.event {
+            Event::Connect(users) | Event::Disconnect(users) => json!(users),
+            Event::Message(text) => Value::String(text.clone()),
+            Event::Data(data) => data.clone(),
+            Event::CommandRequest(request) => match request {
+                Request::Ping { id, args } => command("Ping", true, id, json!(args)),
+                Request::SDPOffer { id, description } => {
+                    command("SDPOffer", true, id, json_string(description))
+                }
+                Request::SDPAnswer { id, description } => {
+                    command("SDPAnswer", false, id, json_string(description))
+                }
+                Request::IceCandidate { id, candidate } => {
+                    command("IceCandidate", true, id, json_string(candidate))
+                }
+                Request::Slash { id, line } => command("Slash", true, id, json!(line)),
+                Request::Call { id, action } => command("Call", true, id, json!(action)),
+            },
+            Event::CommandReply(reply) => match reply {
+                Reply::Pong { id, args } => command("Pong", false, id, json!(args)),
+                Reply::Slash { id, response } => json!({
+                    "cmd": { "op": "Slash", "ack": false, "id": id },
+                    "response": response
+                }),
+            },
+            Event::Rejected(rejection) => json!(rejection),
+            Event::Alert(alert) => json!(alert),
+            Event::Suggestion(suggestion) => json!(suggestion),
+            Event::Hello(hello) => json!(hello),
+            Event::Welcome(welcome) => json!(welcome),
+            Event::Call(call) => json!(call),
+            Event::Mesh(mesh) => json!(mesh),
+        };
+        self.message(body)
+    }
+}
+
+/// Decodes a message from a client, in either format
+pub fn decode(text: &str) -> Result<Envelope, String> {
+    let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    if value.get("event").is_some() {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    } else {
+        let legacy: KMessage<Value> = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        Envelope::from_legacy(legacy)
+    }
+}
+
+/// A message encoded for both formats, so sending it to many clients only encodes it twice
+pub struct Encoded {
+    legacy: String,
+    tagged: Option<String>,
+}
+
+impl Encoded {
+    pub fn new<T: Serialize>(mesg: &KMessage<T>) -> Self {
+        let legacy = serde_json::to_string(mesg).expect("Unable to serialize to Message");
+        let tagged = match Envelope::from_message(mesg) {
+            Ok(envelope) => serde_json::to_string(&envelope).ok(),
+            Err(e) => {
+                log::error!("Unable to encode a {} as an envelope: {}", mesg.event_type, e);
+                None
+            }
+        };
+        Encoded { legacy, tagged }
+    }
+
+    /// The text to send to a client that negotiated the given protocol version
+    pub fn for_version(&self, version: u32) -> &str {
+        match &self.tagged {
+            Some(tagged) if version >= TAGGED_VERSION => tagged,
+            _ => &self.legacy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commands::CommandError,
+                protocol::{negotiate,
+                           Negotiated},
+                signaling::CallState,
+                trends::Trend};
+
+    fn envelope(event: Event) -> Envelope {
+        let mut envelope = Envelope::new("stoner".into(), vec!["whammo".into()], event);
+        envelope.room = Some("dnd".into());
+        envelope
+    }
+
+    fn description() -> SessionDescription {
+        SessionDescription {
+            kind: "offer".into(),
+            sdp: "v=0\r\no=- 46117317 2 IN IP4 127.0.0.1\r\n".into(),
+        }
+    }
+
+    /// One of every event, command and reply
+    fn samples() -> Vec<Envelope> {
+        let users = ConnectionMsg::new(vec!["stoner".into(), "whammo".into()]);
+        let hello = Hello {
+            version: 2,
+            min_version: Some(1),
+            codecs: vec!["json".into()],
+            features: vec!["rooms".into()],
+        };
+        let suggestion: Suggestion = serde_json::from_value(json!({
+            "question": {
+                "id": 1, "room": "dnd", "asker": "rubik", "body": "How do I roll initiative?",
+                "time": 1, "answers": [{ "sender": "whammo", "body": "d20 + dex", "time": 2 }]
+            },
+            "similarity": 0.75
+        }))
+        .unwrap();
+        let mut encrypted = envelope(Event::Message("c2VjcmV0".into()));
+        encrypted.encrypted = true;
+        encrypted.room = None;
+        encrypted.conversation = Some(7);
+        let mut annotated = envelope(Event::Message("hello".into()));
+        annotated.bot = true;
+        annotated.annotations.insert("lang".into(), "en".into());
+
+        vec![
+            envelope(Event::Connect(users.clone())),
+            envelope(Event::Disconnect(users)),
+            envelope(Event::Message("hello there".into())),
+            encrypted,
+            annotated,
+            envelope(Event::Data(json!({ "dice": [4, 6], "note": "\"quoted\"" }))),
+            envelope(Event::CommandRequest(Request::Ping {
+                id: "khadga-1".into(),
+                args: vec![],
+            })),
+            envelope(Event::CommandRequest(Request::SDPOffer {
+                id: "call-1".into(),
+                description: description(),
+            })),
+            envelope(Event::CommandRequest(Request::SDPAnswer {
+                id: "call-1".into(),
+                description: SessionDescription {
+                    kind: "answer".into(),
+                    ..description()
+                },
+            })),
+            envelope(Event::CommandRequest(Request::IceCandidate {
+                id: "call-1".into(),
+                candidate: IceCandidate {
+                    candidate: "candidate:1 1 UDP 2122252543 192.168.1.2 54400 typ host".into(),
+                    sdp_mid: Some("0".into()),
+                    sdp_mline_index: Some(0),
+                },
+            })),
+            envelope(Event::CommandRequest(Request::Slash {
+                id: "7".into(),
+                line: "/roll 2d6".into(),
+            })),
+            envelope(Event::CommandRequest(Request::Call {
+                id: "".into(),
+                action: CallAction::Invite,
+            })),
+            envelope(Event::CommandReply(Reply::Pong {
+                id: "stoner".into(),
+                args: vec!["x".into()],
+            })),
+            envelope(Event::CommandReply(Reply::Slash {
+                id: "7".into(),
+                response: CommandResponse::Output(json!({ "rolls": [3, 5] })),
+            })),
+            envelope(Event::CommandReply(Reply::Slash {
+                id: "8".into(),
+                response: CommandResponse::Error(CommandError {
+                    command: "/nope".into(),
+                    message: "Unknown command".into(),
+                    usage: None,
+                }),
+            })),
+            envelope(Event::Rejected(Rejection {
+                filter: "profanity".into(),
+                reason: "Watch your language".into(),
+                time: 1,
+            })),
+            envelope(Event::Alert(TrendAlert {
+                room: "dnd".into(),
+                trends: vec![Trend {
+                    term: "dragon".into(),
+                    count: 12,
+                    expected: 1.5,
+                    score: 4.0,
+                }],
+            })),
+            envelope(Event::Suggestion(suggestion)),
+            envelope(Event::Hello(hello.clone())),
+            envelope(Event::Welcome(Welcome::new(negotiate(&hello).unwrap()))),
+            envelope(Event::Welcome(Welcome::new(Negotiated::legacy()))),
+            envelope(Event::Call(CallEvent {
+                id: "0123456789abcdef".into(),
+                caller: "stoner".into(),
+                callee: "whammo".into(),
+                state: CallState::Ended,
+                started: 1,
+                duration: Some(42),
+                reason: Some("stoner hung up".into()),
+            })),
+            envelope(Event::Mesh(MeshEvent {
+                id: "fedcba9876543210".into(),
+                room: "dnd".into(),
+                participants: vec!["whammo".into(), "rubik".into(), "stoner".into()],
+                user: "stoner".into(),
+                joined: true,
+                connect_to: vec!["whammo".into(), "rubik".into()],
+            })),
+        ]
+    }
+
+    #[test]
+    fn test_tagged_round_trip() {
+        for sample in samples() {
+            let text = serde_json::to_string(&sample).unwrap();
+            assert_eq!(decode(&text), Ok(sample.clone()), "{}", text);
+            let value: Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(value["event"], json!(sample.event.event_type().to_string()));
+        }
+    }
+
+    #[test]
+    fn test_legacy_round_trip() {
+        for sample in samples() {
+            let legacy = sample.to_legacy();
+            assert_eq!(legacy.event_type.to_string(), sample.event.event_type().to_string());
+            let text = serde_json::to_string(&legacy).unwrap();
+            assert_eq!(decode(&text), Ok(sample.clone()), "{}", text);
+            assert_eq!(Envelope::from_message(&legacy), Ok(sample.clone()));
+
+            let encoded = Encoded::new(&legacy);
+            assert_eq!(encoded.for_version(1), text);
+            assert_eq!(decode(encoded.for_version(TAGGED_VERSION)), Ok(sample));
+        }
+    }
+
+    #[test]
+    fn test_legacy_clients() {
+        // What the vision client sends: bodies are JSON in a string, and so are signaling args
+        let sdp = serde_json::to_string(&description()).unwrap();
+        let body = json!({ "cmd": { "op": "SDPOffer", "id": "", "ack": true }, "args": sdp });
+        let offer = json!({
+            "sender": "stoner", "recipients": ["whammo"], "time": 5,
+            "event_type": "CommandRequest", "body": body.to_string()
+        });
+        let got = decode(&offer.to_string()).unwrap();
+        assert_eq!(
+            got.event,
+            Event::CommandRequest(Request::SDPOffer {
+                id: "".into(),
+                description: description()
+            })
+        );
+
+        let pong = r#"{"sender":"khadga","recipients":[],"event_type":"CommandReply","time":1,
+            "body":"{\"cmd\":{\"op\":\"pong\",\"ack\":false,\"id\":\"placeoftheway\"},\"args\":[]}"}"#;
+        let got = decode(pong).unwrap();
+        assert_eq!(
+            got.event,
+            Event::CommandReply(Reply::Pong {
+                id: "placeoftheway".into(),
+                args: vec![]
+            })
+        );
+
+        let slash = KMessage::new(
+            "stoner".to_string(),
+            vec![],
+            MessageEvent::CommandRequest,
+            json!({ "cmd": { "op": "Slash", "id": "1" }, "args": "/who" }).to_string(),
+        );
+        let got = decode(&serde_json::to_string(&slash).unwrap()).unwrap();
+        assert_eq!(got.event, Event::CommandRequest(Request::Slash {
+            id: "1".into(),
+            line: "/who".into()
+        }));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(decode("not json").is_err());
+        assert!(decode(r#"{"sender": "stoner", "event": "Nope", "payload": 1}"#).is_err());
+        let not_text = r#"{"sender": "stoner", "event": "Message", "payload": {"a": 1}}"#;
+        assert!(decode(not_text).is_err());
+
+        let mut legacy = KMessage::new("stoner".into(), vec![], MessageEvent::Message, json!(1));
+        assert!(Envelope::from_legacy(legacy).is_err());
+        let body = json!({ "cmd": { "op": "Teleport", "id": "1" }, "args": [] });
+        legacy = KMessage::new("stoner".into(), vec![], MessageEvent::CommandRequest, body);
+        assert!(Envelope::from_legacy(legacy).unwrap_err().contains("Teleport"));
+    }
+}
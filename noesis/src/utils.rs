@@ -23,8 +23,7 @@ pub fn set_panic_hook() {
 
 #[wasm_bindgen]
 pub fn get_window() -> Window {
-    let window = web_sys::window().expect("Did not have a global window object");
-    window
+    web_sys::window().expect("Did not have a global window object")
 }
 
 #[wasm_bindgen]
@@ -38,16 +37,14 @@ pub fn get_document() -> Document {
 #[wasm_bindgen]
 pub fn get_body() -> HtmlElement {
     let doc = get_document();
-    let body = doc.body().expect("document must have a body");
-    body
+    doc.body().expect("document must have a body")
 }
 
 /// Returns a Navigator object
 #[wasm_bindgen]
 pub fn get_navigator() -> Navigator {
     let window = get_window();
-    let nav = window.navigator();
-    nav
+    window.navigator()
 }
 
 /// Retrieves MediaStream from the navigator
@@ -39,6 +39,15 @@ class ChatContainer extends React.Component<PropsFromRedux> {
       const ownMessage = msg.sender === this.props.user;
 
       let chatmessage = <ChatMessage body={msg.body} sender={ msg.sender } time={ msg.time } />;
+      if (msg.bot) {
+        chatmessage = (
+          <ChatMessage body={msg.body}
+            sender={ msg.sender }
+            time={ msg.time }
+            bot={ true }
+            highlight=" bot-highlight"/>
+        );
+      }
       if (ownMessage) {
         chatmessage = (
           <ChatMessage body={msg.body}
@@ -4,6 +4,7 @@ export interface MessageBody {
 	body: string;
 	sender: string;
 	time: string;
+	bot?: boolean;
 }
 
 type MediaProps = MessageBody & {
@@ -17,7 +18,8 @@ export class ChatMessage extends React.Component<MediaProps> {
 
     return (
       <div className={ style }>
-        <strong>{ this.props.sender }</strong> <small>{ this.props.time }</small>
+        <strong>{ this.props.sender }</strong>
+        { this.props.bot ? <span className="bot-tag">BOT</span> : null } <small>{ this.props.time }</small>
         <br />
         <p>
           { this.props.body }
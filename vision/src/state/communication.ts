@@ -17,6 +17,7 @@ import {
 import {
   WsMessage,
   makeChatMessage,
+  makeHello,
   CHAT_MESSAGE_ADD,
   WsCommand,
   MessageEvent as MsgEvent
@@ -227,7 +228,9 @@ export class WebComm {
     // FIXME:  Add JWT token
     const url = `wss://${origin}/chat/${user}`;
     logger.log(`Connecting to ${url}`);
-    this.socket$.next(new WebSocket(url));
+    const socket = new WebSocket(url);
+    socket.addEventListener("open", () => socket.send(JSON.stringify(makeHello(user))));
+    this.socket$.next(socket);
   }
 
   /**
@@ -272,6 +275,7 @@ export class WebComm {
             props.loginAction(connected_users, "", auth, USER_CONNECTION_EVT);
             logger.log(`loginAction is`, props.loginAction);
             break;
+          case "Welcome":
           case "Data":
             logger.log(`Got ${msg.event_type} websocket event`, msg);
             break;
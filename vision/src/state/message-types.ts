@@ -1,10 +1,18 @@
-export type MessageEvent = "Connect"
-                         | "Disconnect"
-                         | "Message"
-                         | "Data"
-                         | "CommandRequest"
-                         | "CommandReply"
-                         ;
+// The protocol itself is generated from the khadga types, in protocol.ts
+import {
+  CallAction,
+  CallEvent,
+  CommandRequestMsg,
+  CommandTypes,
+  ConnectionMsg,
+  Hello,
+  MeshEvent,
+  Message,
+  MessageEvent as ProtocolEvent,
+  Welcome
+} from "./protocol";
+
+export type MessageEvent = ProtocolEvent;
 
 export const CHAT_MESSAGE_ADD = "CHAT_MESSAGE_ADD";
 export const CHAT_MESSAGE_DELETE = "CHAT_MESSAGE_DELETE";
@@ -16,43 +24,45 @@ export type CHAT_MESSAGE_ACTIONS = "CHAT_MESSAGE_ADD"
                                  | "CHAT_MESSAGE_REPLY"
                                  ;
 
-export interface UserConnectionEventMessage {
-  connected_users: string[]
-}
+export type UserConnectionEventMessage = ConnectionMsg;
+
+/// The body of a Hello, the first message a client sends (see khadga's protocol.rs)
+export type HelloMessage = Hello;
+
+/// The body of khadga's Welcome reply
+export type WelcomeMessage = Welcome;
+
+/// What this client speaks.  It only handles commands beyond plain messages, so khadga won't send
+/// it alerts, suggestions or call events.
+export const HELLO: HelloMessage = {
+  version: 1,
+  min_version: 1,
+  codecs: ["json"],
+  features: ["commands"]
+};
 
 /// This is the typescript equivalent of the message::Message type
-export interface WsMessage<T> {
-  sender: string,
-  recipients: string[],
-  body: T,
-  event_type: MessageEvent,
-  time: number  // milliseconds since epoch
-}
+export type WsMessage<T> = Message<T>;
 
-export type WsCommandTypes = "Ping"
-                           | "Pong"
-                           | "SDPOffer"
-                           | "SDPAnswer"
-                           | "IceCandidate"
-                           | "Editor"
-                           ;
-
-// FIXME: The args should be generic
-// We might also want fixed op types.
-export interface WsCommand<T> {
-  cmd: {
-    op: WsCommandTypes,
-    id: string,
-    ack: boolean
-  },
-  args: T
-}
+export type WsCommandTypes = CommandTypes;
+
+export type WsCommand<T> = CommandRequestMsg<T>;
+
+/// The args of a Call command, and the body of the Call event khadga sends whenever a call changes
+/// state (see khadga's signaling.rs)
+export type CallActionArgs = CallAction;
+export type CallEventMessage = CallEvent;
+
+/// The body of the Mesh event khadga sends whenever somebody joins or leaves a room's call (see
+/// khadga's mesh.rs)
+export type MeshEventMessage = MeshEvent;
 
 export interface ChatMessageState {
   sender: string,
   recipients: string[],
   time: string,
-  body: string
+  body: string,
+  bot?: boolean,
   replies?: ChatMessageState
 }
 
@@ -65,11 +75,26 @@ export interface ChatMessageAction {
 }
 
 
+/**
+ * The first message on a new websocket.  Without it khadga waits a few seconds before treating the
+ * connection as one from before the handshake.
+ */
+export const makeHello = (user: string): WsMessage<HelloMessage> => {
+  return {
+    sender: user,
+    recipients: [],
+    body: HELLO,
+    event_type: "Hello",
+    time: Date.now()
+  };
+};
+
 export const makeChatMessage = (msg: WsMessage<string>): ChatMessageState => {
   return {
     sender: msg.sender,
     recipients: msg.recipients,
     body: msg.body,
+    bot: msg.bot,
     time: new Date(msg.time).toUTCString()
   };
 };
\ No newline at end of file
@@ -0,0 +1,212 @@
+// Generated by khadga-typegen from the khadga message types.  Do not edit,
+// run `cargo run --bin khadga-typegen` in khadga instead.
+
+export interface Message<T> {
+  sender: string,
+  recipients: string[],
+  body: T,
+  event_type: MessageEvent,
+  time: number,
+  room?: string | null,
+  bot?: boolean,
+  encrypted?: boolean,
+  conversation?: number | null,
+  annotations?: { [key: string]: string }
+}
+
+export type MessageEvent = "Connect"
+                         | "Disconnect"
+                         | "CommandRequest"
+                         | "CommandReply"
+                         | "Message"
+                         | "Data"
+                         | "Rejected"
+                         | "Alert"
+                         | "Suggestion"
+                         | "Hello"
+                         | "Welcome"
+                         | "Call"
+                         | "Mesh"
+                         ;
+
+export interface ConnectionMsg {
+  connected_users: string[]
+}
+
+export interface CommandRequestMsg<T> {
+  cmd: Command,
+  args: T
+}
+
+export interface Command {
+  op: CommandTypes,
+  ack: boolean,
+  id: string
+}
+
+export type CommandTypes = "Ping"
+                         | "Pong"
+                         | "SDPOffer"
+                         | "SDPAnswer"
+                         | "IceCandidate"
+                         | "Slash"
+                         | "Call"
+                         ;
+
+export interface CommandReplyMsg<T> {
+  cmd: Command,
+  response: T
+}
+
+export interface Hello {
+  version: number,
+  min_version?: number | null,
+  codecs?: string[],
+  features?: string[]
+}
+
+export interface Welcome {
+  version: number,
+  min_version: number,
+  codecs: string[],
+  features: string[],
+  negotiated: Negotiated
+}
+
+export interface Negotiated {
+  version: number,
+  codec: string,
+  features: string[]
+}
+
+export type CallAction = "Invite"
+                       | "Accept"
+                       | "Reject"
+                       | "HangUp"
+                       | "Join"
+                       | "Leave"
+                       ;
+
+export interface CallEvent {
+  id: string,
+  caller: string,
+  callee: string,
+  state: CallState,
+  started: number,
+  duration?: number | null,
+  reason?: string | null
+}
+
+export type CallState = "Ringing"
+                      | "Accepted"
+                      | "Rejected"
+                      | "Busy"
+                      | "Missed"
+                      | "Ended"
+                      ;
+
+export interface MeshEvent {
+  id: string,
+  room: string,
+  participants: string[],
+  user: string,
+  joined: boolean,
+  connect_to?: string[]
+}
+
+export interface Rejection {
+  filter: string,
+  reason: string,
+  time: number
+}
+
+export interface TrendAlert {
+  room: string,
+  trends: Trend[]
+}
+
+export interface Trend {
+  term: string,
+  count: number,
+  expected: number,
+  score: number
+}
+
+export interface Suggestion {
+  question: Question,
+  similarity: number
+}
+
+export interface Question {
+  id: number,
+  room: string,
+  asker: string,
+  body: string,
+  time: number,
+  answers: Answer[]
+}
+
+export interface Answer {
+  sender: string,
+  body: string,
+  time: number
+}
+
+export type CommandResponse = { Output: any }
+                            | { Error: CommandError }
+                            ;
+
+export interface CommandError {
+  command: string,
+  message: string,
+  usage?: string | null
+}
+
+export type Envelope = {
+  sender: string,
+  recipients?: string[],
+  time?: number,
+  room?: string | null,
+  bot?: boolean,
+  encrypted?: boolean,
+  conversation?: number | null,
+  annotations?: { [key: string]: string }
+} & Event;
+
+export type Event = { event: "Connect", payload: ConnectionMsg }
+                  | { event: "Disconnect", payload: ConnectionMsg }
+                  | { event: "Message", payload: string }
+                  | { event: "Data", payload: any }
+                  | { event: "CommandRequest", payload: Request }
+                  | { event: "CommandReply", payload: Reply }
+                  | { event: "Rejected", payload: Rejection }
+                  | { event: "Alert", payload: TrendAlert }
+                  | { event: "Suggestion", payload: Suggestion }
+                  | { event: "Hello", payload: Hello }
+                  | { event: "Welcome", payload: Welcome }
+                  | { event: "Call", payload: CallEvent }
+                  | { event: "Mesh", payload: MeshEvent }
+                  ;
+
+export type Request = { op: "Ping", id: string, args?: string[] }
+                    | { op: "SDPOffer", id: string, description: SessionDescription }
+                    | { op: "SDPAnswer", id: string, description: SessionDescription }
+                    | { op: "IceCandidate", id: string, candidate: IceCandidate }
+                    | { op: "Slash", id: string, line: string }
+                    | { op: "Call", id: string, action: CallAction }
+                    ;
+
+export interface SessionDescription {
+  type: string,
+  sdp: string
+}
+
+export interface IceCandidate {
+  candidate: string,
+  sdpMid?: string | null,
+  sdpMLineIndex?: number | null
+}
+
+export type Reply = { op: "Pong", id: string, args?: string[] }
+                  | { op: "Slash", id: string, response: CommandResponse }
+                  ;